@@ -0,0 +1,46 @@
+use bmp_protocol::BmpDecoder;
+
+/// Feed `data` to a BmpDecoder `chunk` bytes at a time, decoding everything that's available
+/// after each chunk. Errors are fine, panics are not
+pub fn decode_all(data: &[u8], chunk: usize) {
+    let mut decoder = BmpDecoder::new();
+    let mut buf = vec![];
+
+    for piece in data.chunks(chunk.max(1)) {
+        buf.extend_from_slice(piece);
+
+        loop {
+            let (used, result) = decoder.decode_slice(&buf);
+            buf.drain(..used);
+            match result {
+                Ok(Some(_)) => {},
+                Ok(None) => break,
+                // A bad common header can't be skipped over
+                Err(_) if used == 0 => return,
+                Err(_) => {},
+            };
+        }
+    }
+}
+
+/// Split a buffer into BMP frames using the common header lengths, anything left over that
+/// doesn't make a complete frame ends up as the last element
+#[allow(dead_code)]
+pub fn split_frames(mut data: &[u8]) -> Vec<Vec<u8>> {
+    let mut frames = vec![];
+
+    while data.len() >= 6 {
+        let length = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+        if length < 6 || length > data.len() {
+            break;
+        }
+
+        frames.push(data[..length].to_vec());
+        data = &data[length..];
+    }
+    if !data.is_empty() {
+        frames.push(data.to_vec());
+    }
+
+    frames
+}
@@ -0,0 +1,14 @@
+#![no_main]
+
+//! Arbitrary bytes into BmpDecoder, both as a single buffer and split into chunks
+
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    common::decode_all(data, data.len());
+
+    // Vary the chunk size with the input so short reads at every offset get covered
+    common::decode_all(data, 1 + data.len() % 61);
+});
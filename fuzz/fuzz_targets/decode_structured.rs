@@ -0,0 +1,228 @@
+#![no_main]
+
+//! Structure-aware fuzzing of Peer Up, Peer Down and Initiation decoding
+//!
+//! Inputs are sequences of BMP frames, so the `.dump` files in `test_data/` can be used as seeds
+//! directly. The custom mutator picks a frame and mutates the fields inside it (OPENs,
+//! capabilities, reasons, TLVs) while keeping the common header length in step, so most inputs
+//! make it past framing and into the message decoders.
+
+use libfuzzer_sys::{fuzz_mutator, fuzz_target, fuzzer_mutate};
+
+mod common;
+
+// Common header + Per-Peer header, and the local address & ports in a Peer Up
+const PEER_HEADER_END: usize = 6 + 42;
+const PEER_UP_OPENS: usize = PEER_HEADER_END + 20;
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+/// Run libFuzzer's own byte-level mutations over `frame[range]`
+fn mutate_bytes(frame: &mut Vec<u8>, start: usize, end: usize) {
+    let start = start.min(frame.len());
+    let end = end.clamp(start, frame.len());
+
+    let mut region = frame[start..end].to_vec();
+    let len = region.len();
+    region.resize(len * 2 + 16, 0);
+    let max = region.len();
+    let len = fuzzer_mutate(&mut region, len, max);
+    region.truncate(len);
+
+    frame.splice(start..end, region);
+}
+
+/// Offsets of the TLVs starting at `offset`, stopping at the first one that doesn't fit
+fn tlvs(frame: &[u8], mut offset: usize) -> Vec<usize> {
+    let mut found = vec![];
+    while offset + 4 <= frame.len() {
+        found.push(offset);
+        offset += 4 + u16::from_be_bytes([frame[offset + 2], frame[offset + 3]]) as usize;
+    }
+    found
+}
+
+fn random_tlv(rng: &mut Rng) -> Vec<u8> {
+    let kind = rng.below(8) as u16;
+    let value = (0..rng.below(32)).map(|_| b'a' + rng.below(26) as u8).collect::<Vec<_>>();
+    // Mostly honest lengths, sometimes not
+    let length = if rng.below(4) == 0 { rng.below(64) } else { value.len() } as u16;
+
+    let mut tlv = kind.to_be_bytes().to_vec();
+    tlv.extend_from_slice(&length.to_be_bytes());
+    tlv.extend_from_slice(&value);
+    tlv
+}
+
+fn mutate_tlvs(frame: &mut Vec<u8>, start: usize, rng: &mut Rng) {
+    let found = tlvs(frame, start);
+    match (rng.below(5), found.is_empty()) {
+        (0, _) | (_, true) => {
+            let tlv = random_tlv(rng);
+            let at = found.get(rng.below(found.len() + 1)).copied().unwrap_or(frame.len());
+            frame.splice(at..at, tlv);
+        },
+        (1, false) => {
+            // TLV type
+            let at = found[rng.below(found.len())];
+            frame[at..at + 2].copy_from_slice(&(rng.below(8) as u16).to_be_bytes());
+        },
+        (2, false) => {
+            // TLV length
+            let at = found[rng.below(found.len())];
+            frame[at + 2..at + 4].copy_from_slice(&(rng.below(300) as u16).to_be_bytes());
+        },
+        (3, false) => {
+            let at = found[rng.below(found.len())];
+            frame.truncate(at + rng.below(frame.len() - at + 1));
+        },
+        _ => mutate_bytes(frame, found[rng.below(found.len())], frame.len()),
+    };
+}
+
+/// The start and end of each OPEN in a Peer Up, according to the BGP header lengths
+fn opens(frame: &[u8]) -> Vec<(usize, usize)> {
+    let mut found = vec![];
+    let mut offset = PEER_UP_OPENS;
+    while found.len() < 2 && offset + 19 <= frame.len() {
+        let length = u16::from_be_bytes([frame[offset + 16], frame[offset + 17]]) as usize;
+        found.push((offset, (offset + length.max(19)).min(frame.len())));
+        offset += length.max(19);
+    }
+    found
+}
+
+fn mutate_peer_up(frame: &mut Vec<u8>, rng: &mut Rng) {
+    let found = opens(frame);
+
+    match rng.below(7) {
+        // Peer flags, V and A change how everything after them is read
+        0 if frame.len() > 7 => frame[7] ^= 1 << (4 + rng.below(4)),
+        // BGP message length or type of an OPEN
+        1 if !found.is_empty() => {
+            let (start, _) = found[rng.below(found.len())];
+            if rng.below(2) == 0 {
+                frame[start + 16..start + 18].copy_from_slice(&(rng.below(200) as u16).to_be_bytes());
+            } else {
+                frame[start + 18] = rng.below(6) as u8;
+            }
+        },
+        // Optional parameters / capabilities of an OPEN
+        2 if !found.is_empty() => {
+            let (start, end) = found[rng.below(found.len())];
+            if end >= start + 29 {
+                let at = start + 29 + rng.below(end - start - 29 + 1);
+                if at < frame.len() {
+                    frame[at] = match rng.below(3) {
+                        0 => rng.below(256) as u8,
+                        1 => frame[at].wrapping_add(1),
+                        _ => frame[at].wrapping_sub(1),
+                    };
+                }
+            }
+        },
+        3 if !found.is_empty() => {
+            let (start, end) = found[rng.below(found.len())];
+            mutate_bytes(frame, start + 19, end);
+        },
+        // Cut the message off part way
+        4 => {
+            let at = rng.below(frame.len() + 1);
+            frame.truncate(at.max(6));
+        },
+        // Information TLVs after the OPENs
+        _ => {
+            let end = found.last().map(|(_, end)| *end).unwrap_or(PEER_UP_OPENS).min(frame.len());
+            mutate_tlvs(frame, end, rng);
+        },
+    };
+}
+
+fn mutate_peer_down(frame: &mut Vec<u8>, rng: &mut Rng) {
+    if frame.len() <= PEER_HEADER_END {
+        frame.resize(PEER_HEADER_END + 1, 0);
+    }
+
+    match rng.below(4) {
+        0 => frame[PEER_HEADER_END] = rng.below(10) as u8,
+        // Swap the data for a NOTIFICATION with random code / subcode and length
+        1 => {
+            frame.truncate(PEER_HEADER_END + 1);
+            frame.extend_from_slice(&[0xff; 16]);
+            frame.extend_from_slice(&(19 + 2 + rng.below(8) as u16).to_be_bytes());
+            frame.push(3);
+            frame.push(rng.below(8) as u8);
+            frame.push(rng.below(12) as u8);
+        },
+        2 => {
+            let at = PEER_HEADER_END + rng.below(frame.len() - PEER_HEADER_END + 1);
+            frame.truncate(at);
+        },
+        _ => mutate_bytes(frame, PEER_HEADER_END + 1, frame.len()),
+    };
+}
+
+fn mutate_frame(frame: &mut Vec<u8>, rng: &mut Rng) {
+    match frame.get(5) {
+        Some(3) => mutate_peer_up(frame, rng),
+        Some(2) => mutate_peer_down(frame, rng),
+        Some(4) | Some(5) => mutate_tlvs(frame, 6, rng),
+        _ => {
+            let len = frame.len();
+            mutate_bytes(frame, 0, len);
+        },
+    };
+
+    // Keep the common header in step with the body, apart from occasionally
+    if frame.len() >= 6 && rng.below(16) != 0 {
+        let length = frame.len() as u32;
+        frame[1..5].copy_from_slice(&length.to_be_bytes());
+    }
+}
+
+fuzz_mutator!(|data: &mut [u8], size: usize, max_size: usize, seed: u32| {
+    let mut rng = Rng(u64::from(seed) | 1 << 32);
+    let mut frames = common::split_frames(&data[..size]);
+
+    // Prefer the message types we're interested in, but sometimes mutate anything
+    let interesting = frames.iter()
+        .enumerate()
+        .filter(|(_, frame)| matches!(frame.get(5), Some(2) | Some(3) | Some(4)))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+
+    match (interesting.is_empty(), rng.below(8)) {
+        (_, 0) => return fuzzer_mutate(data, size, max_size),
+        (false, _) => {
+            let idx = interesting[rng.below(interesting.len())];
+            mutate_frame(&mut frames[idx], &mut rng);
+        },
+        (true, _) if !frames.is_empty() => {
+            let idx = rng.below(frames.len());
+            mutate_frame(&mut frames[idx], &mut rng);
+        },
+        _ => return fuzzer_mutate(data, size, max_size),
+    };
+
+    let out = frames.concat();
+    let len = out.len().min(max_size);
+    data[..len].copy_from_slice(&out[..len]);
+    len
+});
+
+fuzz_target!(|data: &[u8]| {
+    common::decode_all(data, data.len());
+});
@@ -0,0 +1,747 @@
+//! Anonymize BMP messages so captures can be shared
+//!
+//! `Anonymizer` rewrites the identifying fields of a message while keeping its structure, so the
+//! output still decodes and exercises the same code paths as the original:
+//!
+//! * IP addresses, BGP IDs and prefixes are remapped with Crypto-PAn, a keyed prefix-preserving
+//!   scheme (two addresses sharing an N-bit prefix still share an N-bit prefix afterwards)
+//! * ASNs are remapped with a keyed permutation that keeps 2-byte ASNs 2-byte and leaves AS 0,
+//!   AS_TRANS, 65535 and 4294967295 alone
+//! * Communities, extended communities and large communities have their ASN or IP parts remapped
+//! * Route Distinguishers, in VPN NLRI and the Per-Peer Header of every non-global peer, have
+//!   their ASN or IP parts remapped, and distinguishers in any other layout are replaced outright
+//! * Information TLV strings (sysName, sysDescr etc.) are replaced with hex of the same length
+//! * NOTIFICATION data and Route Mirroring payloads are zeroed
+//!
+//! The same key always gives the same mapping, so separate dumps anonymized with one key can
+//! still be correlated. NLRI for families other than IPv4/IPv6 unicast, multicast, labeled unicast
+//! and VPN are passed through unchanged.
+//!
+//! ```no_run
+//! # use bmp_protocol::{BmpReader, anonymize::Anonymizer};
+//! # use std::io::Write;
+//! # let (input, mut output) = (std::io::empty(), std::io::sink());
+//! let mut anonymizer = Anonymizer::new(&[0x42; 32]);
+//!
+//! for message in BmpReader::new(input) {
+//!     let message = anonymizer.anonymize(&message.unwrap()).unwrap();
+//!     output.write_all(&message.raw).unwrap();
+//! }
+//! ```
+
+use crate::{BmpDecoder, Error, Result, route::routes, types::*};
+
+use aes::Aes128;
+use aes::cipher::{BlockEncrypt, KeyInit, generic_array::GenericArray};
+use hashbrown::HashMap;
+
+use std::convert::TryInto;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+
+// Common header and Per-Peer header lengths
+const COMMON_HEADER_LEN: usize = 6;
+const PEER_HEADER_LEN: usize = 42;
+const BGP_HEADER_LEN: usize = 19;
+
+const AS_TRANS: u32 = 23456;
+
+fn truncated() -> Error {
+    Error::decode("truncated message while anonymizing")
+}
+
+fn field(buf: &mut [u8], range: Range<usize>) -> Result<&mut [u8]> {
+    buf.get_mut(range).ok_or_else(truncated)
+}
+
+fn get_u16(buf: &[u8], offset: usize) -> Result<usize> {
+    buf.get(offset..offset + 2)
+        .map(|bytes| usize::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+        .ok_or_else(truncated)
+}
+
+/// ASNs that keep their meaning and are never remapped
+fn reserved_asn(asn: u32) -> bool {
+    asn == 0 || asn == AS_TRANS || asn == 65535 || asn == u32::MAX
+}
+
+/// Keyed anonymizer for BMP messages
+pub struct Anonymizer {
+    cipher: Aes128,
+    pad: u128,
+    addrs: HashMap<IpAddr, IpAddr>,
+    asns: HashMap<u32, u32>,
+    decoder: BmpDecoder,
+}
+
+impl std::fmt::Debug for Anonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Don't leak the key
+        f.debug_struct("Anonymizer").finish()
+    }
+}
+
+impl Anonymizer {
+    /// Create a new anonymizer. The key is used as a Crypto-PAn key, the first 16 bytes are the
+    /// AES key and the last 16 bytes generate the pad
+    pub fn new(key: &[u8; 32]) -> Self {
+        let cipher = Aes128::new(GenericArray::from_slice(&key[..16]));
+
+        let mut pad = GenericArray::clone_from_slice(&key[16..]);
+        cipher.encrypt_block(&mut pad);
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&pad);
+
+        Self {
+            cipher,
+            pad: u128::from_be_bytes(bytes),
+            addrs: HashMap::new(),
+            asns: HashMap::new(),
+            decoder: BmpDecoder::new(),
+        }
+    }
+
+    fn encrypt(&self, block: u128) -> u128 {
+        let mut block = GenericArray::from(block.to_be_bytes());
+        self.cipher.encrypt_block(&mut block);
+
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&block);
+        u128::from_be_bytes(bytes)
+    }
+
+    // Crypto-PAn over the first `width` bits of a left-aligned address
+    fn crypto_pan(&self, addr: u128, width: u32) -> u128 {
+        let mut otp = 0u128;
+        for pos in 0..width {
+            let mask = u128::MAX.checked_shl(128 - pos).unwrap_or(0);
+            let block = (addr & mask) | (self.pad & !mask);
+            otp |= (self.encrypt(block) >> 127) << (127 - pos);
+        }
+
+        addr ^ otp
+    }
+
+    /// Anonymize an address
+    pub fn addr(&mut self, addr: IpAddr) -> IpAddr {
+        if let Some(anon) = self.addrs.get(&addr) {
+            return *anon;
+        }
+
+        let anon = match addr {
+            IpAddr::V4(v4) => {
+                let anon = self.crypto_pan(u128::from(u32::from(v4)) << 96, 32);
+                IpAddr::V4(Ipv4Addr::from((anon >> 96) as u32))
+            },
+            IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(self.crypto_pan(u128::from(v6), 128))),
+        };
+        self.addrs.insert(addr, anon);
+
+        anon
+    }
+
+    /// Anonymize a prefix, the length is unchanged
+    pub fn prefix(&mut self, prefix: IpAddr, length: u8) -> IpAddr {
+        let anon = self.addr(mask(prefix, length));
+        mask(anon, length)
+    }
+
+    // Keyed Feistel permutation over 16 or 32 bits
+    fn permute(&self, value: u32, bits: u32) -> u32 {
+        let half = bits / 2;
+        let mask = (1u32 << half) - 1;
+        let (mut left, mut right) = (value >> half, value & mask);
+
+        for round in 0..4u128 {
+            let f = (self.encrypt(round << 120 | u128::from(bits) << 112 | u128::from(right)) >> 96) as u32 & mask;
+            let next = left ^ f;
+            left = right;
+            right = next;
+        }
+
+        (left << half) | right
+    }
+
+    /// Anonymize an ASN. 2-byte ASNs stay 2-byte and 4-byte ASNs stay 4-byte
+    pub fn asn(&mut self, asn: u32) -> u32 {
+        if reserved_asn(asn) {
+            return asn;
+        }
+        if let Some(anon) = self.asns.get(&asn) {
+            return *anon;
+        }
+
+        // Cycle walk until we land back in the same class of ASN
+        let two_byte = asn <= 0xffff;
+        let mut anon = asn;
+        loop {
+            anon = self.permute(anon, if two_byte { 16 } else { 32 });
+            if !reserved_asn(anon) && (anon <= 0xffff) == two_byte {
+                break;
+            }
+        }
+        self.asns.insert(asn, anon);
+
+        anon
+    }
+
+    /// Replace a string with hex of the same length, derived from the key and the original value
+    pub fn string(&self, value: &[u8]) -> Vec<u8> {
+        let digest = u128::from_be_bytes(md5::compute(value).0);
+
+        let mut out = Vec::with_capacity(value.len() + 32);
+        let mut counter = 0u128;
+        while out.len() < value.len() {
+            out.extend(format!("{:032x}", self.encrypt(digest ^ counter)).bytes());
+            counter += 1;
+        }
+        out.truncate(value.len());
+
+        out
+    }
+
+    /// Anonymize a decoded message, returning the anonymized message. Messages from a session
+    /// should be passed through in order so Peer Up capabilities are known when decoding
+    /// the anonymized Route Monitoring messages
+    pub fn anonymize(&mut self, message: &BmpMessage) -> Result<BmpMessage> {
+        let mut frame = message.raw.to_vec();
+        if frame.len() < COMMON_HEADER_LEN {
+            return Err(Error::decode("no raw bytes to anonymize"));
+        }
+        let body = &mut frame[COMMON_HEADER_LEN..];
+
+        match &message.message {
+            MessageData::Initiation(_) => self.tlvs(body, |_| true)?,
+            MessageData::Termination(_) => self.tlvs(body, |kind| kind != 1)?,
+            MessageData::PeerUp((header, _)) => {
+                self.peer_header(body)?;
+
+                let local = if header.peer_flags.V { 42..58 } else { 54..58 };
+                self.addr_bytes(field(body, local)?)?;
+
+                let mut offset = 62;
+                for _ in 0..2 {
+                    let length = get_u16(body, offset + 16)?;
+                    self.open(field(body, offset..offset + length)?)?;
+                    offset += length;
+                }
+
+                self.tlvs(field(body, offset..body.len())?, |_| true)?;
+            },
+            MessageData::PeerDown(_) => {
+                self.peer_header(body)?;
+
+                match body.get(PEER_HEADER_LEN) {
+                    // NOTIFICATION, keep the code & subcode but clear out any data
+                    Some(1) | Some(3) => {
+                        let data = PEER_HEADER_LEN + 1 + BGP_HEADER_LEN + 2;
+                        if body.len() > data {
+                            body[data..].iter_mut().for_each(|byte| *byte = 0);
+                        }
+                    },
+                    // TLVs, such as the VRF/Table name
+                    Some(6) => self.tlvs(&mut body[PEER_HEADER_LEN + 1..], |_| true)?,
+                    _ => {},
+                };
+            },
+            MessageData::RouteMonitoring((header, update)) => {
+                self.peer_header(body)?;
+
+                let add_path = routes(update).iter().any(|route| route.path_id.is_some());
+                self.update(field(body, PEER_HEADER_LEN..body.len())?, !header.peer_flags.A, add_path)?;
+            },
+            MessageData::Unimplemented => match message.kind {
+                MessageKind::StatisticsReport => self.peer_header(body)?,
+                MessageKind::RouteMirroring => {
+                    self.peer_header(body)?;
+                    body[PEER_HEADER_LEN..].iter_mut().for_each(|byte| *byte = 0);
+                },
+                _ => {},
+            },
+        };
+
+        self.decoder.decode_slice(&frame).1?
+            .ok_or_else(|| Error::decode("anonymized BMP message was incomplete"))
+    }
+
+    /// Anonymize an address in place, 4 bytes for IPv4 and 16 for IPv6
+    fn addr_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        let anon = match bytes.len() {
+            4 => self.addr(IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))),
+            16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                self.addr(IpAddr::V6(Ipv6Addr::from(octets)))
+            },
+            _ => return Err(Error::decode(&format!("invalid address length: {}", bytes.len()))),
+        };
+
+        match anon {
+            IpAddr::V4(addr) => bytes.copy_from_slice(&addr.octets()),
+            IpAddr::V6(addr) => bytes.copy_from_slice(&addr.octets()),
+        };
+        Ok(())
+    }
+
+    fn asn_bytes(&mut self, bytes: &mut [u8]) {
+        match bytes.len() {
+            2 => {
+                let anon = self.asn(u32::from(u16::from_be_bytes([bytes[0], bytes[1]])));
+                bytes.copy_from_slice(&(anon as u16).to_be_bytes());
+            },
+            4 => {
+                let anon = self.asn(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+                bytes.copy_from_slice(&anon.to_be_bytes());
+            },
+            _ => {},
+        };
+    }
+
+    /// Route Distinguisher (RFC4364 Section 4.2)
+    fn rd_bytes(&mut self, rd: &mut [u8]) -> Result<()> {
+        match rd[..2] {
+            [0, 0] => self.asn_bytes(&mut rd[2..4]),
+            [0, 1] => self.addr_bytes(&mut rd[2..6])?,
+            [0, 2] => self.asn_bytes(&mut rd[2..6]),
+            // Some other layout (Local Instance peers can use anything), so replace all of it
+            _ => {
+                let value = u64::from_be_bytes(rd.try_into().map_err(|_| Error::decode("invalid distinguisher length"))?);
+                let anon = self.encrypt(u128::from(value)) as u64;
+                rd.copy_from_slice(&anon.to_be_bytes());
+            },
+        };
+        Ok(())
+    }
+
+    fn peer_header(&mut self, body: &mut [u8]) -> Result<()> {
+        let header = field(body, 0..PEER_HEADER_LEN)?;
+        let flags = PeerFlags::from(header[1]);
+
+        // Every peer type but Global Instance carries a distinguisher from the router
+        if header[0] != PeerType::GlobalInstance as u8 {
+            self.rd_bytes(&mut header[2..10])?;
+        }
+        self.addr_bytes(if flags.V { &mut header[10..26] } else { &mut header[22..26] })?;
+        self.asn_bytes(if flags.A { &mut header[28..30] } else { &mut header[26..30] });
+        self.addr_bytes(&mut header[30..34])
+    }
+
+    /// Replace the values of TLVs matching `strings`
+    fn tlvs(&mut self, mut buf: &mut [u8], strings: impl Fn(usize) -> bool) -> Result<()> {
+        while buf.len() >= 4 {
+            let kind = get_u16(buf, 0)?;
+            let length = get_u16(buf, 2)?;
+            let value = field(buf, 4..4 + length)?;
+            if strings(kind) {
+                let anon = self.string(value);
+                value.copy_from_slice(&anon);
+            }
+
+            buf = &mut buf[4 + length..];
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self, msg: &mut [u8]) -> Result<()> {
+        let open = field(msg, BGP_HEADER_LEN..msg.len())?;
+        if open.len() < 10 {
+            return Err(truncated());
+        }
+
+        self.asn_bytes(&mut open[1..3]);
+        self.addr_bytes(&mut open[5..9])?;
+
+        // Extended optional parameters (RFC9072) aren't anonymized
+        let params_len = usize::from(open[9]);
+        if params_len == 255 {
+            return Ok(());
+        }
+
+        let mut params = field(open, 10..10 + params_len)?;
+        while params.len() >= 2 {
+            let (kind, length) = (params[0], usize::from(params[1]));
+            let value = field(params, 2..2 + length)?;
+
+            if kind == 2 {
+                let mut caps = &mut value[..];
+                while caps.len() >= 2 {
+                    let (code, cap_len) = (caps[0], usize::from(caps[1]));
+                    let cap = field(caps, 2..2 + cap_len)?;
+                    // 4-octet AS number
+                    if code == 65 && cap_len == 4 {
+                        self.asn_bytes(cap);
+                    }
+                    caps = &mut caps[2 + cap_len..];
+                }
+            }
+
+            params = &mut params[2 + length..];
+        }
+
+        Ok(())
+    }
+
+    fn update(&mut self, msg: &mut [u8], as4: bool, add_path: bool) -> Result<()> {
+        let update = field(msg, BGP_HEADER_LEN..msg.len())?;
+
+        let withdrawn_len = get_u16(update, 0)?;
+        self.nlri(field(update, 2..2 + withdrawn_len)?, 1, 1, add_path)?;
+
+        let attrs_start = 4 + withdrawn_len;
+        let attrs_len = get_u16(update, 2 + withdrawn_len)?;
+        self.attributes(field(update, attrs_start..attrs_start + attrs_len)?, as4, add_path)?;
+
+        self.nlri(field(update, attrs_start + attrs_len..update.len())?, 1, 1, add_path)
+    }
+
+    fn attributes(&mut self, mut attrs: &mut [u8], as4: bool, add_path: bool) -> Result<()> {
+        while attrs.len() >= 3 {
+            let flags = attrs[0];
+            let kind = attrs[1];
+            let (header_len, length) = if flags & 0x10 == 0x10 {
+                (4, get_u16(attrs, 2)?)
+            } else {
+                (3, usize::from(attrs[2]))
+            };
+            let value = field(attrs, header_len..header_len + length)?;
+
+            match kind {
+                // AS_PATH and AS4_PATH
+                2 | 17 => {
+                    let width = if kind == 17 || as4 { 4 } else { 2 };
+                    let mut segments = &mut value[..];
+                    while segments.len() >= 2 {
+                        let count = usize::from(segments[1]);
+                        let asns = field(segments, 2..2 + count * width)?;
+                        for asn in asns.chunks_mut(width) {
+                            self.asn_bytes(asn);
+                        }
+                        segments = &mut segments[2 + count * width..];
+                    }
+                },
+                // NEXT_HOP and ORIGINATOR_ID
+                3 | 9 if length == 4 => self.addr_bytes(value)?,
+                // AGGREGATOR and AS4_AGGREGATOR
+                7 | 18 if length == 6 || length == 8 => {
+                    let (asn, addr) = value.split_at_mut(length - 4);
+                    self.asn_bytes(asn);
+                    self.addr_bytes(addr)?;
+                },
+                // COMMUNITY, the well-known communities (0xffff....) and 0:x are left alone
+                8 => {
+                    for community in value.chunks_mut(4) {
+                        if community.len() == 4 && community[..2] != [0xff, 0xff] {
+                            self.asn_bytes(&mut community[..2]);
+                        }
+                    }
+                },
+                // CLUSTER_LIST
+                10 => {
+                    for id in value.chunks_mut(4) {
+                        if id.len() == 4 {
+                            self.addr_bytes(id)?;
+                        }
+                    }
+                },
+                // MP_REACH_NLRI
+                14 if length >= 5 => {
+                    let afi = get_u16(value, 0)?;
+                    let safi = value[2];
+                    let nh_len = usize::from(value[3]);
+                    let next_hop = field(value, 4..4 + nh_len)?;
+                    match nh_len {
+                        4 | 16 => self.addr_bytes(next_hop)?,
+                        32 => {
+                            self.addr_bytes(&mut next_hop[..16])?;
+                            self.addr_bytes(&mut next_hop[16..])?;
+                        },
+                        // VPN next hops have a zero RD in front
+                        12 | 24 => self.addr_bytes(&mut next_hop[8..])?,
+                        _ => {},
+                    };
+
+                    // Skip the reserved byte
+                    self.nlri(field(value, 5 + nh_len..length)?, afi, safi, add_path)?;
+                },
+                // MP_UNREACH_NLRI
+                15 if length >= 3 => {
+                    let afi = get_u16(value, 0)?;
+                    let safi = value[2];
+                    self.nlri(&mut value[3..], afi, safi, add_path)?;
+                },
+                // EXTENDED_COMMUNITIES
+                16 => {
+                    for community in value.chunks_mut(8) {
+                        if community.len() != 8 {
+                            continue;
+                        }
+                        match community[0] & 0x3f {
+                            0x00 => self.asn_bytes(&mut community[2..4]),
+                            0x01 => self.addr_bytes(&mut community[2..6])?,
+                            0x02 => self.asn_bytes(&mut community[2..6]),
+                            _ => {},
+                        };
+                    }
+                },
+                // LARGE_COMMUNITY
+                32 => {
+                    for community in value.chunks_mut(12) {
+                        if community.len() == 12 {
+                            self.asn_bytes(&mut community[..4]);
+                        }
+                    }
+                },
+                _ => {},
+            };
+
+            attrs = &mut attrs[header_len + length..];
+        }
+
+        Ok(())
+    }
+
+    fn nlri(&mut self, mut buf: &mut [u8], afi: usize, safi: u8, add_path: bool) -> Result<()> {
+        let width = match afi {
+            1 => 32,
+            2 => 128,
+            _ => return Ok(()),
+        };
+        // Unicast, multicast, labeled unicast and VPN
+        if ![1, 2, 4, 128].contains(&safi) {
+            return Ok(());
+        }
+
+        while !buf.is_empty() {
+            if add_path {
+                buf = field(buf, 4..buf.len())?;
+            }
+
+            let bits = usize::from(*buf.first().ok_or_else(truncated)?);
+            let bytes = field(buf, 1..1 + bits.div_ceil(8))?;
+
+            // Labels go until the bottom of stack bit, withdrawals may just have 0x800000
+            let mut offset = 0;
+            if safi == 4 || safi == 128 {
+                loop {
+                    let label = field(bytes, offset..offset + 3)?;
+                    offset += 3;
+                    if label[2] & 0x01 == 0x01 || label == [0x80, 0, 0] {
+                        break;
+                    }
+                }
+            }
+            if safi == 128 {
+                self.rd_bytes(field(bytes, offset..offset + 8)?)?;
+                offset += 8;
+            }
+
+            let length = bits.checked_sub(offset * 8).filter(|length| *length <= width).ok_or_else(truncated)?;
+            let prefix = &mut bytes[offset..];
+
+            let mut octets = [0u8; 16];
+            octets[..prefix.len()].copy_from_slice(prefix);
+            let addr = if width == 32 {
+                IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            } else {
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+
+            let anon = match self.prefix(addr, length as u8) {
+                IpAddr::V4(addr) => addr.octets().to_vec(),
+                IpAddr::V6(addr) => addr.octets().to_vec(),
+            };
+            let len = prefix.len();
+            prefix.copy_from_slice(&anon[..len]);
+
+            buf = &mut buf[1 + bits.div_ceil(8)..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Clear the host bits of a prefix
+fn mask(addr: IpAddr, length: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(length.min(32))).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+        },
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(length.min(128))).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::Generator;
+
+    use std::fs;
+
+    // Key and sample addresses from the Crypto-PAn reference implementation
+    const KEY: [u8; 32] = [
+        21, 34, 23, 141, 51, 164, 207, 128, 19, 10, 91, 22, 73, 144, 125, 16,
+        216, 152, 143, 131, 121, 121, 101, 39, 98, 87, 76, 45, 42, 132, 34, 2,
+    ];
+
+    fn decode_all(buf: &[u8]) -> Vec<BmpMessage> {
+        crate::BmpReader::new(buf).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_crypto_pan() {
+        let mut anonymizer = Anonymizer::new(&KEY);
+
+        for (addr, anon) in &[
+            ("128.11.68.132", "135.242.180.132"),
+            ("129.118.74.4", "134.136.186.123"),
+            ("130.132.252.244", "133.68.164.234"),
+            ("141.223.7.43", "141.167.8.160"),
+            ("141.233.145.108", "141.129.237.235"),
+            ("152.163.225.39", "151.140.114.167"),
+            ("156.29.3.236", "147.225.12.42"),
+            ("165.247.96.84", "162.9.99.234"),
+            ("166.107.77.190", "160.132.178.185"),
+            ("192.102.249.13", "252.138.62.131"),
+        ] {
+            assert_eq!(anonymizer.addr(addr.parse().unwrap()), anon.parse::<IpAddr>().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_asn() {
+        let mut anonymizer = Anonymizer::new(&KEY);
+
+        for asn in &[0, AS_TRANS, 65535, u32::MAX] {
+            assert_eq!(anonymizer.asn(*asn), *asn);
+        }
+
+        let two_byte = (1..2000).map(|asn| anonymizer.asn(asn)).collect::<hashbrown::HashSet<_>>();
+        assert_eq!(two_byte.len(), 1999);
+        assert!(two_byte.iter().all(|asn| *asn <= 0xffff && !reserved_asn(*asn)));
+
+        let four_byte = anonymizer.asn(4_200_000_000);
+        assert!(four_byte > 0xffff && four_byte != u32::MAX);
+        assert_eq!(anonymizer.asn(4_200_000_000), four_byte);
+    }
+
+    #[test]
+    fn test_nlri_truncated() {
+        let mut anonymizer = Anonymizer::new(&KEY);
+
+        // A path identifier with no prefix after it
+        assert!(anonymizer.nlri(&mut [0, 0, 0, 1], 1, 1, true).is_err());
+        assert!(anonymizer.nlri(&mut [0, 0, 0, 1, 24, 192], 1, 1, true).is_err());
+        assert!(anonymizer.nlri(&mut [0, 0, 0, 1, 24, 192, 0, 2], 1, 1, true).is_ok());
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let original = decode_all(&fs::read("test_data/synthetic.dump").unwrap());
+        let mut anonymizer = Anonymizer::new(&KEY);
+        let anonymized = original.iter()
+            .map(|message| anonymizer.anonymize(message).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(anonymized.len(), original.len());
+        for (message, anon) in original.iter().zip(&anonymized) {
+            assert_eq!(message.kind, anon.kind);
+            assert_eq!(message.raw.len(), anon.raw.len());
+        }
+
+        // sysDescr is replaced with a string of the same length
+        match (&original[0].message, &anonymized[0].message) {
+            (MessageData::Initiation(orig), MessageData::Initiation(anon)) => {
+                assert_eq!(orig[0].value().unwrap().len(), anon[0].value().unwrap().len());
+                assert_ne!(orig[0].value(), anon[0].value());
+            },
+            _ => panic!("expected an Initiation"),
+        };
+
+        // The peer is mapped the same way in every message
+        let peer = match &anonymized[1].message {
+            MessageData::PeerUp((header, peer_up)) => {
+                assert_ne!(header.peer_addr, "192.0.2.1".parse::<IpAddr>().unwrap());
+                assert_ne!(header.peer_asn, 65001);
+                assert_eq!(peer_up.recv_open.as_ref().unwrap().peer_asn as u32, header.peer_asn);
+                header.peer_addr
+            },
+            _ => panic!("expected a Peer Up"),
+        };
+
+        match &anonymized[3].message {
+            MessageData::RouteMonitoring((header, update)) => {
+                assert_eq!(header.peer_addr, peer);
+
+                let routes = routes(update);
+                assert_eq!(routes.len(), 2);
+                assert!(routes.iter().all(|route| route.length == 24));
+
+                // 198.51.100.0/24 and 203.0.113.0/24 share their first 4 bits
+                let (a, b) = match (routes[0].prefix, routes[1].prefix) {
+                    (IpAddr::V4(a), IpAddr::V4(b)) => (u32::from(a), u32::from(b)),
+                    _ => panic!("expected IPv4 prefixes"),
+                };
+                assert_eq!((a ^ b).leading_zeros(), 4);
+                assert_ne!(routes[0].prefix, "198.51.100.0".parse::<IpAddr>().unwrap());
+            },
+            _ => panic!("expected Route Monitoring"),
+        };
+        assert!(anonymized[4].end_of_rib().is_some());
+
+        // Same key, same output
+        let mut again = Anonymizer::new(&KEY);
+        for (message, anon) in original.iter().zip(&anonymized) {
+            assert_eq!(again.anonymize(message).unwrap().raw, anon.raw);
+        }
+    }
+
+    #[test]
+    fn test_anonymize_distinguishers() {
+        let peer_up = Generator::new(5).find(|frame| frame[5] == MessageKind::PeerUp as u8).unwrap();
+
+        let mut anonymizer = Anonymizer::new(&KEY);
+        for &peer_type in &[PeerType::RdInstance, PeerType::LocalInstance, PeerType::LocRibInstance] {
+            // A type 0 RD (65000:7) and a layout we don't know
+            for distinguisher in &[[0, 0, 0xfd, 0xe8, 0, 0, 0, 7], [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0]] {
+                let mut frame = peer_up.to_vec();
+                frame[6] = peer_type as u8;
+                frame[8..16].copy_from_slice(distinguisher);
+                let message = decode_all(&frame).remove(0);
+
+                let anon = match anonymizer.anonymize(&message).unwrap().message {
+                    MessageData::PeerUp((header, _)) => header.peer_distinguisher,
+                    _ => panic!("expected a Peer Up"),
+                };
+                let original = (
+                    u32::from_be_bytes([distinguisher[0], distinguisher[1], distinguisher[2], distinguisher[3]]),
+                    u32::from_be_bytes([distinguisher[4], distinguisher[5], distinguisher[6], distinguisher[7]]),
+                );
+                assert_ne!(anon, original, "{} {:?}", peer_type, distinguisher);
+            }
+        }
+    }
+
+    #[test]
+    fn test_anonymize_generated() {
+        let frames = Generator::new(3).with_peers(6).with_routes(100).with_churn(50).with_flaps(1)
+            .flat_map(|frame| frame.to_vec())
+            .collect::<Vec<_>>();
+        let original = decode_all(&frames);
+
+        let mut anonymizer = Anonymizer::new(&[7; 32]);
+        for message in &original {
+            let anon = anonymizer.anonymize(message).unwrap();
+            assert_eq!(message.kind, anon.kind);
+
+            if let (MessageData::RouteMonitoring((_, orig)), MessageData::RouteMonitoring((_, update))) = (&message.message, &anon.message) {
+                let lengths = |update| routes(update).iter().map(|route| route.length).collect::<Vec<_>>();
+                assert_eq!(lengths(orig), lengths(update));
+            }
+        }
+    }
+}
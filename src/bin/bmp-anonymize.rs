@@ -0,0 +1,209 @@
+//! Anonymize a BMP dump file so it can be shared
+//!
+//! Addresses and prefixes are remapped with Crypto-PAn, ASNs, communities and strings with keyed
+//! mappings, see `bmp_protocol::anonymize` for the details.
+
+use bmp_protocol::{BmpReader, anonymize::Anonymizer};
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::process;
+
+const USAGE: &str = "\
+Usage: bmp-anonymize (-k HEX | -K FILE) [INPUT] [OUTPUT]
+
+Anonymize the BMP messages in INPUT (default stdin) and write them to OUTPUT (default stdout)
+
+Options:
+  -k, --key HEX          32 byte Crypto-PAn key as 64 hex digits
+  -K, --key-file FILE    Read the key from FILE, either 32 raw bytes or 64 hex digits
+  -h, --help             Show this message
+
+Use the same key for every file that needs to be correlated and keep it private
+";
+
+#[derive(Debug)]
+struct Options {
+    key: [u8; 32],
+    input: Option<String>,
+    output: Option<String>,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("bmp-anonymize: {}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (idx, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+fn read_key_file(path: &str) -> Result<[u8; 32], String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+
+    if data.len() == 32 {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&data);
+        return Ok(key);
+    }
+    parse_key(&String::from_utf8_lossy(&data)).ok_or_else(|| format!("{}: not a valid key", path))
+}
+
+/// Parse the command line (without the program name), None means help was asked for
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut key = None;
+    let mut files = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-k" | "--key" => key = Some(parse_key(&value(&arg)?).ok_or("the key must be 64 hex digits")?),
+            "-K" | "--key-file" => key = Some(read_key_file(&value(&arg)?)?),
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option: {}", arg)),
+            _ if files.len() < 2 => files.push(arg),
+            _ => return Err("too many arguments".into()),
+        };
+    }
+
+    let key = key.ok_or("a key is required")?;
+    let mut files = files.into_iter();
+    let (input, output) = (files.next(), files.next());
+
+    Ok(Some(Options { key, input, output }))
+}
+
+fn anonymize(input: impl Read, output: impl Write, key: &[u8; 32]) -> bmp_protocol::Result<u64> {
+    let mut anonymizer = Anonymizer::new(key);
+    let mut output = BufWriter::new(output);
+    let mut count = 0;
+
+    for message in BmpReader::new(input) {
+        output.write_all(&anonymizer.anonymize(&message?)?.raw)?;
+        count += 1;
+    }
+
+    output.flush()?;
+    Ok(count)
+}
+
+fn main() {
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print!("{}", USAGE);
+            process::exit(0);
+        },
+        Err(msg) => usage_error(&msg),
+    };
+
+    let input: Box<dyn Read> = match opts.input.as_deref() {
+        None | Some("-") => Box::new(io::stdin()),
+        Some(path) => Box::new(File::open(path).unwrap_or_else(|err| usage_error(&format!("{}: {}", path, err)))),
+    };
+    let output: Box<dyn Write> = match opts.output.as_deref() {
+        None | Some("-") => Box::new(io::stdout()),
+        Some(path) => Box::new(File::create(path).unwrap_or_else(|err| usage_error(&format!("{}: {}", path, err)))),
+    };
+
+    match anonymize(input, output, &opts.key) {
+        Ok(count) => eprintln!("bmp-anonymize: anonymized {} messages", count),
+        Err(err) => {
+            eprintln!("bmp-anonymize: {}", err);
+            process::exit(1);
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = parse_key(KEY).unwrap();
+        assert_eq!(key[0], 0);
+        assert_eq!(key[31], 0x1f);
+        assert_eq!(parse_key(&format!("  {}\n", KEY.to_uppercase())), Some(key));
+
+        assert_eq!(parse_key(&KEY[..62]), None);
+        assert_eq!(parse_key(&KEY.replace('0', "g")), None);
+        assert_eq!(parse_key(&format!("{}é", &KEY[..62])), None);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse(&["-k", KEY]).unwrap().unwrap();
+        assert_eq!(opts.key, parse_key(KEY).unwrap());
+        assert_eq!((opts.input, opts.output), (None, None));
+
+        let opts = parse(&["--key", KEY, "-", "out.bmp"]).unwrap().unwrap();
+        assert_eq!((opts.input.as_deref(), opts.output.as_deref()), (Some("-"), Some("out.bmp")));
+
+        // Both raw and hex key files are accepted
+        let dir = std::env::temp_dir();
+        let raw = dir.join(format!("bmp-anonymize-{}.raw", process::id()));
+        let hex = dir.join(format!("bmp-anonymize-{}.hex", process::id()));
+        fs::write(&raw, parse_key(KEY).unwrap()).unwrap();
+        fs::write(&hex, format!("{}\n", KEY)).unwrap();
+        for path in &[&raw, &hex] {
+            let opts = parse(&["-K", path.to_str().unwrap(), "in.bmp"]).unwrap().unwrap();
+            assert_eq!(opts.key, parse_key(KEY).unwrap());
+            assert_eq!(opts.input.as_deref(), Some("in.bmp"));
+        }
+        fs::write(&hex, "not a key").unwrap();
+        assert!(parse(&["-K", hex.to_str().unwrap()]).unwrap_err().ends_with("not a valid key"));
+        fs::remove_file(&raw).unwrap();
+        fs::remove_file(&hex).unwrap();
+        assert!(parse(&["-K", raw.to_str().unwrap()]).is_err());
+
+        assert!(parse(&["-k", KEY, "-h"]).unwrap().is_none());
+
+        for (args, error) in &[
+            (&[][..], "a key is required"),
+            (&["-k"], "-k needs a value"),
+            (&["-k", "00"], "the key must be 64 hex digits"),
+            (&["-k", KEY, "-x"], "unknown option: -x"),
+            (&["-k", KEY, "a", "b", "c"], "too many arguments"),
+        ] {
+            assert_eq!(parse(args).unwrap_err(), *error, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let input = fs::read("test_data/synthetic.dump").unwrap();
+        let key = parse_key(KEY).unwrap();
+
+        let mut output = vec![];
+        let count = anonymize(&input[..], &mut output, &key).unwrap();
+        assert_eq!(count as usize, BmpReader::new(&input[..]).count());
+        assert_ne!(output, input);
+
+        // The output is still valid BMP and the same key gives the same result
+        let messages = BmpReader::new(&output[..]).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(messages.len() as u64, count);
+        let mut again = vec![];
+        anonymize(&input[..], &mut again, &key).unwrap();
+        assert_eq!(again, output);
+
+        assert!(anonymize(&input[..10], io::sink(), &key).is_err());
+    }
+}
@@ -0,0 +1,594 @@
+//! Print the contents of a BMP session
+//!
+//! Reads BMP messages from a file, stdin or a listening socket and prints them either one line
+//! per message, in a verbose multi-line format or (with the `pmacct` feature) as pmacct-style JSON.
+
+#[cfg(feature = "pmacct")]
+use bmp_protocol::pmacct::PmacctFormatter;
+use bmp_protocol::{
+    BmpReader,
+    notification::{shutdown_communication, NotificationError},
+    route::{routes, Route, RouteAttributes},
+    types::*,
+};
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+const USAGE: &str = "\
+Usage: bmp-dump [OPTIONS] [FILE]
+
+Print BMP messages read from FILE, or stdin if FILE is - or missing. Files ending .gz, .bz2
+or .zst are decompressed when built with the matching feature
+
+Options:
+  -l, --listen ADDR      Accept BMP sessions on ADDR (eg. 0.0.0.0:11019) instead of reading a file
+  -v, --verbose          Print every field of each message
+  -j, --json             Print pmacct-style JSON, one event per line (needs the pmacct feature)
+  -k, --kind KIND        Only print messages of KIND (eg. route_monitoring, peer_up), may be repeated
+  -p, --peer ADDR        Only print messages for peer ADDR, may be repeated
+  -P, --prefix PREFIX    Only print routes equal to or more specific than PREFIX, may be repeated
+  -h, --help             Show this message
+";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Format {
+    Line,
+    Verbose,
+    #[cfg(feature = "pmacct")]
+    Json,
+}
+
+#[derive(Debug)]
+struct Options {
+    input: Option<String>,
+    listen: Option<SocketAddr>,
+    format: Format,
+    kinds: Vec<MessageKind>,
+    peers: Vec<IpAddr>,
+    prefixes: Vec<(IpAddr, u8)>,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("bmp-dump: {}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse_prefix(value: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = value.splitn(2, '/');
+    let addr: IpAddr = parts.next()?.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let length = match parts.next() {
+        Some(length) => length.parse().ok().filter(|length| *length <= max)?,
+        None => max,
+    };
+
+    Some((addr, length))
+}
+
+/// Parse the command line (without the program name), None means help was asked for
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut opts = Options {
+        input: None,
+        listen: None,
+        format: Format::Line,
+        kinds: vec![],
+        peers: vec![],
+        prefixes: vec![],
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-v" | "--verbose" => opts.format = Format::Verbose,
+            #[cfg(feature = "pmacct")]
+            "-j" | "--json" => opts.format = Format::Json,
+            #[cfg(not(feature = "pmacct"))]
+            "-j" | "--json" => return Err("JSON output needs bmp-dump built with the pmacct feature".into()),
+            "-l" | "--listen" => {
+                let addr = value(&arg)?;
+                opts.listen = Some(addr.parse().map_err(|_| format!("invalid address: {}", addr))?);
+            },
+            "-k" | "--kind" => {
+                let kind = value(&arg)?;
+                let parsed = (0..=6u8)
+                    .filter_map(|v| MessageKind::try_from(v).ok())
+                    .find(|k| k.to_string() == kind)
+                    .ok_or_else(|| format!("unknown message kind: {}", kind))?;
+                opts.kinds.push(parsed);
+            },
+            "-p" | "--peer" => {
+                let peer = value(&arg)?;
+                opts.peers.push(peer.parse().map_err(|_| format!("invalid address: {}", peer))?);
+            },
+            "-P" | "--prefix" => {
+                let prefix = value(&arg)?;
+                opts.prefixes.push(parse_prefix(&prefix).ok_or_else(|| format!("invalid prefix: {}", prefix))?);
+            },
+            _ if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option: {}", arg)),
+            _ if opts.input.is_none() => opts.input = Some(arg),
+            _ => return Err("only one input file can be given".into()),
+        };
+    }
+
+    if opts.input.is_some() && opts.listen.is_some() {
+        return Err("can't read a file and listen at the same time".into());
+    }
+
+    Ok(Some(opts))
+}
+
+fn peer_header(message: &BmpMessage) -> Option<&PeerHeader> {
+    match &message.message {
+        MessageData::PeerUp((header, _)) => Some(header),
+        MessageData::PeerDown((header, _)) => Some(header),
+        MessageData::RouteMonitoring((header, _)) => Some(header),
+        _ => None,
+    }
+}
+
+impl Options {
+    fn wants_message(&self, message: &BmpMessage) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&message.kind) {
+            return false;
+        }
+
+        if !self.peers.is_empty() {
+            match peer_header(message) {
+                Some(header) if self.peers.contains(&header.peer_addr) => {},
+                _ => return false,
+            };
+        }
+
+        // Only Route Monitoring messages carry prefixes
+        self.prefixes.is_empty() || message.kind == MessageKind::RouteMonitoring
+    }
+
+    fn wants_route(&self, route: &Route) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|(prefix, length)| route.is_covered_by(*prefix, *length))
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:06}", since_epoch.as_secs(), since_epoch.subsec_micros())
+}
+
+fn format_peer(header: &PeerHeader) -> String {
+    let mut peer = format!("peer {} AS{}", header.peer_addr, header.peer_asn);
+    if header.peer_type != PeerType::GlobalInstance {
+        write!(peer, " ({})", header.peer_type).unwrap();
+    }
+    peer.push_str(if header.peer_flags.L { " post-policy" } else { " pre-policy" });
+    peer.push_str(if header.peer_flags.O { " adj-rib-out" } else { " adj-rib-in" });
+
+    peer
+}
+
+/// Name and value of an information TLV, unknown ones are shown by type number in hex
+fn format_information(info: &InformationTlv) -> (String, String) {
+    match info {
+        InformationTlv::Unknown { kind, bytes } => (format!("type_{}", kind), hex(bytes)),
+        _ => (info.information_type().unwrap().to_string(), info.value().unwrap().to_string()),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn format_notification(notification: &bgp_rs::Notification) -> String {
+    let mut text = NotificationError::from(notification).to_string();
+    if let Some(message) = shutdown_communication(notification) {
+        write!(text, " {:?}", message).unwrap();
+    }
+    text
+}
+
+fn format_peer_down(reason: &PeerDown) -> String {
+    match reason {
+        PeerDown::LocalShutdown(n) => format!("local notification {}", format_notification(n)),
+        PeerDown::LocalTerminate(event) => format!("local no-notification fsm-event {}", event),
+        PeerDown::RemoteShutdown(n) => format!("remote notification {}", format_notification(n)),
+        PeerDown::RemoteTerminate => "remote no-notification".into(),
+        PeerDown::ConfigurationChange => "deconfigured".into(),
+        PeerDown::LocalClosed(tlvs) => {
+            let mut text = String::from("local closed");
+            for info in tlvs {
+                let (name, value) = format_information(info);
+                write!(text, " {}={:?}", name, value).unwrap();
+            }
+            text
+        },
+        PeerDown::Unknown(reason, data) => format!("reason {} ({} bytes)", reason, data.len()),
+        _ => "unknown".into(),
+    }
+}
+
+fn format_prefixes(routes: &[&Route]) -> String {
+    routes.iter()
+        .map(|route| match route.path_id {
+            Some(path_id) => format!("{}/{} (path-id {})", route.prefix, route.length, path_id),
+            None => format!("{}/{}", route.prefix, route.length),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Format a message, returning None if it's been filtered out
+fn format_line(message: &BmpMessage, opts: &Options) -> Option<String> {
+    let time = peer_header(message).map(|header| format_time(header.system_time())).unwrap_or_else(|| "-".into());
+    let mut line = format!("{} {}", time, message.kind);
+
+    match &message.message {
+        MessageData::Initiation(tlv) => {
+            for info in tlv {
+                let (name, value) = format_information(info);
+                write!(line, " {}={:?}", name, value).unwrap();
+            }
+        },
+        MessageData::Termination(tlv) => {
+            for info in tlv {
+                match info {
+                    TerminationTlv::String(value) => write!(line, " string={:?}", value).unwrap(),
+                    TerminationTlv::Reason(reason) => write!(line, " reason={}", reason).unwrap(),
+                    TerminationTlv::Unknown { kind, bytes } => write!(line, " type_{}={}", kind, hex(bytes)).unwrap(),
+                };
+            }
+        },
+        MessageData::PeerUp((header, peer_up)) => {
+            write!(
+                line, " {} local {} ports {}/{}",
+                format_peer(header), peer_up.local_addr, peer_up.local_port, peer_up.remote_port
+            ).unwrap();
+        },
+        MessageData::PeerDown((header, reason)) => {
+            write!(line, " {} {}", format_peer(header), format_peer_down(reason)).unwrap();
+        },
+        MessageData::RouteMonitoring((header, update)) => {
+            write!(line, " {}", format_peer(header)).unwrap();
+
+            if let Some((afi, safi)) = message.end_of_rib() {
+                writeln!(line, " end-of-rib {} {}", afi, safi).unwrap();
+                return Some(line).filter(|_| opts.prefixes.is_empty());
+            }
+
+            let routes = routes(update);
+            let (withdrawn, announced): (Vec<_>, Vec<_>) = routes.iter()
+                .filter(|route| opts.wants_route(route))
+                .partition(|route| route.withdrawn);
+            if announced.is_empty() && withdrawn.is_empty() {
+                return None;
+            }
+
+            if !announced.is_empty() {
+                let attrs = RouteAttributes::from_update(update);
+                write!(line, " announce {} path [{}]", format_prefixes(&announced), attrs.as_path_string()).unwrap();
+                if let Some(next_hop) = attrs.next_hop {
+                    write!(line, " nh {}", next_hop).unwrap();
+                }
+                if !attrs.communities.is_empty() {
+                    write!(line, " comm [{}]", attrs.community_strings().join(" ")).unwrap();
+                }
+            }
+            if !withdrawn.is_empty() {
+                write!(line, " withdraw {}", format_prefixes(&withdrawn)).unwrap();
+            }
+        },
+        MessageData::Unimplemented => {},
+    };
+
+    line.push('\n');
+    Some(line)
+}
+
+fn format_verbose(message: &BmpMessage, opts: &Options) -> Option<String> {
+    let mut out = String::new();
+    writeln!(out, "TYPE: {}", message.kind).unwrap();
+    writeln!(out, "LENGTH: {}", message.raw.len()).unwrap();
+
+    if let Some(header) = peer_header(message) {
+        writeln!(out, "TIME: {}", format_time(header.system_time())).unwrap();
+        writeln!(out, "PEER: {} AS{} BGP-ID {}", header.peer_addr, header.peer_asn, header.peer_bgp_id).unwrap();
+        writeln!(out, "PEER_TYPE: {}", header.peer_type).unwrap();
+        if header.peer_type != PeerType::GlobalInstance {
+            writeln!(out, "PEER_DISTINGUISHER: {}:{}", header.peer_distinguisher.0, header.peer_distinguisher.1).unwrap();
+        }
+        writeln!(
+            out, "FLAGS: {} {}",
+            if header.peer_flags.L { "post-policy" } else { "pre-policy" },
+            if header.peer_flags.O { "adj-rib-out" } else { "adj-rib-in" },
+        ).unwrap();
+    }
+
+    match &message.message {
+        MessageData::Initiation(tlv) => {
+            for info in tlv {
+                let (name, value) = format_information(info);
+                writeln!(out, "{}: {}", name.to_uppercase(), value).unwrap();
+            }
+        },
+        MessageData::Termination(tlv) => {
+            for info in tlv {
+                match info {
+                    TerminationTlv::String(value) => writeln!(out, "STRING: {}", value).unwrap(),
+                    TerminationTlv::Reason(reason) => writeln!(out, "REASON: {}", reason).unwrap(),
+                    TerminationTlv::Unknown { kind, bytes } => writeln!(out, "TYPE {}: {}", kind, hex(bytes)).unwrap(),
+                };
+            }
+        },
+        MessageData::PeerUp((_, peer_up)) => {
+            writeln!(out, "LOCAL: {} port {}", peer_up.local_addr, peer_up.local_port).unwrap();
+            writeln!(out, "REMOTE_PORT: {}", peer_up.remote_port).unwrap();
+            for (name, open) in &[("SENT_OPEN", &peer_up.sent_open), ("RECV_OPEN", &peer_up.recv_open)] {
+                if let Some(open) = open {
+                    writeln!(
+                        out, "{}: AS{} hold {} BGP-ID {}",
+                        name, open.peer_asn, open.hold_timer, Ipv4Addr::from(open.identifier)
+                    ).unwrap();
+                }
+            }
+            for info in &peer_up.information {
+                let (name, value) = format_information(info);
+                writeln!(out, "INFO: {} {}", name, value).unwrap();
+            }
+            if let Some(capabilities) = &peer_up.capabilities {
+                writeln!(out, "CAPABILITIES: {}", capabilities).unwrap();
+            }
+        },
+        MessageData::PeerDown((_, reason)) => {
+            writeln!(out, "REASON: {}", format_peer_down(reason)).unwrap();
+        },
+        MessageData::RouteMonitoring((_, update)) => {
+            if let Some((afi, safi)) = message.end_of_rib() {
+                if !opts.prefixes.is_empty() {
+                    return None;
+                }
+                writeln!(out, "END_OF_RIB: {} {}", afi, safi).unwrap();
+            } else {
+                let routes = routes(update);
+                let (withdrawn, announced): (Vec<_>, Vec<_>) = routes.iter()
+                    .filter(|route| opts.wants_route(route))
+                    .partition(|route| route.withdrawn);
+                if announced.is_empty() && withdrawn.is_empty() {
+                    return None;
+                }
+
+                if !announced.is_empty() {
+                    let attrs = RouteAttributes::from_update(update);
+                    writeln!(out, "ORIGIN: {}", attrs.origin_string()).unwrap();
+                    writeln!(out, "ASPATH: {}", attrs.as_path_string()).unwrap();
+                    if let Some(next_hop) = attrs.next_hop {
+                        writeln!(out, "NEXT_HOP: {}", next_hop).unwrap();
+                    }
+                    if let Some(med) = attrs.med {
+                        writeln!(out, "MULTI_EXIT_DISC: {}", med).unwrap();
+                    }
+                    if let Some(local_pref) = attrs.local_pref {
+                        writeln!(out, "LOCAL_PREF: {}", local_pref).unwrap();
+                    }
+                    if !attrs.communities.is_empty() {
+                        writeln!(out, "COMMUNITY: {}", attrs.community_strings().join(" ")).unwrap();
+                    }
+                    if !attrs.ext_communities.is_empty() {
+                        writeln!(out, "EXTENDED_COMMUNITY: {}", attrs.ext_community_strings().join(" ")).unwrap();
+                    }
+                    if !attrs.large_communities.is_empty() {
+                        writeln!(out, "LARGE_COMMUNITY: {}", attrs.large_community_strings().join(" ")).unwrap();
+                    }
+                    writeln!(out, "ANNOUNCE").unwrap();
+                    for route in announced {
+                        writeln!(out, "  {}", format_prefixes(&[route])).unwrap();
+                    }
+                }
+                if !withdrawn.is_empty() {
+                    writeln!(out, "WITHDRAW").unwrap();
+                    for route in withdrawn {
+                        writeln!(out, "  {}", format_prefixes(&[route])).unwrap();
+                    }
+                }
+            }
+        },
+        MessageData::Unimplemented => {},
+    };
+
+    out.push('\n');
+    Some(out)
+}
+
+#[cfg(feature = "pmacct")]
+fn format_json(message: &BmpMessage, opts: &Options, formatter: &mut PmacctFormatter) -> Option<String> {
+    let events = formatter.events(message);
+
+    // Route Monitoring gives one event per prefix, in the same order as `routes`
+    let events = match &message.message {
+        MessageData::RouteMonitoring((_, update)) => routes(update).iter()
+            .zip(events)
+            .filter(|(route, _)| opts.wants_route(route))
+            .map(|(_, event)| event)
+            .collect(),
+        _ => events,
+    };
+
+    if events.is_empty() {
+        return None;
+    }
+
+    Some(events.iter().map(|event| format!("{}\n", event)).collect())
+}
+
+fn dump(reader: BmpReader<impl Read>, source: SocketAddr, opts: &Options) {
+    #[cfg(feature = "pmacct")]
+    let mut formatter = PmacctFormatter::new(source);
+
+    // The reader stops by itself once it hits something it can't get past
+    for message in reader {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("bmp-dump: {}: {}", source, err);
+                continue;
+            },
+        };
+
+        if !opts.wants_message(&message) {
+            continue;
+        }
+
+        let text = match opts.format {
+            Format::Line => format_line(&message, opts),
+            Format::Verbose => format_verbose(&message, opts),
+            #[cfg(feature = "pmacct")]
+            Format::Json => format_json(&message, opts, &mut formatter),
+        };
+
+        if let Some(text) = text {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            if out.write_all(text.as_bytes()).and_then(|_| out.flush()).is_err() {
+                // Most likely piped into head or similar
+                process::exit(0);
+            }
+        }
+    }
+}
+
+fn main() {
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(opts)) => Arc::new(opts),
+        Ok(None) => {
+            print!("{}", USAGE);
+            process::exit(0);
+        },
+        Err(msg) => usage_error(&msg),
+    };
+    let unknown = SocketAddr::from(([0, 0, 0, 0], 0));
+
+    let result = match (&opts.listen, opts.input.as_deref()) {
+        (Some(addr), _) => TcpListener::bind(addr).and_then(|listener| {
+            eprintln!("bmp-dump: listening on {}", addr);
+
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let peer = stream.peer_addr()?;
+                let opts = Arc::clone(&opts);
+
+                eprintln!("bmp-dump: connection from {}", peer);
+                thread::spawn(move || {
+                    dump(BmpReader::new(stream), peer, &opts);
+                    eprintln!("bmp-dump: {} disconnected", peer);
+                });
+            }
+
+            Ok(())
+        }),
+        (None, None) | (None, Some("-")) => {
+            dump(BmpReader::new(io::stdin()), unknown, &opts);
+            Ok(())
+        },
+        (None, Some(path)) => BmpReader::from_path(path)
+            .map(|reader| dump(reader, unknown, &opts))
+            .map_err(io::Error::from),
+    };
+
+    if let Err(err) = result {
+        eprintln!("bmp-dump: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse(&[]).unwrap().unwrap();
+        assert_eq!(opts.format, Format::Line);
+        assert!(opts.input.is_none() && opts.listen.is_none());
+
+        let args = ["-v", "-k", "peer_up", "--kind", "route_monitoring", "-p", "192.0.2.1", "-P", "10.0.0.0/8", "in.bmp"];
+        let opts = parse(&args).unwrap().unwrap();
+        assert_eq!(opts.format, Format::Verbose);
+        assert_eq!(opts.kinds, vec![MessageKind::PeerUp, MessageKind::RouteMonitoring]);
+        assert_eq!(opts.peers, vec!["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(opts.prefixes, vec![("10.0.0.0".parse().unwrap(), 8)]);
+        assert_eq!(opts.input.as_deref(), Some("in.bmp"));
+
+        let opts = parse(&["--listen", "127.0.0.1:11019"]).unwrap().unwrap();
+        assert_eq!(opts.listen, Some("127.0.0.1:11019".parse().unwrap()));
+        assert_eq!(parse(&["-"]).unwrap().unwrap().input.as_deref(), Some("-"));
+
+        assert!(parse(&["-v", "--help"]).unwrap().is_none());
+
+        for (args, error) in &[
+            (&["-k"][..], "-k needs a value"),
+            (&["-k", "nope"], "unknown message kind: nope"),
+            (&["-p", "nope"], "invalid address: nope"),
+            (&["-P", "10.0.0.0/33"], "invalid prefix: 10.0.0.0/33"),
+            (&["-l", "nope"], "invalid address: nope"),
+            (&["-x"], "unknown option: -x"),
+            (&["a", "b"], "only one input file can be given"),
+            (&["-l", "127.0.0.1:11019", "a"], "can't read a file and listen at the same time"),
+        ] {
+            assert_eq!(parse(args).unwrap_err(), *error, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_parse_args_json() {
+        #[cfg(feature = "pmacct")]
+        assert_eq!(parse(&["-j"]).unwrap().unwrap().format, Format::Json);
+        #[cfg(not(feature = "pmacct"))]
+        assert!(parse(&["-j"]).unwrap_err().contains("pmacct feature"));
+    }
+
+    #[test]
+    fn test_parse_prefix() {
+        assert_eq!(parse_prefix("192.0.2.0/24"), Some(("192.0.2.0".parse().unwrap(), 24)));
+        assert_eq!(parse_prefix("192.0.2.1"), Some(("192.0.2.1".parse().unwrap(), 32)));
+        assert_eq!(parse_prefix("2001:db8::/32"), Some(("2001:db8::".parse().unwrap(), 32)));
+        assert_eq!(parse_prefix("2001:db8::1"), Some(("2001:db8::1".parse().unwrap(), 128)));
+        assert_eq!(parse_prefix("2001:db8::/129"), None);
+        assert_eq!(parse_prefix("192.0.2.0/"), None);
+        assert_eq!(parse_prefix("nope/8"), None);
+    }
+
+    #[test]
+    fn test_format() {
+        let opts = parse(&[]).unwrap().unwrap();
+        let messages = BmpReader::from_path("test_data/synthetic.dump")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!messages.is_empty());
+
+        for message in &messages {
+            let line = format_line(message, &opts).unwrap();
+            assert!(line.contains(&message.kind.to_string()), "{}", line);
+            assert_eq!(line.matches('\n').count(), 1, "{}", line);
+            assert!(format_verbose(message, &opts).unwrap().ends_with('\n'));
+        }
+
+        // The first update announces 198.51.100.0/24 and 203.0.113.0/24
+        let rm = messages.iter().find(|m| m.kind == MessageKind::RouteMonitoring).unwrap();
+        let opts = parse(&["-P", "198.51.0.0/16"]).unwrap().unwrap();
+        let line = format_line(rm, &opts).unwrap();
+        assert!(line.contains("announce 198.51.100.0/24 path"), "{}", line);
+        let opts = parse(&["-P", "192.0.2.0/24"]).unwrap().unwrap();
+        assert!(format_line(rm, &opts).is_none());
+    }
+}
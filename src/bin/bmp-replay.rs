@@ -0,0 +1,368 @@
+//! Replay BMP dump files to a collector
+//!
+//! Reads files of back-to-back BMP messages (the format used in `test_data/`) and sends them over
+//! TCP as one or more virtual routers, either as fast as possible, at a fixed rate or with the
+//! original gaps between messages.
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const USAGE: &str = "\
+Usage: bmp-replay [OPTIONS] COLLECTOR FILE...
+
+Send the BMP messages in each FILE to COLLECTOR (eg. 127.0.0.1:11019)
+
+Options:
+  -n, --routers N          Number of concurrent virtual routers, each with its own connection (default 1)
+  -L, --loop COUNT         Play the files COUNT times, 0 to loop forever (default 1). Each pass uses a new connection
+  -t, --now                Rewrite Per-Peer header timestamps to the current time
+  -o, --original-timing    Keep the gaps between messages, based on their Per-Peer header timestamps
+  -r, --rate N             Send at most N messages per second per router
+  -h, --help               Show this message
+
+Messages are sent as fast as possible unless --original-timing or --rate is given
+";
+
+// Common header is 6 bytes, the timestamp follows type, flags, distinguisher, address, ASN and BGP ID
+const TIMESTAMP_OFFSET: usize = 6 + 34;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Timing {
+    Fast,
+    Original,
+    Rate(f64),
+}
+
+impl Timing {
+    /// How long after the start of a pass frame `idx` is due, None to send it straight away
+    fn due(self, idx: usize, timestamp: Option<Duration>, first: Option<Duration>) -> Option<Duration> {
+        match self {
+            Timing::Fast => None,
+            Timing::Rate(rate) => Some(Duration::from_secs_f64(idx as f64 / rate)),
+            // Messages without a timestamp are sent straight away, ones that go backwards are due at the start
+            Timing::Original => match (timestamp, first) {
+                (Some(timestamp), Some(first)) => Some(timestamp.checked_sub(first).unwrap_or_default()),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    collector: SocketAddr,
+    files: Vec<String>,
+    routers: usize,
+    loops: usize,
+    now: bool,
+    timing: Timing,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("bmp-replay: {}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+/// Parse the command line (without the program name), None means help was asked for
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut collector = None;
+    let mut files = vec![];
+    let mut routers = 1;
+    let mut loops = 1;
+    let mut now = false;
+    let mut timing = Timing::Fast;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-n" | "--routers" => {
+                let value = value(&arg)?;
+                routers = value.parse().ok()
+                    .filter(|routers| *routers > 0)
+                    .ok_or_else(|| format!("invalid number of routers: {}", value))?;
+            },
+            "-L" | "--loop" => {
+                let value = value(&arg)?;
+                loops = value.parse().map_err(|_| format!("invalid loop count: {}", value))?;
+            },
+            "-t" | "--now" => now = true,
+            "-o" | "--original-timing" => timing = Timing::Original,
+            "-r" | "--rate" => {
+                let value = value(&arg)?;
+                let rate = value.parse().ok()
+                    .filter(|rate: &f64| *rate > 0.0 && rate.is_finite())
+                    .ok_or_else(|| format!("invalid rate: {}", value))?;
+                timing = Timing::Rate(rate);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option: {}", arg)),
+            _ if collector.is_none() => {
+                let addr = arg.to_socket_addrs().ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or_else(|| format!("invalid collector address: {}", arg))?;
+                collector = Some(addr);
+            },
+            _ => files.push(arg),
+        };
+    }
+
+    let collector = collector.ok_or("no collector given")?;
+    if files.is_empty() {
+        return Err("no files given".into());
+    }
+
+    Ok(Some(Options { collector, files, routers, loops, now, timing }))
+}
+
+/// A single message from a dump file
+#[derive(Debug)]
+struct Frame {
+    data: Vec<u8>,
+    /// Per-Peer header timestamp, for messages that have one
+    timestamp: Option<Duration>,
+}
+
+impl Frame {
+    fn has_peer_header(data: &[u8]) -> bool {
+        // Route Monitoring, Statistics Report, Peer Down, Peer Up and Route Mirroring
+        matches!(data[5], 0 | 1 | 2 | 3 | 6) && data.len() >= TIMESTAMP_OFFSET + 8
+    }
+
+    fn new(data: Vec<u8>) -> Self {
+        let timestamp = if Self::has_peer_header(&data) {
+            let field = |offset: usize| {
+                let mut bytes = [0u8; 4];
+                bytes.copy_from_slice(&data[offset..offset + 4]);
+                u32::from_be_bytes(bytes)
+            };
+            let secs = field(TIMESTAMP_OFFSET);
+            let micros = field(TIMESTAMP_OFFSET + 4);
+
+            Some(Duration::from_secs(u64::from(secs)) + Duration::from_micros(u64::from(micros % 1_000_000)))
+        } else {
+            None
+        };
+
+        Self { data, timestamp }
+    }
+}
+
+/// Split a dump into messages using the length in each common header
+fn parse_frames(data: &[u8]) -> Result<Vec<Frame>, String> {
+    let mut frames = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.len() < 6 {
+            return Err(format!("{} trailing bytes at offset {}", rest.len(), offset));
+        }
+        if rest[0] != 3 {
+            return Err(format!("unsupported BMP version {} at offset {}", rest[0], offset));
+        }
+
+        let length = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        if length < 6 || length > rest.len() {
+            return Err(format!("invalid message length {} at offset {}", length, offset));
+        }
+
+        frames.push(Frame::new(rest[..length].to_vec()));
+        offset += length;
+    }
+
+    Ok(frames)
+}
+
+fn read_frames(path: &str) -> io::Result<Vec<Frame>> {
+    parse_frames(&fs::read(path)?)
+        .map_err(|msg| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg)))
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    messages: u64,
+    bytes: u64,
+}
+
+fn sleep_until(deadline: Instant) {
+    let now = Instant::now();
+    if deadline > now {
+        thread::sleep(deadline - now);
+    }
+}
+
+/// Play every frame once over a new connection
+fn play(opts: &Options, frames: &[Frame], stats: &mut Stats) -> io::Result<()> {
+    let mut stream = TcpStream::connect(opts.collector)?;
+    stream.set_nodelay(true)?;
+
+    let start = Instant::now();
+    let first_timestamp = frames.iter().find_map(|frame| frame.timestamp);
+    let mut buf = Vec::new();
+
+    for (idx, frame) in frames.iter().enumerate() {
+        if let Some(due) = opts.timing.due(idx, frame.timestamp, first_timestamp) {
+            sleep_until(start + due);
+        }
+
+        buf.clear();
+        buf.extend_from_slice(&frame.data);
+        if opts.now && frame.timestamp.is_some() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+            buf[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 4].copy_from_slice(&(now.as_secs() as u32).to_be_bytes());
+            buf[TIMESTAMP_OFFSET + 4..TIMESTAMP_OFFSET + 8].copy_from_slice(&now.subsec_micros().to_be_bytes());
+        }
+
+        stream.write_all(&buf)?;
+        stats.messages += 1;
+        stats.bytes += buf.len() as u64;
+    }
+
+    stream.flush()
+}
+
+fn router(id: usize, opts: &Options, frames: &[Frame]) -> Stats {
+    let mut stats = Stats::default();
+
+    let mut pass = 0;
+    while opts.loops == 0 || pass < opts.loops {
+        if let Err(err) = play(opts, frames, &mut stats) {
+            eprintln!("bmp-replay: router {}: {}", id, err);
+            break;
+        }
+        pass += 1;
+    }
+
+    stats
+}
+
+fn main() {
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(opts)) => Arc::new(opts),
+        Ok(None) => {
+            print!("{}", USAGE);
+            process::exit(0);
+        },
+        Err(msg) => usage_error(&msg),
+    };
+
+    let mut frames = vec![];
+    for path in &opts.files {
+        match read_frames(path) {
+            Ok(file_frames) => frames.extend(file_frames),
+            Err(err) => {
+                eprintln!("bmp-replay: {}", err);
+                process::exit(1);
+            },
+        };
+    }
+    let frames = Arc::new(frames);
+
+    let start = Instant::now();
+    let handles = (0..opts.routers)
+        .map(|id| {
+            let opts = Arc::clone(&opts);
+            let frames = Arc::clone(&frames);
+            thread::spawn(move || router(id, &opts, &frames))
+        })
+        .collect::<Vec<_>>();
+
+    let mut total = Stats::default();
+    for handle in handles {
+        let stats = handle.join().unwrap_or_default();
+        total.messages += stats.messages;
+        total.bytes += stats.bytes;
+    }
+
+    let elapsed = start.elapsed().as_secs_f64();
+    eprintln!(
+        "bmp-replay: sent {} messages ({} bytes) from {} routers in {:.3}s ({:.0} msg/s)",
+        total.messages, total.bytes, opts.routers, elapsed, total.messages as f64 / elapsed.max(1e-9),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse(&["127.0.0.1:11019", "a.bmp", "b.bmp"]).unwrap().unwrap();
+        assert_eq!(opts.collector, "127.0.0.1:11019".parse().unwrap());
+        assert_eq!(opts.files, vec!["a.bmp", "b.bmp"]);
+        assert_eq!((opts.routers, opts.loops, opts.now, opts.timing), (1, 1, false, Timing::Fast));
+
+        let opts = parse(&["-n", "4", "--loop", "0", "-t", "-r", "2.5", "127.0.0.1:11019", "a.bmp"]).unwrap().unwrap();
+        assert_eq!((opts.routers, opts.loops, opts.now, opts.timing), (4, 0, true, Timing::Rate(2.5)));
+        assert_eq!(parse(&["-o", "127.0.0.1:11019", "a.bmp"]).unwrap().unwrap().timing, Timing::Original);
+
+        assert!(parse(&["--help", "127.0.0.1:11019"]).unwrap().is_none());
+
+        for (args, error) in &[
+            (&[][..], "no collector given"),
+            (&["127.0.0.1:11019"], "no files given"),
+            (&["nope", "a.bmp"], "invalid collector address: nope"),
+            (&["-n"], "-n needs a value"),
+            (&["-n", "0"], "invalid number of routers: 0"),
+            (&["-L", "-1"], "invalid loop count: -1"),
+            (&["-r", "0"], "invalid rate: 0"),
+            (&["-r", "inf"], "invalid rate: inf"),
+            (&["-x"], "unknown option: -x"),
+        ] {
+            assert_eq!(parse(args).unwrap_err(), *error, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let data = fs::read("test_data/synthetic.dump").unwrap();
+        let frames = read_frames("test_data/synthetic.dump").unwrap();
+        assert_eq!(frames.iter().map(|frame| frame.data.len()).sum::<usize>(), data.len());
+        assert_eq!(frames.len(), bmp_protocol::BmpReader::new(&data[..]).count());
+
+        // Initiation has no Per-Peer header, everything after it does
+        assert_eq!(frames[0].data[5], 4);
+        assert!(frames[0].timestamp.is_none());
+        assert_eq!(frames[1].timestamp, Some(Duration::from_secs(1_577_836_800)));
+
+        let err = read_frames("test_data/nope.dump").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let first = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+        assert!(parse_frames(&data[..first + 3]).unwrap_err().contains("3 trailing bytes"));
+        assert!(parse_frames(&data[..first + 10]).unwrap_err().starts_with("invalid message length"));
+
+        let mut bad = data[..first].to_vec();
+        bad[0] = 2;
+        assert_eq!(parse_frames(&bad).unwrap_err(), "unsupported BMP version 2 at offset 0");
+        bad[0] = 3;
+        bad[1..5].copy_from_slice(&5u32.to_be_bytes());
+        assert_eq!(parse_frames(&bad).unwrap_err(), "invalid message length 5 at offset 0");
+    }
+
+    #[test]
+    fn test_timing() {
+        let first = Some(Duration::from_secs(100));
+        let at = |secs| Some(Duration::from_secs(secs));
+
+        assert_eq!(Timing::Fast.due(10, at(200), first), None);
+
+        assert_eq!(Timing::Rate(4.0).due(0, None, first), Some(Duration::from_secs(0)));
+        assert_eq!(Timing::Rate(4.0).due(10, None, first), Some(Duration::from_millis(2500)));
+
+        assert_eq!(Timing::Original.due(3, at(102), first), Some(Duration::from_secs(2)));
+        assert_eq!(Timing::Original.due(3, at(99), first), Some(Duration::from_secs(0)));
+        assert_eq!(Timing::Original.due(3, None, first), None);
+        assert_eq!(Timing::Original.due(3, at(102), None), None);
+    }
+}
@@ -0,0 +1,371 @@
+//! Arrow / Parquet export of route events (requires the `arrow` feature)
+//!
+//! `RouteBatchBuilder` collects one row per prefix from Route Monitoring messages and produces
+//! Arrow `RecordBatch`es, `ParquetRouteWriter` writes those batches to a directory of Parquet
+//! files, starting a new file once the current one reaches a size or covers a span of time.
+
+use crate::{
+    Result,
+    route::{routes, RouteAttributes},
+    types::*,
+};
+
+use arrow_array::{
+    ArrayRef,
+    RecordBatch,
+    builder::{BooleanBuilder, ListBuilder, StringBuilder, TimestampMicrosecondBuilder, UInt32Builder},
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Schema of the batches produced by `RouteBatchBuilder`
+pub fn route_schema() -> SchemaRef {
+    let list = |name| Field::new(name, DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))), false);
+
+    Arc::new(Schema::new(vec![
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("router", DataType::Utf8, false),
+        Field::new("peer_addr", DataType::Utf8, false),
+        Field::new("peer_asn", DataType::UInt32, false),
+        Field::new("peer_type", DataType::Utf8, false),
+        Field::new("post_policy", DataType::Boolean, false),
+        Field::new("adj_rib_out", DataType::Boolean, false),
+        Field::new("prefix", DataType::Utf8, false),
+        Field::new("path_id", DataType::UInt32, true),
+        Field::new("action", DataType::Utf8, false),
+        Field::new("as_path", DataType::Utf8, true),
+        Field::new("next_hop", DataType::Utf8, true),
+        list("communities"),
+        list("large_communities"),
+    ]))
+}
+
+/// Builds Arrow record batches of route events, one row per announced or withdrawn prefix
+#[derive(Debug)]
+pub struct RouteBatchBuilder {
+    router: String,
+    rows: usize,
+    timestamp: TimestampMicrosecondBuilder,
+    router_col: StringBuilder,
+    peer_addr: StringBuilder,
+    peer_asn: UInt32Builder,
+    peer_type: StringBuilder,
+    post_policy: BooleanBuilder,
+    adj_rib_out: BooleanBuilder,
+    prefix: StringBuilder,
+    path_id: UInt32Builder,
+    action: StringBuilder,
+    as_path: StringBuilder,
+    next_hop: StringBuilder,
+    communities: ListBuilder<StringBuilder>,
+    large_communities: ListBuilder<StringBuilder>,
+}
+
+impl RouteBatchBuilder {
+    /// Create a new builder for messages from `router`
+    pub fn new(router: IpAddr) -> Self {
+        Self {
+            router: router.to_string(),
+            rows: 0,
+            timestamp: TimestampMicrosecondBuilder::new().with_timezone("UTC"),
+            router_col: StringBuilder::new(),
+            peer_addr: StringBuilder::new(),
+            peer_asn: UInt32Builder::new(),
+            peer_type: StringBuilder::new(),
+            post_policy: BooleanBuilder::new(),
+            adj_rib_out: BooleanBuilder::new(),
+            prefix: StringBuilder::new(),
+            path_id: UInt32Builder::new(),
+            action: StringBuilder::new(),
+            as_path: StringBuilder::new(),
+            next_hop: StringBuilder::new(),
+            communities: ListBuilder::new(StringBuilder::new()),
+            large_communities: ListBuilder::new(StringBuilder::new()),
+        }
+    }
+
+    /// Number of rows waiting to be turned into a batch
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    /// Whether there are any rows waiting
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    /// Add a row for every prefix in a Route Monitoring message, returning the number of rows
+    /// added. Any other type of message is ignored
+    pub fn append(&mut self, message: &BmpMessage) -> usize {
+        let (header, update) = match &message.message {
+            MessageData::RouteMonitoring((header, update)) => (header, update),
+            _ => return 0,
+        };
+
+        let routes = routes(update);
+        let attrs = RouteAttributes::from_update(update);
+        let timestamp = header.system_time().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        for route in &routes {
+            self.timestamp.append_value(timestamp.as_micros() as i64);
+            self.router_col.append_value(&self.router);
+            self.peer_addr.append_value(header.peer_addr.to_string());
+            self.peer_asn.append_value(header.peer_asn);
+            self.peer_type.append_value(header.peer_type.to_string());
+            self.post_policy.append_value(header.peer_flags.L);
+            self.adj_rib_out.append_value(header.peer_flags.O);
+            self.prefix.append_value(format!("{}/{}", route.prefix, route.length));
+            self.path_id.append_option(route.path_id);
+
+            if route.withdrawn {
+                self.action.append_value("withdraw");
+                self.as_path.append_null();
+                self.next_hop.append_null();
+                self.communities.append(true);
+                self.large_communities.append(true);
+            } else {
+                self.action.append_value("announce");
+                self.as_path.append_value(attrs.as_path_string());
+                self.next_hop.append_option(attrs.next_hop.map(|nh| nh.to_string()));
+                for community in attrs.community_strings() {
+                    self.communities.values().append_value(community);
+                }
+                self.communities.append(true);
+                for community in attrs.large_community_strings() {
+                    self.large_communities.values().append_value(community);
+                }
+                self.large_communities.append(true);
+            }
+        }
+
+        self.rows += routes.len();
+        routes.len()
+    }
+
+    /// Produce a batch from the rows added so far and reset the builder
+    pub fn finish(&mut self) -> RecordBatch {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(self.timestamp.finish()),
+            Arc::new(self.router_col.finish()),
+            Arc::new(self.peer_addr.finish()),
+            Arc::new(self.peer_asn.finish()),
+            Arc::new(self.peer_type.finish()),
+            Arc::new(self.post_policy.finish()),
+            Arc::new(self.adj_rib_out.finish()),
+            Arc::new(self.prefix.finish()),
+            Arc::new(self.path_id.finish()),
+            Arc::new(self.action.finish()),
+            Arc::new(self.as_path.finish()),
+            Arc::new(self.next_hop.finish()),
+            Arc::new(self.communities.finish()),
+            Arc::new(self.large_communities.finish()),
+        ];
+        self.rows = 0;
+
+        RecordBatch::try_new(route_schema(), columns).expect("route batch doesn't match schema")
+    }
+}
+
+/// Writes route events to a directory of Parquet files
+///
+/// Files are named `routes-NNNNNN.parquet`. A new file is started once the current one is
+/// bigger than the maximum size, or once a message arrives with a timestamp more than the
+/// maximum age after the first message in the file. Message timestamps are used rather than
+/// the system clock so replaying old data gives the same set of files.
+#[derive(Debug)]
+pub struct ParquetRouteWriter {
+    dir: PathBuf,
+    builder: RouteBatchBuilder,
+    batch_size: usize,
+    max_file_size: Option<usize>,
+    max_file_age: Option<Duration>,
+    writer: Option<ArrowWriter<File>>,
+    file_start: Option<Duration>,
+    files: Vec<PathBuf>,
+}
+
+impl ParquetRouteWriter {
+    /// Create a new writer for messages from `router`, the directory must already exist
+    pub fn new(dir: impl AsRef<Path>, router: IpAddr) -> Self {
+        Self {
+            dir: dir.as_ref().into(),
+            builder: RouteBatchBuilder::new(router),
+            batch_size: 8192,
+            max_file_size: None,
+            max_file_age: None,
+            writer: None,
+            file_start: None,
+            files: vec![],
+        }
+    }
+
+    /// Number of rows to buffer before writing a batch
+    pub fn with_batch_size(mut self, rows: usize) -> Self {
+        self.batch_size = rows.max(1);
+        self
+    }
+
+    /// Start a new file once the current one reaches this many bytes
+    pub fn with_max_file_size(mut self, bytes: usize) -> Self {
+        self.max_file_size = Some(bytes);
+        self
+    }
+
+    /// Start a new file once it covers this span of (message) time
+    pub fn with_max_file_age(mut self, age: Duration) -> Self {
+        self.max_file_age = Some(age);
+        self
+    }
+
+    /// Files which have been completely written
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Add the routes from a message
+    pub fn write(&mut self, message: &BmpMessage) -> Result<()> {
+        let header = match &message.message {
+            MessageData::RouteMonitoring((header, _)) => header,
+            _ => return Ok(()),
+        };
+        let timestamp = header.system_time().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        if let (Some(max_age), Some(start)) = (self.max_file_age, self.file_start) {
+            if timestamp >= start + max_age {
+                self.rotate()?;
+            }
+        }
+
+        if self.builder.append(message) > 0 && self.file_start.is_none() {
+            self.file_start = Some(timestamp);
+        }
+
+        if self.builder.len() >= self.batch_size {
+            self.flush()?;
+
+            let size = self.writer.as_ref()
+                .map(|writer| writer.bytes_written() + writer.in_progress_size())
+                .unwrap_or(0);
+            if self.max_file_size.map(|max| size >= max).unwrap_or(false) {
+                self.rotate()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write any buffered rows to the current file
+    pub fn flush(&mut self) -> Result<()> {
+        if self.builder.is_empty() {
+            return Ok(());
+        }
+
+        let batch = self.builder.finish();
+        if self.writer.is_none() {
+            let path = self.dir.join(format!("routes-{:06}.parquet", self.files.len()));
+            let file = File::create(&path)?;
+            self.writer = Some(ArrowWriter::try_new(file, route_schema(), None)?);
+        }
+
+        if let Some(writer) = &mut self.writer {
+            writer.write(&batch)?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the current file, the next rows will go into a new one
+    pub fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+            self.files.push(self.dir.join(format!("routes-{:06}.parquet", self.files.len())));
+        }
+        self.file_start = None;
+
+        Ok(())
+    }
+
+    /// Finish writing, returning the paths of every file written
+    pub fn close(mut self) -> Result<Vec<PathBuf>> {
+        self.rotate()?;
+        Ok(self.files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BmpDecoder;
+
+    use arrow_array::{Array, StringArray};
+    use bytes::BytesMut;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use std::fs;
+
+    fn messages() -> Vec<BmpMessage> {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+
+        let mut messages = vec![];
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            messages.push(message);
+        }
+
+        messages
+    }
+
+    #[test]
+    fn test_route_batch() {
+        let mut builder = RouteBatchBuilder::new("192.0.2.254".parse().unwrap());
+        for message in messages() {
+            builder.append(&message);
+        }
+
+        let batch = builder.finish();
+        assert_eq!(batch.num_rows(), 3);
+        assert!(builder.is_empty());
+
+        let column = |name| batch.column_by_name(name).unwrap().as_any().downcast_ref::<StringArray>().unwrap().clone();
+        assert_eq!(column("prefix").value(2), "2001:db8:1000::/36");
+        assert_eq!(column("as_path").value(0), "65001 64512");
+        assert_eq!(column("next_hop").value(1), "192.0.2.1");
+        assert_eq!(column("action").value(0), "announce");
+    }
+
+    #[test]
+    fn test_parquet_rotation() {
+        let dir = std::env::temp_dir().join(format!("bmp-protocol-parquet-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // The IPv6 route is a second after the IPv4 ones so ends up in its own file
+        let mut writer = ParquetRouteWriter::new(&dir, "192.0.2.254".parse().unwrap())
+            .with_batch_size(1)
+            .with_max_file_age(Duration::from_secs(1));
+        for message in messages() {
+            writer.write(&message).unwrap();
+        }
+        let files = writer.close().unwrap();
+        assert_eq!(files.len(), 2);
+
+        let rows = files.iter()
+            .map(|path| {
+                let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap()
+                    .build()
+                    .unwrap();
+                reader.map(|batch| batch.unwrap().num_rows()).sum::<usize>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![2, 1]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
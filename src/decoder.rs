@@ -1,23 +1,31 @@
 use crate::{
     Error,
     Result,
+    state::{CapabilityOverride, CapabilitySet, DecoderState, OverrideMode, PeerCapabilities, STATE_VERSION},
     types::*,
+    validate::check_update,
 };
 
 use bgp_rs::Capabilities;
 use bytes::{
     Buf,
     buf::BufExt,
+    Bytes,
     BytesMut
 };
 use hashbrown::HashMap;
+#[cfg(feature = "tokio-codec")]
 use tokio_util::codec::Decoder;
 
 use std::convert::TryInto;
 use std::net::IpAddr;
+use std::sync::Arc;
 
 // We need at least 5 bytes worth of the message in order to get the length
 const BMP_HEADER_LEN: usize = 5;
+// The biggest message we'll take: an RFC8654 extended BGP message, with another 64KiB for the BMP
+// headers and any information TLVs. Anything longer is a corrupt header, and not worth buffering for
+pub(crate) const MAX_MESSAGE_LEN: usize = 65535 + 64 * 1024;
 
 /// Work out the common set of capabilities on a peering session
 fn common_capabilities(source: &Capabilities, other: &Capabilities) -> Capabilities {
@@ -78,17 +86,22 @@ fn common_capabilities(source: &Capabilities, other: &Capabilities) -> Capabilit
     negotiated
 }
 
-#[derive(Clone, Debug)]
-enum DecoderState {
-    Head,
-    Data((u8, usize))
-}
-
-/// Decoder implementation for use with a FramedReader
+/// Decoder for BMP messages
+///
+/// The decoding itself is sans-IO, `decode_slice` takes whatever bytes are available and reports
+/// how many of them were used. With the `tokio-codec` feature (on by default) this also implements
+/// `tokio_util::codec::Decoder` for use with a FramedRead, and `BmpReader` wraps it around anything
+/// that implements `std::io::Read`.
+///
+/// Output doesn't depend on how the input is split up: feeding the same bytes all at once, a byte
+/// at a time or anything in between produces the same messages and errors, in the same order.
 #[derive(Clone, Debug)]
 pub struct BmpDecoder {
-    client_capabilities: HashMap<IpAddr, Capabilities>,
-    state: DecoderState,
+    // Shared so Route Monitoring messages can be parsed elsewhere, see `PendingUpdate`
+    client_capabilities: HashMap<PeerKey, Arc<Capabilities>>,
+    // The BMP speaker we're decoding for, to pick out overrides that only apply to it
+    router: Option<IpAddr>,
+    overrides: Vec<(CapabilityOverride, Arc<Capabilities>)>,
 }
 
 impl BmpDecoder {
@@ -96,82 +109,166 @@ impl BmpDecoder {
     pub fn new() -> Self {
         Self {
             client_capabilities: HashMap::new(),
-            state: DecoderState::Head,
+            router: None,
+            overrides: vec![],
         }
     }
 
-    fn decode_head(&mut self, src: &mut BytesMut) -> Result<Option<(u8, usize)>> {
-        if src.len() < BMP_HEADER_LEN {
-            return Ok(None);
+    /// Set the address of the router (BMP speaker) being decoded, so capability overrides for that
+    /// router apply
+    pub fn with_router(mut self, router: IpAddr) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    /// Use fixed capabilities for some peers instead of (or when missing) the ones from their
+    /// Peer Up OPENs, replacing any previous overrides
+    ///
+    /// Overrides for a specific peer win over those for any peer, and overrides for a specific
+    /// router win over those for any router. They take effect from the next Peer Up.
+    pub fn set_overrides(&mut self, overrides: Vec<CapabilityOverride>) -> Result<()> {
+        let mut overrides = overrides.into_iter()
+            .map(|ovr| {
+                let capabilities = Arc::new(ovr.capabilities.to_capabilities()?);
+                Ok((ovr, capabilities))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Most specific first
+        overrides.sort_by_key(|(ovr, _)| (ovr.peer.is_none(), ovr.router.is_none()));
+        self.overrides = overrides;
+
+        Ok(())
+    }
+
+    fn capability_override(&self, peer: &PeerKey) -> Option<(OverrideMode, Arc<Capabilities>)> {
+        self.overrides.iter()
+            .find(|(ovr, _)| {
+                ovr.peer.is_none_or(|key| key == *peer)
+                    && ovr.router.is_none_or(|router| Some(router) == self.router)
+            })
+            .map(|(ovr, capabilities)| (ovr.mode, Arc::clone(capabilities)))
+    }
+
+    /// Peers the decoder has capabilities for, from Peer Up messages (or `import_state`)
+    pub fn peers(&self) -> impl Iterator<Item = &PeerKey> {
+        self.client_capabilities.keys()
+    }
+
+    /// The capabilities Route Monitoring messages from `peer` are decoded with
+    pub fn capabilities(&self, peer: &PeerKey) -> Option<&Capabilities> {
+        self.client_capabilities.get(peer).map(|caps| &**caps)
+    }
+
+    /// Snapshot the capabilities negotiated with each peer, so another decoder can carry on from
+    /// here with `import_state`
+    pub fn export_state(&self) -> DecoderState {
+        let mut peers = self.client_capabilities.iter()
+            .map(|(peer, caps)| PeerCapabilities { peer: *peer, capabilities: CapabilitySet::from(&**caps) })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.peer);
+
+        DecoderState { version: STATE_VERSION, peers }
+    }
+
+    /// Restore capabilities saved by `export_state`, replacing any this decoder already has for
+    /// the same peers
+    pub fn import_state(&mut self, state: DecoderState) -> Result<()> {
+        if state.version != STATE_VERSION {
+            return Err(Error::decode(&format!("unsupported decoder state version {}", state.version)));
         }
 
-        let version = src.get_u8();
-        let length = src.get_u32() as usize;
-        let remaining = length - BMP_HEADER_LEN;
+        let peers = state.peers.iter()
+            .map(|peer| Ok((peer.peer, Arc::new(peer.capabilities.to_capabilities()?))))
+            .collect::<Result<Vec<_>>>()?;
+        self.client_capabilities.extend(peers);
 
-        src.reserve(remaining);
-        tracing::trace!(buf_capacity = %src.capacity());
+        Ok(())
+    }
+
+    /// Decode the BMP message at the start of `data`
+    ///
+    /// Returns the number of bytes used along with the result. Nothing is used until `data` holds
+    /// a complete message, a message which fails to decode is still used up so the caller can carry
+    /// on with the next one, and a common header which can't be decoded uses nothing at all.
+    pub fn decode_slice(&mut self, data: &[u8]) -> (usize, Result<Option<BmpMessage>>) {
+        match frame_length(data) {
+            Ok(Some(length)) if data.len() >= length => {
+                let raw = Bytes::copy_from_slice(&data[..length]);
+                (length, self.decode_frame(raw).map(Some))
+            },
+            Ok(_) => (0, Ok(None)),
+            Err(err) => (0, Err(err)),
+        }
+    }
 
-        Ok(Some((version, remaining)))
+    /// Decode the message at the start of `src`, taking it from the buffer without copying
+    pub(crate) fn decode_buf(&mut self, src: &mut BytesMut) -> Result<Option<BmpMessage>> {
+        match take_frame(src)? {
+            Some(raw) => self.decode_frame(raw).map(Some),
+            None => Ok(None),
+        }
     }
 
-    fn decode_data(&mut self, version: u8, length: usize, src: &mut BytesMut) -> Result<Option<BmpMessage>> {
-        // The BytesMut should already have the required capacity reserved so if we haven't read
-        // the entire message yet, just keep on reading!
-        if src.len() < length {
-            return Ok(None);
+    /// Decode a single, complete, BMP frame
+    pub(crate) fn decode_frame(&mut self, raw: Bytes) -> Result<BmpMessage> {
+        match self.prepare_frame(raw)? {
+            Frame::Message(message) => Ok(message),
+            Frame::RouteMonitoring(update) => update.decode(),
         }
+    }
 
-        // Now we take the message while leaving anything else in the buffer
-        let mut buf = src.split_to(length);
+    /// Decode a single, complete, BMP frame apart from the BGP UPDATE in a Route Monitoring
+    /// message, which is left for the caller along with the capabilities needed to parse it.
+    /// Everything that changes the decoder's state happens here, in order.
+    pub(crate) fn prepare_frame(&mut self, raw: Bytes) -> Result<Frame> {
+        let version = raw[0];
+        let mut buf = raw.slice(BMP_HEADER_LEN..);
 
         // Now decode based on the MessageKind
         let kind: MessageKind = buf.get_u8().try_into()?;
         let message = match kind {
             MessageKind::Initiation => {
                 let mut tlv = vec![];
-                while buf.remaining() > 0 {
-                    let kind = buf.get_u16();
-
-                    let info = match kind {
-                        x if x <= 2 => InformationTlv::decode(kind, &mut buf)?,
-                        _ => { break; }
-                    };
-
-                    tlv.push(info);
+                while buf.has_remaining() {
+                    tlv.push(InformationTlv::decode(&mut buf)?);
                 }
 
                 MessageData::Initiation(tlv)
             },
             MessageKind::PeerUp => {
                 let peer_header = PeerHeader::decode(&mut buf)?;
-                let message = PeerUp::decode(&peer_header.peer_flags, &mut buf)?;
-
-                // Record the speaker capabilities, we'll use these later
-                self.client_capabilities.entry(peer_header.peer_addr)
-                    .or_insert_with(|| {
-                        match (&message.sent_open, &message.recv_open) {
-                            (Some(s), Some(r)) => {
-                                let local_caps = Capabilities::from_parameters(s.parameters.clone());
-                                let remote_caps = Capabilities::from_parameters(r.parameters.clone());
-
-                                let mut caps = common_capabilities(&local_caps, &remote_caps);
-
-                                // Use the BMP header val, not the negotiated val
-                                if !peer_header.peer_flags.A { caps.FOUR_OCTET_ASN_SUPPORT = true; }
-                                caps
-                            },
-                            _ => {
-                                tracing::warn!("Missing BGP OPENs (local: {} remote: {}", message.local_addr, peer_header.peer_addr);
-
-                                let mut caps = Capabilities::default();
-                                if !peer_header.peer_flags.A { caps.FOUR_OCTET_ASN_SUPPORT = true; }
-
-                                caps
-                            }
-                        }
-                    });
-                    // .or_insert_with(|| Capabilities::common(&message.sent_open, &message.recv_open).expect("missing capabilities"));
+                let mut message = PeerUp::decode(&peer_header.peer_flags, &mut buf)?;
+
+                // Record the speaker capabilities, we'll use these later. Each Peer Up replaces what
+                // we had for the peer, unless it has nothing better to offer
+                let peer = PeerKey::from(&peer_header);
+                let capabilities = match (&message.sent_open, &message.recv_open, self.capability_override(&peer)) {
+                    (_, _, Some((OverrideMode::Always, caps))) => caps,
+                    (Some(s), Some(r), _) => {
+                        let local_caps = Capabilities::from_parameters(s.parameters.clone());
+                        let remote_caps = Capabilities::from_parameters(r.parameters.clone());
+
+                        let mut caps = common_capabilities(&local_caps, &remote_caps);
+
+                        // Use the BMP header val, not the negotiated val
+                        if !peer_header.peer_flags.A { caps.FOUR_OCTET_ASN_SUPPORT = true; }
+                        Arc::new(caps)
+                    },
+                    (_, _, Some((OverrideMode::Missing, caps))) => caps,
+                    _ => match self.client_capabilities.get(&peer) {
+                        Some(caps) => Arc::clone(caps),
+                        None => {
+                            tracing::warn!("Missing BGP OPENs (local: {} remote: {}", message.local_addr, peer);
+
+                            let mut caps = Capabilities::default();
+                            if !peer_header.peer_flags.A { caps.FOUR_OCTET_ASN_SUPPORT = true; }
+
+                            Arc::new(caps)
+                        },
+                    },
+                };
+                self.client_capabilities.insert(peer, Arc::clone(&capabilities));
+                message.capabilities = Some(Box::new(CapabilitySet::from(&*capabilities)));
 
                 MessageData::PeerUp((peer_header, message))
             },
@@ -180,57 +277,635 @@ impl BmpDecoder {
                 let peer_header = PeerHeader::decode(&mut buf)?;
                 let message = PeerDown::decode(&mut buf)?;
 
-                self.client_capabilities.remove(&peer_header.peer_addr);
+                self.client_capabilities.remove(&PeerKey::from(&peer_header));
 
                 MessageData::PeerDown((peer_header, message))
             },
             MessageKind::RouteMonitoring => {
                 let peer_header = PeerHeader::decode(&mut buf)?;
-                let capabilities = self.client_capabilities.get(&peer_header.peer_addr)
+                let peer = PeerKey::from(&peer_header);
+                let capabilities = self.client_capabilities.get(&peer)
                     // .ok_or_else(|| format_err!("No capabilities found for neighbor {}", peer_header.peer_addr))?;
-                    .ok_or_else(|| Error::decode(&format!("No capabilities found for neighbor {}", peer_header.peer_addr)))?;
+                    .ok_or_else(|| Error::decode(&format!("No capabilities found for neighbor {}", peer)))?;
+
+                return Ok(Frame::RouteMonitoring(PendingUpdate {
+                    version,
+                    peer_header,
+                    capabilities: Arc::clone(capabilities),
+                    update: buf,
+                    raw,
+                }));
+            },
+            MessageKind::Termination => {
+                let mut tlv = vec![];
+                while buf.remaining() > 0 {
+                    tlv.push(TerminationTlv::decode(&mut buf)?);
+                }
 
-                let mut rdr = buf.reader();
-                let header = bgp_rs::Header::parse(&mut rdr)?;
-                let update = bgp_rs::Update::parse(&header, &mut rdr, &capabilities)?;
+                // The speaker is closing the session, none of the peers are relevant any more
+                self.client_capabilities.clear();
 
-                MessageData::RouteMonitoring((peer_header, update))
+                MessageData::Termination(tlv)
             },
             _ => MessageData::Unimplemented
         };
 
-        Ok(
-            Some(BmpMessage { version, kind, message })
-        )
+        Ok(Frame::Message(BmpMessage { version, kind, message, raw }))
+    }
+}
+
+/// A BMP frame after the decoder has been through it
+#[derive(Debug)]
+pub(crate) enum Frame {
+    Message(BmpMessage),
+    RouteMonitoring(PendingUpdate),
+}
+
+/// A Route Monitoring message with a BGP UPDATE still to parse
+///
+/// This holds a snapshot of the peer's capabilities, so it can be parsed on another thread while
+/// the decoder carries on (and perhaps sees the peer go down).
+#[derive(Debug)]
+pub(crate) struct PendingUpdate {
+    version: u8,
+    peer_header: PeerHeader,
+    capabilities: Arc<Capabilities>,
+    update: Bytes,
+    raw: Bytes,
+}
+
+impl PendingUpdate {
+    pub(crate) fn decode(self) -> Result<BmpMessage> {
+        check_update(&self.update)?;
+        let mut rdr = self.update.reader();
+        let header = bgp_rs::Header::parse(&mut rdr)?;
+        let update = bgp_rs::Update::parse(&header, &mut rdr, &self.capabilities)?;
+
+        Ok(BmpMessage {
+            version: self.version,
+            kind: MessageKind::RouteMonitoring,
+            message: MessageData::RouteMonitoring((self.peer_header, update)),
+            raw: self.raw,
+        })
     }
 }
 
+/// Take the message at the start of `src` from the buffer, once all of it is there
+pub(crate) fn take_frame(src: &mut BytesMut) -> Result<Option<Bytes>> {
+    let length = match frame_length(src)? {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+
+    // If we haven't read the entire message yet make room for it, and just keep on reading!
+    if src.len() < length {
+        src.reserve(length - src.len());
+        tracing::trace!(buf_capacity = %src.capacity());
+        return Ok(None);
+    }
+
+    // Now we take the message while leaving anything else in the buffer
+    Ok(Some(src.split_to(length).freeze()))
+}
+
+/// The length of the message at the start of `data`, from its common header
+fn frame_length(data: &[u8]) -> Result<Option<usize>> {
+    if data.len() < BMP_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let length = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    if length <= BMP_HEADER_LEN || length > MAX_MESSAGE_LEN {
+        return Err(Error::decode(&format!("invalid BMP message length: {}", length)));
+    }
+
+    Ok(Some(length))
+}
+
+impl Default for BmpDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
 impl Decoder for BmpDecoder {
     type Item = BmpMessage;
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<BmpMessage>> {
-        let (version, length) = match self.state {
-            DecoderState::Head => {
-                match self.decode_head(src)? {
-                    Some((ver, len)) => {
-                        self.state = DecoderState::Data((ver, len));
-                        (ver, len)
+        Ok(self.decode_buf(src)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::generator::{Generator, Rng};
+    use crate::notification::{shutdown_communication, CeaseReason, NotificationError};
+
+    use bytes::Bytes;
+
+    type Output = Vec<std::result::Result<Bytes, String>>;
+
+    /// Decode `data` handing it to the decoder in pieces of the given sizes (the last one repeated
+    /// until we run out), and record the raw frames / errors plus anything left over at the end
+    fn decode_chunked(data: &[u8], sizes: &[usize]) -> (Output, usize) {
+        let mut decoder = BmpDecoder::new();
+        let mut buf = BytesMut::new();
+        let mut output = vec![];
+        let mut offset = 0;
+
+        for size in sizes.iter().chain(std::iter::repeat(sizes.last().unwrap())) {
+            if offset >= data.len() {
+                break;
+            }
+            let end = (offset + size).min(data.len());
+            buf.extend_from_slice(&data[offset..end]);
+            offset = end;
+
+            loop {
+                let before = buf.len();
+                match decoder.decode_buf(&mut buf) {
+                    Ok(Some(message)) => output.push(Ok(message.raw)),
+                    Ok(None) => break,
+                    Err(err) => {
+                        output.push(Err(err.to_string()));
+                        // A bad common header doesn't consume anything, there's no getting past it
+                        if buf.len() == before {
+                            return (output, data.len() - offset + buf.len());
+                        }
                     },
-                    None => return Ok(None)
+                };
+            }
+        }
+
+        (output, buf.len())
+    }
+
+    /// The same again, but through the sans-IO interface
+    fn decode_slices(mut data: &[u8]) -> (Output, usize) {
+        let mut decoder = BmpDecoder::new();
+        let mut output = vec![];
+
+        loop {
+            let (used, result) = decoder.decode_slice(data);
+            data = &data[used..];
+            match result {
+                Ok(Some(message)) => output.push(Ok(message.raw)),
+                Ok(None) => break,
+                Err(err) => {
+                    output.push(Err(err.to_string()));
+                    if used == 0 {
+                        break;
+                    }
+                },
+            };
+        }
+
+        (output, data.len())
+    }
+
+    fn frame_lengths(mut data: &[u8]) -> Vec<usize> {
+        let mut lengths = vec![];
+        while data.len() >= BMP_HEADER_LEN {
+            let length = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+            lengths.push(length.min(data.len()));
+            data = &data[length.min(data.len())..];
+        }
+        lengths
+    }
+
+    /// Every way of splitting the input we care about should decode exactly like the whole thing
+    fn assert_chunk_independent(data: &[u8], rng: &mut Rng) {
+        let expected = decode_chunked(data, &[data.len().max(1)]);
+        assert_eq!(decode_slices(data), expected);
+
+        let frames = frame_lengths(data);
+        // The header, then the rest of the body in small reads
+        let split_bodies = frames.iter()
+            .flat_map(|length| {
+                let mut sizes = vec![BMP_HEADER_LEN + 1];
+                let mut remaining = length.saturating_sub(BMP_HEADER_LEN + 1);
+                while remaining > 0 {
+                    let size = (1 + rng.below(7) as usize).min(remaining);
+                    sizes.push(size);
+                    remaining -= size;
+                }
+                sizes
+            })
+            .collect::<Vec<_>>();
+        let random = (0..data.len()).map(|_| 1 + rng.below(300) as usize).collect::<Vec<_>>();
+
+        for sizes in [vec![1], frames, split_bodies, random].iter() {
+            let sizes = if sizes.is_empty() { vec![1] } else { sizes.clone() };
+            assert_eq!(decode_chunked(data, &sizes), expected, "split into {:?}", sizes);
+        }
+    }
+
+    #[test]
+    fn test_chunk_independence() {
+        for seed in 0..8 {
+            let mut rng = Rng(seed);
+            let mut data = Generator::new(seed)
+                .with_peers(4)
+                .with_routes(40)
+                .with_churn(20)
+                .with_flaps(1)
+                .flat_map(|frame| frame.to_vec())
+                .collect::<Vec<_>>();
+            assert_chunk_independent(&data, &mut rng);
+
+            // Damage some message bodies so errors turn up part way through, leaving the common
+            // headers alone so the frames themselves are still intact
+            let frames = frame_lengths(&data);
+            let mut offset = 0;
+            for length in frames {
+                if rng.below(8) == 0 {
+                    data[offset + BMP_HEADER_LEN] = 0xff;
+                } else if rng.below(4) == 0 {
+                    let at = offset + BMP_HEADER_LEN + 1 + rng.below((length - BMP_HEADER_LEN - 1) as u64) as usize;
+                    data[at] ^= 1 << rng.below(8);
                 }
+                offset += length;
+            }
+            assert!(decode_chunked(&data, &[data.len()]).0.iter().any(|output| output.is_err()));
+            assert_chunk_independent(&data, &mut rng);
+
+            // And finish with a truncated frame, then a header that can't be decoded at all
+            let mut truncated = data.clone();
+            truncated.truncate(data.len() - 3);
+            assert_chunk_independent(&truncated, &mut rng);
+
+            data.extend_from_slice(&[3, 0, 0, 0, 2, 4]);
+            data.extend_from_slice(&[0; 16]);
+            assert_chunk_independent(&data, &mut rng);
+        }
+    }
+
+    #[test]
+    fn test_message_length() {
+        let header = [3, 0xff, 0xff, 0xff, 0xff, 0];
+        let (used, result) = BmpDecoder::new().decode_slice(&header);
+        assert_eq!(used, 0);
+        assert!(result.unwrap_err().to_string().contains("invalid BMP message length: 4294967295"));
+
+        // Nothing is reserved for it either
+        let mut buf = BytesMut::from(&header[..]);
+        assert!(take_frame(&mut buf).is_err());
+        assert!(buf.capacity() < MAX_MESSAGE_LEN);
+
+        let mut header = header;
+        header[1..5].copy_from_slice(&(MAX_MESSAGE_LEN as u32).to_be_bytes());
+        assert!(BmpDecoder::new().decode_slice(&header).1.unwrap().is_none());
+        header[1..5].copy_from_slice(&(MAX_MESSAGE_LEN as u32 + 1).to_be_bytes());
+        assert!(BmpDecoder::new().decode_slice(&header).1.is_err());
+    }
+
+    #[test]
+    fn test_peer_capabilities() {
+        let mut decoder = BmpDecoder::new();
+        let mut peer_ups = 0;
+
+        for frame in Generator::new(5).with_peers(2).with_routes(10) {
+            let message = decoder.decode_slice(&frame).1.unwrap().unwrap();
+            if let MessageData::PeerUp((header, peer_up)) = &message.message {
+                peer_ups += 1;
+                let capabilities = decoder.capabilities(&PeerKey::from(header)).unwrap();
+                assert_eq!(peer_up.capabilities.as_deref(), Some(&CapabilitySet::from(capabilities)));
+                assert!(capabilities.FOUR_OCTET_ASN_SUPPORT);
+
+                // IPv6 peers need multiprotocol support for their routes to decode
+                let ipv6 = crate::state::Family { afi: 2, safi: 1 };
+                assert_eq!(peer_up.capabilities.as_ref().unwrap().multiprotocol.contains(&ipv6), header.peer_addr.is_ipv6());
+
+                // All the Peer Ups come first
+                assert_eq!(decoder.peers().count(), peer_ups);
+            }
+        }
+
+        // Everyone's gone down by the end
+        assert_eq!(peer_ups, 2);
+        assert_eq!(decoder.peers().count(), 0);
+        assert!(decoder.capabilities(&PeerKey::from("192.0.2.1".parse::<IpAddr>().unwrap())).is_none());
+    }
+
+    #[test]
+    fn test_vrf_peer_capabilities() {
+        let frames = Generator::new(5).with_routes(10).collect::<Vec<_>>();
+        let find = |kind: MessageKind| frames.iter().find(|frame| frame[5] == kind as u8).unwrap();
+
+        // The same peer address in two VRFs
+        let in_vrf = |frame: &[u8], vrf: u8| {
+            let mut frame = frame.to_vec();
+            frame[6] = PeerType::RdInstance as u8;
+            frame[15] = vrf;
+            frame
+        };
+        let (peer_up, route, peer_down) = (find(MessageKind::PeerUp), find(MessageKind::RouteMonitoring), find(MessageKind::PeerDown));
+
+        let mut decoder = BmpDecoder::new();
+        for frame in &[in_vrf(peer_up, 1), in_vrf(peer_up, 2)] {
+            decoder.decode_slice(frame).1.unwrap();
+        }
+        assert_eq!(decoder.peers().count(), 2);
+
+        // Taking one down leaves the other able to decode its routes
+        decoder.decode_slice(&in_vrf(peer_down, 1)).1.unwrap();
+        let key = PeerKey {
+            peer_type: PeerType::RdInstance,
+            peer_distinguisher: (0, 2),
+            peer_addr: "10.0.0.1".parse().unwrap(),
+        };
+        assert_eq!(decoder.peers().collect::<Vec<_>>(), vec![&key]);
+        assert!(decoder.decode_slice(&in_vrf(route, 2)).1.is_ok());
+        let err = decoder.decode_slice(&in_vrf(route, 1)).1.unwrap_err();
+        assert!(err.to_string().contains("10.0.0.1 (rd 0:1)"), "{}", err);
+
+        assert_eq!(decoder.export_state().peers.iter().map(|peer| peer.peer).collect::<Vec<_>>(), vec![key]);
+    }
+
+    #[test]
+    fn test_capability_overrides() {
+        use crate::state::{CapabilityOverride, Family, OverrideMode};
+
+        // Drop the OPENs from the second peer's Peer Up, like some routers do
+        let mut frames = Generator::new(5).with_peers(2).with_routes(10).map(|frame| frame.to_vec()).collect::<Vec<_>>();
+        let peer_ups = frames.iter()
+            .enumerate()
+            .filter(|(_, frame)| frame[5] == 3)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let stripped = &mut frames[peer_ups[1]];
+        stripped.truncate(BMP_HEADER_LEN + 1 + 42 + 20);
+        let length = stripped.len() as u32;
+        stripped[1..5].copy_from_slice(&length.to_be_bytes());
+
+        let families = |decoder: &mut BmpDecoder| {
+            frames.iter()
+                .filter_map(|frame| match decoder.decode_slice(frame).1.unwrap().unwrap().message {
+                    MessageData::PeerUp((_, peer_up)) => Some(peer_up.capabilities.unwrap().multiprotocol),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let ipv4 = Family { afi: 1, safi: 1 };
+        let ipv6 = Family { afi: 2, safi: 1 };
+        let fixed = |router: Option<&str>, peer: Option<PeerKey>, mode, family| CapabilityOverride {
+            router: router.map(|addr| addr.parse().unwrap()),
+            peer,
+            mode,
+            capabilities: CapabilitySet { multiprotocol: vec![family], four_octet_asn: true, ..Default::default() },
+        };
+
+        assert_eq!(families(&mut BmpDecoder::new()), vec![vec![ipv4], vec![]]);
+
+        // Filling in for the missing OPENs only
+        let mut decoder = BmpDecoder::new();
+        decoder.set_overrides(vec![fixed(None, None, OverrideMode::Missing, ipv6)]).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv4], vec![ipv6]]);
+
+        // Or always, with a specific peer beating the catch-all
+        let peer = match BmpDecoder::new().decode_slice(&frames[peer_ups[0]]).1.unwrap().unwrap().message {
+            MessageData::PeerUp((header, _)) => PeerKey::from(&header),
+            _ => unreachable!(),
+        };
+        let mut decoder = BmpDecoder::new();
+        decoder.set_overrides(vec![
+            fixed(None, None, OverrideMode::Always, ipv6),
+            fixed(None, Some(peer), OverrideMode::Always, ipv4),
+        ]).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv4], vec![ipv6]]);
+
+        // A peer override only matches in the same instance
+        let other_vrf = PeerKey { peer_type: PeerType::RdInstance, peer_distinguisher: (0, 1), ..peer };
+        let mut decoder = BmpDecoder::new();
+        decoder.set_overrides(vec![
+            fixed(None, None, OverrideMode::Always, ipv6),
+            fixed(None, Some(other_vrf), OverrideMode::Always, ipv4),
+        ]).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv6], vec![ipv6]]);
+
+        // Only for the right router
+        let overrides = vec![fixed(Some("192.0.2.1"), None, OverrideMode::Always, ipv6)];
+        let mut decoder = BmpDecoder::new().with_router("192.0.2.2".parse().unwrap());
+        decoder.set_overrides(overrides.clone()).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv4], vec![]]);
+        let mut decoder = BmpDecoder::new().with_router("192.0.2.1".parse().unwrap());
+        decoder.set_overrides(overrides).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv6], vec![ipv6]]);
+
+        // Peer Ups for peers we already know (here from imported state) still use the overrides
+        let mut decoder = BmpDecoder::new();
+        for frame in &frames[..=peer_ups[1]] {
+            decoder.decode_slice(frame).1.unwrap();
+        }
+        let state = decoder.export_state();
+        assert_eq!(state.peers.len(), 2);
+        let mut decoder = BmpDecoder::new();
+        decoder.import_state(state.clone()).unwrap();
+        decoder.set_overrides(vec![fixed(None, None, OverrideMode::Always, ipv6)]).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv6], vec![ipv6]]);
+
+        // Without an override, a Peer Up missing its OPENs keeps what we knew about the peer
+        let mut state = state;
+        state.peers.iter_mut().for_each(|peer| peer.capabilities.multiprotocol = vec![ipv6]);
+        let mut decoder = BmpDecoder::new();
+        decoder.import_state(state).unwrap();
+        assert_eq!(families(&mut decoder), vec![vec![ipv4], vec![ipv6]]);
+
+        // Capabilities bgp-rs can't represent are refused up front
+        let bad = fixed(None, None, OverrideMode::Always, Family { afi: 99, safi: 1 });
+        assert!(BmpDecoder::new().set_overrides(vec![bad]).is_err());
+    }
+
+    #[test]
+    fn test_peer_up_information() {
+        let mut frame = Generator::new(5).find(|frame| frame[5] == 3).unwrap().to_vec();
+        for (kind, value) in &[(0u16, "hello"), (3, "vrf-a"), (4, "edge"), (0, "")] {
+            frame.extend_from_slice(&kind.to_be_bytes());
+            frame.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            frame.extend_from_slice(value.as_bytes());
+        }
+        let length = frame.len() as u32;
+        frame[1..5].copy_from_slice(&length.to_be_bytes());
+
+        let message = BmpDecoder::new().decode_slice(&frame).1.unwrap().unwrap();
+        let peer_up = match message.message {
+            MessageData::PeerUp((_, peer_up)) => peer_up,
+            _ => panic!("expected a Peer Up"),
+        };
+
+        assert_eq!(peer_up.information, vec![
+            InformationTlv::String("hello".into()),
+            InformationTlv::VrfTableName("vrf-a".into()),
+            InformationTlv::AdminLabel("edge".into()),
+            InformationTlv::String("".into()),
+        ]);
+        assert_eq!(peer_up.find_information(InformationType::AdminLabel), Some("edge"));
+        assert_eq!(peer_up.find_information(InformationType::SysName), None);
+
+        let (sent, recv) = (peer_up.sent_open.as_ref().unwrap(), peer_up.recv_open.as_ref().unwrap());
+        assert_eq!(peer_up.hold_time(), Some(sent.hold_timer.min(recv.hold_timer)));
+        assert_eq!(peer_up.local_router_id(), Some(sent.identifier.into()));
+        assert_eq!(peer_up.remote_router_id(), Some(recv.identifier.into()));
+        assert!(peer_up.local_asn().is_some());
+        assert_ne!(peer_up.local_asn(), peer_up.remote_asn());
+
+        // A TLV running past the end of the message
+        frame.truncate(frame.len() - 2);
+        frame.extend_from_slice(&[0, 9]);
+        assert!(BmpDecoder::new().decode_slice(&frame).1.is_err());
+    }
+
+    #[test]
+    fn test_initiation_information() {
+        let tlvs: &[(u16, &[u8])] = &[
+            (1, b"router"),
+            (5, b"\x01\x02"),
+            (0x8001, b"\x00\x00\x7f\xffvendor"),
+            (2, b"r1\xff"),
+            (3, b"vrf-a"),
+        ];
+        let mut frame = vec![3, 0, 0, 0, 0, 4];
+        for (kind, value) in tlvs {
+            frame.extend_from_slice(&kind.to_be_bytes());
+            frame.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            frame.extend_from_slice(value);
+        }
+        let length = frame.len() as u32;
+        frame[1..5].copy_from_slice(&length.to_be_bytes());
+
+        // Nothing is skipped, whatever comes before it
+        let message = BmpDecoder::new().decode_slice(&frame).1.unwrap().unwrap();
+        let information = match message.message {
+            MessageData::Initiation(information) => information,
+            _ => panic!("expected an Initiation"),
+        };
+        assert_eq!(information, vec![
+            InformationTlv::SysDescr("router".into()),
+            InformationTlv::Unknown { kind: 5, bytes: Bytes::from_static(b"\x01\x02") },
+            InformationTlv::Unknown { kind: 0x8001, bytes: Bytes::from_static(b"\x00\x00\x7f\xffvendor") },
+            InformationTlv::SysName("r1\u{fffd}".into()),
+            InformationTlv::VrfTableName("vrf-a".into()),
+        ]);
+        assert_eq!(information.iter().map(InformationTlv::kind).collect::<Vec<_>>(), vec![1, 5, 0x8001, 2, 3]);
+        assert_eq!(information[1].value(), None);
+
+        // But a TLV running past the end of the message is an error
+        frame.pop();
+        frame[1..5].copy_from_slice(&(length - 1).to_be_bytes());
+        assert!(BmpDecoder::new().decode_slice(&frame).1.is_err());
+    }
+
+    #[test]
+    fn test_termination_tlvs() {
+        let termination = |tlvs: &[u8]| {
+            let mut frame = vec![3, 0, 0, 0, 0, 5];
+            frame.extend_from_slice(tlvs);
+            let length = frame.len() as u32;
+            frame[1..5].copy_from_slice(&length.to_be_bytes());
+
+            BmpDecoder::new().decode_slice(&frame).1.map(|message| match message.unwrap().message {
+                MessageData::Termination(tlvs) => tlvs,
+                _ => panic!("expected a Termination"),
+            })
+        };
+
+        // Unknown reasons and TLV types are kept
+        let tlvs = termination(&[0, 1, 0, 2, 0, 9, 0, 9, 0, 1, 0xab]).unwrap();
+        match &tlvs[..] {
+            [TerminationTlv::Reason(reason), TerminationTlv::Unknown { kind: 9, bytes }] => {
+                assert_eq!(*reason, TerminationReason::Unknown(9));
+                assert_eq!(reason.code(), 9);
+                assert_eq!(&bytes[..], &[0xab]);
             },
-            DecoderState::Data((ver, len)) => (ver, len)
+            other => panic!("unexpected {:?}", other),
         };
 
-        match self.decode_data(version, length, src)? {
-            Some(message) => {
-                self.state = DecoderState::Head;
-                src.reserve(BMP_HEADER_LEN);
+        // A reason of the wrong length is an error
+        let err = termination(&[0, 1, 0, 1, 0]).unwrap_err();
+        assert!(err.to_string().contains("invalid length for BMP Termination reason: 1"), "{}", err);
+    }
 
-                Ok(Some(message))
+    #[test]
+    fn test_peer_down_reasons() {
+        let frames = Generator::new(5).with_routes(10).collect::<Vec<_>>();
+        let peer_down = frames.iter().find(|frame| frame[5] == 2).unwrap();
+        let with_reason = |data: &[u8]| {
+            let mut frame = peer_down[..BMP_HEADER_LEN + 1 + 42].to_vec();
+            frame.extend_from_slice(data);
+            let length = frame.len() as u32;
+            frame[1..5].copy_from_slice(&length.to_be_bytes());
+            frame
+        };
+        let decode_as = |peer_type: PeerType, frame: &[u8]| {
+            let mut decoder = BmpDecoder::new();
+            for frame in &frames[..2] {
+                let mut frame = frame.to_vec();
+                if frame[5] == 3 {
+                    frame[6] = peer_type as u8;
+                }
+                decoder.decode_slice(&frame).1.unwrap();
+            }
+            assert_eq!(decoder.peers().count(), 1);
+
+            let mut frame = frame.to_vec();
+            frame[6] = peer_type as u8;
+            let result = decoder.decode_slice(&frame).1.map(|message| match message.unwrap().message {
+                MessageData::PeerDown((header, peer_down)) => {
+                    assert_eq!(header.peer_type, peer_type);
+                    peer_down
+                },
+                _ => panic!("expected a Peer Down"),
+            });
+            (result, decoder.peers().count())
+        };
+        let decode = |frame: &[u8]| decode_as(PeerType::GlobalInstance, frame);
+
+        // Reason 6 is only sent for Loc-RIB Instance Peers (RFC9069)
+        let (peer_down, peers) = decode_as(
+            PeerType::LocRibInstance,
+            &with_reason(&[6, 0, 3, 0, 5, b'v', b'r', b'f', b'-', b'a']),
+        );
+        match peer_down.unwrap() {
+            PeerDown::LocalClosed(tlvs) => assert_eq!(tlvs, vec![InformationTlv::VrfTableName("vrf-a".into())]),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(peers, 0);
+
+        // Reasons we don't know about still take the peer down
+        let (peer_down, peers) = decode(&with_reason(&[7, 1, 2, 3]));
+        let peer_down = peer_down.unwrap();
+        assert_eq!(peer_down.reason(), 7);
+        match peer_down {
+            PeerDown::Unknown(7, data) => assert_eq!(&data[..], &[1, 2, 3]),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(peers, 0);
+        assert_eq!(decode(&with_reason(&[0])).0.unwrap().reason(), 0);
+
+        // Broken TLVs are still an error
+        assert!(decode(&with_reason(&[6, 0, 3, 0, 5, b'v'])).0.is_err());
+
+        match decode(&with_reason(&[2, 0, 10])).0.unwrap() {
+            PeerDown::LocalTerminate(event) => {
+                assert_eq!(event, FsmEvent::HoldTimerExpires);
+                assert_eq!(event.to_string(), "hold_timer_expires");
             },
-            None => Ok(None)
-        }
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(FsmEvent::from(99), FsmEvent::Unknown(99));
+        assert!((0..=30).all(|code| FsmEvent::from(code).code() == code));
+
+        // A remote Administrative Shutdown with a Shutdown Communication
+        let mut notification = vec![0xff; 16];
+        notification.extend_from_slice(&[0, 28, 3, 6, 2, 6]);
+        notification.extend_from_slice(b"maint!");
+        let peer_down = decode(&with_reason(&[&[3][..], &notification].concat())).0.unwrap();
+        let notification = peer_down.notification().unwrap();
+        assert_eq!(NotificationError::from(notification), NotificationError::Cease(CeaseReason::AdministrativeShutdown));
+        assert_eq!(shutdown_communication(notification).as_deref(), Some("maint!"));
     }
-}
\ No newline at end of file
+}
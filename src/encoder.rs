@@ -0,0 +1,43 @@
+use crate::{
+    Error,
+    types::*,
+};
+
+use bytes::BytesMut;
+use tokio_util::codec::Encoder;
+
+/// Encoder implementation for use with a FramedWrite
+///
+/// Messages are written using the raw bytes they were decoded from, so anything produced by
+/// `BmpDecoder` (or `mrt::MrtReader`) can be written straight back out to a `.dump` file or a
+/// collector.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BmpEncoder;
+
+impl BmpEncoder {
+    /// Create a new instance of the Encoder
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encoder<BmpMessage> for BmpEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: BmpMessage, dst: &mut BytesMut) -> std::io::Result<()> {
+        Encoder::<&BmpMessage>::encode(self, &item, dst)
+    }
+}
+
+impl Encoder<&BmpMessage> for BmpEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &BmpMessage, dst: &mut BytesMut) -> std::io::Result<()> {
+        if item.raw.is_empty() {
+            return Err(Error::decode(&format!("no raw bytes to encode for {} message", item.kind)).into());
+        }
+
+        dst.extend_from_slice(&item.raw);
+        Ok(())
+    }
+}
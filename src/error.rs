@@ -50,14 +50,8 @@ impl From<Error> for std::io::Error {
     fn from(err: Error) -> std::io::Error {
         match err {
             Error::WireError(e) => e,
-            Error::DecodeError(e) => Self::new(
-                std::io::ErrorKind::Other,
-                format!("{}", e).as_str()
-            ),
-            Error::Unknown(e) => Self::new(
-                std::io::ErrorKind::Other,
-                format!("{}", e).as_str()
-            ),
+            Error::DecodeError(e) => Self::other(e),
+            Error::Unknown(e) => Self::other(e),
         }
     }
 }
@@ -82,4 +76,11 @@ impl From<Box<dyn std::error::Error + Sync + Send>> for Error {
     fn from(error: Box<dyn std::error::Error + Sync + Send>) -> Self {
         Self::Unknown(error)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "arrow")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(error: parquet::errors::ParquetError) -> Self {
+        Self::Unknown(Box::new(error))
+    }
+}
@@ -0,0 +1,624 @@
+//! Synthetic BMP sessions
+//!
+//! `Generator` produces a complete BMP session from a seed: an Initiation, a Peer Up for each
+//! peer, a full table dump per peer ending with End-of-RIB, a mix of route churn and session flaps,
+//! then a Peer Down for each peer and a Termination. The same seed and settings always produce the
+//! same bytes, so the output can be used for benchmarks and round-trip tests.
+//!
+//! Messages are built lazily, so very large sessions (eg. 100 peers with 100k routes each) don't
+//! need to fit in memory.
+//!
+//! ```
+//! # use bmp_protocol::{BmpDecoder, generator::Generator};
+//! let generator = Generator::new(42)
+//!     .with_peers(4)
+//!     .with_routes(1000)
+//!     .with_churn(100)
+//!     .with_flaps(1);
+//!
+//! let mut decoder = BmpDecoder::new();
+//! for frame in generator {
+//!     let (_, message) = decoder.decode_slice(&frame);
+//!     let message = message.unwrap().unwrap();
+//! }
+//! ```
+//!
+//! Peers alternate between IPv4 and IPv6 unicast and cycle through the global, RD and local
+//! instance peer types. Every peer announces the same set of prefixes (/24s for IPv4, /48s for
+//! IPv6) with randomised AS_PATHs, MEDs and communities.
+
+use crate::{types::*, util::put_attribute};
+
+use bgp_rs::AFI;
+use bytes::{BufMut, Bytes};
+
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
+
+// Local end of every BGP session
+const LOCAL_ASN: u32 = 65000;
+const LOCAL_BGP_ID: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 254);
+const LOCAL_ADDR_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 254);
+const LOCAL_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0xfe);
+
+const AS_TRANS: u32 = 23456;
+
+// Time between messages in a table dump
+const DUMP_INTERVAL_US: u64 = 100;
+// Upper bound on the time between churn events
+const CHURN_INTERVAL_US: u64 = 1_000_000;
+
+// Keeps the largest UPDATE well under the 4096 byte BGP message limit
+const MAX_PREFIXES_PER_UPDATE: usize = 256;
+
+/// SplitMix64, small and good enough for picking synthetic attributes
+#[derive(Clone, Debug)]
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in 0..n
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    /// True `percent`% of the time
+    fn chance(&mut self, percent: u64) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// Everything about a peer that doesn't change between sessions, derived from its index
+#[derive(Clone, Debug)]
+struct Peer {
+    afi: AFI,
+    peer_type: PeerType,
+    post_policy: bool,
+    distinguisher: (u32, u32),
+    addr: IpAddr,
+    asn: u32,
+    bgp_id: Ipv4Addr,
+    remote_port: u16,
+}
+
+impl Peer {
+    fn new(idx: usize) -> Self {
+        let idx32 = idx as u32;
+        let afi = if idx.is_multiple_of(2) { AFI::IPV4 } else { AFI::IPV6 };
+
+        let (peer_type, distinguisher) = match (idx / 2) % 3 {
+            0 => (PeerType::GlobalInstance, (0, 0)),
+            1 => (PeerType::RdInstance, (LOCAL_ASN, idx32)),
+            _ => (PeerType::LocalInstance, (0, 0)),
+        };
+
+        let addr = match afi {
+            AFI::IPV4 => IpAddr::V4(Ipv4Addr::from(0x0a00_0000u32.wrapping_add(idx32 + 1))),
+            _ => IpAddr::V6(Ipv6Addr::from(0x2001_0db8_0001u128 << 80 | u128::from(idx32 + 1))),
+        };
+
+        // Mix of 2-byte and 4-byte private ASNs
+        let asn = if idx < 1023 { 64512 + idx32 } else { 4_200_000_000 + idx32 };
+
+        Self {
+            afi,
+            peer_type,
+            post_policy: (idx / 6) % 2 == 1,
+            distinguisher,
+            addr,
+            asn,
+            bgp_id: Ipv4Addr::from(0xac10_0000u32.wrapping_add(idx32 + 1)),
+            remote_port: 30000 + (idx % 30000) as u16,
+        }
+    }
+}
+
+/// Prefix `idx` in the table announced by every peer. The index is scattered across the address
+/// space so consecutive routes don't aggregate
+fn prefix(afi: AFI, idx: usize) -> (IpAddr, u8) {
+    // Multiplying by an odd constant is a bijection modulo a power of two
+    let scattered = (idx as u32).wrapping_mul(0x9e37_79b1);
+
+    match afi {
+        AFI::IPV4 => (IpAddr::V4(Ipv4Addr::from((scattered & 0x00ff_ffff) << 8)), 24),
+        _ => (IpAddr::V6(Ipv6Addr::from(0x2001u128 << 112 | u128::from(scattered) << 80)), 48),
+    }
+}
+
+fn put_prefix(buf: &mut Vec<u8>, (addr, length): (IpAddr, u8)) {
+    let octets = match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    };
+
+    buf.put_u8(length);
+    buf.put_slice(&octets[..usize::from(length).div_ceil(8)]);
+}
+
+fn bgp_message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(19 + body.len());
+    msg.put_slice(&[0xff; 16]);
+    msg.put_u16((19 + body.len()) as u16);
+    msg.put_u8(kind);
+    msg.put_slice(body);
+
+    msg
+}
+
+fn update(withdrawn: &[u8], attrs: &[u8], nlri: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + withdrawn.len() + attrs.len() + nlri.len());
+    body.put_u16(withdrawn.len() as u16);
+    body.put_slice(withdrawn);
+    body.put_u16(attrs.len() as u16);
+    body.put_slice(attrs);
+    body.put_slice(nlri);
+
+    bgp_message(2, &body)
+}
+
+/// OPEN advertising a single unicast family, route refresh and 4-byte ASNs
+fn open(asn: u32, bgp_id: Ipv4Addr, afi: AFI) -> Vec<u8> {
+    let mut capabilities = vec![];
+    capabilities.put_slice(&[1, 4]);
+    capabilities.put_u16(afi as u16);
+    capabilities.put_slice(&[0, 1]);
+    capabilities.put_slice(&[2, 0]);
+    capabilities.put_slice(&[65, 4]);
+    capabilities.put_u32(asn);
+
+    let mut body = vec![];
+    body.put_u8(4);
+    body.put_u16(if asn > u32::from(u16::MAX) { AS_TRANS as u16 } else { asn as u16 });
+    body.put_u16(90);
+    body.put_slice(&bgp_id.octets());
+    body.put_u8((2 + capabilities.len()) as u8);
+    body.put_u8(2);
+    body.put_u8(capabilities.len() as u8);
+    body.put_slice(&capabilities);
+
+    bgp_message(1, &body)
+}
+
+/// Wrap a message body in a BMP common header
+fn frame(kind: MessageKind, body: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(6 + body.len());
+    frame.put_u8(3);
+    frame.put_u32((6 + body.len()) as u32);
+    frame.put_u8(kind as u8);
+    frame.put_slice(body);
+
+    frame.into()
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Phase {
+    Initiation,
+    PeerUp(usize),
+    Dump(usize),
+    Events,
+    PeerDown(usize),
+    Termination,
+    Done,
+}
+
+/// Progress through a single peer's table dump
+#[derive(Copy, Clone, Debug)]
+struct Dump {
+    peer: usize,
+    next: usize,
+}
+
+/// Deterministic generator of BMP sessions, yielding one BMP message (including the common
+/// header) at a time
+#[derive(Clone, Debug)]
+pub struct Generator {
+    seed: u64,
+    rng: Rng,
+    peers: usize,
+    routes: usize,
+    prefixes_per_update: usize,
+    churn: usize,
+    flaps: usize,
+    time_us: u64,
+
+    phase: Phase,
+    dump: Option<Dump>,
+    pending: VecDeque<Bytes>,
+}
+
+impl Generator {
+    /// Create a new generator with 2 peers announcing 100 routes each and no churn or flaps
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: Rng(seed),
+            peers: 2,
+            routes: 100,
+            prefixes_per_update: 20,
+            churn: 0,
+            flaps: 0,
+            time_us: 1_577_836_800 * 1_000_000,
+
+            phase: Phase::Initiation,
+            dump: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Set the number of peers
+    pub fn with_peers(mut self, peers: usize) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    /// Set the number of routes in each peer's table dump. IPv4 peers have at most 2^24 distinct
+    /// prefixes, after that they repeat
+    pub fn with_routes(mut self, routes: usize) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// Set the maximum number of prefixes packed into a single UPDATE during a table dump (1-256)
+    pub fn with_prefixes_per_update(mut self, prefixes: usize) -> Self {
+        self.prefixes_per_update = prefixes.clamp(1, MAX_PREFIXES_PER_UPDATE);
+        self
+    }
+
+    /// Set the number of single-route announcements and withdrawals sent after the table dumps
+    pub fn with_churn(mut self, updates: usize) -> Self {
+        self.churn = updates;
+        self
+    }
+
+    /// Set the number of times a random peer goes down, comes back up and dumps its table again,
+    /// interleaved with the churn
+    pub fn with_flaps(mut self, flaps: usize) -> Self {
+        self.flaps = flaps;
+        self
+    }
+
+    /// Set the timestamp (seconds since epoch) of the first message
+    pub fn with_start_time(mut self, timestamp: u32) -> Self {
+        self.time_us = u64::from(timestamp) * 1_000_000;
+        self
+    }
+
+    fn peer_header(&mut self, peer: &Peer) -> PeerHeader {
+        let header = PeerHeader {
+            peer_type: peer.peer_type,
+            peer_flags: PeerFlags { V: peer.afi == AFI::IPV6, L: peer.post_policy, A: false, O: false },
+            peer_distinguisher: peer.distinguisher,
+            peer_addr: peer.addr,
+            peer_asn: peer.asn,
+            peer_bgp_id: peer.bgp_id,
+            timestamp: (self.time_us / 1_000_000) as u32,
+            timestamp_ms: (self.time_us % 1_000_000) as u32,
+        };
+        self.time_us += DUMP_INTERVAL_US;
+
+        header
+    }
+
+    fn initiation(&self) -> Bytes {
+        let mut body = vec![];
+        for (kind, value) in &[(1u16, "bmp-protocol synthetic session".to_string()), (2, format!("synthetic-{}", self.seed))] {
+            body.put_u16(*kind);
+            body.put_u16(value.len() as u16);
+            body.put_slice(value.as_bytes());
+        }
+
+        frame(MessageKind::Initiation, &body)
+    }
+
+    fn termination(&self) -> Bytes {
+        let reason = "generator finished";
+
+        let mut body = vec![];
+        body.put_u16(0);
+        body.put_u16(reason.len() as u16);
+        body.put_slice(reason.as_bytes());
+        body.put_u16(1);
+        body.put_u16(2);
+        body.put_u16(TerminationReason::AdminClose.code());
+
+        frame(MessageKind::Termination, &body)
+    }
+
+    fn peer_up(&mut self, idx: usize) -> Bytes {
+        let peer = Peer::new(idx);
+
+        let mut body = vec![];
+        self.peer_header(&peer).encode(&mut body);
+        match peer.afi {
+            AFI::IPV4 => {
+                body.put_slice(&[0; 12]);
+                body.put_slice(&LOCAL_ADDR_V4.octets());
+            },
+            _ => body.put_slice(&LOCAL_ADDR_V6.octets()),
+        };
+        body.put_u16(179);
+        body.put_u16(peer.remote_port);
+        body.put_slice(&open(LOCAL_ASN, LOCAL_BGP_ID, peer.afi));
+        body.put_slice(&open(peer.asn, peer.bgp_id, peer.afi));
+
+        frame(MessageKind::PeerUp, &body)
+    }
+
+    fn peer_down(&mut self, idx: usize) -> Bytes {
+        let peer = Peer::new(idx);
+
+        let mut body = vec![];
+        self.peer_header(&peer).encode(&mut body);
+
+        let reason = 1 + self.rng.below(5) as u8;
+        body.put_u8(reason);
+        match reason {
+            // NOTIFICATION, Cease / Administrative Shutdown or Administrative Reset
+            1 => body.put_slice(&bgp_message(3, &[6, 2])),
+            3 => body.put_slice(&bgp_message(3, &[6, 4])),
+            // FSM event
+            2 => body.put_u16(1 + self.rng.below(28) as u16),
+            _ => {},
+        };
+
+        frame(MessageKind::PeerDown, &body)
+    }
+
+    fn route_monitoring(&mut self, peer: &Peer, bgp: &[u8]) -> Bytes {
+        let mut body = Vec::with_capacity(42 + bgp.len());
+        self.peer_header(peer).encode(&mut body);
+        body.put_slice(bgp);
+
+        frame(MessageKind::RouteMonitoring, &body)
+    }
+
+    fn announce(&mut self, idx: usize, routes: Range<usize>) -> Bytes {
+        let peer = Peer::new(idx);
+
+        let mut nlri = vec![];
+        for route in routes {
+            put_prefix(&mut nlri, prefix(peer.afi, route));
+        }
+
+        let mut attrs = vec![];
+        put_attribute(&mut attrs, 0x40, 1, &[if self.rng.chance(80) { 0 } else { 2 }]);
+
+        // AS_PATH, the peer followed by 1-5 public ASNs
+        let mut path = vec![peer.asn];
+        for _ in 0..1 + self.rng.below(5) {
+            path.push(1 + self.rng.below(64495) as u32);
+        }
+        let mut as_path = vec![2, path.len() as u8];
+        for asn in path {
+            as_path.put_u32(asn);
+        }
+        put_attribute(&mut attrs, 0x40, 2, &as_path);
+
+        if let IpAddr::V4(next_hop) = peer.addr {
+            put_attribute(&mut attrs, 0x40, 3, &next_hop.octets());
+        }
+        if self.rng.chance(50) {
+            put_attribute(&mut attrs, 0x80, 4, &(self.rng.below(1000) as u32).to_be_bytes());
+        }
+
+        let mut communities = vec![];
+        for _ in 0..self.rng.below(4) {
+            communities.put_u16(peer.asn as u16);
+            communities.put_u16(self.rng.below(1000) as u16);
+        }
+        if !communities.is_empty() {
+            put_attribute(&mut attrs, 0xc0, 8, &communities);
+        }
+
+        let bgp = match peer.addr {
+            IpAddr::V4(_) => update(&[], &attrs, &nlri),
+            IpAddr::V6(next_hop) => {
+                let mut reach = vec![];
+                reach.put_u16(AFI::IPV6 as u16);
+                reach.put_u8(1);
+                reach.put_u8(16);
+                reach.put_slice(&next_hop.octets());
+                reach.put_u8(0);
+                reach.put_slice(&nlri);
+                put_attribute(&mut attrs, 0x80, 14, &reach);
+
+                update(&[], &attrs, &[])
+            },
+        };
+
+        self.route_monitoring(&peer, &bgp)
+    }
+
+    fn withdraw(&mut self, idx: usize, route: usize) -> Bytes {
+        let peer = Peer::new(idx);
+
+        let mut nlri = vec![];
+        put_prefix(&mut nlri, prefix(peer.afi, route));
+
+        let bgp = match peer.afi {
+            AFI::IPV4 => update(&nlri, &[], &[]),
+            _ => {
+                let mut unreach = vec![];
+                unreach.put_u16(AFI::IPV6 as u16);
+                unreach.put_u8(1);
+                unreach.put_slice(&nlri);
+
+                let mut attrs = vec![];
+                put_attribute(&mut attrs, 0x80, 15, &unreach);
+                update(&[], &attrs, &[])
+            },
+        };
+
+        self.route_monitoring(&peer, &bgp)
+    }
+
+    fn end_of_rib(&mut self, idx: usize) -> Bytes {
+        let peer = Peer::new(idx);
+
+        let bgp = match peer.afi {
+            AFI::IPV4 => update(&[], &[], &[]),
+            _ => {
+                let mut attrs = vec![];
+                put_attribute(&mut attrs, 0x80, 15, &[0, AFI::IPV6 as u8, 1]);
+                update(&[], &attrs, &[])
+            },
+        };
+
+        self.route_monitoring(&peer, &bgp)
+    }
+
+    /// A single churn update or flap
+    fn event(&mut self) -> Bytes {
+        self.time_us += self.rng.below(CHURN_INTERVAL_US);
+        let peer = self.rng.below(self.peers as u64) as usize;
+
+        let remaining = (self.churn + self.flaps) as u64;
+        if self.rng.below(remaining) < self.flaps as u64 {
+            self.flaps -= 1;
+
+            let up = self.peer_up(peer);
+            self.pending.push_back(up);
+            self.dump = Some(Dump { peer, next: 0 });
+
+            return self.peer_down(peer);
+        }
+
+        self.churn -= 1;
+        let route = self.rng.below(self.routes as u64) as usize;
+        if self.rng.chance(20) {
+            self.withdraw(peer, route)
+        } else {
+            self.announce(peer, route..route + 1)
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return Some(frame);
+            }
+
+            if let Some(dump) = self.dump.take() {
+                if dump.next >= self.routes {
+                    return Some(self.end_of_rib(dump.peer));
+                }
+
+                let end = (dump.next + self.prefixes_per_update).min(self.routes);
+                self.dump = Some(Dump { peer: dump.peer, next: end });
+                return Some(self.announce(dump.peer, dump.next..end));
+            }
+
+            match self.phase {
+                Phase::Initiation => {
+                    self.phase = Phase::PeerUp(0);
+                    return Some(self.initiation());
+                },
+                Phase::PeerUp(idx) if idx < self.peers => {
+                    self.phase = Phase::PeerUp(idx + 1);
+                    return Some(self.peer_up(idx));
+                },
+                Phase::PeerUp(_) => self.phase = Phase::Dump(0),
+                Phase::Dump(idx) if idx < self.peers => {
+                    self.phase = Phase::Dump(idx + 1);
+                    self.dump = Some(Dump { peer: idx, next: 0 });
+                },
+                Phase::Dump(_) => {
+                    // Churn needs routes to change and both need peers
+                    if self.routes == 0 || self.peers == 0 {
+                        self.churn = 0;
+                    }
+                    if self.peers == 0 {
+                        self.flaps = 0;
+                    }
+                    self.phase = Phase::Events;
+                },
+                Phase::Events if self.churn + self.flaps > 0 => return Some(self.event()),
+                Phase::Events => self.phase = Phase::PeerDown(0),
+                Phase::PeerDown(idx) if idx < self.peers => {
+                    self.phase = Phase::PeerDown(idx + 1);
+                    return Some(self.peer_down(idx));
+                },
+                Phase::PeerDown(_) => self.phase = Phase::Termination,
+                Phase::Termination => {
+                    self.phase = Phase::Done;
+                    return Some(self.termination());
+                },
+                Phase::Done => return None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BmpDecoder, RouterSession, route::routes};
+
+    use bytes::BytesMut;
+    use hashbrown::HashSet;
+
+    #[test]
+    fn test_generator_round_trip() {
+        let generator = Generator::new(7)
+            .with_peers(6)
+            .with_routes(250)
+            .with_churn(200)
+            .with_flaps(2);
+
+        let mut decoder = BmpDecoder::new();
+        let mut session = RouterSession::new();
+        let mut buf = BytesMut::new();
+        let mut counts = [0usize; 7];
+        let mut announced = 0;
+        let mut peer_types = HashSet::new();
+
+        for frame in generator {
+            buf.extend_from_slice(&frame);
+            let message = decoder.decode_buf(&mut buf).unwrap().unwrap();
+            assert!(buf.is_empty());
+            assert_eq!(message.raw, frame);
+
+            counts[message.kind as usize] += 1;
+            if let MessageData::RouteMonitoring((header, update)) = &message.message {
+                peer_types.insert(header.peer_type);
+                announced += routes(update).iter().filter(|route| !route.withdrawn).count();
+            }
+
+            session.process(&message);
+            if counts[MessageKind::RouteMonitoring as usize] == 6 * (250 / 20 + 2) {
+                // Every peer has finished its initial dump
+                assert!(session.initial_sync_complete());
+            }
+        }
+
+        assert_eq!(counts[MessageKind::Initiation as usize], 1);
+        assert_eq!(counts[MessageKind::PeerUp as usize], 6 + 2);
+        assert_eq!(counts[MessageKind::PeerDown as usize], 6 + 2);
+        assert_eq!(counts[MessageKind::Termination as usize], 1);
+        // Initial dumps, flap dumps and churn, each dump is 13 UPDATEs and an End-of-RIB
+        assert_eq!(counts[MessageKind::RouteMonitoring as usize], (6 + 2) * 14 + 200);
+        assert!(announced >= (6 + 2) * 250);
+        assert_eq!(peer_types.len(), 3);
+        assert!(session.is_terminated());
+    }
+
+    #[test]
+    fn test_generator_deterministic() {
+        let session = |seed| Generator::new(seed).with_peers(3).with_churn(50).with_flaps(1).collect::<Vec<_>>();
+
+        assert_eq!(session(1), session(1));
+        assert_ne!(session(1), session(2));
+    }
+}
@@ -6,19 +6,49 @@
 //! to provide telemetry relating to BGP state.
 //!
 //! ## Errors
-//! This crate will panic if the BMP headers don't decode correctly, but as soon as we have
-//! a valid set of headers, failures on decoding the inner BGP messages will be handled via Result<T>
+//! Malformed BMP headers and inner BGP messages are reported via Result<T> rather than panicking,
+//! the decoder is fuzzed (see `fuzz/`) to keep it that way
 
 mod decoder;
+#[cfg(feature = "tokio-codec")]
+mod encoder;
 mod error;
+mod reader;
+mod session;
+mod util;
+mod validate;
 
 /// Contains types and decode implementations
 pub mod types;
+pub mod route;
+#[cfg(feature = "anonymize")]
+pub mod anonymize;
+pub mod generator;
+pub mod mrt;
+pub mod notification;
+#[cfg(feature = "openbmp")]
+pub mod openbmp;
+pub mod pcap;
+pub mod pipeline;
+pub mod state;
+#[cfg(feature = "pmacct")]
+pub mod pmacct;
+#[cfg(feature = "arrow")]
+pub mod columnar;
 
 /// Error type
 pub use error::Error;
 /// Some docs ay
 pub use decoder::BmpDecoder;
+/// Encoder for writing BMP messages
+#[cfg(feature = "tokio-codec")]
+pub use encoder::BmpEncoder;
+/// Blocking reader for BMP messages
+pub use reader::BmpReader;
+/// Identifies a peer on the monitored router
+pub use types::PeerKey;
+/// Router session state tracking
+pub use session::{PeerCounters, PeerState, RibView, RouterSession, SessionEvent};
 
 /// Result type wrapper
 pub type Result<T> = std::result::Result<T, error::Error>;
@@ -27,35 +57,35 @@ pub type Result<T> = std::result::Result<T, error::Error>;
 mod tests {
     use super::*;
 
-    use tokio::{
-        fs::File,
-        stream::StreamExt,
-    };
-    use tokio_util::codec::FramedRead;
-
     use std::ffi::OsStr;
     use std::fs;
 
-    #[tokio::test]
-    async fn test_data() {
+    #[test]
+    fn test_data() {
         for entry in fs::read_dir("test_data/").unwrap() {
-            let entry = entry.unwrap();
-
-            match entry.path().extension() {
-                Some(ext) if ext == OsStr::new("dump") => {
-                    println!("Testing {}", entry.path().display());
-                    let fh = File::open(&entry.path()).await.unwrap();
-                    let mut rdr = FramedRead::new(fh, BmpDecoder::new());
-
-                    while let Some(msg) = rdr.next().await {
-                        match msg {
-                            Ok(_) => {},
-                            Err(err) => panic!("Error: {}", err)
-                        };
-                    }
-                },
-                _ => {}
-            };
+            let path = entry.unwrap().path();
+            if path.extension() != Some(OsStr::new("dump")) {
+                continue;
+            }
+
+            println!("Testing {}", path.display());
+            for msg in BmpReader::from_path(&path).unwrap() {
+                if let Err(err) = msg {
+                    panic!("{}: {}", path.display(), err);
+                }
+            }
+        }
+    }
+
+    // Inputs that used to panic the decoder, found by the fuzz targets in fuzz/
+    #[test]
+    fn test_fuzz_regressions() {
+        for entry in fs::read_dir("test_data/fuzz/").unwrap() {
+            let path = entry.unwrap().path();
+            let errors = BmpReader::from_path(&path).unwrap()
+                .filter(Result::is_err)
+                .count();
+            assert!(errors > 0, "{} decoded without errors", path.display());
         }
     }
-}
\ No newline at end of file
+}
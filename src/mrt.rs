@@ -0,0 +1,1188 @@
+//! MRT ([RFC6396](https://tools.ietf.org/html/rfc6396)) export
+//!
+//! `MrtWriter` converts decoded BMP messages into the MRT records produced by route collectors
+//! so the output can be read by existing tooling (bgpdump, bgpkit, pybgpstream etc.)
+//!
+//! Route Monitoring messages are written as `BGP4MP_MESSAGE_AS4` records (or `BGP4MP_MESSAGE` for
+//! peers using 2-byte AS_PATHs) containing the original UPDATE bytes, Peer Up is written as the
+//! two OPENs followed by a state change and Peer Down as a state change. Only pre-policy
+//! Adj-RIB-In messages are exported since this is what an MRT collector peering directly with
+//! the router would have seen.
+//!
+//! `MrtReader` goes the other way, turning BGP4MP records into BMP messages with synthetic
+//! Per-Peer Headers so MRT archives can be fed through the same pipeline as live BMP sessions.
+//! The A flag in those headers (2-byte AS_PATHs) comes from the 4-octet ASN capability in the
+//! peer's OPENs. Only when there aren't any OPENs does it fall back to the record subtype, which
+//! assumes the collector picked the AS4 subtypes to match what the session negotiated.
+
+use crate::{BmpDecoder, Error, Result, types::*, util::put_attribute};
+
+use bgp_rs::{Capabilities, NLRIEncoding, PathAttribute, Segment, Update};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hashbrown::HashMap;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// RFC6396 Section 4
+const MRT_TABLE_DUMP_V2: u16 = 13;
+const MRT_BGP4MP: u16 = 16;
+const MRT_BGP4MP_ET: u16 = 17;
+
+// RFC6396 Section 4.3 & RFC8050
+const PEER_INDEX_TABLE: u16 = 1;
+const RIB_IPV4_UNICAST: u16 = 2;
+const RIB_IPV6_UNICAST: u16 = 4;
+const RIB_IPV4_UNICAST_ADDPATH: u16 = 8;
+const RIB_IPV6_UNICAST_ADDPATH: u16 = 10;
+
+// RFC6396 Section 4.4
+const BGP4MP_STATE_CHANGE: u16 = 0;
+const BGP4MP_MESSAGE: u16 = 1;
+const BGP4MP_MESSAGE_AS4: u16 = 4;
+const BGP4MP_STATE_CHANGE_AS4: u16 = 5;
+const BGP4MP_MESSAGE_LOCAL: u16 = 6;
+const BGP4MP_MESSAGE_AS4_LOCAL: u16 = 7;
+
+// Anything bigger than this is not a real MRT record
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+// BGP FSM states used in STATE_CHANGE records
+const STATE_IDLE: u16 = 1;
+const STATE_OPENCONFIRM: u16 = 5;
+const STATE_ESTABLISHED: u16 = 6;
+
+const AS_TRANS: u32 = 23456;
+
+// Common header + Per-Peer header, the BGP message follows these in a Route Monitoring message
+const ROUTE_MONITORING_OFFSET: usize = 6 + 42;
+// Common header + Per-Peer header + local address and ports, the OPENs follow these in a Peer Up
+const PEER_UP_OFFSET: usize = 6 + 42 + 20;
+// BGP message header
+const BGP_HEADER_LEN: usize = 19;
+
+/// Local end of a monitored BGP session, taken from the Peer Up message
+#[derive(Copy, Clone, Debug)]
+struct LocalInfo {
+    addr: IpAddr,
+    asn: u32,
+}
+
+impl LocalInfo {
+    fn from_peer_up(peer_up: &PeerUp) -> Self {
+        Self { addr: peer_up.local_addr, asn: peer_up.local_asn().unwrap_or(0) }
+    }
+}
+
+/// Key for a single route in a peer RIB
+type RouteKey = (IpAddr, u8, Option<u32>);
+
+#[derive(Clone, Debug)]
+struct RibEntry {
+    originated: u32,
+    attributes: Bytes,
+}
+
+/// Peer index, path ID and route for a single entry in a TABLE_DUMP_V2 RIB record
+type RibRecordEntry<'a> = (u16, Option<u32>, &'a RibEntry);
+
+#[derive(Clone, Debug)]
+struct PeerRib {
+    header: PeerHeader,
+    routes: HashMap<RouteKey, RibEntry>,
+}
+
+/// Writes decoded BMP messages as MRT records
+///
+/// ```no_run
+/// # use bmp_protocol::mrt::MrtWriter;
+/// # use std::net::Ipv4Addr;
+/// let fh = std::fs::File::create("updates.mrt").unwrap();
+/// // Write a TABLE_DUMP_V2 snapshot every 15 minutes
+/// let mut writer = MrtWriter::new(fh).with_table_dumps(Ipv4Addr::new(192, 0, 2, 1), 900);
+/// ```
+#[derive(Debug)]
+pub struct MrtWriter<W: Write> {
+    inner: W,
+    locals: HashMap<PeerKey, LocalInfo>,
+    collector_id: Ipv4Addr,
+    view_name: String,
+    rib: Option<HashMap<PeerKey, PeerRib>>,
+    dump_interval: Option<u32>,
+    next_dump: Option<u32>,
+}
+
+impl<W: Write> MrtWriter<W> {
+    /// Create a new writer which will only write BGP4MP records
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            locals: HashMap::new(),
+            collector_id: Ipv4Addr::UNSPECIFIED,
+            view_name: String::new(),
+            rib: None,
+            dump_interval: None,
+            next_dump: None,
+        }
+    }
+
+    /// Maintain a per-peer RIB so that TABLE_DUMP_V2 snapshots can be written. If `interval` is
+    /// non-zero, a snapshot is written automatically every `interval` seconds based on the BMP
+    /// message timestamps
+    pub fn with_table_dumps(mut self, collector_id: Ipv4Addr, interval: u32) -> Self {
+        self.collector_id = collector_id;
+        self.rib = Some(HashMap::new());
+        self.dump_interval = if interval > 0 { Some(interval) } else { None };
+
+        self
+    }
+
+    /// Set the view name used in the PEER_INDEX_TABLE
+    pub fn with_view_name(mut self, view_name: &str) -> Self {
+        self.view_name = view_name.into();
+        self
+    }
+
+    /// Get a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Consume the MrtWriter, returning the underlying writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write the MRT record(s) for a BMP message
+    pub fn write(&mut self, message: &BmpMessage) -> Result<()> {
+        let header = match &message.message {
+            MessageData::PeerUp((header, _)) |
+            MessageData::PeerDown((header, _)) |
+            MessageData::RouteMonitoring((header, _)) => header,
+            _ => return Ok(()),
+        };
+
+        if header.peer_flags.L || header.peer_flags.O {
+            return Ok(());
+        }
+
+        self.maybe_dump(header.timestamp)?;
+        let peer = PeerKey::from(header);
+
+        match &message.message {
+            MessageData::PeerUp((header, peer_up)) => {
+                self.locals.insert(peer, LocalInfo::from_peer_up(peer_up));
+                if let Some(rib) = self.rib.as_mut() {
+                    rib.insert(peer, PeerRib { header: *header, routes: HashMap::new() });
+                }
+
+                // Sent OPEN then received OPEN, if the speaker included them
+                let mut opens = message.raw.get(PEER_UP_OFFSET..).unwrap_or_default();
+                for local in &[true, false] {
+                    let len = match opens.get(16..18) {
+                        Some(len) => usize::from(u16::from_be_bytes([len[0], len[1]])),
+                        None => break,
+                    };
+                    let open = opens.get(..len)
+                        .ok_or_else(|| Error::decode("truncated OPEN in Peer Up message"))?;
+
+                    self.write_message(header, open, *local)?;
+                    opens = &opens[len..];
+                }
+
+                self.write_state_change(header, STATE_OPENCONFIRM, STATE_ESTABLISHED)
+            },
+            MessageData::PeerDown((header, _)) => {
+                if let Some(rib) = self.rib.as_mut() {
+                    rib.remove(&peer);
+                }
+                self.write_state_change(header, STATE_ESTABLISHED, STATE_IDLE)?;
+                self.locals.remove(&peer);
+
+                Ok(())
+            },
+            MessageData::RouteMonitoring((header, update)) => {
+                let bgp = message.raw.get(ROUTE_MONITORING_OFFSET..)
+                    .filter(|bgp| bgp.len() >= BGP_HEADER_LEN)
+                    .ok_or_else(|| Error::decode("Route Monitoring message is missing the raw UPDATE"))?;
+
+                if let Some(rib) = self.rib.as_mut() {
+                    let peer = rib.entry(peer)
+                        .or_insert_with(|| PeerRib { header: *header, routes: HashMap::new() });
+
+                    apply_update(peer, header.timestamp, bgp, update)?;
+                }
+
+                self.write_message(header, bgp, false)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn maybe_dump(&mut self, timestamp: u32) -> Result<()> {
+        let interval = match self.dump_interval {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let next = (timestamp / interval + 1) * interval;
+
+        match self.next_dump {
+            Some(due) if timestamp >= due => {
+                self.write_table_dump(due)?;
+                self.next_dump = Some(next);
+            },
+            Some(_) => {},
+            None => { self.next_dump = Some(next); },
+        };
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, timestamp: u32, kind: u16, subtype: u16, body: &[u8]) -> Result<()> {
+        let mut header = Vec::with_capacity(12);
+        header.put_u32(timestamp);
+        header.put_u16(kind);
+        header.put_u16(subtype);
+        header.put_u32(body.len() as u32);
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(body)?;
+
+        Ok(())
+    }
+
+    // Common prefix of all BGP4MP records: peer AS, local AS, interface index, AFI, peer IP, local IP
+    fn bgp4mp_header(&self, header: &PeerHeader, as4: bool) -> Vec<u8> {
+        let local = self.locals.get(&PeerKey::from(header)).copied()
+            .unwrap_or(LocalInfo { addr: Ipv4Addr::UNSPECIFIED.into(), asn: 0 });
+
+        let mut buf = Vec::with_capacity(44);
+        if as4 {
+            buf.put_u32(header.peer_asn);
+            buf.put_u32(local.asn);
+        } else {
+            buf.put_u16(as2(header.peer_asn));
+            buf.put_u16(as2(local.asn));
+        }
+        buf.put_u16(0);
+
+        match (header.peer_addr, local.addr) {
+            (IpAddr::V4(peer), local) => {
+                let local = match local {
+                    IpAddr::V4(local) => local,
+                    IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+                };
+                buf.put_u16(1);
+                buf.put_slice(&peer.octets());
+                buf.put_slice(&local.octets());
+            },
+            (IpAddr::V6(peer), local) => {
+                let local = match local {
+                    IpAddr::V6(local) => local,
+                    IpAddr::V4(_) => Ipv6Addr::UNSPECIFIED,
+                };
+                buf.put_u16(2);
+                buf.put_slice(&peer.octets());
+                buf.put_slice(&local.octets());
+            },
+        };
+
+        buf
+    }
+
+    fn write_state_change(&mut self, header: &PeerHeader, old: u16, new: u16) -> Result<()> {
+        let mut body = self.bgp4mp_header(header, true);
+        body.put_u16(old);
+        body.put_u16(new);
+
+        self.write_record(header.timestamp, MRT_BGP4MP, BGP4MP_STATE_CHANGE_AS4, &body)
+    }
+
+    fn write_message(&mut self, header: &PeerHeader, bgp: &[u8], local: bool) -> Result<()> {
+        // AS_PATHs in the UPDATE are only 4-byte if the BMP speaker says so
+        let as4 = !header.peer_flags.A;
+        let subtype = match (as4, local) {
+            (true, false) => BGP4MP_MESSAGE_AS4,
+            (true, true) => BGP4MP_MESSAGE_AS4_LOCAL,
+            (false, false) => BGP4MP_MESSAGE,
+            (false, true) => BGP4MP_MESSAGE_LOCAL,
+        };
+
+        let mut body = self.bgp4mp_header(header, as4);
+        body.put_slice(bgp);
+
+        self.write_record(header.timestamp, MRT_BGP4MP, subtype, &body)
+    }
+
+    /// Write a TABLE_DUMP_V2 snapshot of the current RIB for every peer
+    pub fn write_table_dump(&mut self, timestamp: u32) -> Result<()> {
+        let rib = self.rib.as_ref()
+            .ok_or_else(|| Error::decode("table dumps are not enabled on this MrtWriter"))?;
+
+        // Peers sharing an address in different VRFs get an entry each, MRT has no room for the RD
+        let mut peers: Vec<&PeerRib> = rib.values().collect();
+        peers.sort_by_key(|peer| (peer.header.peer_addr, PeerKey::from(&peer.header)));
+
+        // PEER_INDEX_TABLE
+        let mut index = Vec::new();
+        index.put_slice(&self.collector_id.octets());
+        index.put_u16(self.view_name.len() as u16);
+        index.put_slice(self.view_name.as_bytes());
+        index.put_u16(peers.len() as u16);
+        for peer in &peers {
+            // Always use 4-byte ASNs
+            match peer.header.peer_addr {
+                IpAddr::V4(addr) => {
+                    index.put_u8(0x02);
+                    index.put_slice(&peer.header.peer_bgp_id.octets());
+                    index.put_slice(&addr.octets());
+                },
+                IpAddr::V6(addr) => {
+                    index.put_u8(0x03);
+                    index.put_slice(&peer.header.peer_bgp_id.octets());
+                    index.put_slice(&addr.octets());
+                },
+            };
+            index.put_u32(peer.header.peer_asn);
+        }
+
+        // Group every route by prefix, keeping ADD-PATH routes in their own records
+        let mut prefixes: BTreeMap<(bool, IpAddr, u8), Vec<RibRecordEntry>> = BTreeMap::new();
+        for (peer_index, peer) in peers.iter().enumerate() {
+            for ((addr, len, path_id), entry) in &peer.routes {
+                prefixes.entry((path_id.is_some(), *addr, *len))
+                    .or_default()
+                    .push((peer_index as u16, *path_id, entry));
+            }
+        }
+
+        let mut records = Vec::with_capacity(prefixes.len());
+        for (sequence, ((addpath, addr, len), mut entries)) in prefixes.into_iter().enumerate() {
+            entries.sort_by_key(|(peer_index, path_id, _)| (*peer_index, *path_id));
+
+            let subtype = match (addr, addpath) {
+                (IpAddr::V4(_), false) => RIB_IPV4_UNICAST,
+                (IpAddr::V4(_), true) => RIB_IPV4_UNICAST_ADDPATH,
+                (IpAddr::V6(_), false) => RIB_IPV6_UNICAST,
+                (IpAddr::V6(_), true) => RIB_IPV6_UNICAST_ADDPATH,
+            };
+
+            let mut body = Vec::new();
+            body.put_u32(sequence as u32);
+            body.put_u8(len);
+            let octets = match addr {
+                IpAddr::V4(addr) => addr.octets().to_vec(),
+                IpAddr::V6(addr) => addr.octets().to_vec(),
+            };
+            body.put_slice(&octets[..usize::from(len).div_ceil(8)]);
+            body.put_u16(entries.len() as u16);
+
+            for (peer_index, path_id, entry) in entries {
+                body.put_u16(peer_index);
+                body.put_u32(entry.originated);
+                if let Some(path_id) = path_id {
+                    body.put_u32(path_id);
+                }
+                body.put_u16(entry.attributes.len() as u16);
+                body.put_slice(&entry.attributes);
+            }
+
+            records.push((subtype, body));
+        }
+
+        self.write_record(timestamp, MRT_TABLE_DUMP_V2, PEER_INDEX_TABLE, &index)?;
+        for (subtype, body) in records {
+            self.write_record(timestamp, MRT_TABLE_DUMP_V2, subtype, &body)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn as2(asn: u32) -> u16 {
+    if asn > u32::from(u16::MAX) { AS_TRANS as u16 } else { asn as u16 }
+}
+
+fn route_key(nlri: &NLRIEncoding) -> Option<RouteKey> {
+    match nlri {
+        NLRIEncoding::IP(prefix) => Some((IpAddr::from(prefix), prefix.length, None)),
+        NLRIEncoding::IP_WITH_PATH_ID((prefix, path_id)) => Some((IpAddr::from(prefix), prefix.length, Some(*path_id))),
+        // Only unicast routes are included in the table dumps
+        _ => None,
+    }
+}
+
+/// Update a peer RIB with the routes from an UPDATE
+fn apply_update(peer: &mut PeerRib, timestamp: u32, bgp: &[u8], update: &Update) -> Result<()> {
+    let mut withdrawn = update.withdrawn_routes.iter().collect::<Vec<_>>();
+    let mut announced = update.announced_routes.iter().collect::<Vec<_>>();
+
+    for attribute in &update.attributes {
+        match attribute {
+            PathAttribute::MP_REACH_NLRI(reach) => announced.extend(reach.announced_routes.iter()),
+            PathAttribute::MP_UNREACH_NLRI(unreach) => withdrawn.extend(unreach.withdrawn_routes.iter()),
+            _ => {},
+        };
+    }
+
+    for key in withdrawn.into_iter().filter_map(route_key) {
+        peer.routes.remove(&key);
+    }
+
+    if announced.is_empty() {
+        return Ok(());
+    }
+
+    let entry = RibEntry {
+        originated: timestamp,
+        attributes: rib_attributes(bgp, update)?,
+    };
+    for key in announced.into_iter().filter_map(route_key) {
+        peer.routes.insert(key, entry.clone());
+    }
+
+    Ok(())
+}
+
+/// Build the attributes for a RIB entry from the raw UPDATE (RFC6396 Section 4.3.4). Attributes
+/// are copied verbatim apart from AS_PATH which is always re-encoded with 4-byte ASNs (splitting
+/// segments of more than 255 ASNs), MP_REACH_NLRI which is reduced to just the next hop and
+/// MP_UNREACH_NLRI which is dropped
+fn rib_attributes(bgp: &[u8], update: &Update) -> Result<Bytes> {
+    let truncated = || Error::decode("truncated UPDATE message");
+
+    let body = bgp.get(BGP_HEADER_LEN..).ok_or_else(truncated)?;
+    let withdrawn_len = usize::from(u16::from_be_bytes([
+        *body.first().ok_or_else(truncated)?,
+        *body.get(1).ok_or_else(truncated)?,
+    ]));
+    let attrs = body.get(2 + withdrawn_len..).ok_or_else(truncated)?;
+    let attrs_len = usize::from(u16::from_be_bytes([
+        *attrs.first().ok_or_else(truncated)?,
+        *attrs.get(1).ok_or_else(truncated)?,
+    ]));
+    let mut attrs = attrs.get(2..2 + attrs_len).ok_or_else(truncated)?;
+
+    let mut out = Vec::with_capacity(attrs_len);
+    while !attrs.is_empty() {
+        let flags = attrs[0];
+        let kind = *attrs.get(1).ok_or_else(truncated)?;
+        let (header_len, len) = if flags & 0x10 == 0x10 {
+            (4, usize::from(u16::from_be_bytes([*attrs.get(2).ok_or_else(truncated)?, *attrs.get(3).ok_or_else(truncated)?])))
+        } else {
+            (3, usize::from(*attrs.get(2).ok_or_else(truncated)?))
+        };
+        let attribute = attrs.get(..header_len + len).ok_or_else(truncated)?;
+        let value = &attribute[header_len..];
+
+        match kind {
+            // AS_PATH
+            2 => {
+                let mut value = vec![];
+                if let Some(PathAttribute::AS_PATH(path)) = update.get(bgp_rs::Identifier::AS_PATH) {
+                    for segment in &path.segments {
+                        let (kind, asns) = match segment {
+                            Segment::AS_SET(asns) => (1, asns),
+                            Segment::AS_SEQUENCE(asns) => (2, asns),
+                        };
+                        // The segment length is a single octet
+                        for asns in asns.chunks(usize::from(u8::MAX)) {
+                            value.put_u8(kind);
+                            value.put_u8(asns.len() as u8);
+                            for asn in asns {
+                                value.put_u32(*asn);
+                            }
+                        }
+                    }
+                }
+                put_attribute(&mut out, flags, kind, &value);
+            },
+            // MP_REACH_NLRI, keep next hop length and next hop
+            14 => {
+                let nh_len = usize::from(*value.get(3).ok_or_else(truncated)?);
+                let next_hop = value.get(3..4 + nh_len).ok_or_else(truncated)?;
+                put_attribute(&mut out, flags, kind, next_hop);
+            },
+            // MP_UNREACH_NLRI
+            15 => {},
+            _ => out.put_slice(attribute),
+        };
+
+        attrs = &attrs[header_len + len..];
+    }
+
+    Ok(out.into())
+}
+
+/// Per-peer state kept while reading BGP4MP records
+#[derive(Clone, Debug, Default)]
+struct MrtPeer {
+    up: bool,
+    sent_open: Option<Bytes>,
+    recv_open: Option<Bytes>,
+}
+
+/// Fields common to every BGP4MP record
+#[derive(Copy, Clone, Debug)]
+struct Bgp4mpHeader {
+    timestamp: u32,
+    microseconds: u32,
+    as4: bool,
+    peer_asn: u32,
+    peer_addr: IpAddr,
+    local_addr: IpAddr,
+}
+
+impl Bgp4mpHeader {
+    fn decode(timestamp: u32, microseconds: u32, as4: bool, buf: &mut Bytes) -> Result<Self> {
+        let truncated = || Error::decode("truncated BGP4MP record");
+
+        let asn_len = if as4 { 4 } else { 2 };
+        if buf.remaining() < asn_len * 2 + 4 {
+            return Err(truncated());
+        }
+
+        let (peer_asn, _local_asn) = if as4 {
+            (buf.get_u32(), buf.get_u32())
+        } else {
+            (u32::from(buf.get_u16()), u32::from(buf.get_u16()))
+        };
+        let _ifindex = buf.get_u16();
+
+        let (peer_addr, local_addr) = match buf.get_u16() {
+            1 if buf.remaining() >= 8 => (
+                IpAddr::V4(Ipv4Addr::from(buf.get_u32())),
+                IpAddr::V4(Ipv4Addr::from(buf.get_u32())),
+            ),
+            2 if buf.remaining() >= 32 => (
+                IpAddr::V6(Ipv6Addr::from(buf.get_u128())),
+                IpAddr::V6(Ipv6Addr::from(buf.get_u128())),
+            ),
+            1 | 2 => return Err(truncated()),
+            v => return Err(Error::decode(&format!("invalid AFI in BGP4MP record: {}", v))),
+        };
+
+        Ok(Self { timestamp, microseconds, as4, peer_asn, peer_addr, local_addr })
+    }
+
+    fn peer_header(&self, peer_bgp_id: Ipv4Addr, as4: bool) -> PeerHeader {
+        PeerHeader {
+            peer_type: PeerType::GlobalInstance,
+            peer_flags: PeerFlags { V: self.peer_addr.is_ipv6(), L: false, A: !as4, O: false },
+            peer_distinguisher: (0, 0),
+            peer_addr: self.peer_addr,
+            peer_asn: self.peer_asn,
+            peer_bgp_id,
+            timestamp: self.timestamp,
+            timestamp_ms: self.microseconds,
+        }
+    }
+}
+
+/// Reads MRT records and produces BMP messages
+///
+/// BGP4MP state changes become Peer Up / Peer Down messages and received UPDATEs become Route
+/// Monitoring messages. OPENs are held on to and included in the Peer Up, if a peer sends an
+/// UPDATE without having come up first (which is common at the start of an updates file) a Peer
+/// Up is synthesized with IPv4 and IPv6 unicast capabilities. Other record types, including
+/// TABLE_DUMP_V2, are skipped.
+///
+/// The messages are built as BMP frames and decoded with a `BmpDecoder`, so the `raw` bytes on
+/// each message can be written out with a `BmpEncoder` to produce a `.dump` file.
+///
+/// ```no_run
+/// # use bmp_protocol::mrt::MrtReader;
+/// let fh = std::fs::File::open("updates.20200101.0000").unwrap();
+/// for message in MrtReader::new(fh) {
+///     println!("{:?}", message.unwrap());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MrtReader<R: Read> {
+    inner: R,
+    decoder: BmpDecoder,
+    buf: BytesMut,
+    peers: HashMap<IpAddr, MrtPeer>,
+    pending: VecDeque<BmpMessage>,
+}
+
+impl<R: Read> MrtReader<R> {
+    /// Create a new reader
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            decoder: BmpDecoder::new(),
+            buf: BytesMut::new(),
+            peers: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Consume the MrtReader, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_record(&mut self) -> Result<Option<(u32, u16, u16, Bytes)>> {
+        let mut header = [0u8; 12];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(Error::decode("truncated MRT header")),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        let timestamp = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = u16::from_be_bytes([header[4], header[5]]);
+        let subtype = u16::from_be_bytes([header[6], header[7]]);
+        let length = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        if length > MAX_RECORD_LEN {
+            return Err(Error::decode(&format!("MRT record length {} is too long", length)));
+        }
+
+        let mut body = vec![0u8; length];
+        self.inner.read_exact(&mut body)?;
+
+        Ok(Some((timestamp, kind, subtype, body.into())))
+    }
+
+    fn process(&mut self, timestamp: u32, kind: u16, subtype: u16, mut body: Bytes) -> Result<()> {
+        let microseconds = match kind {
+            MRT_BGP4MP => 0,
+            MRT_BGP4MP_ET if body.remaining() >= 4 => body.get_u32(),
+            _ => {
+                tracing::trace!(kind, subtype, "skipping MRT record");
+                return Ok(());
+            },
+        };
+
+        match subtype {
+            BGP4MP_STATE_CHANGE | BGP4MP_STATE_CHANGE_AS4 => {
+                let record = Bgp4mpHeader::decode(timestamp, microseconds, subtype == BGP4MP_STATE_CHANGE_AS4, &mut body)?;
+                if body.remaining() < 4 {
+                    return Err(Error::decode("truncated BGP4MP state change"));
+                }
+                let old_state = body.get_u16();
+                let new_state = body.get_u16();
+
+                let up = self.peers.get(&record.peer_addr).map(|peer| peer.up).unwrap_or(false);
+                if new_state == STATE_ESTABLISHED && !up {
+                    self.peer_up(&record)?;
+                } else if old_state == STATE_ESTABLISHED && new_state != STATE_ESTABLISHED && up {
+                    self.peer_down(&record, 4, &[])?;
+                }
+            },
+            BGP4MP_MESSAGE | BGP4MP_MESSAGE_AS4 | BGP4MP_MESSAGE_LOCAL | BGP4MP_MESSAGE_AS4_LOCAL => {
+                let as4 = subtype == BGP4MP_MESSAGE_AS4 || subtype == BGP4MP_MESSAGE_AS4_LOCAL;
+                let local = subtype == BGP4MP_MESSAGE_LOCAL || subtype == BGP4MP_MESSAGE_AS4_LOCAL;
+
+                let record = Bgp4mpHeader::decode(timestamp, microseconds, as4, &mut body)?;
+                if body.len() < BGP_HEADER_LEN {
+                    return Err(Error::decode("truncated BGP message in BGP4MP record"));
+                }
+                let up = self.peers.get(&record.peer_addr).map(|peer| peer.up).unwrap_or(false);
+
+                match body[18] {
+                    // OPEN
+                    1 => {
+                        let peer = self.peers.entry(record.peer_addr).or_default();
+                        if local {
+                            peer.sent_open = Some(body);
+                        } else {
+                            peer.recv_open = Some(body);
+                        }
+                    },
+                    // UPDATE, we only care about what the peer sent
+                    2 if !local => {
+                        if !up {
+                            self.peer_up(&record)?;
+                        }
+
+                        let mut message = BytesMut::new();
+                        self.peer_header(&record).encode(&mut message);
+                        message.extend_from_slice(&body);
+
+                        self.emit(MessageKind::RouteMonitoring, &message)?;
+                    },
+                    // NOTIFICATION
+                    3 if up => {
+                        let reason = if local { 1 } else { 3 };
+                        self.peer_down(&record, reason, &body)?;
+                    },
+                    _ => {},
+                };
+            },
+            _ => tracing::trace!(kind, subtype, "skipping BGP4MP record"),
+        };
+
+        Ok(())
+    }
+
+    fn peer_header(&self, record: &Bgp4mpHeader) -> PeerHeader {
+        let peer = self.peers.get(&record.peer_addr);
+
+        // Take the BGP ID from the received OPEN if we have one
+        let bgp_id = peer
+            .and_then(|peer| peer.recv_open.as_ref())
+            .and_then(|open| open.get(24..28))
+            .map(|id| Ipv4Addr::new(id[0], id[1], id[2], id[3]))
+            .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+        // 4-byte AS_PATHs if the OPENs negotiated them, the record subtype only says how the MRT
+        // header was written. A lone OPEN is used for both sides, as in the Peer Up
+        let opens = peer.map(|peer| (peer.sent_open.as_deref(), peer.recv_open.as_deref()));
+        let as4 = match opens {
+            Some((Some(sent), Some(recv))) => open_as4(sent) && open_as4(recv),
+            Some((Some(open), None)) | Some((None, Some(open))) => open_as4(open),
+            _ => record.as4,
+        };
+
+        record.peer_header(bgp_id, as4)
+    }
+
+    fn peer_up(&mut self, record: &Bgp4mpHeader) -> Result<()> {
+        let header = self.peer_header(record);
+        let peer = self.peers.entry(record.peer_addr).or_default();
+        peer.up = true;
+
+        // If only one OPEN is known use it for both sides so the negotiated capabilities match it
+        let (sent_open, recv_open) = match (peer.sent_open.clone(), peer.recv_open.clone()) {
+            (Some(sent), Some(recv)) => (sent, recv),
+            (Some(open), None) | (None, Some(open)) => (open.clone(), open),
+            (None, None) => {
+                let open = synthetic_open(record.peer_asn, record.as4);
+                (open.clone(), open)
+            },
+        };
+
+        let mut message = BytesMut::new();
+        header.encode(&mut message);
+        match record.local_addr {
+            IpAddr::V4(addr) => {
+                message.put_slice(&[0; 12]);
+                message.put_slice(&addr.octets());
+            },
+            IpAddr::V6(addr) => message.put_slice(&addr.octets()),
+        };
+        // TCP ports aren't recorded in MRT
+        message.put_u16(0);
+        message.put_u16(0);
+        message.put_slice(&sent_open);
+        message.put_slice(&recv_open);
+
+        self.emit(MessageKind::PeerUp, &message)
+    }
+
+    fn peer_down(&mut self, record: &Bgp4mpHeader, reason: u8, data: &[u8]) -> Result<()> {
+        let mut message = BytesMut::new();
+        self.peer_header(record).encode(&mut message);
+        message.put_u8(reason);
+        message.put_slice(data);
+
+        // The next session will have its own OPENs
+        self.peers.remove(&record.peer_addr);
+
+        self.emit(MessageKind::PeerDown, &message)
+    }
+
+    // Wrap a message body in a BMP common header and run it through the decoder
+    fn emit(&mut self, kind: MessageKind, body: &[u8]) -> Result<()> {
+        self.buf.reserve(6 + body.len());
+        self.buf.put_u8(3);
+        self.buf.put_u32((6 + body.len()) as u32);
+        self.buf.put_u8(kind as u8);
+        self.buf.put_slice(body);
+
+        let message = self.decoder.decode_buf(&mut self.buf)?
+            .ok_or_else(|| Error::decode("synthesized BMP message was incomplete"))?;
+        self.pending.push_back(message);
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for MrtReader<R> {
+    type Item = Result<BmpMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(message) = self.pending.pop_front() {
+                return Some(Ok(message));
+            }
+
+            match self.read_record() {
+                Ok(Some((timestamp, kind, subtype, body))) => {
+                    if let Err(err) = self.process(timestamp, kind, subtype, body) {
+                        return Some(Err(err));
+                    }
+                },
+                Ok(None) => return None,
+                Err(err) => return Some(Err(err)),
+            };
+        }
+    }
+}
+
+/// Whether an OPEN (with its header) advertises the 4-octet ASN capability
+fn open_as4(mut open: &[u8]) -> bool {
+    bgp_rs::Header::parse(&mut open)
+        .and_then(|_| bgp_rs::Open::parse(&mut open))
+        .map(|open| Capabilities::from_parameters(open.parameters).FOUR_OCTET_ASN_SUPPORT)
+        .unwrap_or(false)
+}
+
+/// Build an OPEN advertising IPv4 & IPv6 unicast (and 4-byte ASNs if `as4` is set)
+fn synthetic_open(asn: u32, as4: bool) -> Bytes {
+    let mut capabilities = vec![
+        1, 4, 0, 1, 0, 1,   // IPv4 unicast
+        1, 4, 0, 2, 0, 1,   // IPv6 unicast
+    ];
+    if as4 {
+        capabilities.extend_from_slice(&[65, 4]);
+        capabilities.put_u32(asn);
+    }
+
+    let mut open = Vec::with_capacity(64);
+    open.put_slice(&[0xff; 16]);
+    open.put_u16((BGP_HEADER_LEN + 10 + 2 + capabilities.len()) as u16);
+    open.put_u8(1);
+    open.put_u8(4);
+    open.put_u16(as2(asn));
+    open.put_u16(0);
+    open.put_u32(0);
+    open.put_u8((2 + capabilities.len()) as u8);
+    open.put_u8(2);
+    open.put_u8(capabilities.len() as u8);
+    open.put_slice(&capabilities);
+
+    open.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Walk the MRT records in a buffer, returning (type, subtype, body)
+    fn records(mut buf: &[u8]) -> Vec<(u16, u16, Vec<u8>)> {
+        let mut out = vec![];
+        while !buf.is_empty() {
+            let kind = u16::from_be_bytes([buf[4], buf[5]]);
+            let subtype = u16::from_be_bytes([buf[6], buf[7]]);
+            let len = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+            out.push((kind, subtype, buf[12..12 + len].to_vec()));
+            buf = &buf[12 + len..];
+        }
+        out
+    }
+
+    #[test]
+    fn test_mrt_export() {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut writer = MrtWriter::new(vec![]).with_table_dumps(Ipv4Addr::new(192, 0, 2, 100), 0);
+
+        let mut updates = vec![];
+        let mut count = 0;
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            if let MessageData::RouteMonitoring(_) = message.message {
+                updates.push(message.raw.slice(ROUTE_MONITORING_OFFSET..));
+            }
+            writer.write(&message).unwrap();
+            count += 1;
+
+            // Snapshot once both peers have finished their initial dump
+            if count == 7 {
+                writer.write_table_dump(1_577_836_900).unwrap();
+            }
+        }
+
+        let records = records(writer.get_ref());
+        let kinds = records.iter().map(|(kind, subtype, _)| (*kind, *subtype)).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4_LOCAL),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_BGP4MP, BGP4MP_STATE_CHANGE_AS4),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4_LOCAL),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_BGP4MP, BGP4MP_STATE_CHANGE_AS4),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_BGP4MP, BGP4MP_MESSAGE_AS4),
+            (MRT_TABLE_DUMP_V2, PEER_INDEX_TABLE),
+            (MRT_TABLE_DUMP_V2, RIB_IPV4_UNICAST),
+            (MRT_TABLE_DUMP_V2, RIB_IPV4_UNICAST),
+            (MRT_TABLE_DUMP_V2, RIB_IPV6_UNICAST),
+            (MRT_BGP4MP, BGP4MP_STATE_CHANGE_AS4),
+            (MRT_BGP4MP, BGP4MP_STATE_CHANGE_AS4),
+        ]);
+
+        // IPv4 peer: peer AS, local AS, ifindex, AFI, peer IP, local IP then the UPDATE untouched
+        let (_, _, body) = &records[6];
+        assert_eq!(&body[..8], &[0, 0, 0xfd, 0xe9, 0, 0, 0xfd, 0xe8]);
+        assert_eq!(&body[10..12], &[0, 1]);
+        assert_eq!(&body[20..], &updates[0][..]);
+
+        // 198.51.100.0/24 from peer 0 (192.0.2.1)
+        let (_, _, body) = &records[11];
+        assert_eq!(&body[..8], &[0, 0, 0, 0, 24, 198, 51, 100]);
+        assert_eq!(&body[8..10], &[0, 1]);
+        assert_eq!(&body[10..12], &[0, 0]);
+
+        // 2001:db8:1000::/36 from peer 1, MP_REACH_NLRI is reduced to the next hop
+        let (_, _, body) = &records[13];
+        let attributes = &body[4 + 1 + 5 + 2 + 2 + 4 + 2..];
+        assert!(attributes.ends_with(&[0x80, 14, 17, 16, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_mrt_export_rd_peers() {
+        let data = fs::read("test_data/synthetic.dump").unwrap();
+        let mut frames = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let length = u32::from_be_bytes([data[offset + 1], data[offset + 2], data[offset + 3], data[offset + 4]]) as usize;
+            frames.push(&data[offset..offset + length]);
+            offset += length;
+        }
+
+        // The IPv4 peer in two VRFs with the same address, then VRF 1 goes down
+        let in_vrf = |frame: &[u8], vrf: u8| {
+            let mut frame = frame.to_vec();
+            frame[6] = PeerType::RdInstance as u8;
+            frame[15] = vrf;
+            frame
+        };
+        let (peer_up, update, peer_down) = (frames[1], frames[3], frames[7]);
+
+        let mut decoder = BmpDecoder::new();
+        let mut writer = MrtWriter::new(vec![]).with_table_dumps(Ipv4Addr::new(192, 0, 2, 100), 0);
+        let frames = [in_vrf(peer_up, 1), in_vrf(peer_up, 2), in_vrf(update, 1), in_vrf(update, 2), in_vrf(peer_down, 1)];
+        for frame in &frames {
+            writer.write(&decoder.decode_slice(frame).1.unwrap().unwrap()).unwrap();
+        }
+        writer.write_table_dump(1_577_836_900).unwrap();
+        writer.write(&decoder.decode_slice(&in_vrf(update, 2)).1.unwrap().unwrap()).unwrap();
+
+        let records = records(writer.get_ref());
+        let n = records.len();
+
+        // Only VRF 2 is left in the table dump, with both of its routes
+        let (_, subtype, index) = &records[n - 4];
+        assert_eq!(*subtype, PEER_INDEX_TABLE);
+        assert_eq!(&index[6..8], &[0, 1]);
+        for (_, subtype, body) in &records[n - 3..n - 1] {
+            assert_eq!(*subtype, RIB_IPV4_UNICAST);
+            assert_eq!(&body[8..12], &[0, 1, 0, 0]);
+        }
+
+        // and it keeps its local AS
+        let (_, subtype, body) = &records[n - 1];
+        assert_eq!(*subtype, BGP4MP_MESSAGE_AS4);
+        assert_eq!(&body[..8], &[0, 0, 0xfd, 0xe9, 0, 0, 0xfd, 0xe8]);
+    }
+
+    #[test]
+    fn test_mrt_long_as_path() {
+        // An UPDATE with just ORIGIN and an AS_PATH, which is rebuilt from the parsed UPDATE
+        let mut bgp = vec![0xff; 16];
+        bgp.put_u16((BGP_HEADER_LEN + 4 + 7) as u16);
+        bgp.put_slice(&[2, 0, 0, 0, 7, 0x40, 1, 1, 0, 0x40, 2, 0]);
+        let update = Update {
+            withdrawn_routes: vec![],
+            attributes: vec![PathAttribute::AS_PATH(bgp_rs::ASPath {
+                segments: vec![Segment::AS_SEQUENCE((1..=300).collect()), Segment::AS_SET(vec![64512, 64513])],
+            })],
+            announced_routes: vec![],
+        };
+
+        let attributes = rib_attributes(&bgp, &update).unwrap();
+        assert_eq!(&attributes[..4], &[0x40, 1, 1, 0]);
+        // Extended length AS_PATH, then 255 + 45 ASNs and the set
+        let value_len = 2 + 255 * 4 + 2 + 45 * 4 + 2 + 2 * 4;
+        assert_eq!(&attributes[4..8], &[0x50, 2, (value_len >> 8) as u8, value_len as u8]);
+        let value = &attributes[8..];
+        assert_eq!(value.len(), value_len);
+        assert_eq!(&value[..6], &[2, 255, 0, 0, 0, 1]);
+        assert_eq!(&value[2 + 255 * 4..2 + 255 * 4 + 6], &[2, 45, 0, 0, 1, 0]);
+        assert_eq!(&value[value_len - 10..], &[1, 2, 0, 0, 0xfc, 0, 0, 0, 0xfc, 1]);
+    }
+
+    #[test]
+    fn test_mrt_import() {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut writer = MrtWriter::new(vec![]);
+
+        let mut original = vec![];
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            writer.write(&message).unwrap();
+            original.push(message);
+        }
+
+        let messages = MrtReader::new(&writer.get_ref()[..])
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let kinds = messages.iter().map(|message| message.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            MessageKind::PeerUp,
+            MessageKind::PeerUp,
+            MessageKind::RouteMonitoring,
+            MessageKind::RouteMonitoring,
+            MessageKind::RouteMonitoring,
+            MessageKind::RouteMonitoring,
+            MessageKind::PeerDown,
+            MessageKind::PeerDown,
+        ]);
+
+        // The OPENs and UPDATEs make it through untouched
+        let messages = messages.into_iter()
+            .filter(|message| message.kind != MessageKind::PeerDown)
+            .collect::<Vec<_>>();
+        for (imported, original) in messages.iter().zip(original.iter().skip(1)) {
+            let offset = match imported.kind {
+                MessageKind::PeerUp => PEER_UP_OFFSET,
+                _ => ROUTE_MONITORING_OFFSET,
+            };
+            assert_eq!(imported.raw.slice(offset..), original.raw.slice(offset..));
+        }
+
+        // And re-encoding produces something the decoder is happy with
+        let mut dump = BytesMut::new();
+        for message in &messages {
+            dump.extend_from_slice(&message.raw);
+        }
+
+        let mut decoder = BmpDecoder::new();
+        let mut count = 0;
+        while decoder.decode_buf(&mut dump).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, messages.len());
+    }
+
+    #[test]
+    fn test_mrt_import_without_peer_up() {
+        // A lone BGP4MP_MESSAGE_AS4 UPDATE for 192.0.2.1 AS65001 announcing 198.51.100.0/24
+        let update: &[u8] = &[
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0, 47, 2, 0, 0, 0, 20,
+            0x40, 1, 1, 0,
+            0x40, 2, 6, 2, 1, 0, 0, 0xfd, 0xe9,
+            0x40, 3, 4, 192, 0, 2, 1,
+            24, 198, 51, 100,
+        ];
+        let mut record = vec![];
+        record.put_u32(1_577_836_800);
+        record.put_u16(MRT_BGP4MP);
+        record.put_u16(BGP4MP_MESSAGE_AS4);
+        record.put_u32(20 + update.len() as u32);
+        record.put_slice(&[0, 0, 0xfd, 0xe9, 0, 0, 0xfd, 0xe8, 0, 0, 0, 1, 192, 0, 2, 1, 192, 0, 2, 254]);
+        record.put_slice(update);
+
+        let messages = MrtReader::new(&record[..])
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(messages.len(), 2);
+        match &messages[1].message {
+            MessageData::RouteMonitoring((header, update)) => {
+                assert_eq!(header.peer_addr, "192.0.2.1".parse::<IpAddr>().unwrap());
+                assert_eq!(header.peer_asn, 65001);
+                assert_eq!(update.announced_routes.len(), 1);
+            },
+            _ => panic!("expected Route Monitoring, got {:?}", messages[1].kind),
+        };
+
+        // A corrupt length is an error, not a huge allocation
+        record[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(MrtReader::new(&record[..]).next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_mrt_import_mixed_subtypes() {
+        // A BGP4MP record from 192.0.2.1 AS65001, the subtype sets the size of the ASNs in its header
+        let bgp4mp = |subtype: u16, message: &[u8]| {
+            let as4 = matches!(subtype, BGP4MP_STATE_CHANGE_AS4 | BGP4MP_MESSAGE_AS4 | BGP4MP_MESSAGE_AS4_LOCAL);
+            let mut body = vec![];
+            if as4 {
+                body.put_u32(65001);
+                body.put_u32(65000);
+            } else {
+                body.put_u16(65001);
+                body.put_u16(65000);
+            }
+            body.put_slice(&[0, 0, 0, 1, 192, 0, 2, 1, 192, 0, 2, 254]);
+            body.put_slice(message);
+
+            let mut record = vec![];
+            record.put_u32(1_577_836_800);
+            record.put_u16(MRT_BGP4MP);
+            record.put_u16(subtype);
+            record.put_u32(body.len() as u32);
+            record.put_slice(&body);
+            record
+        };
+        // An OPEN for AS65001 with IPv4 unicast, and optionally the 4-octet ASN capability
+        let open = |as4: bool| {
+            let mut capabilities = vec![1, 4, 0, 1, 0, 1];
+            if as4 {
+                capabilities.extend_from_slice(&[65, 4, 0, 0, 0xfd, 0xe9]);
+            }
+            let mut open = vec![0xff; 16];
+            open.put_u16((BGP_HEADER_LEN + 10 + 2 + capabilities.len()) as u16);
+            open.put_slice(&[1, 4, 0xfd, 0xe9, 0, 90, 192, 0, 2, 1]);
+            open.put_u8((2 + capabilities.len()) as u8);
+            open.put_slice(&[2, capabilities.len() as u8]);
+            open.put_slice(&capabilities);
+            open
+        };
+        // 198.51.100.0/24 with an AS_PATH of 65001, in 2 or 4 byte form
+        let update = |as4: bool| {
+            let as_path: &[u8] = if as4 { &[0x40, 2, 6, 2, 1, 0, 0, 0xfd, 0xe9] } else { &[0x40, 2, 4, 2, 1, 0xfd, 0xe9] };
+            let mut attributes = vec![0x40, 1, 1, 0];
+            attributes.extend_from_slice(as_path);
+            attributes.extend_from_slice(&[0x40, 3, 4, 192, 0, 2, 1]);
+
+            let mut update = vec![0xff; 16];
+            update.put_u16((BGP_HEADER_LEN + 4 + attributes.len() + 4) as u16);
+            update.put_slice(&[2, 0, 0]);
+            update.put_u16(attributes.len() as u16);
+            update.put_slice(&attributes);
+            update.put_slice(&[24, 198, 51, 100]);
+            update
+        };
+        let import = |as4: bool, subtypes: (u16, u16, u16)| {
+            let records = [
+                bgp4mp(subtypes.0, &open(as4)),
+                bgp4mp(subtypes.1, &open(as4)),
+                bgp4mp(subtypes.2, &update(as4)),
+            ].concat();
+            let messages = MrtReader::new(&records[..]).collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(messages.len(), 2);
+            match &messages[1].message {
+                MessageData::RouteMonitoring((header, update)) => {
+                    assert_eq!(header.peer_flags.A, !as4);
+                    assert!(update.attributes.iter().any(|attribute| match attribute {
+                        PathAttribute::AS_PATH(path) => matches!(&path.segments[..], [Segment::AS_SEQUENCE(asns)] if asns == &[65001]),
+                        _ => false,
+                    }));
+                },
+                _ => panic!("expected Route Monitoring, got {:?}", messages[1].kind),
+            };
+        };
+
+        // A session without 4-byte ASNs, recorded by a collector that always writes the AS4 subtypes
+        import(false, (BGP4MP_MESSAGE_AS4_LOCAL, BGP4MP_MESSAGE_AS4, BGP4MP_MESSAGE_AS4));
+        // And the other way around, with a mix of subtypes
+        import(true, (BGP4MP_MESSAGE_LOCAL, BGP4MP_MESSAGE_AS4, BGP4MP_MESSAGE));
+        import(true, (BGP4MP_MESSAGE_AS4_LOCAL, BGP4MP_MESSAGE_AS4, BGP4MP_MESSAGE_AS4));
+    }
+}
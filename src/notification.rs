@@ -0,0 +1,545 @@
+//! BGP NOTIFICATION error codes
+//!
+//! `bgp_rs::Notification` only carries the raw error code and subcode. `NotificationError` turns
+//! them into names (RFC4271 Section 4.5 and the IANA BGP Error Subcodes registry), and
+//! `shutdown_communication` pulls out the message an operator can attach to an Administrative
+//! Shutdown or Reset (RFC8203, RFC9003).
+//!
+//! ```
+//! # use bmp_protocol::notification::{CeaseReason, NotificationError};
+//! let notification = bgp_rs::Notification { major_err_code: 6, minor_err_code: 2, data: vec![] };
+//!
+//! let error = NotificationError::from(&notification);
+//! assert_eq!(error, NotificationError::Cease(CeaseReason::AdministrativeShutdown));
+//! assert_eq!(error.to_string(), "cease/administrative_shutdown");
+//! ```
+
+use bgp_rs::Notification;
+
+use std::fmt;
+
+// Cease subcodes which may carry a Shutdown Communication
+const ADMINISTRATIVE_SHUTDOWN: u8 = 2;
+const ADMINISTRATIVE_RESET: u8 = 4;
+const CEASE: u8 = 6;
+
+/// Error code and subcode of a BGP NOTIFICATION
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum NotificationError {
+    /// Message Header Error (1)
+    MessageHeader(MessageHeaderError),
+    /// OPEN Message Error (2)
+    OpenMessage(OpenMessageError),
+    /// UPDATE Message Error (3)
+    UpdateMessage(UpdateMessageError),
+    /// Hold Timer Expired (4), which has no subcodes
+    HoldTimerExpired,
+    /// Finite State Machine Error (5)
+    FiniteStateMachine(FsmError),
+    /// Cease (6)
+    Cease(CeaseReason),
+    /// ROUTE-REFRESH Message Error (7)
+    RouteRefresh(RouteRefreshError),
+    /// Any other error code, with its subcode
+    Unknown(u8, u8),
+}
+
+impl NotificationError {
+    /// Decode an error code and subcode
+    pub fn new(code: u8, subcode: u8) -> Self {
+        match code {
+            1 => NotificationError::MessageHeader(subcode.into()),
+            2 => NotificationError::OpenMessage(subcode.into()),
+            3 => NotificationError::UpdateMessage(subcode.into()),
+            4 => NotificationError::HoldTimerExpired,
+            5 => NotificationError::FiniteStateMachine(subcode.into()),
+            6 => NotificationError::Cease(subcode.into()),
+            7 => NotificationError::RouteRefresh(subcode.into()),
+
+            v => NotificationError::Unknown(v, subcode),
+        }
+    }
+
+    /// The error code
+    pub fn code(&self) -> u8 {
+        match self {
+            NotificationError::MessageHeader(_) => 1,
+            NotificationError::OpenMessage(_) => 2,
+            NotificationError::UpdateMessage(_) => 3,
+            NotificationError::HoldTimerExpired => 4,
+            NotificationError::FiniteStateMachine(_) => 5,
+            NotificationError::Cease(_) => 6,
+            NotificationError::RouteRefresh(_) => 7,
+            NotificationError::Unknown(code, _) => *code,
+        }
+    }
+}
+
+impl From<&Notification> for NotificationError {
+    fn from(notification: &Notification) -> Self {
+        Self::new(notification.major_err_code, notification.minor_err_code)
+    }
+}
+
+impl fmt::Display for NotificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NotificationError::MessageHeader(subcode) => write!(f, "message_header_error/{}", subcode),
+            NotificationError::OpenMessage(subcode) => write!(f, "open_message_error/{}", subcode),
+            NotificationError::UpdateMessage(subcode) => write!(f, "update_message_error/{}", subcode),
+            NotificationError::HoldTimerExpired => write!(f, "hold_timer_expired"),
+            NotificationError::FiniteStateMachine(subcode) => write!(f, "fsm_error/{}", subcode),
+            NotificationError::Cease(subcode) => write!(f, "cease/{}", subcode),
+            NotificationError::RouteRefresh(subcode) => write!(f, "route_refresh_error/{}", subcode),
+            NotificationError::Unknown(code, subcode) => write!(f, "error_{}/subcode_{}", code, subcode),
+        }
+    }
+}
+
+/// The Shutdown Communication in an Administrative Shutdown or Reset, if there is one
+///
+/// This is a length byte followed by that many bytes of UTF-8 (up to 255 since RFC9003). Invalid
+/// UTF-8 is replaced rather than rejected, and a length running past the end of the data means
+/// there's no (usable) message.
+pub fn shutdown_communication(notification: &Notification) -> Option<String> {
+    if notification.major_err_code != CEASE
+        || (notification.minor_err_code != ADMINISTRATIVE_SHUTDOWN && notification.minor_err_code != ADMINISTRATIVE_RESET)
+    {
+        return None;
+    }
+
+    let (length, data) = notification.data.split_first()?;
+    let message = data.get(..usize::from(*length))?;
+    if message.is_empty() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(message).into_owned())
+}
+
+/// Message Header Error subcodes (RFC4271)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum MessageHeaderError {
+    /// Connection Not Synchronized
+    ConnectionNotSynchronized,
+    /// Bad Message Length
+    BadMessageLength,
+    /// Bad Message Type
+    BadMessageType,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for MessageHeaderError {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => MessageHeaderError::ConnectionNotSynchronized,
+            2 => MessageHeaderError::BadMessageLength,
+            3 => MessageHeaderError::BadMessageType,
+
+            v => MessageHeaderError::Unknown(v),
+        }
+    }
+}
+
+impl MessageHeaderError {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            MessageHeaderError::ConnectionNotSynchronized => 1,
+            MessageHeaderError::BadMessageLength => 2,
+            MessageHeaderError::BadMessageType => 3,
+            MessageHeaderError::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for MessageHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageHeaderError::ConnectionNotSynchronized => write!(f, "connection_not_synchronized"),
+            MessageHeaderError::BadMessageLength => write!(f, "bad_message_length"),
+            MessageHeaderError::BadMessageType => write!(f, "bad_message_type"),
+            MessageHeaderError::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+/// OPEN Message Error subcodes (RFC4271, RFC5492, RFC9234)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum OpenMessageError {
+    /// Unsupported Version Number
+    UnsupportedVersionNumber,
+    /// Bad Peer AS
+    BadPeerAs,
+    /// Bad BGP Identifier
+    BadBgpIdentifier,
+    /// Unsupported Optional Parameter
+    UnsupportedOptionalParameter,
+    /// Unacceptable Hold Time
+    UnacceptableHoldTime,
+    /// Unsupported Capability
+    UnsupportedCapability,
+    /// Role Mismatch
+    RoleMismatch,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for OpenMessageError {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => OpenMessageError::UnsupportedVersionNumber,
+            2 => OpenMessageError::BadPeerAs,
+            3 => OpenMessageError::BadBgpIdentifier,
+            4 => OpenMessageError::UnsupportedOptionalParameter,
+            6 => OpenMessageError::UnacceptableHoldTime,
+            7 => OpenMessageError::UnsupportedCapability,
+            11 => OpenMessageError::RoleMismatch,
+
+            v => OpenMessageError::Unknown(v),
+        }
+    }
+}
+
+impl OpenMessageError {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            OpenMessageError::UnsupportedVersionNumber => 1,
+            OpenMessageError::BadPeerAs => 2,
+            OpenMessageError::BadBgpIdentifier => 3,
+            OpenMessageError::UnsupportedOptionalParameter => 4,
+            OpenMessageError::UnacceptableHoldTime => 6,
+            OpenMessageError::UnsupportedCapability => 7,
+            OpenMessageError::RoleMismatch => 11,
+            OpenMessageError::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for OpenMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenMessageError::UnsupportedVersionNumber => write!(f, "unsupported_version_number"),
+            OpenMessageError::BadPeerAs => write!(f, "bad_peer_as"),
+            OpenMessageError::BadBgpIdentifier => write!(f, "bad_bgp_identifier"),
+            OpenMessageError::UnsupportedOptionalParameter => write!(f, "unsupported_optional_parameter"),
+            OpenMessageError::UnacceptableHoldTime => write!(f, "unacceptable_hold_time"),
+            OpenMessageError::UnsupportedCapability => write!(f, "unsupported_capability"),
+            OpenMessageError::RoleMismatch => write!(f, "role_mismatch"),
+            OpenMessageError::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+/// UPDATE Message Error subcodes (RFC4271)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum UpdateMessageError {
+    /// Malformed Attribute List
+    MalformedAttributeList,
+    /// Unrecognized Well-known Attribute
+    UnrecognizedWellKnownAttribute,
+    /// Missing Well-known Attribute
+    MissingWellKnownAttribute,
+    /// Attribute Flags Error
+    AttributeFlagsError,
+    /// Attribute Length Error
+    AttributeLengthError,
+    /// Invalid ORIGIN Attribute
+    InvalidOriginAttribute,
+    /// Invalid NEXT_HOP Attribute
+    InvalidNextHopAttribute,
+    /// Optional Attribute Error
+    OptionalAttributeError,
+    /// Invalid Network Field
+    InvalidNetworkField,
+    /// Malformed AS_PATH
+    MalformedAsPath,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for UpdateMessageError {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => UpdateMessageError::MalformedAttributeList,
+            2 => UpdateMessageError::UnrecognizedWellKnownAttribute,
+            3 => UpdateMessageError::MissingWellKnownAttribute,
+            4 => UpdateMessageError::AttributeFlagsError,
+            5 => UpdateMessageError::AttributeLengthError,
+            6 => UpdateMessageError::InvalidOriginAttribute,
+            8 => UpdateMessageError::InvalidNextHopAttribute,
+            9 => UpdateMessageError::OptionalAttributeError,
+            10 => UpdateMessageError::InvalidNetworkField,
+            11 => UpdateMessageError::MalformedAsPath,
+
+            v => UpdateMessageError::Unknown(v),
+        }
+    }
+}
+
+impl UpdateMessageError {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            UpdateMessageError::MalformedAttributeList => 1,
+            UpdateMessageError::UnrecognizedWellKnownAttribute => 2,
+            UpdateMessageError::MissingWellKnownAttribute => 3,
+            UpdateMessageError::AttributeFlagsError => 4,
+            UpdateMessageError::AttributeLengthError => 5,
+            UpdateMessageError::InvalidOriginAttribute => 6,
+            UpdateMessageError::InvalidNextHopAttribute => 8,
+            UpdateMessageError::OptionalAttributeError => 9,
+            UpdateMessageError::InvalidNetworkField => 10,
+            UpdateMessageError::MalformedAsPath => 11,
+            UpdateMessageError::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for UpdateMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateMessageError::MalformedAttributeList => write!(f, "malformed_attribute_list"),
+            UpdateMessageError::UnrecognizedWellKnownAttribute => write!(f, "unrecognized_well_known_attribute"),
+            UpdateMessageError::MissingWellKnownAttribute => write!(f, "missing_well_known_attribute"),
+            UpdateMessageError::AttributeFlagsError => write!(f, "attribute_flags_error"),
+            UpdateMessageError::AttributeLengthError => write!(f, "attribute_length_error"),
+            UpdateMessageError::InvalidOriginAttribute => write!(f, "invalid_origin_attribute"),
+            UpdateMessageError::InvalidNextHopAttribute => write!(f, "invalid_next_hop_attribute"),
+            UpdateMessageError::OptionalAttributeError => write!(f, "optional_attribute_error"),
+            UpdateMessageError::InvalidNetworkField => write!(f, "invalid_network_field"),
+            UpdateMessageError::MalformedAsPath => write!(f, "malformed_as_path"),
+            UpdateMessageError::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+/// Finite State Machine Error subcodes (RFC6608)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum FsmError {
+    /// Unspecified Error
+    Unspecified,
+    /// Receive Unexpected Message in OpenSent State
+    UnexpectedMessageInOpenSent,
+    /// Receive Unexpected Message in OpenConfirm State
+    UnexpectedMessageInOpenConfirm,
+    /// Receive Unexpected Message in Established State
+    UnexpectedMessageInEstablished,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for FsmError {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => FsmError::Unspecified,
+            1 => FsmError::UnexpectedMessageInOpenSent,
+            2 => FsmError::UnexpectedMessageInOpenConfirm,
+            3 => FsmError::UnexpectedMessageInEstablished,
+
+            v => FsmError::Unknown(v),
+        }
+    }
+}
+
+impl FsmError {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            FsmError::Unspecified => 0,
+            FsmError::UnexpectedMessageInOpenSent => 1,
+            FsmError::UnexpectedMessageInOpenConfirm => 2,
+            FsmError::UnexpectedMessageInEstablished => 3,
+            FsmError::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for FsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsmError::Unspecified => write!(f, "unspecified"),
+            FsmError::UnexpectedMessageInOpenSent => write!(f, "unexpected_message_in_open_sent"),
+            FsmError::UnexpectedMessageInOpenConfirm => write!(f, "unexpected_message_in_open_confirm"),
+            FsmError::UnexpectedMessageInEstablished => write!(f, "unexpected_message_in_established"),
+            FsmError::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+/// Cease subcodes (RFC4486, RFC8538, RFC9384)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CeaseReason {
+    /// Maximum Number of Prefixes Reached
+    MaximumPrefixesReached,
+    /// Administrative Shutdown
+    AdministrativeShutdown,
+    /// Peer De-configured
+    PeerDeconfigured,
+    /// Administrative Reset
+    AdministrativeReset,
+    /// Connection Rejected
+    ConnectionRejected,
+    /// Other Configuration Change
+    OtherConfigurationChange,
+    /// Connection Collision Resolution
+    ConnectionCollisionResolution,
+    /// Out of Resources
+    OutOfResources,
+    /// Hard Reset
+    HardReset,
+    /// BFD Down
+    BfdDown,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for CeaseReason {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => CeaseReason::MaximumPrefixesReached,
+            2 => CeaseReason::AdministrativeShutdown,
+            3 => CeaseReason::PeerDeconfigured,
+            4 => CeaseReason::AdministrativeReset,
+            5 => CeaseReason::ConnectionRejected,
+            6 => CeaseReason::OtherConfigurationChange,
+            7 => CeaseReason::ConnectionCollisionResolution,
+            8 => CeaseReason::OutOfResources,
+            9 => CeaseReason::HardReset,
+            10 => CeaseReason::BfdDown,
+
+            v => CeaseReason::Unknown(v),
+        }
+    }
+}
+
+impl CeaseReason {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            CeaseReason::MaximumPrefixesReached => 1,
+            CeaseReason::AdministrativeShutdown => 2,
+            CeaseReason::PeerDeconfigured => 3,
+            CeaseReason::AdministrativeReset => 4,
+            CeaseReason::ConnectionRejected => 5,
+            CeaseReason::OtherConfigurationChange => 6,
+            CeaseReason::ConnectionCollisionResolution => 7,
+            CeaseReason::OutOfResources => 8,
+            CeaseReason::HardReset => 9,
+            CeaseReason::BfdDown => 10,
+            CeaseReason::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for CeaseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CeaseReason::MaximumPrefixesReached => write!(f, "maximum_prefixes_reached"),
+            CeaseReason::AdministrativeShutdown => write!(f, "administrative_shutdown"),
+            CeaseReason::PeerDeconfigured => write!(f, "peer_deconfigured"),
+            CeaseReason::AdministrativeReset => write!(f, "administrative_reset"),
+            CeaseReason::ConnectionRejected => write!(f, "connection_rejected"),
+            CeaseReason::OtherConfigurationChange => write!(f, "other_configuration_change"),
+            CeaseReason::ConnectionCollisionResolution => write!(f, "connection_collision_resolution"),
+            CeaseReason::OutOfResources => write!(f, "out_of_resources"),
+            CeaseReason::HardReset => write!(f, "hard_reset"),
+            CeaseReason::BfdDown => write!(f, "bfd_down"),
+            CeaseReason::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+/// ROUTE-REFRESH Message Error subcodes (RFC7313)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum RouteRefreshError {
+    /// Invalid Message Length
+    InvalidMessageLength,
+    /// Any other subcode
+    Unknown(u8),
+}
+
+impl From<u8> for RouteRefreshError {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => RouteRefreshError::InvalidMessageLength,
+
+            v => RouteRefreshError::Unknown(v),
+        }
+    }
+}
+
+impl RouteRefreshError {
+    /// The subcode
+    pub fn code(&self) -> u8 {
+        match self {
+            RouteRefreshError::InvalidMessageLength => 1,
+            RouteRefreshError::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for RouteRefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteRefreshError::InvalidMessageLength => write!(f, "invalid_message_length"),
+            RouteRefreshError::Unknown(v) => write!(f, "subcode_{}", v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(code: u8, subcode: u8, data: &[u8]) -> Notification {
+        Notification { major_err_code: code, minor_err_code: subcode, data: data.to_vec() }
+    }
+
+    #[test]
+    fn test_notification_error() {
+        for code in 0..=8 {
+            for subcode in 0..=12 {
+                let error = NotificationError::new(code, subcode);
+                assert_eq!(error.code(), code);
+                let subcode_back = match error {
+                    NotificationError::MessageHeader(s) => s.code(),
+                    NotificationError::OpenMessage(s) => s.code(),
+                    NotificationError::UpdateMessage(s) => s.code(),
+                    NotificationError::HoldTimerExpired => subcode,
+                    NotificationError::FiniteStateMachine(s) => s.code(),
+                    NotificationError::Cease(s) => s.code(),
+                    NotificationError::RouteRefresh(s) => s.code(),
+                    NotificationError::Unknown(_, s) => s,
+                };
+                assert_eq!(subcode_back, subcode);
+            }
+        }
+
+        assert_eq!(NotificationError::new(2, 2).to_string(), "open_message_error/bad_peer_as");
+        assert_eq!(NotificationError::new(4, 0).to_string(), "hold_timer_expired");
+        assert_eq!(NotificationError::new(6, 42).to_string(), "cease/subcode_42");
+        assert_eq!(NotificationError::new(9, 1).to_string(), "error_9/subcode_1");
+    }
+
+    #[test]
+    fn test_shutdown_communication() {
+        let message = "maintenance, back at 10:00 \u{1f6a7}";
+        let mut data = vec![message.len() as u8];
+        data.extend_from_slice(message.as_bytes());
+
+        assert_eq!(shutdown_communication(&notification(6, 2, &data)).as_deref(), Some(message));
+        assert_eq!(shutdown_communication(&notification(6, 4, &data)).as_deref(), Some(message));
+        // Only for shutdowns and resets
+        assert_eq!(shutdown_communication(&notification(6, 3, &data)), None);
+        assert_eq!(shutdown_communication(&notification(3, 2, &data)), None);
+
+        // Empty, truncated or not UTF-8
+        assert_eq!(shutdown_communication(&notification(6, 2, &[])), None);
+        assert_eq!(shutdown_communication(&notification(6, 2, &[0])), None);
+        assert_eq!(shutdown_communication(&notification(6, 2, &data[..10])), None);
+        assert_eq!(shutdown_communication(&notification(6, 2, &[2, b'o', 0xff])).as_deref(), Some("o\u{fffd}"));
+    }
+}
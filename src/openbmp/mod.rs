@@ -0,0 +1,444 @@
+//! OpenBMP binary message format (v1.7)
+//!
+//! OpenBMP collectors publish every BMP frame they receive to the `openbmp.bmp_raw` Kafka topic
+//! wrapped in a binary header identifying the collector and the router it came from. This module
+//! can produce those messages (`OpenBmpEncoder`) and consume them (`OpenBmpDecoder`), giving back
+//! the usual `BmpMessage` along with the OpenBMP header. The codecs need the `tokio-codec`
+//! feature, `OpenBmpHeader` can be used to build and take apart messages without it. The parsed
+//! message types (`peer`, `unicast_prefix` etc.) are in the `parsed` module.
+//!
+//! See <https://github.com/OpenBMP/openbmp/blob/master/docs/MESSAGE_BUS_API.md> for the format.
+
+pub mod parsed;
+
+use crate::{Error, Result};
+
+use bytes::{Buf, BufMut, Bytes};
+
+#[cfg(feature = "tokio-codec")]
+use crate::{BmpDecoder, types::BmpMessage};
+#[cfg(feature = "tokio-codec")]
+use bytes::BytesMut;
+#[cfg(feature = "tokio-codec")]
+use hashbrown::HashMap;
+#[cfg(feature = "tokio-codec")]
+use tokio_util::codec::{Decoder, Encoder};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// "OBMP"
+const MAGIC: u32 = 0x4F42_4D50;
+const VERSION_MAJOR: u8 = 1;
+const VERSION_MINOR: u8 = 7;
+
+/// Object type for raw BMP messages
+const OBJECT_BMP_RAW: u8 = 12;
+
+const FLAG_ROUTER_MESSAGE: u8 = 0x80;
+const FLAG_ROUTER_IPV6: u8 = 0x40;
+
+// Everything up to and including the header length field
+const PREAMBLE_LEN: usize = 12;
+// Length of the header without the admin ID & router group strings
+const FIXED_HEADER_LEN: usize = 78;
+// Anything bigger than this is not a real BMP message
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+
+/// Hash identifying a collector, the MD5 of the collector admin ID
+pub fn collector_hash(admin_id: &str) -> [u8; 16] {
+    md5::compute(admin_id).0
+}
+
+/// Hash identifying a router, the MD5 of the router IP (as a string) followed by the collector hash
+pub fn router_hash(router_ip: &IpAddr, collector_hash: &[u8; 16]) -> [u8; 16] {
+    let mut ctx = md5::Context::new();
+    ctx.consume(router_ip.to_string());
+    ctx.consume(collector_hash);
+
+    ctx.compute().0
+}
+
+/// OpenBMP binary header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenBmpHeader {
+    /// Whether the message came from a router (as opposed to being generated by the collector)
+    pub router_message: bool,
+    /// When the collector received the message
+    pub collection_time: SystemTime,
+    /// Hash of the collector admin ID
+    pub collector_hash: [u8; 16],
+    /// Collector admin ID
+    pub collector_admin_id: String,
+    /// Hash of the router IP and collector hash
+    pub router_hash: [u8; 16],
+    /// Router IP address
+    pub router_ip: IpAddr,
+    /// Router group, may be empty
+    pub router_group: String,
+    /// Number of rows in the message, always 1 for raw BMP
+    pub row_count: u32,
+}
+
+impl OpenBmpHeader {
+    /// Create a header for a message from a router, the hashes are derived from the admin ID
+    /// and router IP
+    pub fn new(collector_admin_id: &str, router_ip: IpAddr) -> Self {
+        let collector_hash = collector_hash(collector_admin_id);
+
+        Self {
+            router_message: true,
+            collection_time: SystemTime::now(),
+            router_hash: router_hash(&router_ip, &collector_hash),
+            collector_hash,
+            collector_admin_id: collector_admin_id.into(),
+            router_ip,
+            router_group: String::new(),
+            row_count: 1,
+        }
+    }
+
+    /// Length of the encoded header
+    pub fn encoded_len(&self) -> usize {
+        FIXED_HEADER_LEN + self.collector_admin_id.len() + self.router_group.len()
+    }
+
+    /// Decode a header, returning it along with the BMP frame which follows
+    pub fn decode(buf: &mut Bytes) -> Result<(Self, Bytes)> {
+        if buf.remaining() < PREAMBLE_LEN {
+            return Err(Error::decode("OpenBMP header too short"));
+        }
+
+        let (header_len, message_len) = preamble(buf)?;
+        buf.advance(PREAMBLE_LEN);
+        if buf.remaining() < header_len - PREAMBLE_LEN + message_len {
+            return Err(Error::decode("OpenBMP message too short"));
+        }
+
+        // Anything added by a later minor version is skipped over using the header length
+        let mut header = buf.split_to(header_len - PREAMBLE_LEN);
+        let frame = buf.split_to(message_len);
+
+        let flags = header.get_u8();
+        let object_type = header.get_u8();
+        if object_type != OBJECT_BMP_RAW {
+            return Err(Error::decode(&format!("unsupported OpenBMP object type: {}", object_type)));
+        }
+
+        let secs = header.get_u32();
+        let usecs = header.get_u32();
+        let collection_time = UNIX_EPOCH + Duration::from_secs(u64::from(secs)) + Duration::from_micros(u64::from(usecs));
+
+        let collector_hash = get_hash(&mut header)?;
+        let collector_admin_id = get_string(&mut header)?;
+        let router_hash = get_hash(&mut header)?;
+
+        // IPv4 addresses are in the first 4 bytes
+        let addr = get_hash(&mut header)?;
+        let router_ip = if flags & FLAG_ROUTER_IPV6 == FLAG_ROUTER_IPV6 {
+            IpAddr::V6(Ipv6Addr::from(addr))
+        } else {
+            IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+        };
+
+        let router_group = get_string(&mut header)?;
+        if header.remaining() < 4 {
+            return Err(Error::decode("OpenBMP header too short"));
+        }
+        let row_count = header.get_u32();
+
+        Ok((
+            Self {
+                router_message: flags & FLAG_ROUTER_MESSAGE == FLAG_ROUTER_MESSAGE,
+                collection_time,
+                collector_hash,
+                collector_admin_id,
+                router_hash,
+                router_ip,
+                router_group,
+                row_count,
+            },
+            frame
+        ))
+    }
+
+    /// Write the header followed by a raw BMP frame
+    pub fn encode(&self, frame: &[u8], buf: &mut impl BufMut) {
+        let since_epoch = self.collection_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut flags = 0;
+        if self.router_message { flags |= FLAG_ROUTER_MESSAGE; }
+        if self.router_ip.is_ipv6() { flags |= FLAG_ROUTER_IPV6; }
+
+        buf.put_u32(MAGIC);
+        buf.put_u8(VERSION_MAJOR);
+        buf.put_u8(VERSION_MINOR);
+        buf.put_u16(self.encoded_len() as u16);
+        buf.put_u32(frame.len() as u32);
+        buf.put_u8(flags);
+        buf.put_u8(OBJECT_BMP_RAW);
+        buf.put_u32(since_epoch.as_secs() as u32);
+        buf.put_u32(since_epoch.subsec_micros());
+        buf.put_slice(&self.collector_hash);
+        buf.put_u16(self.collector_admin_id.len() as u16);
+        buf.put_slice(self.collector_admin_id.as_bytes());
+        buf.put_slice(&self.router_hash);
+        match self.router_ip {
+            IpAddr::V4(addr) => {
+                buf.put_slice(&addr.octets());
+                buf.put_slice(&[0; 12]);
+            },
+            IpAddr::V6(addr) => buf.put_slice(&addr.octets()),
+        };
+        buf.put_u16(self.router_group.len() as u16);
+        buf.put_slice(self.router_group.as_bytes());
+        buf.put_u32(self.row_count);
+
+        buf.put_slice(frame);
+    }
+}
+
+fn get_hash(buf: &mut Bytes) -> Result<[u8; 16]> {
+    if buf.remaining() < 16 {
+        return Err(Error::decode("OpenBMP header too short"));
+    }
+
+    let mut hash = [0u8; 16];
+    buf.copy_to_slice(&mut hash);
+    Ok(hash)
+}
+
+fn get_string(buf: &mut Bytes) -> Result<String> {
+    if buf.remaining() < 2 {
+        return Err(Error::decode("OpenBMP header too short"));
+    }
+
+    let len = buf.get_u16() as usize;
+    if buf.remaining() < len {
+        return Err(Error::decode("OpenBMP header too short"));
+    }
+
+    Ok(String::from_utf8_lossy(&buf.split_to(len)).into_owned())
+}
+
+// Check the magic and version, returning the header and message lengths
+fn preamble(buf: &[u8]) -> Result<(usize, usize)> {
+    let magic = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    if magic != MAGIC {
+        return Err(Error::decode(&format!("invalid OpenBMP magic: {:#x}", magic)));
+    }
+
+    let (major, minor) = (buf[4], buf[5]);
+    if major != VERSION_MAJOR || minor < VERSION_MINOR {
+        return Err(Error::decode(&format!("unsupported OpenBMP version: {}.{}", major, minor)));
+    }
+
+    let header_len = usize::from(u16::from_be_bytes([buf[6], buf[7]]));
+    let message_len = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+    if header_len < FIXED_HEADER_LEN {
+        return Err(Error::decode("OpenBMP header too short"));
+    }
+    if message_len > MAX_MESSAGE_LEN {
+        return Err(Error::decode(&format!("OpenBMP message length {} is too long", message_len)));
+    }
+
+    Ok((header_len, message_len))
+}
+
+/// Decoder for OpenBMP raw messages, for use with a FramedRead or on Kafka message payloads
+///
+/// Messages from different routers may be interleaved so a `BmpDecoder` is kept for each
+/// router hash.
+#[cfg(feature = "tokio-codec")]
+#[derive(Clone, Debug, Default)]
+pub struct OpenBmpDecoder {
+    routers: HashMap<[u8; 16], BmpDecoder>,
+}
+
+#[cfg(feature = "tokio-codec")]
+impl OpenBmpDecoder {
+    /// Create a new instance of the Decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl Decoder for OpenBmpDecoder {
+    type Item = (OpenBmpHeader, BmpMessage);
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        if src.len() < PREAMBLE_LEN {
+            return Ok(None);
+        }
+
+        // Nothing after a bad preamble can be trusted, so drop what we have rather than
+        // returning the same error again
+        let (header_len, message_len) = preamble(src).inspect_err(|_| src.clear())?;
+        if src.len() < header_len + message_len {
+            src.reserve(header_len + message_len - src.len());
+            return Ok(None);
+        }
+
+        let mut buf = src.split_to(header_len + message_len).freeze();
+        let (header, frame) = OpenBmpHeader::decode(&mut buf)?;
+
+        let decoder = self.routers.entry(header.router_hash).or_default();
+        match decoder.decode_slice(&frame).1? {
+            Some(message) => Ok(Some((header, message))),
+            None => Err(Error::decode("truncated BMP message in OpenBMP message").into()),
+        }
+    }
+}
+
+/// Encoder producing OpenBMP raw messages for a single router
+///
+/// The collection time is taken from the system clock when each message is encoded.
+#[cfg(feature = "tokio-codec")]
+#[derive(Clone, Debug)]
+pub struct OpenBmpEncoder {
+    header: OpenBmpHeader,
+}
+
+#[cfg(feature = "tokio-codec")]
+impl OpenBmpEncoder {
+    /// Create a new encoder for messages from `router_ip`
+    pub fn new(collector_admin_id: &str, router_ip: IpAddr) -> Self {
+        Self { header: OpenBmpHeader::new(collector_admin_id, router_ip) }
+    }
+
+    /// Set the router group
+    pub fn with_router_group(mut self, router_group: &str) -> Self {
+        self.header.router_group = router_group.into();
+        self
+    }
+
+    /// Header which will be written with the next message
+    pub fn header(&self) -> &OpenBmpHeader {
+        &self.header
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl Encoder<BmpMessage> for OpenBmpEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: BmpMessage, dst: &mut BytesMut) -> std::io::Result<()> {
+        Encoder::<&BmpMessage>::encode(self, &item, dst)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl Encoder<&BmpMessage> for OpenBmpEncoder {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: &BmpMessage, dst: &mut BytesMut) -> std::io::Result<()> {
+        if item.raw.is_empty() {
+            return Err(Error::decode(&format!("no raw bytes to encode for {} message", item.kind)).into());
+        }
+
+        self.header.collection_time = SystemTime::now();
+
+        dst.reserve(self.header.encoded_len() + item.raw.len());
+        self.header.encode(&item.raw, dst);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tokio-codec"))]
+mod tests {
+    use super::*;
+    use crate::types::MessageKind;
+
+    use std::fs;
+
+    const COLLECTOR_HASH: [u8; 16] = [
+        0x9a, 0xe8, 0x14, 0x89, 0x74, 0xc9, 0xca, 0x01, 0xec, 0x92, 0x71, 0x75, 0x34, 0x26, 0xd2, 0x14,
+    ];
+    const ROUTER_HASH: [u8; 16] = [
+        0x7d, 0x37, 0xc0, 0x17, 0xec, 0x02, 0xaf, 0xeb, 0x1c, 0x45, 0x88, 0xb2, 0x7c, 0xa2, 0x07, 0x2f,
+    ];
+
+    #[test]
+    fn test_openbmp_decode() {
+        let data = fs::read("test_data/openbmp_raw.bin").unwrap();
+        let mut buf = BytesMut::from(&data[..]);
+        let mut decoder = OpenBmpDecoder::new();
+
+        let mut messages = vec![];
+        while let Some(item) = decoder.decode(&mut buf).unwrap() {
+            messages.push(item);
+        }
+        assert_eq!(messages.len(), 10);
+
+        let (header, message) = &messages[3];
+        assert_eq!(message.kind, MessageKind::RouteMonitoring);
+        assert_eq!(header, &OpenBmpHeader {
+            router_message: true,
+            collection_time: UNIX_EPOCH + Duration::from_secs(1_577_836_803) + Duration::from_millis(500),
+            collector_hash: COLLECTOR_HASH,
+            collector_admin_id: "collector1".into(),
+            router_hash: ROUTER_HASH,
+            router_ip: "192.0.2.254".parse().unwrap(),
+            router_group: "lab".into(),
+            row_count: 1,
+        });
+
+        // Re-encoding gives back exactly the same bytes
+        let mut out = BytesMut::new();
+        for (header, message) in &messages {
+            header.encode(&message.raw, &mut out);
+        }
+        assert_eq!(&out[..], &data[..]);
+    }
+
+    #[test]
+    fn test_openbmp_bad_preamble() {
+        let data = fs::read("test_data/openbmp_raw.bin").unwrap();
+
+        // Bad magic or version is reported straight away, before waiting for (or reserving room
+        // for) a message of whatever length follows
+        for (offset, value) in &[(0, b'X'), (4, 2), (5, 6)] {
+            let mut buf = BytesMut::from(&data[..PREAMBLE_LEN]);
+            buf[*offset] = *value;
+            buf[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+
+            assert!(OpenBmpDecoder::new().decode(&mut buf).is_err());
+            assert!(buf.is_empty());
+            assert!(buf.capacity() < 1024);
+        }
+
+        let mut buf = BytesMut::from(&data[..PREAMBLE_LEN]);
+        buf[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(OpenBmpDecoder::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_openbmp_encode() {
+        let router_ip: IpAddr = "192.0.2.254".parse().unwrap();
+        assert_eq!(collector_hash("collector1"), COLLECTOR_HASH);
+        assert_eq!(router_hash(&router_ip, &COLLECTOR_HASH), ROUTER_HASH);
+
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut encoder = OpenBmpEncoder::new("collector1", router_ip).with_router_group("lab");
+
+        let mut out = BytesMut::new();
+        while let Some(message) = decoder.decode(&mut buf).unwrap() {
+            encoder.encode(&message, &mut out).unwrap();
+        }
+
+        let mut decoder = OpenBmpDecoder::new();
+        let (header, message) = decoder.decode(&mut out).unwrap().unwrap();
+        assert_eq!(message.kind, MessageKind::Initiation);
+        assert_eq!(header.router_hash, ROUTER_HASH);
+        assert_eq!(header.router_group, "lab");
+
+        let mut count = 1;
+        while decoder.decode(&mut out).unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 10);
+    }
+}
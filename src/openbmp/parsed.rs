@@ -0,0 +1,911 @@
+//! OpenBMP parsed message types
+//!
+//! Besides the raw BMP frames, OpenBMP publishes parsed `collector`, `router`, `peer`,
+//! `base_attribute` and `unicast_prefix` messages. `ParsedFormatter` turns decoded messages into
+//! those records and renders them in either the legacy tab-separated format or JSON.
+//!
+//! ```
+//! # use bmp_protocol::{BmpReader, RouterSession, openbmp::parsed::ParsedFormatter};
+//! # let input = std::io::empty();
+//! let mut session = RouterSession::new();
+//! let mut formatter = ParsedFormatter::new("collector1", "192.0.2.254".parse().unwrap());
+//!
+//! for message in BmpReader::new(input) {
+//!     let message = message.unwrap();
+//!     // Format the message before updating the session, Peer Down records use the state of the
+//!     // peer from before it went down
+//!     let records = formatter.records(&message, &session);
+//!     session.process(&message);
+//!
+//!     for message in formatter.tsv(&records) {
+//!         println!("{}", message);
+//!     }
+//! }
+//! ```
+
+use crate::{
+    route::{routes, RouteAttributes},
+    session::RouterSession,
+    types::*,
+    util::{format_rd, format_time},
+};
+use super::{collector_hash, router_hash};
+
+use bgp_rs::{Open, OpenCapability, OpenParameter};
+use hashbrown::HashMap;
+use serde_derive::Serialize;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::SystemTime;
+
+/// Version of the message bus API the records follow
+const VERSION: &str = "1.7";
+
+/// OpenBMP parsed message types
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum RecordKind {
+    /// Collector status
+    Collector,
+    /// Router connected or disconnected
+    Router,
+    /// Peer up or down
+    Peer,
+    /// Path attributes shared by a set of prefixes
+    BaseAttribute,
+    /// IPv4/IPv6 unicast prefix
+    UnicastPrefix,
+}
+
+impl fmt::Display for RecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordKind::Collector => write!(f, "collector"),
+            RecordKind::Router => write!(f, "router"),
+            RecordKind::Peer => write!(f, "peer"),
+            RecordKind::BaseAttribute => write!(f, "base_attribute"),
+            RecordKind::UnicastPrefix => write!(f, "unicast_prefix"),
+        }
+    }
+}
+
+/// Collector record actions
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum CollectorAction {
+    /// Collector has started
+    Started,
+    /// A router connected or disconnected
+    Change,
+    /// Periodic heartbeat
+    Heartbeat,
+    /// Collector is stopping
+    Stopped,
+}
+
+impl fmt::Display for CollectorAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CollectorAction::Started => write!(f, "started"),
+            CollectorAction::Change => write!(f, "change"),
+            CollectorAction::Heartbeat => write!(f, "heartbeat"),
+            CollectorAction::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// `collector` record
+#[derive(Clone, Debug, Serialize)]
+pub struct CollectorRecord {
+    /// started, change, heartbeat or stopped
+    pub action: String,
+    /// Sequence number
+    pub seq: u64,
+    /// Collector admin ID
+    pub admin_id: String,
+    /// Collector hash
+    pub hash: String,
+    /// Connected routers, comma separated
+    pub routers: String,
+    /// Number of connected routers
+    pub router_count: usize,
+    /// Time the record was generated
+    pub timestamp: String,
+}
+
+/// `router` record
+#[derive(Clone, Debug, Serialize)]
+pub struct RouterRecord {
+    /// init or term
+    pub action: String,
+    /// Sequence number
+    pub seq: u64,
+    /// sysName
+    pub name: String,
+    /// Router hash
+    pub hash: String,
+    /// Router IP address
+    pub ip_address: IpAddr,
+    /// sysDescr
+    pub description: String,
+    /// Termination reason code
+    pub term_code: Option<u16>,
+    /// Termination reason
+    pub term_reason: String,
+    /// String TLVs from the Initiation message
+    pub init_data: String,
+    /// String TLVs from the Termination message
+    pub term_data: String,
+    /// Time the record was generated
+    pub timestamp: String,
+    /// Router BGP ID, which BMP doesn't provide
+    pub bgp_id: String,
+}
+
+/// `peer` record
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerRecord {
+    /// up or down
+    pub action: String,
+    /// Sequence number
+    pub seq: u64,
+    /// Peer hash, MD5 of the peer address, RD and router hash
+    pub hash: String,
+    /// Router hash
+    pub router_hash: String,
+    /// Peer hostname, not resolved
+    pub name: String,
+    /// Peer BGP ID
+    pub remote_bgp_id: Ipv4Addr,
+    /// Router IP address
+    pub router_ip: IpAddr,
+    /// Per-Peer Header timestamp
+    pub timestamp: String,
+    /// Peer ASN
+    pub remote_asn: u32,
+    /// Peer IP address
+    pub remote_ip: IpAddr,
+    /// Peer Route Distinguisher
+    pub peer_rd: String,
+    /// Peer TCP port
+    pub remote_port: Option<u16>,
+    /// Router ASN
+    pub local_asn: Option<u32>,
+    /// Router IP address for the session
+    pub local_ip: Option<IpAddr>,
+    /// Router TCP port
+    pub local_port: Option<u16>,
+    /// Router BGP ID for the session
+    pub local_bgp_id: Option<Ipv4Addr>,
+    /// String TLVs from the Peer Up message
+    pub info_data: String,
+    /// Capabilities sent by the router
+    pub adv_cap: String,
+    /// Capabilities received from the peer
+    pub recv_cap: String,
+    /// Hold time sent by the peer
+    pub remote_holddown: Option<u16>,
+    /// Hold time sent by the router
+    pub adv_holddown: Option<u16>,
+    /// Peer Down reason code
+    pub bmp_reason: Option<u8>,
+    /// NOTIFICATION error code
+    pub bgp_error_code: Option<u8>,
+    /// NOTIFICATION error subcode
+    pub bgp_error_subcode: Option<u8>,
+    /// Peer Down reason
+    pub error_text: String,
+    /// Whether the peer is in a VRF
+    pub is_l3vpn: bool,
+    /// Whether the peer is reporting pre-policy
+    pub is_prepolicy: bool,
+    /// Whether the peer address is IPv4
+    pub is_ipv4: bool,
+    /// Whether the peer is the Loc-RIB, which we don't support yet
+    pub is_locrib: bool,
+    /// Whether the Loc-RIB is filtered
+    pub is_locrib_filtered: bool,
+    /// Loc-RIB table name
+    pub table_name: String,
+}
+
+/// `base_attribute` record
+#[derive(Clone, Debug, Serialize)]
+pub struct BaseAttributeRecord {
+    /// Always add
+    pub action: String,
+    /// Sequence number
+    pub seq: u64,
+    /// Attribute hash, MD5 of the formatted attributes and the peer hash
+    pub hash: String,
+    /// Router hash
+    pub router_hash: String,
+    /// Router IP address
+    pub router_ip: IpAddr,
+    /// Peer hash
+    pub peer_hash: String,
+    /// Peer IP address
+    pub peer_ip: IpAddr,
+    /// Peer ASN
+    pub peer_asn: u32,
+    /// Per-Peer Header timestamp
+    pub timestamp: String,
+    /// igp, egp or incomplete
+    pub origin: String,
+    /// AS_PATH, sets are wrapped in braces
+    pub as_path: String,
+    /// Number of ASNs in the AS_PATH
+    pub as_path_count: usize,
+    /// Originating ASN
+    pub origin_as: Option<u32>,
+    /// Next hop
+    pub next_hop: Option<IpAddr>,
+    /// MULTI_EXIT_DISC
+    pub med: Option<u32>,
+    /// LOCAL_PREF
+    pub local_pref: Option<u32>,
+    /// AGGREGATOR as "asn ip"
+    pub aggregator: String,
+    /// Standard communities, space separated
+    pub community_list: String,
+    /// Extended communities, space separated
+    pub ext_community_list: String,
+    /// CLUSTER_LIST, space separated
+    pub cluster_list: String,
+    /// Whether ATOMIC_AGGREGATE is set
+    pub is_atomic_agg: bool,
+    /// Whether the next hop is IPv4
+    pub is_nexthop_ipv4: bool,
+    /// ORIGINATOR_ID
+    pub originator_id: Option<Ipv4Addr>,
+    /// Large communities, space separated
+    pub large_community_list: String,
+}
+
+/// `unicast_prefix` record
+#[derive(Clone, Debug, Serialize)]
+pub struct UnicastPrefixRecord {
+    /// add or del
+    pub action: String,
+    /// Sequence number
+    pub seq: u64,
+    /// Prefix hash, MD5 of the prefix, length, path ID and peer hash
+    pub hash: String,
+    /// Router hash
+    pub router_hash: String,
+    /// Router IP address
+    pub router_ip: IpAddr,
+    /// Hash of the base_attribute record, empty for withdrawals
+    pub base_attr_hash: String,
+    /// Peer hash
+    pub peer_hash: String,
+    /// Peer IP address
+    pub peer_ip: IpAddr,
+    /// Peer ASN
+    pub peer_asn: u32,
+    /// Per-Peer Header timestamp
+    pub timestamp: String,
+    /// Prefix address
+    pub prefix: IpAddr,
+    /// Prefix length
+    pub prefix_len: u8,
+    /// Whether the prefix is IPv4
+    pub is_ipv4: bool,
+    /// igp, egp or incomplete
+    pub origin: String,
+    /// AS_PATH, sets are wrapped in braces
+    pub as_path: String,
+    /// Number of ASNs in the AS_PATH
+    pub as_path_count: usize,
+    /// Originating ASN
+    pub origin_as: Option<u32>,
+    /// Next hop
+    pub next_hop: Option<IpAddr>,
+    /// MULTI_EXIT_DISC
+    pub med: Option<u32>,
+    /// LOCAL_PREF
+    pub local_pref: Option<u32>,
+    /// AGGREGATOR as "asn ip"
+    pub aggregator: String,
+    /// Standard communities, space separated
+    pub community_list: String,
+    /// Extended communities, space separated
+    pub ext_community_list: String,
+    /// CLUSTER_LIST, space separated
+    pub cluster_list: String,
+    /// Whether ATOMIC_AGGREGATE is set
+    pub is_atomic_agg: bool,
+    /// Whether the next hop is IPv4
+    pub is_nexthop_ipv4: bool,
+    /// ORIGINATOR_ID
+    pub originator_id: Option<Ipv4Addr>,
+    /// ADD-PATH path ID
+    pub path_id: Option<u32>,
+    /// MPLS labels, which bgp-rs doesn't decode
+    pub labels: String,
+    /// Whether the prefix is pre-policy
+    pub is_prepolicy: bool,
+    /// Whether the prefix is from the Adj-RIB-In (as opposed to Adj-RIB-Out)
+    pub is_adj_rib_in: bool,
+    /// Large communities, space separated
+    pub large_community_list: String,
+}
+
+/// A single parsed record
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged)]
+pub enum Record {
+    /// `collector` record
+    Collector(CollectorRecord),
+    /// `router` record
+    Router(RouterRecord),
+    /// `peer` record
+    Peer(PeerRecord),
+    /// `base_attribute` record
+    BaseAttribute(BaseAttributeRecord),
+    /// `unicast_prefix` record
+    UnicastPrefix(UnicastPrefixRecord),
+}
+
+impl Record {
+    /// Message type of the record
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            Record::Collector(_) => RecordKind::Collector,
+            Record::Router(_) => RecordKind::Router,
+            Record::Peer(_) => RecordKind::Peer,
+            Record::BaseAttribute(_) => RecordKind::BaseAttribute,
+            Record::UnicastPrefix(_) => RecordKind::UnicastPrefix,
+        }
+    }
+
+    /// Tab separated fields, in message bus API order
+    pub fn to_tsv(&self) -> String {
+        let fields = match self {
+            Record::Collector(r) => vec![
+                r.action.clone(), r.seq.to_string(), r.admin_id.clone(), r.hash.clone(), r.routers.clone(),
+                r.router_count.to_string(), r.timestamp.clone(),
+            ],
+            Record::Router(r) => vec![
+                r.action.clone(), r.seq.to_string(), r.name.clone(), r.hash.clone(), r.ip_address.to_string(),
+                r.description.clone(), opt(&r.term_code), r.term_reason.clone(), r.init_data.clone(),
+                r.term_data.clone(), r.timestamp.clone(), r.bgp_id.clone(),
+            ],
+            Record::Peer(r) => vec![
+                r.action.clone(), r.seq.to_string(), r.hash.clone(), r.router_hash.clone(), r.name.clone(),
+                r.remote_bgp_id.to_string(), r.router_ip.to_string(), r.timestamp.clone(), r.remote_asn.to_string(),
+                r.remote_ip.to_string(), r.peer_rd.clone(), opt(&r.remote_port), opt(&r.local_asn), opt(&r.local_ip),
+                opt(&r.local_port), opt(&r.local_bgp_id), r.info_data.clone(), r.adv_cap.clone(), r.recv_cap.clone(),
+                opt(&r.remote_holddown), opt(&r.adv_holddown), opt(&r.bmp_reason), opt(&r.bgp_error_code),
+                opt(&r.bgp_error_subcode), r.error_text.clone(), flag(r.is_l3vpn), flag(r.is_prepolicy),
+                flag(r.is_ipv4), flag(r.is_locrib), flag(r.is_locrib_filtered), r.table_name.clone(),
+            ],
+            Record::BaseAttribute(r) => vec![
+                r.action.clone(), r.seq.to_string(), r.hash.clone(), r.router_hash.clone(), r.router_ip.to_string(),
+                r.peer_hash.clone(), r.peer_ip.to_string(), r.peer_asn.to_string(), r.timestamp.clone(),
+                r.origin.clone(), r.as_path.clone(), r.as_path_count.to_string(), opt(&r.origin_as),
+                opt(&r.next_hop), opt(&r.med), opt(&r.local_pref), r.aggregator.clone(), r.community_list.clone(),
+                r.ext_community_list.clone(), r.cluster_list.clone(), flag(r.is_atomic_agg),
+                flag(r.is_nexthop_ipv4), opt(&r.originator_id), r.large_community_list.clone(),
+            ],
+            Record::UnicastPrefix(r) => vec![
+                r.action.clone(), r.seq.to_string(), r.hash.clone(), r.router_hash.clone(), r.router_ip.to_string(),
+                r.base_attr_hash.clone(), r.peer_hash.clone(), r.peer_ip.to_string(), r.peer_asn.to_string(),
+                r.timestamp.clone(), r.prefix.to_string(), r.prefix_len.to_string(), flag(r.is_ipv4),
+                r.origin.clone(), r.as_path.clone(), r.as_path_count.to_string(), opt(&r.origin_as),
+                opt(&r.next_hop), opt(&r.med), opt(&r.local_pref), r.aggregator.clone(), r.community_list.clone(),
+                r.ext_community_list.clone(), r.cluster_list.clone(), flag(r.is_atomic_agg),
+                flag(r.is_nexthop_ipv4), opt(&r.originator_id), opt(&r.path_id), r.labels.clone(),
+                flag(r.is_prepolicy), flag(r.is_adj_rib_in), r.large_community_list.clone(),
+            ],
+        };
+
+        fields.iter()
+            .map(|field| field.replace(['\t', '\n'], " "))
+            .collect::<Vec<_>>()
+            .join("\t")
+    }
+}
+
+fn opt<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+fn flag(value: bool) -> String {
+    if value { "1".into() } else { "0".into() }
+}
+
+fn hex(hash: &[u8; 16]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn md5_of(parts: &[&[u8]]) -> [u8; 16] {
+    let mut ctx = md5::Context::new();
+    for part in parts {
+        ctx.consume(part);
+    }
+
+    ctx.compute().0
+}
+
+/// Hash identifying a peer, the MD5 of the peer address and RD (as strings) followed by the
+/// router hash
+pub fn peer_hash(peer_addr: &IpAddr, peer_rd: &str, router_hash: &[u8; 16]) -> [u8; 16] {
+    md5_of(&[peer_addr.to_string().as_bytes(), peer_rd.as_bytes(), router_hash])
+}
+
+/// Capabilities from an OPEN in the format OpenBMP uses, eg.
+/// "MPBGP (1) : afi=1 safi=1 : Unicast IPv4"
+fn capabilities(open: &Open) -> String {
+    open.parameters.iter()
+        .flat_map(|param| match param {
+            OpenParameter::Capabilities(caps) => caps.iter().collect(),
+            _ => vec![],
+        })
+        .map(|cap| match cap {
+            OpenCapability::MultiProtocol((afi, safi)) => {
+                format!("MPBGP (1) : afi={} safi={} : {} {}", *afi as u16, *safi as u8, safi, afi)
+            },
+            OpenCapability::RouteRefresh => "Route Refresh (2)".into(),
+            OpenCapability::OutboundRouteFiltering(_) => "Outbound Route Filtering (3)".into(),
+            OpenCapability::FourByteASN(_) => "4 Octet ASN (65)".into(),
+            OpenCapability::AddPath(_) => "ADD Path (69)".into(),
+            OpenCapability::Unknown { cap_code, .. } => format!("Unknown ({})", cap_code),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn string_tlvs(information: &[InformationTlv]) -> String {
+    information.iter()
+        .filter_map(|tlv| match tlv {
+            InformationTlv::String(value) => Some(value.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Builds OpenBMP parsed records for the messages from a single router
+///
+/// Hash IDs are derived from the collector admin ID and router IP the same way as the raw
+/// message header (see `OpenBmpHeader::new`).
+#[derive(Clone, Debug)]
+pub struct ParsedFormatter {
+    collector_admin_id: String,
+    collector_hash: [u8; 16],
+    router_ip: IpAddr,
+    router_hash: [u8; 16],
+    sequence: HashMap<RecordKind, u64>,
+}
+
+impl ParsedFormatter {
+    /// Create a new formatter for messages from `router_ip`
+    pub fn new(collector_admin_id: &str, router_ip: IpAddr) -> Self {
+        let collector_hash = collector_hash(collector_admin_id);
+
+        Self {
+            collector_admin_id: collector_admin_id.into(),
+            router_hash: router_hash(&router_ip, &collector_hash),
+            collector_hash,
+            router_ip,
+            sequence: HashMap::new(),
+        }
+    }
+
+    fn next_seq(&mut self, kind: RecordKind) -> u64 {
+        let seq = self.sequence.entry(kind).or_insert(0);
+        let current = *seq;
+        *seq += 1;
+
+        current
+    }
+
+    /// Build a `collector` record
+    pub fn collector(&mut self, action: CollectorAction, routers: &[IpAddr]) -> Record {
+        Record::Collector(CollectorRecord {
+            action: action.to_string(),
+            seq: self.next_seq(RecordKind::Collector),
+            admin_id: self.collector_admin_id.clone(),
+            hash: hex(&self.collector_hash),
+            routers: routers.iter().map(ToString::to_string).collect::<Vec<_>>().join(","),
+            router_count: routers.len(),
+            timestamp: format_time(SystemTime::now()),
+        })
+    }
+
+    /// Build the records for a message. This should be called before the message is passed to
+    /// `RouterSession::process` so details of peers which have gone down are still available
+    pub fn records(&mut self, message: &BmpMessage, session: &RouterSession) -> Vec<Record> {
+        match &message.message {
+            MessageData::Initiation(tlv) => {
+                let find = |kind| tlv.iter()
+                    .find(|tlv| tlv.information_type() == Some(kind))
+                    .and_then(InformationTlv::value)
+                    .unwrap_or_default()
+                    .to_string();
+
+                vec![Record::Router(RouterRecord {
+                    action: "init".into(),
+                    seq: self.next_seq(RecordKind::Router),
+                    name: find(InformationType::SysName),
+                    hash: hex(&self.router_hash),
+                    ip_address: self.router_ip,
+                    description: find(InformationType::SysDescr),
+                    term_code: None,
+                    term_reason: String::new(),
+                    init_data: string_tlvs(tlv),
+                    term_data: String::new(),
+                    timestamp: format_time(SystemTime::now()),
+                    bgp_id: String::new(),
+                })]
+            },
+            MessageData::Termination(tlv) => {
+                let reason = tlv.iter().find_map(|tlv| match tlv {
+                    TerminationTlv::Reason(reason) => Some(*reason),
+                    _ => None,
+                });
+                let term_data = tlv.iter()
+                    .filter_map(|tlv| match tlv {
+                        TerminationTlv::String(value) => Some(value.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("; ");
+
+                vec![Record::Router(RouterRecord {
+                    action: "term".into(),
+                    seq: self.next_seq(RecordKind::Router),
+                    name: session.sys_name().unwrap_or_default().into(),
+                    hash: hex(&self.router_hash),
+                    ip_address: self.router_ip,
+                    description: session.sys_descr().unwrap_or_default().into(),
+                    term_code: reason.map(|reason| reason.code()),
+                    term_reason: opt(&reason),
+                    init_data: string_tlvs(session.information()),
+                    term_data,
+                    timestamp: format_time(SystemTime::now()),
+                    bgp_id: String::new(),
+                })]
+            },
+            MessageData::PeerUp((header, peer_up)) => {
+                vec![Record::Peer(self.peer_record("up", header, Some(peer_up)))]
+            },
+            MessageData::PeerDown((header, peer_down)) => {
+                let peer_up = session.peer(&header.into()).map(|state| &state.peer_up);
+                let mut record = self.peer_record("down", header, peer_up);
+
+                let text = match peer_down {
+                    PeerDown::LocalShutdown(_) => "Local close by NOTIFICATION",
+                    PeerDown::LocalTerminate(_) => "Local close without NOTIFICATION",
+                    PeerDown::RemoteShutdown(_) => "Remote close by NOTIFICATION",
+                    PeerDown::RemoteTerminate => "Remote close without NOTIFICATION",
+                    PeerDown::ConfigurationChange => "Peer de-configured",
+                    PeerDown::LocalClosed(_) => "Local close, TLV data follows",
+                    PeerDown::Unknown(..) => "Unknown reason",
+                };
+                let notification = peer_down.notification();
+                record.bmp_reason = Some(peer_down.reason());
+                record.bgp_error_code = notification.map(|n| n.major_err_code);
+                record.bgp_error_subcode = notification.map(|n| n.minor_err_code);
+                record.error_text = text.into();
+
+                vec![Record::Peer(record)]
+            },
+            MessageData::RouteMonitoring((header, update)) => self.route_records(header, update),
+            _ => vec![],
+        }
+    }
+
+    fn peer_record(&mut self, action: &str, header: &PeerHeader, peer_up: Option<&PeerUp>) -> PeerRecord {
+        let peer_rd = format_rd(header.peer_distinguisher);
+        let sent_open = peer_up.and_then(|p| p.sent_open.as_ref());
+        let recv_open = peer_up.and_then(|p| p.recv_open.as_ref());
+
+        PeerRecord {
+            action: action.into(),
+            seq: self.next_seq(RecordKind::Peer),
+            hash: hex(&peer_hash(&header.peer_addr, &peer_rd, &self.router_hash)),
+            router_hash: hex(&self.router_hash),
+            name: String::new(),
+            remote_bgp_id: header.peer_bgp_id,
+            router_ip: self.router_ip,
+            timestamp: format_time(header.system_time()),
+            remote_asn: header.peer_asn,
+            remote_ip: header.peer_addr,
+            peer_rd,
+            remote_port: peer_up.map(|p| p.remote_port),
+            local_asn: peer_up.and_then(PeerUp::local_asn),
+            local_ip: peer_up.map(|p| p.local_addr),
+            local_port: peer_up.map(|p| p.local_port),
+            local_bgp_id: sent_open.map(|open| Ipv4Addr::from(open.identifier)),
+            info_data: peer_up.map(|p| string_tlvs(&p.information)).unwrap_or_default(),
+            adv_cap: sent_open.map(capabilities).unwrap_or_default(),
+            recv_cap: recv_open.map(capabilities).unwrap_or_default(),
+            remote_holddown: recv_open.map(|open| open.hold_timer),
+            adv_holddown: sent_open.map(|open| open.hold_timer),
+            bmp_reason: None,
+            bgp_error_code: None,
+            bgp_error_subcode: None,
+            error_text: String::new(),
+            is_l3vpn: header.peer_type == PeerType::RdInstance,
+            is_prepolicy: !header.peer_flags.L,
+            is_ipv4: !header.peer_flags.V,
+            is_locrib: header.peer_type == PeerType::LocRibInstance,
+            is_locrib_filtered: header.peer_type == PeerType::LocRibInstance && header.peer_flags.V,
+            table_name: peer_up
+                .and_then(|p| p.find_information(InformationType::VrfTableName))
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    fn route_records(&mut self, header: &PeerHeader, update: &bgp_rs::Update) -> Vec<Record> {
+        let routes = routes(update);
+        if routes.is_empty() {
+            return vec![];
+        }
+
+        let attrs = RouteAttributes::from_update(update);
+        let peer_hash = peer_hash(&header.peer_addr, &format_rd(header.peer_distinguisher), &self.router_hash);
+        let timestamp = format_time(header.system_time());
+
+        let as_path = attrs.as_path_string();
+        let aggregator = attrs.aggregator.map(|(asn, addr)| format!("{} {}", asn, addr)).unwrap_or_default();
+        let community_list = attrs.community_strings().join(" ");
+        let ext_community_list = attrs.ext_community_strings().join(" ");
+        let large_community_list = attrs.large_community_strings().join(" ");
+        let cluster_list = attrs.cluster_list_strings().join(" ");
+        let originator_id = attrs.originator_id.map(Ipv4Addr::from);
+        let is_nexthop_ipv4 = attrs.next_hop.map(|nh| nh.is_ipv4()).unwrap_or(false);
+
+        let mut records = vec![];
+        let mut base_attr_hash = String::new();
+
+        if routes.iter().any(|route| !route.withdrawn) {
+            let hash = md5_of(&[
+                as_path.as_bytes(),
+                opt(&attrs.next_hop).as_bytes(),
+                aggregator.as_bytes(),
+                attrs.origin_string().as_bytes(),
+                opt(&attrs.med).as_bytes(),
+                opt(&attrs.local_pref).as_bytes(),
+                community_list.as_bytes(),
+                ext_community_list.as_bytes(),
+                &peer_hash,
+            ]);
+            base_attr_hash = hex(&hash);
+
+            records.push(Record::BaseAttribute(BaseAttributeRecord {
+                action: "add".into(),
+                seq: self.next_seq(RecordKind::BaseAttribute),
+                hash: base_attr_hash.clone(),
+                router_hash: hex(&self.router_hash),
+                router_ip: self.router_ip,
+                peer_hash: hex(&peer_hash),
+                peer_ip: header.peer_addr,
+                peer_asn: header.peer_asn,
+                timestamp: timestamp.clone(),
+                origin: attrs.origin_string(),
+                as_path: as_path.clone(),
+                as_path_count: attrs.as_path_count(),
+                origin_as: attrs.origin_as(),
+                next_hop: attrs.next_hop,
+                med: attrs.med,
+                local_pref: attrs.local_pref,
+                aggregator: aggregator.clone(),
+                community_list: community_list.clone(),
+                ext_community_list: ext_community_list.clone(),
+                cluster_list: cluster_list.clone(),
+                is_atomic_agg: attrs.atomic_aggregate,
+                is_nexthop_ipv4,
+                originator_id,
+                large_community_list: large_community_list.clone(),
+            }));
+        }
+
+        for route in routes {
+            let hash = md5_of(&[
+                route.prefix.to_string().as_bytes(),
+                &[route.length],
+                &route.path_id.unwrap_or(0).to_be_bytes(),
+                &peer_hash,
+            ]);
+
+            // Withdrawals don't carry any attributes
+            let announced = |value: String| if route.withdrawn { String::new() } else { value };
+            let announced_opt = |value| if route.withdrawn { None } else { value };
+
+            records.push(Record::UnicastPrefix(UnicastPrefixRecord {
+                action: if route.withdrawn { "del".into() } else { "add".into() },
+                seq: self.next_seq(RecordKind::UnicastPrefix),
+                hash: hex(&hash),
+                router_hash: hex(&self.router_hash),
+                router_ip: self.router_ip,
+                base_attr_hash: announced(base_attr_hash.clone()),
+                peer_hash: hex(&peer_hash),
+                peer_ip: header.peer_addr,
+                peer_asn: header.peer_asn,
+                timestamp: timestamp.clone(),
+                prefix: route.prefix,
+                prefix_len: route.length,
+                is_ipv4: route.prefix.is_ipv4(),
+                origin: announced(attrs.origin_string()),
+                as_path: announced(as_path.clone()),
+                as_path_count: if route.withdrawn { 0 } else { attrs.as_path_count() },
+                origin_as: announced_opt(attrs.origin_as()),
+                next_hop: if route.withdrawn { None } else { attrs.next_hop },
+                med: announced_opt(attrs.med),
+                local_pref: announced_opt(attrs.local_pref),
+                aggregator: announced(aggregator.clone()),
+                community_list: announced(community_list.clone()),
+                ext_community_list: announced(ext_community_list.clone()),
+                cluster_list: announced(cluster_list.clone()),
+                is_atomic_agg: !route.withdrawn && attrs.atomic_aggregate,
+                is_nexthop_ipv4: !route.withdrawn && is_nexthop_ipv4,
+                originator_id: if route.withdrawn { None } else { originator_id },
+                path_id: route.path_id,
+                labels: String::new(),
+                is_prepolicy: !header.peer_flags.L,
+                is_adj_rib_in: !header.peer_flags.O,
+                large_community_list: announced(large_community_list.clone()),
+            }));
+        }
+
+        records
+    }
+
+    /// Render records as message bus messages in the legacy tab separated format. Consecutive
+    /// records of the same type are grouped into a single message
+    pub fn tsv(&self, records: &[Record]) -> Vec<String> {
+        group(records).into_iter()
+            .map(|(kind, records)| {
+                let rows: String = records.iter()
+                    .map(|record| format!("{}\n", record.to_tsv()))
+                    .collect();
+
+                format!(
+                    "V: {}\nC_HASH_ID: {}\nT: {}\nL: {}\nR: {}\n\n{}",
+                    VERSION, hex(&self.collector_hash), kind, rows.len(), records.len(), rows
+                )
+            })
+            .collect()
+    }
+
+    /// Render records as JSON messages. Consecutive records of the same type are grouped into a
+    /// single message
+    pub fn json(&self, records: &[Record]) -> Vec<String> {
+        group(records).into_iter()
+            .map(|(kind, records)| {
+                serde_json::json!({
+                    "version": VERSION,
+                    "collector_hash_id": hex(&self.collector_hash),
+                    "type": kind.to_string(),
+                    "rows": records,
+                }).to_string()
+            })
+            .collect()
+    }
+}
+
+fn group(records: &[Record]) -> Vec<(RecordKind, &[Record])> {
+    let mut groups = vec![];
+    let mut start = 0;
+
+    for idx in 1..=records.len() {
+        if idx == records.len() || records[idx].kind() != records[start].kind() {
+            groups.push((records[start].kind(), &records[start..idx]));
+            start = idx;
+        }
+    }
+
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BmpDecoder;
+
+    use bytes::BytesMut;
+
+    use std::fs;
+
+    fn records() -> Vec<Record> {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut session = RouterSession::new();
+        let mut formatter = ParsedFormatter::new("collector1", "192.0.2.254".parse().unwrap());
+
+        let mut records = vec![];
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            records.extend(formatter.records(&message, &session));
+            session.process(&message);
+        }
+
+        records
+    }
+
+    #[test]
+    fn test_parsed_records() {
+        let records = records();
+        let kinds = records.iter().map(Record::kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            RecordKind::Router,
+            RecordKind::Peer,
+            RecordKind::Peer,
+            RecordKind::BaseAttribute,
+            RecordKind::UnicastPrefix,
+            RecordKind::UnicastPrefix,
+            RecordKind::BaseAttribute,
+            RecordKind::UnicastPrefix,
+            RecordKind::Peer,
+            RecordKind::Peer,
+            RecordKind::Router,
+        ]);
+
+        let router_hash = "7d37c017ec02afeb1c4588b27ca2072f";
+        let peer_hash = "cd024a93c02f19508b0627b524aa770e";
+
+        match &records[1] {
+            Record::Peer(peer) => {
+                assert_eq!(peer.hash, peer_hash);
+                assert_eq!(peer.router_hash, router_hash);
+                assert_eq!(peer.local_asn, Some(65000));
+                assert_eq!(peer.adv_cap, "MPBGP (1) : afi=1 safi=1 : Unicast IPv4, Route Refresh (2), 4 Octet ASN (65)");
+            },
+            r => panic!("unexpected record {:?}", r),
+        };
+
+        match &records[4] {
+            Record::UnicastPrefix(prefix) => {
+                assert_eq!(prefix.seq, 0);
+                assert_eq!(prefix.peer_hash, peer_hash);
+                assert_eq!(prefix.timestamp, "2020-01-01 00:00:00.000000");
+                assert_eq!(records[4].to_tsv().split('\t').skip(10).take(8).collect::<Vec<_>>(), vec![
+                    "198.51.100.0", "24", "1", "igp", "65001 64512", "2", "64512", "192.0.2.1",
+                ]);
+            },
+            r => panic!("unexpected record {:?}", r),
+        };
+
+        // The peer had gone by the time the session saw the Peer Down, but the details were kept
+        match &records[8] {
+            Record::Peer(peer) => {
+                assert_eq!(peer.action, "down");
+                assert_eq!(peer.bmp_reason, Some(2));
+                assert_eq!(peer.local_port, Some(179));
+            },
+            r => panic!("unexpected record {:?}", r),
+        };
+
+        match &records[10] {
+            Record::Router(router) => {
+                assert_eq!(router.description, "Synthetic Router OS 1.0");
+                assert_eq!(router.term_code, Some(0));
+                assert_eq!(router.term_data, "shutting down");
+            },
+            r => panic!("unexpected record {:?}", r),
+        };
+    }
+
+    #[test]
+    fn test_parsed_output() {
+        let records = records();
+        let formatter = ParsedFormatter::new("collector1", "192.0.2.254".parse().unwrap());
+
+        let tsv = formatter.tsv(&records[3..6]);
+        assert_eq!(tsv.len(), 2);
+        assert!(tsv[1].starts_with("V: 1.7\nC_HASH_ID: 9ae8148974c9ca01ec9271753426d214\nT: unicast_prefix\n"));
+
+        let lines = tsv[1].lines().collect::<Vec<_>>();
+        assert_eq!(lines[4], "R: 2");
+        assert_eq!(lines[3], format!("L: {}", lines[6].len() + lines[7].len() + 2));
+        assert_eq!(lines[6].split('\t').count(), 32);
+
+        let json = formatter.json(&records[1..3]);
+        let value: serde_json::Value = serde_json::from_str(&json[0]).unwrap();
+        assert_eq!(value["type"], "peer");
+        assert_eq!(value["rows"][1]["remote_ip"], "2001:db8::1");
+        assert_eq!(value["rows"][1]["is_ipv4"], false);
+    }
+}
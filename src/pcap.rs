@@ -0,0 +1,871 @@
+//! Read BMP sessions from pcap / pcapng captures
+//!
+//! `PcapReader` parses a capture file, reassembles each TCP flow and runs the payload through a
+//! `BmpDecoder` per flow. Out-of-order segments are buffered until the missing data arrives,
+//! retransmitted data is discarded and if data never turns up (or the capture started part way
+//! through a session) the flow skips the gap and resynchronises on the next plausible BMP header.
+//!
+//! Supported link types are Ethernet (including VLAN tags), raw IP, Linux cooked captures (v1 & v2)
+//! and BSD loopback.
+
+use crate::{BmpDecoder, Error, Result, decoder::MAX_MESSAGE_LEN, types::BmpMessage};
+
+use bytes::BytesMut;
+use hashbrown::HashMap;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// Link types (https://www.tcpdump.org/linktypes.html)
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+// pcapng block types
+const PCAPNG_SHB: u32 = 0x0A0D_0D0A;
+const PCAPNG_IDB: u32 = 1;
+const PCAPNG_SPB: u32 = 3;
+const PCAPNG_EPB: u32 = 6;
+
+// TCP flags
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+// Give up waiting for missing data once this much has been buffered after the hole
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+// Anything bigger than this is not a real pcapng block
+const MAX_PCAPNG_BLOCK_LEN: usize = 16 * 1024 * 1024;
+// Captured lengths are capped at this even if the file header claims a bigger snaplen
+const MAX_PCAP_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+/// One direction of a TCP connection
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct Flow {
+    /// Sender (ie. the BMP speaker)
+    pub src: SocketAddr,
+    /// Receiver (ie. the collector)
+    pub dst: SocketAddr,
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} > {}", self.src, self.dst)
+    }
+}
+
+/// A TCP segment extracted from a captured packet
+#[derive(Clone, Debug)]
+struct Segment {
+    flow: Flow,
+    seq: u32,
+    flags: u8,
+    payload: Vec<u8>,
+    // Set if the snaplen cut off some of the payload
+    truncated: bool,
+}
+
+/// Reassembly and decoding state for a single flow
+#[derive(Debug)]
+struct Stream {
+    decoder: BmpDecoder,
+    buf: BytesMut,
+    next_seq: Option<u32>,
+    pending: Vec<(u32, Vec<u8>)>,
+    pending_bytes: usize,
+    // Set when we don't know where the next BMP message starts
+    resync: bool,
+}
+
+impl Stream {
+    fn new() -> Self {
+        Self {
+            decoder: BmpDecoder::new(),
+            buf: BytesMut::new(),
+            next_seq: None,
+            pending: vec![],
+            pending_bytes: 0,
+            resync: false,
+        }
+    }
+
+    fn add_segment(&mut self, seg: Segment) {
+        if seg.flags & TCP_SYN == TCP_SYN {
+            self.next_seq = Some(seg.seq.wrapping_add(1));
+            return;
+        }
+
+        let next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            None => {
+                // We missed the start of the connection
+                self.resync = true;
+                seg.seq
+            }
+        };
+        self.next_seq = Some(next_seq);
+
+        if seg.payload.is_empty() {
+            return;
+        }
+
+        if seg.truncated {
+            // Treat a truncated segment as lost, anything after it will be picked up as a gap
+            tracing::debug!("discarding truncated segment on {}", seg.flow);
+            return;
+        }
+
+        self.pending_bytes += seg.payload.len();
+        self.pending.push((seg.seq, seg.payload));
+        self.drain();
+
+        if self.pending_bytes > MAX_PENDING_BYTES {
+            self.skip_gap();
+        }
+    }
+
+    // Move any segments which are now in order onto the buffer
+    fn drain(&mut self) {
+        let mut next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            None => return,
+        };
+
+        loop {
+            let mut progress = false;
+            let mut idx = 0;
+
+            while idx < self.pending.len() {
+                let (seq, ref payload) = self.pending[idx];
+                let offset = next_seq.wrapping_sub(seq) as i32;
+                let end = offset as i64 - payload.len() as i64;
+
+                if offset < 0 {
+                    // Still in the future
+                    idx += 1;
+                    continue;
+                }
+
+                let (_, payload) = self.pending.swap_remove(idx);
+                self.pending_bytes -= payload.len();
+
+                // If end >= 0 this is a complete retransmit of data we already have
+                if end < 0 {
+                    self.buf.extend_from_slice(&payload[offset as usize..]);
+                    next_seq = seq.wrapping_add(payload.len() as u32);
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+
+        self.next_seq = Some(next_seq);
+    }
+
+    // Give up on the missing data and carry on from the earliest segment we do have
+    fn skip_gap(&mut self) {
+        let next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            None => return,
+        };
+
+        let earliest = self.pending.iter()
+            .map(|(seq, _)| *seq)
+            .min_by_key(|seq| seq.wrapping_sub(next_seq));
+
+        if let Some(seq) = earliest {
+            tracing::debug!(missing = seq.wrapping_sub(next_seq), "skipping gap in TCP stream");
+
+            self.next_seq = Some(seq);
+            self.resync = true;
+            self.buf.clear();
+            self.drain();
+        }
+    }
+
+    // Pull as many messages from the buffer as we can
+    fn decode(&mut self, flow: Flow, out: &mut VecDeque<Result<(Flow, BmpMessage)>>) {
+        loop {
+            if self.resync && !self.find_header() {
+                return;
+            }
+
+            let before = self.buf.len();
+            match self.decoder.decode_buf(&mut self.buf) {
+                Ok(Some(message)) => out.push_back(Ok((flow, message))),
+                Ok(None) => return,
+                Err(err) => {
+                    // If nothing was consumed the header itself is bad, look for the next one
+                    if self.buf.len() == before {
+                        self.resync = true;
+                        let _ = self.buf.split_to(1);
+                    }
+                    let err = match err {
+                        Error::DecodeError(msg) => Error::decode(&format!("{}: {}", flow, msg)),
+                        err => err,
+                    };
+                    out.push_back(Err(err));
+                },
+            };
+        }
+    }
+
+    // Discard bytes until the buffer starts with something that looks like a BMP common header
+    fn find_header(&mut self) -> bool {
+        let plausible = |buf: &[u8]| -> Option<usize> {
+            if buf.len() < 6 || buf[0] != 3 || buf[5] > 6 {
+                return None;
+            }
+            let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
+            if (6..=MAX_MESSAGE_LEN).contains(&len) { Some(len) } else { None }
+        };
+
+        let mut idx = 0;
+        while self.buf.len() - idx >= 6 {
+            if let Some(len) = plausible(&self.buf[idx..]) {
+                // If the following header is available make sure that looks right too
+                let next = &self.buf[idx..];
+                if next.len() < len + 6 || plausible(&next[len..]).is_some() {
+                    let _ = self.buf.split_to(idx);
+                    self.resync = false;
+
+                    return true;
+                }
+            }
+            idx += 1;
+        }
+
+        // Keep the last few bytes, they could be the start of a header
+        let _ = self.buf.split_to(idx);
+        false
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Endian {
+    Big,
+    Little,
+}
+
+impl Endian {
+    fn u16(self, buf: &[u8]) -> u16 {
+        let bytes = [buf[0], buf[1]];
+        match self {
+            Endian::Big => u16::from_be_bytes(bytes),
+            Endian::Little => u16::from_le_bytes(bytes),
+        }
+    }
+
+    fn u32(self, buf: &[u8]) -> u32 {
+        let bytes = [buf[0], buf[1], buf[2], buf[3]];
+        match self {
+            Endian::Big => u32::from_be_bytes(bytes),
+            Endian::Little => u32::from_le_bytes(bytes),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Format {
+    // Nothing read yet
+    Unknown,
+    Pcap { endian: Endian, linktype: u32, snaplen: usize },
+    PcapNg { endian: Endian, interfaces: Vec<u32> },
+}
+
+/// Reads BMP messages from a pcap or pcapng capture
+///
+/// Each item is the flow the message was sent on along with the message itself. Decoding
+/// errors are returned for the flow they happen on but don't end iteration, the next call will
+/// carry on with the next message.
+///
+/// ```no_run
+/// # use bmp_protocol::pcap::PcapReader;
+/// let fh = std::fs::File::open("bmp.pcap").unwrap();
+/// for item in PcapReader::new(fh).with_port(11019) {
+///     let (flow, message) = item.unwrap();
+///     println!("{}: {}", flow, message.kind);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PcapReader<R: Read> {
+    inner: R,
+    format: Format,
+    ports: Vec<u16>,
+    streams: HashMap<Flow, Stream>,
+    pending: VecDeque<Result<(Flow, BmpMessage)>>,
+    finished: bool,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Create a new reader, the capture format is detected automatically
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            format: Format::Unknown,
+            ports: vec![],
+            streams: HashMap::new(),
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Only decode flows to or from this TCP port. May be called multiple times, by default
+    /// every TCP flow carrying data is decoded
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.ports.push(port);
+        self
+    }
+
+    // Fill buf, returning false on a clean EOF
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(Error::decode("truncated capture file")),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e.into()),
+            };
+        }
+
+        Ok(true)
+    }
+
+    // Read the next captured packet, returning the link type and data
+    fn read_packet(&mut self) -> Result<Option<(u32, Vec<u8>, bool)>> {
+        loop {
+            match self.format.clone() {
+                Format::Unknown => {
+                    let mut magic = [0u8; 4];
+                    if !self.read_exact_or_eof(&mut magic)? {
+                        return Ok(None);
+                    }
+
+                    match magic {
+                        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => self.read_pcap_header(Endian::Big)?,
+                        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => self.read_pcap_header(Endian::Little)?,
+                        [0x0a, 0x0d, 0x0d, 0x0a] => self.read_section_header()?,
+                        _ => return Err(Error::decode("not a pcap or pcapng file")),
+                    };
+                },
+                Format::Pcap { endian, linktype, snaplen } => {
+                    let mut header = [0u8; 16];
+                    if !self.read_exact_or_eof(&mut header)? {
+                        return Ok(None);
+                    }
+
+                    let captured = endian.u32(&header[8..]) as usize;
+                    let original = endian.u32(&header[12..]) as usize;
+                    if captured > snaplen {
+                        return Err(Error::decode(&format!("pcap packet length {} is over the limit of {}", captured, snaplen)));
+                    }
+
+                    let mut data = vec![0u8; captured];
+                    self.inner.read_exact(&mut data)?;
+
+                    return Ok(Some((linktype, data, captured < original)));
+                },
+                Format::PcapNg { endian, interfaces } => {
+                    let mut header = [0u8; 8];
+                    if !self.read_exact_or_eof(&mut header)? {
+                        return Ok(None);
+                    }
+
+                    let kind = endian.u32(&header);
+                    if kind == PCAPNG_SHB {
+                        // New section, which may change the byte order
+                        self.read_section_header()?;
+                        continue;
+                    }
+
+                    let total = endian.u32(&header[4..]) as usize;
+                    if !(12..=MAX_PCAPNG_BLOCK_LEN).contains(&total) {
+                        return Err(Error::decode(&format!("invalid pcapng block length {}", total)));
+                    }
+                    let mut body = vec![0u8; total - 8];
+                    self.inner.read_exact(&mut body)?;
+                    let body = &body[..body.len() - 4];
+
+                    match kind {
+                        PCAPNG_IDB if body.len() >= 2 => {
+                            if let Format::PcapNg { interfaces, .. } = &mut self.format {
+                                interfaces.push(u32::from(endian.u16(body)));
+                            }
+                        },
+                        PCAPNG_EPB if body.len() >= 20 => {
+                            let interface = endian.u32(body) as usize;
+                            let captured = endian.u32(&body[12..]) as usize;
+                            let original = endian.u32(&body[16..]) as usize;
+                            let linktype = *interfaces.get(interface)
+                                .ok_or_else(|| Error::decode("pcapng packet for unknown interface"))?;
+                            let data = body.get(20..20 + captured)
+                                .ok_or_else(|| Error::decode("truncated pcapng packet block"))?;
+
+                            return Ok(Some((linktype, data.to_vec(), captured < original)));
+                        },
+                        PCAPNG_SPB if body.len() >= 4 => {
+                            let original = endian.u32(body) as usize;
+                            let linktype = *interfaces.first()
+                                .ok_or_else(|| Error::decode("pcapng packet for unknown interface"))?;
+                            let data = &body[4..];
+                            let captured = data.len().min(original);
+
+                            return Ok(Some((linktype, data[..captured].to_vec(), captured < original)));
+                        },
+                        _ => {},
+                    };
+                },
+            };
+        }
+    }
+
+    fn read_pcap_header(&mut self, endian: Endian) -> Result<()> {
+        let mut header = [0u8; 20];
+        self.inner.read_exact(&mut header)?;
+
+        self.format = Format::Pcap {
+            endian,
+            linktype: endian.u32(&header[16..]) & 0x0fff_ffff,
+            snaplen: (endian.u32(&header[12..]) as usize).min(MAX_PCAP_RECORD_LEN),
+        };
+        Ok(())
+    }
+
+    // The block type has already been read
+    fn read_section_header(&mut self) -> Result<()> {
+        let mut header = [0u8; 8];
+        self.inner.read_exact(&mut header)?;
+
+        let endian = match header[4..8] {
+            [0x1a, 0x2b, 0x3c, 0x4d] => Endian::Big,
+            [0x4d, 0x3c, 0x2b, 0x1a] => Endian::Little,
+            _ => return Err(Error::decode("invalid pcapng byte-order magic")),
+        };
+
+        let total = endian.u32(&header) as usize;
+        if !(12..=MAX_PCAPNG_BLOCK_LEN).contains(&total) {
+            return Err(Error::decode(&format!("invalid pcapng block length {}", total)));
+        }
+        let mut rest = vec![0u8; total - 12];
+        self.inner.read_exact(&mut rest)?;
+
+        self.format = Format::PcapNg { endian, interfaces: vec![] };
+        Ok(())
+    }
+
+    fn process(&mut self, segment: Segment) {
+        if !self.ports.is_empty() &&
+            !self.ports.contains(&segment.flow.src.port()) &&
+            !self.ports.contains(&segment.flow.dst.port()) {
+            return;
+        }
+
+        let flow = segment.flow;
+        let flags = segment.flags;
+
+        // Collectors don't send anything so ignore empty flows until they carry data
+        if segment.payload.is_empty() && flags & TCP_SYN == 0 && !self.streams.contains_key(&flow) {
+            return;
+        }
+
+        let stream = self.streams.entry(flow).or_insert_with(Stream::new);
+        stream.add_segment(segment);
+        stream.decode(flow, &mut self.pending);
+
+        if flags & (TCP_FIN | TCP_RST) != 0 {
+            self.finish(flow);
+        }
+    }
+
+    // Flush whatever is left of a flow, skipping any gaps
+    fn finish(&mut self, flow: Flow) {
+        if let Some(mut stream) = self.streams.remove(&flow) {
+            while !stream.pending.is_empty() {
+                stream.skip_gap();
+                stream.decode(flow, &mut self.pending);
+            }
+
+            if !stream.buf.is_empty() && !stream.resync {
+                tracing::debug!(bytes = stream.buf.len(), "incomplete BMP message at end of {}", flow);
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = Result<(Flow, BmpMessage)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.read_packet() {
+                Ok(Some((linktype, data, truncated))) => {
+                    if let Some(mut segment) = parse_packet(linktype, &data) {
+                        segment.truncated |= truncated;
+                        self.process(segment);
+                    }
+                },
+                Ok(None) => {
+                    self.finished = true;
+
+                    let mut flows = self.streams.keys().copied().collect::<Vec<_>>();
+                    flows.sort();
+                    for flow in flows {
+                        self.finish(flow);
+                    }
+                },
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                },
+            };
+        }
+    }
+}
+
+/// Extract the TCP segment from a captured packet
+fn parse_packet(linktype: u32, data: &[u8]) -> Option<Segment> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut ethertype = u16::from_be_bytes([*data.get(12)?, *data.get(13)?]);
+            let mut offset = 14;
+
+            // 802.1Q / 802.1ad tags
+            while ethertype == 0x8100 || ethertype == 0x88a8 {
+                ethertype = u16::from_be_bytes([*data.get(offset + 2)?, *data.get(offset + 3)?]);
+                offset += 4;
+            }
+
+            parse_ethertype(ethertype, data.get(offset..)?)
+        },
+        LINKTYPE_RAW => match data.first()? >> 4 {
+            4 => parse_ipv4(data),
+            6 => parse_ipv6(data),
+            _ => None,
+        },
+        LINKTYPE_NULL | LINKTYPE_LOOP => {
+            // Address family, in the capturing host's byte order for NULL (and big endian for LOOP)
+            // so just swap it if it looks wrong. IPv6 has several values depending on the OS
+            let family = u32::from_be_bytes([*data.first()?, *data.get(1)?, *data.get(2)?, *data.get(3)?]);
+            let family = if family > 0xffff { family.swap_bytes() } else { family };
+
+            match family {
+                2 => parse_ipv4(data.get(4..)?),
+                24 | 28 | 30 => parse_ipv6(data.get(4..)?),
+                _ => None,
+            }
+        },
+        LINKTYPE_LINUX_SLL => {
+            let ethertype = u16::from_be_bytes([*data.get(14)?, *data.get(15)?]);
+            parse_ethertype(ethertype, data.get(16..)?)
+        },
+        LINKTYPE_LINUX_SLL2 => {
+            let ethertype = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+            parse_ethertype(ethertype, data.get(20..)?)
+        },
+        _ => None,
+    }
+}
+
+fn parse_ethertype(ethertype: u16, data: &[u8]) -> Option<Segment> {
+    match ethertype {
+        0x0800 => parse_ipv4(data),
+        0x86dd => parse_ipv6(data),
+        _ => None,
+    }
+}
+
+fn parse_ipv4(data: &[u8]) -> Option<Segment> {
+    let header = data.get(..20)?;
+    let header_len = usize::from(header[0] & 0x0f) * 4;
+    let total_len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let fragment = u16::from_be_bytes([header[6], header[7]]);
+
+    // Not TCP, a fragment or a bogus header length
+    if header[9] != 6 || fragment & 0x3fff != 0 || header_len < 20 {
+        return None;
+    }
+
+    let src = Ipv4Addr::new(header[12], header[13], header[14], header[15]);
+    let dst = Ipv4Addr::new(header[16], header[17], header[18], header[19]);
+
+    // Ethernet padding can leave junk after the IP packet
+    let (packet, truncated) = if data.len() >= total_len { (&data[..total_len], false) } else { (data, true) };
+    parse_tcp(src.into(), dst.into(), packet.get(header_len..)?, truncated)
+}
+
+fn parse_ipv6(data: &[u8]) -> Option<Segment> {
+    let payload_len = usize::from(u16::from_be_bytes([*data.get(4)?, *data.get(5)?]));
+    let mut next_header = *data.get(6)?;
+
+    let mut src = [0u8; 16];
+    src.copy_from_slice(data.get(8..24)?);
+    let mut dst = [0u8; 16];
+    dst.copy_from_slice(data.get(24..40)?);
+
+    let (mut packet, truncated) = if data.len() >= 40 + payload_len {
+        (&data[40..40 + payload_len], false)
+    } else {
+        (&data[40..], true)
+    };
+
+    // Skip extension headers (Hop-by-Hop, Routing, Destination Options), fragments are ignored
+    while next_header != 6 {
+        match next_header {
+            0 | 43 | 60 => {
+                let len = (usize::from(*packet.get(1)?) + 1) * 8;
+                next_header = *packet.first()?;
+                packet = packet.get(len..)?;
+            },
+            _ => return None,
+        };
+    }
+
+    parse_tcp(IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), packet, truncated)
+}
+
+fn parse_tcp(src: IpAddr, dst: IpAddr, data: &[u8], truncated: bool) -> Option<Segment> {
+    let header = data.get(..20)?;
+    let src_port = u16::from_be_bytes([header[0], header[1]]);
+    let dst_port = u16::from_be_bytes([header[2], header[3]]);
+    let seq = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let header_len = usize::from(header[12] >> 4) * 4;
+    let flags = header[13];
+
+    if header_len < 20 {
+        return None;
+    }
+
+    Some(Segment {
+        flow: Flow { src: SocketAddr::new(src, src_port), dst: SocketAddr::new(dst, dst_port) },
+        seq,
+        flags,
+        payload: data.get(header_len..)?.to_vec(),
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageKind;
+
+    use bytes::BufMut;
+
+    use std::fs;
+
+    const ROUTER: [u8; 4] = [192, 0, 2, 254];
+    const COLLECTOR: [u8; 4] = [198, 51, 100, 10];
+
+    // Ethernet + IPv4 + TCP
+    fn packet(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.put_slice(&[0, 1, 2, 3, 4, 5, 0, 1, 2, 3, 4, 6]);
+        buf.put_u16(0x0800);
+
+        buf.put_u8(0x45);
+        buf.put_u8(0);
+        buf.put_u16((20 + 20 + payload.len()) as u16);
+        buf.put_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        buf.put_slice(&ROUTER);
+        buf.put_slice(&COLLECTOR);
+
+        buf.put_u16(50000);
+        buf.put_u16(11019);
+        buf.put_u32(seq);
+        buf.put_u32(0);
+        buf.put_u8(5 << 4);
+        buf.put_u8(flags);
+        buf.put_slice(&[0xff, 0xff, 0, 0, 0, 0]);
+        buf.put_slice(payload);
+
+        buf
+    }
+
+    fn pcap(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![];
+        buf.put_slice(&[0xd4, 0xc3, 0xb2, 0xa1]);
+        buf.put_u16_le(2);
+        buf.put_u16_le(4);
+        buf.put_slice(&[0; 8]);
+        buf.put_u32_le(65535);
+        buf.put_u32_le(LINKTYPE_ETHERNET);
+
+        for (idx, packet) in packets.iter().enumerate() {
+            buf.put_u32_le(1_577_836_800 + idx as u32);
+            buf.put_u32_le(0);
+            buf.put_u32_le(packet.len() as u32);
+            buf.put_u32_le(packet.len() as u32);
+            buf.put_slice(packet);
+        }
+
+        buf
+    }
+
+    fn pcapng(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut buf = vec![];
+        // Section header
+        buf.put_u32(PCAPNG_SHB);
+        buf.put_u32(28);
+        buf.put_slice(&[0x1a, 0x2b, 0x3c, 0x4d, 0, 1, 0, 0]);
+        buf.put_i64(-1);
+        buf.put_u32(28);
+        // Interface description
+        buf.put_u32(PCAPNG_IDB);
+        buf.put_u32(20);
+        buf.put_u16(LINKTYPE_ETHERNET as u16);
+        buf.put_u16(0);
+        buf.put_u32(65535);
+        buf.put_u32(20);
+
+        for packet in packets {
+            let padded = packet.len().div_ceil(4) * 4;
+            buf.put_u32(PCAPNG_EPB);
+            buf.put_u32((32 + padded) as u32);
+            buf.put_slice(&[0; 12]);
+            buf.put_u32(packet.len() as u32);
+            buf.put_u32(packet.len() as u32);
+            buf.put_slice(packet);
+            buf.put_slice(&vec![0; padded - packet.len()]);
+            buf.put_u32((32 + padded) as u32);
+        }
+
+        buf
+    }
+
+    // Split the synthetic dump into TCP segments
+    fn segments(size: usize) -> Vec<(u32, Vec<u8>)> {
+        let data = fs::read("test_data/synthetic.dump").unwrap();
+        let isn = u32::MAX - 100;
+
+        data.chunks(size)
+            .enumerate()
+            .map(|(idx, chunk)| (isn.wrapping_add(1 + (idx * size) as u32), chunk.to_vec()))
+            .collect()
+    }
+
+    fn kinds(items: Vec<Result<(Flow, BmpMessage)>>) -> Vec<MessageKind> {
+        items.into_iter()
+            .map(|item| item.unwrap().1.kind)
+            .collect()
+    }
+
+    const ALL: [MessageKind; 10] = [
+        MessageKind::Initiation,
+        MessageKind::PeerUp,
+        MessageKind::PeerUp,
+        MessageKind::RouteMonitoring,
+        MessageKind::RouteMonitoring,
+        MessageKind::RouteMonitoring,
+        MessageKind::RouteMonitoring,
+        MessageKind::PeerDown,
+        MessageKind::PeerDown,
+        MessageKind::Termination,
+    ];
+
+    #[test]
+    fn test_pcap_reassembly() {
+        let segments = segments(97);
+        let isn = segments[0].0.wrapping_sub(1);
+
+        // SYN, then the segments out of order with a couple of retransmits thrown in
+        let mut packets = vec![packet(isn, TCP_SYN, &[])];
+        for chunk in segments.chunks(3) {
+            for (seq, payload) in chunk.iter().rev() {
+                packets.push(packet(*seq, 0x18, payload));
+            }
+            packets.push(packet(chunk[0].0, 0x18, &chunk[0].1));
+        }
+
+        let items = PcapReader::new(&pcap(&packets)[..]).collect::<Vec<_>>();
+        let flow = *items[0].as_ref().map(|(flow, _)| flow).unwrap();
+        assert_eq!(flow.src, SocketAddr::new(ROUTER.into(), 50000));
+        assert_eq!(kinds(items), ALL.to_vec());
+
+        let items = PcapReader::new(&pcapng(&packets)[..]).with_port(11019).collect::<Vec<_>>();
+        assert_eq!(kinds(items), ALL.to_vec());
+
+        let items = PcapReader::new(&pcap(&packets)[..]).with_port(179).collect::<Vec<_>>();
+        assert!(items.is_empty());
+    }
+
+    #[test]
+    fn test_pcap_malformed() {
+        let full = packet(1, 0x18, &[3, 0, 0, 0, 6, 4]);
+        assert!(parse_packet(LINKTYPE_ETHERNET, &full).is_some());
+
+        // Packets cut short anywhere, or with header lengths under the minimum, are skipped
+        for len in 0..full.len() - 6 {
+            assert!(parse_packet(LINKTYPE_ETHERNET, &full[..len]).is_none(), "length {}", len);
+        }
+        let mut bad = full.clone();
+        bad[14] = 0x44;
+        assert!(parse_packet(LINKTYPE_ETHERNET, &bad).is_none());
+        let mut bad = full.clone();
+        bad[14 + 20 + 12] = 4 << 4;
+        assert!(parse_packet(LINKTYPE_ETHERNET, &bad).is_none());
+
+        // Record lengths over the snaplen or block limit are errors rather than huge allocations
+        let mut data = pcap(std::slice::from_ref(&full));
+        data[24 + 8..24 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let items = PcapReader::new(&data[..]).collect::<Vec<_>>();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_err());
+
+        // Even when the file header claims a huge snaplen
+        let mut data = pcap(std::slice::from_ref(&full));
+        data[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        data[24 + 8..24 + 12].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        data[24 + 12..24 + 16].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        let items = PcapReader::new(&data[..]).collect::<Vec<_>>();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].as_ref().unwrap_err().to_string().contains("over the limit"));
+
+        let mut data = pcapng(&[full]);
+        data[48 + 4..48 + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+        let items = PcapReader::new(&data[..]).collect::<Vec<_>>();
+        assert_eq!(items.len(), 1);
+        assert!(items[0].is_err());
+    }
+
+    #[test]
+    fn test_pcap_gap() {
+        // The 6th segment (the middle of the 2nd Peer Up) never makes it into the capture, nor does the SYN
+        let packets = segments(50).into_iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != 5)
+            .map(|(_, (seq, payload))| packet(seq, 0x18, &payload))
+            .collect::<Vec<_>>();
+
+        let items = PcapReader::new(&pcap(&packets)[..]).collect::<Vec<_>>();
+        let (ok, errors): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| item.is_ok());
+
+        // The IPv6 peer never came up, so its Route Monitoring messages can't be decoded
+        assert_eq!(errors.len(), 2);
+        assert_eq!(kinds(ok), vec![
+            MessageKind::Initiation,
+            MessageKind::PeerUp,
+            MessageKind::RouteMonitoring,
+            MessageKind::RouteMonitoring,
+            MessageKind::PeerDown,
+            MessageKind::PeerDown,
+            MessageKind::Termination,
+        ]);
+    }
+}
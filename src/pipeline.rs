@@ -0,0 +1,260 @@
+//! Decoding on more than one core
+//!
+//! Most of the work in decoding a BMP feed is parsing the BGP UPDATEs inside Route Monitoring
+//! messages. `ParallelReader` reads and frames messages, and tracks Peer Up / Peer Down
+//! capabilities, on the calling thread as `BmpReader` does, then hands each UPDATE to a pool of
+//! worker threads along with a snapshot of its peer's capabilities. Messages come back out in the
+//! order they were read, so the output is exactly what `BmpReader` would produce.
+//!
+//! ```
+//! # use bmp_protocol::{BmpReader, pipeline::ParallelReader};
+//! let reader = BmpReader::from_path("test_data/synthetic.dump").unwrap();
+//!
+//! for message in ParallelReader::new(reader).with_threads(4) {
+//!     println!("{}", message.unwrap().kind);
+//! }
+//! ```
+
+use crate::{BmpDecoder, BmpReader, Error, Result, types::BmpMessage};
+use crate::decoder::{Frame, PendingUpdate};
+
+use hashbrown::HashMap;
+
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{self, JoinHandle};
+
+// Messages each worker may have in flight before we wait for the oldest to come back
+const WINDOW_PER_THREAD: usize = 64;
+
+/// Reads BMP messages, parsing Route Monitoring messages on a pool of worker threads
+///
+/// The workers are started on the first call to `next()` and stopped when the reader is dropped.
+#[derive(Debug)]
+pub struct ParallelReader<R> {
+    reader: BmpReader<R>,
+    threads: usize,
+    workers: Option<Workers>,
+    // How the workers parse an UPDATE, only replaced in tests
+    decode: fn(PendingUpdate) -> Result<BmpMessage>,
+    // Messages are numbered as they're read, and wait here until it's their turn
+    finished: HashMap<u64, Result<BmpMessage>>,
+    next_in: u64,
+    next_out: u64,
+    done: bool,
+}
+
+#[derive(Debug)]
+struct Workers {
+    jobs: Option<mpsc::Sender<(u64, PendingUpdate)>>,
+    results: mpsc::Receiver<(u64, Result<BmpMessage>)>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl<R: Read> ParallelReader<R> {
+    /// Decode messages from `reader`, using one worker per available core
+    pub fn new(reader: BmpReader<R>) -> Self {
+        let threads = thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1);
+
+        Self {
+            reader,
+            threads,
+            workers: None,
+            decode: PendingUpdate::decode,
+            finished: HashMap::new(),
+            next_in: 0,
+            next_out: 0,
+            done: false,
+        }
+    }
+
+    /// Set the number of worker threads (at least one)
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// The decoder, and the capabilities it has learned
+    pub fn decoder(&self) -> &BmpDecoder {
+        self.reader.decoder()
+    }
+
+    // Read ahead until the window is full, sending UPDATEs off to the workers
+    fn read_ahead(&mut self) {
+        let (threads, decode) = (self.threads, self.decode);
+        let workers = self.workers.get_or_insert_with(|| Workers::start(threads, decode));
+
+        while !self.done && self.next_in - self.next_out < (threads * WINDOW_PER_THREAD) as u64 {
+            let frame = match self.reader.next_frame() {
+                Some(frame) => frame,
+                None => {
+                    self.done = true;
+                    break;
+                },
+            };
+            let seq = self.next_in;
+            self.next_in += 1;
+
+            let result = match frame {
+                Ok(Frame::RouteMonitoring(update)) => {
+                    match workers.jobs.as_ref().map(|jobs| jobs.send((seq, update)).is_ok()) {
+                        Some(true) => continue,
+                        _ => Err(Error::decode("worker threads have stopped")),
+                    }
+                },
+                Ok(Frame::Message(message)) => Ok(message),
+                Err(err) => Err(err),
+            };
+            self.finished.insert(seq, result);
+        }
+    }
+
+    fn wait_for(&mut self, seq: u64) -> Result<BmpMessage> {
+        while !self.finished.contains_key(&seq) {
+            let workers = self.workers.as_ref().expect("workers are started before anything is read");
+            match workers.results.recv() {
+                Ok((done, result)) => { self.finished.insert(done, result); },
+                Err(_) => return Err(Error::decode("worker threads have stopped")),
+            };
+        }
+
+        self.finished.remove(&seq).unwrap()
+    }
+}
+
+impl<R: Read> Iterator for ParallelReader<R> {
+    type Item = Result<BmpMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_ahead();
+        if self.next_out == self.next_in {
+            return None;
+        }
+
+        let seq = self.next_out;
+        self.next_out += 1;
+        Some(self.wait_for(seq))
+    }
+}
+
+impl Workers {
+    fn start(threads: usize, decode: fn(PendingUpdate) -> Result<BmpMessage>) -> Self {
+        let (jobs, job_rx) = mpsc::channel::<(u64, PendingUpdate)>();
+        let (result_tx, results) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let handles = (0..threads)
+            .map(|i| {
+                let job_rx = Arc::clone(&job_rx);
+                let result_tx = result_tx.clone();
+
+                thread::Builder::new()
+                    .name(format!("bmp-decode-{}", i))
+                    .spawn(move || loop {
+                        // Hold the lock just long enough to take a job
+                        let job = job_rx.lock().map_err(drop).and_then(|rx| rx.recv().map_err(drop));
+                        let (seq, update) = match job {
+                            Ok(job) => job,
+                            Err(()) => break,
+                        };
+                        // A panic would leave the reader waiting forever for this result
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| decode(update)))
+                            .unwrap_or_else(|_| Err(Error::decode("worker thread panicked parsing a Route Monitoring message")));
+                        if result_tx.send((seq, result)).is_err() {
+                            break;
+                        }
+                    })
+                    .expect("failed to spawn decode thread")
+            })
+            .collect();
+
+        Self { jobs: Some(jobs), results, handles }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        // Closing the job channel lets the workers finish up
+        self.jobs.take();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::generator::{Generator, Rng};
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn outputs<I: Iterator<Item = Result<BmpMessage>>>(messages: I) -> Vec<std::result::Result<Vec<u8>, String>> {
+        messages
+            .map(|message| message.map(|message| message.raw.to_vec()).map_err(|err| err.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_reader() {
+        for seed in 0..4 {
+            let mut data = Generator::new(seed)
+                .with_peers(6)
+                .with_routes(200)
+                .with_churn(100)
+                .with_flaps(2)
+                .flat_map(|frame| frame.to_vec())
+                .collect::<Vec<_>>();
+
+            // Damage the end of some messages, which is mostly inside the BGP UPDATEs
+            let mut rng = Rng(seed);
+            let mut offset = 0;
+            while offset < data.len() {
+                let length = u32::from_be_bytes([data[offset + 1], data[offset + 2], data[offset + 3], data[offset + 4]]) as usize;
+                if rng.below(6) == 0 {
+                    data[offset + length - 1 - rng.below(8) as usize] ^= 0xff;
+                }
+                offset += length;
+            }
+            // And finish part way through a message
+            data.truncate(data.len() - 3);
+
+            let expected = outputs(BmpReader::new(&data[..]));
+            assert!(expected.iter().any(|output| output.is_err()));
+
+            for &threads in &[1, 3, 8] {
+                let reader = ParallelReader::new(BmpReader::new(&data[..])).with_threads(threads);
+                assert_eq!(outputs(reader), expected, "seed {} with {} threads", seed, threads);
+            }
+        }
+
+        // Stopping early shuts the workers down cleanly
+        let data = Generator::new(1).with_routes(500).flat_map(|frame| frame.to_vec()).collect::<Vec<_>>();
+        let mut reader = ParallelReader::new(BmpReader::new(&data[..])).with_threads(2);
+        assert!(reader.by_ref().take(10).all(|message| message.is_ok()));
+        drop(reader);
+    }
+
+    #[test]
+    fn test_parallel_reader_panic() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let data = Generator::new(2).with_routes(50).flat_map(|frame| frame.to_vec()).collect::<Vec<_>>();
+        let expected = outputs(BmpReader::new(&data[..]));
+
+        // One worker dies part way through, the others carry on and the reader doesn't hang
+        let mut reader = ParallelReader::new(BmpReader::new(&data[..])).with_threads(3);
+        reader.decode = |update| match CALLS.fetch_add(1, Ordering::SeqCst) {
+            5 => panic!("boom"),
+            _ => update.decode(),
+        };
+        let outputs = outputs(reader);
+
+        assert_eq!(outputs.len(), expected.len());
+        let errors = outputs.iter().zip(&expected).filter(|(output, expected)| output != expected).collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].0.as_ref().unwrap_err().contains("panicked"), "{:?}", errors[0]);
+    }
+}
@@ -0,0 +1,262 @@
+//! pmacct-compatible JSON output
+//!
+//! Renders decoded messages in the JSON schema used by pmacct's `bmp_daemon` message logging so
+//! existing consumers of pmacct output can be pointed at this crate instead. Route Monitoring
+//! messages produce one event per prefix.
+//!
+//! ```
+//! # use bmp_protocol::{BmpReader, pmacct::PmacctFormatter};
+//! # let input = std::io::empty();
+//! let mut formatter = PmacctFormatter::new("192.0.2.254:50000".parse().unwrap());
+//!
+//! for message in BmpReader::new(input) {
+//!     for event in formatter.events(&message.unwrap()) {
+//!         println!("{}", event);
+//!     }
+//! }
+//! ```
+
+use crate::{
+    route::{decode_ext_community, routes, RouteAttributes},
+    types::*,
+    util::{format_rd, format_time},
+};
+
+use bgp_rs::Origin;
+use serde_json::{Map, Value};
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::SystemTime;
+
+/// Builds pmacct `bmp_daemon` log events for the messages from a single router
+#[derive(Clone, Debug)]
+pub struct PmacctFormatter {
+    router: SocketAddr,
+    seq: u64,
+}
+
+impl PmacctFormatter {
+    /// Create a new formatter for messages from `router`
+    pub fn new(router: SocketAddr) -> Self {
+        Self { router, seq: 0 }
+    }
+
+    /// Build the events for a message
+    pub fn events(&mut self, message: &BmpMessage) -> Vec<Value> {
+        match &message.message {
+            MessageData::Initiation(tlv) => {
+                let mut event = self.event("init", SystemTime::now());
+                for info in tlv {
+                    let (key, value) = match info {
+                        InformationTlv::String(value) => ("bmp_init_info_string", value),
+                        InformationTlv::SysDescr(value) => ("bmp_init_info_sysdescr", value),
+                        InformationTlv::SysName(value) => ("bmp_init_info_sysname", value),
+                        _ => continue,
+                    };
+                    event.insert(key.into(), value.clone().into());
+                }
+
+                vec![event.into()]
+            },
+            MessageData::Termination(tlv) => {
+                let mut event = self.event("term", SystemTime::now());
+                for info in tlv {
+                    match info {
+                        TerminationTlv::String(value) => event.insert("bmp_term_info_string".into(), value.clone().into()),
+                        TerminationTlv::Reason(reason) => event.insert("bmp_term_info_reason".into(), reason.to_string().into()),
+                        TerminationTlv::Unknown { .. } => continue,
+                    };
+                }
+
+                vec![event.into()]
+            },
+            MessageData::PeerUp((header, peer_up)) => {
+                let mut event = self.peer_event("peer_up", header);
+                if let Some(open) = &peer_up.sent_open {
+                    event.insert("bgp_id".into(), Ipv4Addr::from(open.identifier).to_string().into());
+                }
+                event.insert("local_ip".into(), peer_up.local_addr.to_string().into());
+                event.insert("local_port".into(), peer_up.local_port.into());
+                event.insert("remote_port".into(), peer_up.remote_port.into());
+
+                vec![event.into()]
+            },
+            MessageData::PeerDown((header, peer_down)) => {
+                let mut event = self.peer_event("peer_down", header);
+
+                let text = match peer_down {
+                    PeerDown::LocalShutdown(_) => "The local system closed the session with a notification",
+                    PeerDown::LocalTerminate(fsm_event) => {
+                        event.insert("reason_loc_code".into(), fsm_event.code().into());
+                        "The local system closed the session without a notification"
+                    },
+                    PeerDown::RemoteShutdown(_) => "The remote system closed the session with a notification",
+                    PeerDown::RemoteTerminate => "The remote system closed the session without a notification",
+                    PeerDown::ConfigurationChange => "Information for this peer will no longer be sent for configuration reasons",
+                    PeerDown::LocalClosed(_) => "The local system closed the session, TLV data follows",
+                    PeerDown::Unknown(..) => "Unknown reason",
+                };
+                event.insert("reason_type".into(), peer_down.reason().into());
+                event.insert("reason_str".into(), text.into());
+
+                vec![event.into()]
+            },
+            MessageData::RouteMonitoring((header, update)) => {
+                let attrs = RouteAttributes::from_update(update);
+
+                routes(update).into_iter()
+                    .map(|route| {
+                        let mut event = self.peer_event("route_monitor", header);
+                        event.insert("afi".into(), (route.afi as u16).into());
+                        event.insert("safi".into(), (route.safi as u8).into());
+                        event.insert("ip_prefix".into(), format!("{}/{}", route.prefix, route.length).into());
+                        if let Some(path_id) = route.path_id {
+                            event.insert("path_id".into(), path_id.into());
+                        }
+
+                        if route.withdrawn {
+                            event.insert("log_type".into(), "withdraw".into());
+                        } else {
+                            event.insert("log_type".into(), "update".into());
+                            route_attributes(&mut event, &attrs);
+                        }
+
+                        event.into()
+                    })
+                    .collect()
+            },
+            _ => vec![],
+        }
+    }
+
+    fn event(&mut self, kind: &str, timestamp: SystemTime) -> Map<String, Value> {
+        let mut event = Map::new();
+        event.insert("event_type".into(), "log".into());
+        event.insert("seq".into(), self.seq.into());
+        event.insert("timestamp".into(), format_time(timestamp).into());
+        event.insert("bmp_router".into(), self.router.ip().to_string().into());
+        event.insert("bmp_router_port".into(), self.router.port().into());
+        event.insert("bmp_msg_type".into(), kind.into());
+
+        self.seq += 1;
+        event
+    }
+
+    fn peer_event(&mut self, kind: &str, header: &PeerHeader) -> Map<String, Value> {
+        let mut event = self.event(kind, header.system_time());
+
+        let peer_type_str = match header.peer_type {
+            PeerType::GlobalInstance => "Global Instance Peer",
+            PeerType::RdInstance => "RD Instance Peer",
+            PeerType::LocalInstance => "Local Instance Peer",
+            PeerType::LocRibInstance => "Loc-RIB Instance Peer",
+        };
+
+        event.insert("peer_ip".into(), header.peer_addr.to_string().into());
+        event.insert("peer_asn".into(), header.peer_asn.into());
+        event.insert("peer_type".into(), (header.peer_type as u8).into());
+        event.insert("peer_type_str".into(), peer_type_str.into());
+        if matches!(header.peer_type, PeerType::RdInstance | PeerType::LocRibInstance) {
+            event.insert("rd".into(), format_rd(header.peer_distinguisher).into());
+        }
+        event.insert("is_in".into(), u8::from(!header.peer_flags.O).into());
+        event.insert("is_out".into(), u8::from(header.peer_flags.O).into());
+        event.insert("is_post".into(), u8::from(header.peer_flags.L).into());
+
+        event
+    }
+}
+
+fn route_attributes(event: &mut Map<String, Value>, attrs: &RouteAttributes) {
+    let ecomms = attrs.ext_communities.iter()
+        .map(|community| match decode_ext_community(*community) {
+            Some((kind, global, local)) => format!("{}:{}:{}", kind, global, local),
+            None => format!("{:016x}", community),
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(next_hop) = attrs.next_hop {
+        event.insert("bgp_nexthop".into(), next_hop.to_string().into());
+    }
+    event.insert("as_path".into(), attrs.as_path_string().into());
+    event.insert("comms".into(), attrs.community_strings().join(" ").into());
+    event.insert("ecomms".into(), ecomms.join(" ").into());
+    event.insert("lcomms".into(), attrs.large_community_strings().join(" ").into());
+
+    if let Some(origin) = &attrs.origin {
+        let origin = match origin {
+            Origin::IGP => "i",
+            Origin::EGP => "e",
+            Origin::INCOMPLETE => "u",
+        };
+        event.insert("origin".into(), origin.into());
+    }
+    if let Some(local_pref) = attrs.local_pref {
+        event.insert("local_pref".into(), local_pref.into());
+    }
+    if let Some(med) = attrs.med {
+        event.insert("med".into(), med.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BmpDecoder;
+
+    use bytes::BytesMut;
+    use serde_json::json;
+
+    use std::fs;
+
+    #[test]
+    fn test_pmacct_events() {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut formatter = PmacctFormatter::new("192.0.2.254:50000".parse().unwrap());
+
+        let mut events = vec![];
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            events.extend(formatter.events(&message));
+        }
+
+        let types = events.iter()
+            .map(|event| event["bmp_msg_type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![
+            "init", "peer_up", "peer_up", "route_monitor", "route_monitor", "route_monitor", "peer_down", "peer_down", "term",
+        ]);
+
+        assert_eq!(events[3], json!({
+            "event_type": "log",
+            "seq": 3,
+            "timestamp": "2020-01-01 00:00:00.000000",
+            "bmp_router": "192.0.2.254",
+            "bmp_router_port": 50000,
+            "bmp_msg_type": "route_monitor",
+            "peer_ip": "192.0.2.1",
+            "peer_asn": 65001,
+            "peer_type": 0,
+            "peer_type_str": "Global Instance Peer",
+            "is_in": 1,
+            "is_out": 0,
+            "is_post": 0,
+            "afi": 1,
+            "safi": 1,
+            "ip_prefix": "198.51.100.0/24",
+            "log_type": "update",
+            "bgp_nexthop": "192.0.2.1",
+            "as_path": "65001 64512",
+            "comms": "",
+            "ecomms": "",
+            "lcomms": "",
+            "origin": "i",
+        }));
+
+        assert_eq!(events[5]["ip_prefix"], "2001:db8:1000::/36");
+        assert_eq!(events[5]["afi"], 2);
+        assert_eq!(events[6]["reason_type"], 2);
+        assert_eq!(events[6]["reason_loc_code"], 9);
+        assert_eq!(events[8]["bmp_term_info_string"], "shutting down");
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{BmpDecoder, Error, Result, types::BmpMessage};
+use crate::decoder::{Frame, take_frame};
+
+use bytes::{Bytes, BytesMut};
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::Path;
+
+const READ_SIZE: usize = 64 * 1024;
+
+/// Blocking reader producing BMP messages from anything that implements `std::io::Read`
+///
+/// Messages that fail to decode are returned as errors and reading carries on with the next one.
+/// If a common header can't be decoded, or the input ends part way through a message, that is
+/// returned as an error and iteration stops, since there is no way to find the next message.
+///
+/// ```
+/// # use bmp_protocol::BmpReader;
+/// let reader = BmpReader::from_path("test_data/synthetic.dump").unwrap();
+///
+/// for message in reader {
+///     println!("{}", message.unwrap().kind);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BmpReader<R> {
+    inner: R,
+    decoder: BmpDecoder,
+    buf: BytesMut,
+    chunk: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> BmpReader<R> {
+    /// Read BMP messages from `inner`
+    pub fn new(inner: R) -> Self {
+        Self::with_decoder(inner, BmpDecoder::new())
+    }
+
+    /// Read BMP messages from `inner` using an existing decoder, so peers it has already seen
+    /// Peer Up messages for can be decoded
+    pub fn with_decoder(inner: R, decoder: BmpDecoder) -> Self {
+        Self {
+            inner,
+            decoder,
+            buf: BytesMut::with_capacity(READ_SIZE),
+            chunk: vec![0; READ_SIZE],
+            done: false,
+        }
+    }
+
+    /// The decoder, and the capabilities it has learned
+    pub fn decoder(&self) -> &BmpDecoder {
+        &self.decoder
+    }
+
+    /// Get back the underlying reader, anything read from it that hasn't been decoded is lost
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The next frame, partially decoded, for `ParallelReader`
+    pub(crate) fn next_frame(&mut self) -> Option<Result<Frame>> {
+        self.next_with(BmpDecoder::prepare_frame)
+    }
+
+    fn next_with<T>(&mut self, mut decode: impl FnMut(&mut BmpDecoder, Bytes) -> Result<T>) -> Option<Result<T>> {
+        while !self.done {
+            match take_frame(&mut self.buf) {
+                Ok(Some(raw)) => return Some(decode(&mut self.decoder, raw)),
+                Ok(None) => {},
+                Err(err) => {
+                    // A bad header means there's no getting past it
+                    self.done = true;
+                    return Some(Err(err));
+                },
+            };
+
+            match self.fill_buf() {
+                Ok(0) => {
+                    self.done = true;
+                    if !self.buf.is_empty() {
+                        return Some(Err(Error::decode(&format!("{} bytes of incomplete message at end of input", self.buf.len()))));
+                    }
+                },
+                Ok(_) => {},
+                Err(err) => {
+                    // Interrupted reads are already retried, anything else won't get better
+                    self.done = true;
+                    return Some(Err(err));
+                },
+            };
+        }
+
+        None
+    }
+
+    fn fill_buf(&mut self) -> Result<usize> {
+        loop {
+            match self.inner.read(&mut self.chunk) {
+                Ok(read) => {
+                    self.buf.extend_from_slice(&self.chunk[..read]);
+                    return Ok(read);
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+        }
+    }
+}
+
+impl BmpReader<Box<dyn Read + Send>> {
+    /// Open a dump file for reading
+    ///
+    /// Files ending `.gz`, `.bz2` or `.zst` are decompressed on the fly, which needs the `gzip`,
+    /// `bzip2` or `zstd` feature respectively (or `compression` for all three).
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = BufReader::new(File::open(path)?);
+        let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+
+        Ok(Self::new(decompress(file, extension)?))
+    }
+}
+
+fn decompress(file: BufReader<File>, extension: &str) -> Result<Box<dyn Read + Send>> {
+    match extension {
+        #[cfg(feature = "gzip")]
+        "gz" => return Ok(Box::new(flate2::bufread::MultiGzDecoder::new(file))),
+        #[cfg(feature = "bzip2")]
+        "bz2" => return Ok(Box::new(bzip2::bufread::MultiBzDecoder::new(file))),
+        #[cfg(feature = "zstd")]
+        "zst" => return Ok(Box::new(zstd::Decoder::with_buffer(file)?)),
+        _ => {},
+    };
+
+    let feature = match extension {
+        "gz" => "gzip",
+        "bz2" => "bzip2",
+        "zst" => "zstd",
+        _ => return Ok(Box::new(file)),
+    };
+    Err(io::Error::new(ErrorKind::Unsupported, format!("reading .{} files needs the `{}` feature", extension, feature)).into())
+}
+
+impl<R: Read> Iterator for BmpReader<R> {
+    type Item = Result<BmpMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(BmpDecoder::decode_frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::generator::Generator;
+
+    use std::fs;
+    use std::io::Write;
+
+    /// Hands out data a few bytes at a time, with the odd interruption
+    struct Trickle<'a>(&'a [u8], usize);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 += 1;
+            if self.1.is_multiple_of(5) {
+                return Err(io::Error::new(ErrorKind::Interrupted, "interrupted"));
+            }
+
+            let len = buf.len().min(self.0.len()).min(1 + self.1 % 13);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_reader() {
+        let data = Generator::new(7)
+            .with_peers(3)
+            .with_routes(20)
+            .flat_map(|frame| frame.to_vec())
+            .collect::<Vec<_>>();
+
+        let expected = BmpReader::new(&data[..])
+            .map(|message| message.unwrap().raw)
+            .collect::<Vec<_>>();
+        assert_eq!(expected.concat(), data);
+
+        let trickled = BmpReader::new(Trickle(&data, 0))
+            .map(|message| message.unwrap().raw)
+            .collect::<Vec<_>>();
+        assert_eq!(trickled, expected);
+
+        // A truncated message at the end is an error, and then we're done
+        let mut reader = BmpReader::new(&data[..data.len() - 1]);
+        assert_eq!(reader.by_ref().take(expected.len() - 1).filter(Result::is_ok).count(), expected.len() - 1);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        // As is a bad common header
+        let mut reader = BmpReader::new(&[3, 0, 0, 0, 1, 4, 0, 0][..]);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_read_error() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        let mut reader = BmpReader::new(Failing);
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_from_path() {
+        let plain = fs::read("test_data/synthetic.dump").unwrap();
+        let expected = BmpReader::new(&plain[..]).count();
+        assert_eq!(BmpReader::from_path("test_data/synthetic.dump").unwrap().count(), expected);
+
+        // Whichever compression features are enabled
+        #[allow(unused_mut)]
+        let mut files: Vec<(&str, Vec<u8>)> = vec![];
+        #[cfg(feature = "gzip")]
+        files.push(("gz", {
+            let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(&plain).unwrap();
+            encoder.finish().unwrap()
+        }));
+        #[cfg(feature = "bzip2")]
+        files.push(("bz2", {
+            let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+            encoder.write_all(&plain).unwrap();
+            encoder.finish().unwrap()
+        }));
+        #[cfg(feature = "zstd")]
+        files.push(("zst", zstd::encode_all(&plain[..], 0).unwrap()));
+
+        for (extension, data) in files {
+            let path = std::env::temp_dir().join(format!("bmp-protocol-{}.dump.{}", std::process::id(), extension));
+            fs::File::create(&path).unwrap().write_all(&data).unwrap();
+
+            let messages = BmpReader::from_path(&path).unwrap()
+                .map(|message| message.unwrap().raw)
+                .collect::<Vec<_>>();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(messages.concat(), plain, ".{}", extension);
+        }
+    }
+}
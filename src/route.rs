@@ -0,0 +1,278 @@
+//! Per-prefix view of Route Monitoring messages
+//!
+//! `routes` flattens an UPDATE into the individual prefixes it announces or withdraws and
+//! `RouteAttributes` pulls out the commonly used path attributes along with some helpers to
+//! format them.
+
+use bgp_rs::{AFI, NLRIEncoding, Origin, PathAttribute, SAFI, Segment, Update};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Path attributes from an UPDATE, flattened out for output formats
+#[derive(Clone, Debug, Default)]
+pub struct RouteAttributes {
+    /// ORIGIN
+    pub origin: Option<Origin>,
+    /// AS_PATH segments
+    pub as_path: Vec<Segment>,
+    /// NEXT_HOP, or the next hop from MP_REACH_NLRI
+    pub next_hop: Option<IpAddr>,
+    /// MULTI_EXIT_DISC
+    pub med: Option<u32>,
+    /// LOCAL_PREF
+    pub local_pref: Option<u32>,
+    /// AGGREGATOR
+    pub aggregator: Option<(u32, Ipv4Addr)>,
+    /// Whether ATOMIC_AGGREGATE is present
+    pub atomic_aggregate: bool,
+    /// COMMUNITIES
+    pub communities: Vec<u32>,
+    /// EXTENDED_COMMUNITIES
+    pub ext_communities: Vec<u64>,
+    /// LARGE_COMMUNITY
+    pub large_communities: Vec<(u32, u32, u32)>,
+    /// ORIGINATOR_ID
+    pub originator_id: Option<u32>,
+    /// CLUSTER_LIST
+    pub cluster_list: Vec<u32>,
+}
+
+impl RouteAttributes {
+    /// Collect the attributes from an UPDATE
+    pub fn from_update(update: &Update) -> Self {
+        let mut attrs = Self::default();
+
+        for attr in &update.attributes {
+            match attr {
+                PathAttribute::ORIGIN(origin) => attrs.origin = Some(origin.clone()),
+                PathAttribute::AS_PATH(path) => attrs.as_path = path.segments.clone(),
+                PathAttribute::NEXT_HOP(next_hop) => attrs.next_hop = Some(*next_hop),
+                PathAttribute::MULTI_EXIT_DISC(med) => attrs.med = Some(*med),
+                PathAttribute::LOCAL_PREF(pref) => attrs.local_pref = Some(*pref),
+                PathAttribute::AGGREGATOR(aggregator) => attrs.aggregator = Some(*aggregator),
+                PathAttribute::ATOMIC_AGGREGATOR => attrs.atomic_aggregate = true,
+                PathAttribute::COMMUNITY(communities) => attrs.communities = communities.clone(),
+                PathAttribute::EXTENDED_COMMUNITIES(communities) => attrs.ext_communities = communities.clone(),
+                PathAttribute::LARGE_COMMUNITY(communities) => attrs.large_communities = communities.clone(),
+                PathAttribute::ORIGINATOR_ID(id) => attrs.originator_id = Some(*id),
+                PathAttribute::CLUSTER_LIST(list) => attrs.cluster_list = list.clone(),
+                PathAttribute::MP_REACH_NLRI(reach) => {
+                    // For IPv6 there may be a link-local address as well, the global one comes first
+                    attrs.next_hop = match reach.next_hop.len() {
+                        4 => Some(IpAddr::V4(Ipv4Addr::new(reach.next_hop[0], reach.next_hop[1], reach.next_hop[2], reach.next_hop[3]))),
+                        16 | 32 => {
+                            let mut addr = [0u8; 16];
+                            addr.copy_from_slice(&reach.next_hop[..16]);
+                            Some(IpAddr::V6(Ipv6Addr::from(addr)))
+                        },
+                        _ => attrs.next_hop,
+                    };
+                },
+                _ => {},
+            };
+        }
+
+        attrs
+    }
+
+    /// AS_PATH as a string, sets are wrapped in braces (eg. "64512 64513 {64514,64515}")
+    pub fn as_path_string(&self) -> String {
+        self.as_path.iter()
+            .map(|segment| match segment {
+                Segment::AS_SEQUENCE(asns) => join(asns, " "),
+                Segment::AS_SET(asns) => format!("{{{}}}", join(asns, ",")),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Number of ASNs in the AS_PATH, an AS_SET counts as one
+    pub fn as_path_count(&self) -> usize {
+        self.as_path.iter()
+            .map(|segment| match segment {
+                Segment::AS_SEQUENCE(asns) => asns.len(),
+                Segment::AS_SET(_) => 1,
+            })
+            .sum()
+    }
+
+    /// Originating ASN, if the path doesn't end with an AS_SET
+    pub fn origin_as(&self) -> Option<u32> {
+        match self.as_path.last()? {
+            Segment::AS_SEQUENCE(asns) => asns.last().copied(),
+            Segment::AS_SET(_) => None,
+        }
+    }
+
+    /// ORIGIN as igp, egp or incomplete
+    pub fn origin_string(&self) -> String {
+        match self.origin {
+            Some(Origin::IGP) => "igp".into(),
+            Some(Origin::EGP) => "egp".into(),
+            Some(Origin::INCOMPLETE) => "incomplete".into(),
+            None => String::new(),
+        }
+    }
+
+    /// Standard communities as "asn:value"
+    pub fn community_strings(&self) -> Vec<String> {
+        self.communities.iter()
+            .map(|community| format!("{}:{}", community >> 16, community & 0xffff))
+            .collect()
+    }
+
+    /// Extended communities as "rt=asn:value", anything other than route targets and sites of
+    /// origin is left as hex
+    pub fn ext_community_strings(&self) -> Vec<String> {
+        self.ext_communities.iter()
+            .map(|community| match decode_ext_community(*community) {
+                Some((kind, global, local)) => format!("{}={}:{}", kind.to_lowercase(), global, local),
+                None => format!("{:016x}", community),
+            })
+            .collect()
+    }
+
+    /// Large communities as "global:local1:local2"
+    pub fn large_community_strings(&self) -> Vec<String> {
+        self.large_communities.iter()
+            .map(|(global, local1, local2)| format!("{}:{}:{}", global, local1, local2))
+            .collect()
+    }
+
+    /// CLUSTER_LIST as dotted quads
+    pub fn cluster_list_strings(&self) -> Vec<String> {
+        self.cluster_list.iter()
+            .map(|id| Ipv4Addr::from(*id).to_string())
+            .collect()
+    }
+}
+
+/// Split a route target or site of origin into ("RT" | "SoO", global admin, local admin)
+pub(crate) fn decode_ext_community(community: u64) -> Option<(&'static str, String, u64)> {
+    let kind = (community >> 56) as u8;
+    let name = match (community >> 48) as u8 {
+        0x02 => "RT",
+        0x03 => "SoO",
+        _ => return None,
+    };
+
+    match kind {
+        // Two-octet AS specific
+        0x00 => Some((name, ((community >> 32) & 0xffff).to_string(), community & 0xffff_ffff)),
+        // IPv4 address specific
+        0x01 => Some((name, Ipv4Addr::from(((community >> 16) & 0xffff_ffff) as u32).to_string(), community & 0xffff)),
+        // Four-octet AS specific
+        0x02 => Some((name, ((community >> 16) & 0xffff_ffff).to_string(), community & 0xffff)),
+        _ => None,
+    }
+}
+
+fn join(asns: &[u32], sep: &str) -> String {
+    asns.iter()
+        .map(|asn| asn.to_string())
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+/// A single prefix from an UPDATE
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    /// Whether the prefix was withdrawn rather than announced
+    pub withdrawn: bool,
+    /// Address Family Identifier
+    pub afi: AFI,
+    /// Subsequent Address Family Identifier
+    pub safi: SAFI,
+    /// Prefix address
+    pub prefix: IpAddr,
+    /// Prefix length
+    pub length: u8,
+    /// ADD-PATH path ID
+    pub path_id: Option<u32>,
+}
+
+impl Route {
+    /// Whether this prefix is equal to or more specific than `prefix/length`
+    pub fn is_covered_by(&self, prefix: IpAddr, length: u8) -> bool {
+        if self.length < length {
+            return false;
+        }
+
+        match (self.prefix, prefix) {
+            (IpAddr::V4(addr), IpAddr::V4(other)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(length.min(32))).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(other) & mask
+            },
+            (IpAddr::V6(addr), IpAddr::V6(other)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(length.min(128))).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(other) & mask
+            },
+            _ => false,
+        }
+    }
+
+    fn from_nlri(nlri: &NLRIEncoding, safi: SAFI, withdrawn: bool) -> Option<Self> {
+        let (prefix, path_id) = match nlri {
+            NLRIEncoding::IP(prefix) => (prefix, None),
+            NLRIEncoding::IP_WITH_PATH_ID((prefix, path_id)) => (prefix, Some(*path_id)),
+            NLRIEncoding::IP_MPLS((prefix, _)) => (prefix, None),
+            NLRIEncoding::IP_MPLS_WITH_PATH_ID((prefix, _, path_id)) => (prefix, Some(*path_id)),
+            NLRIEncoding::IP_VPN_MPLS((_, prefix, _)) => (prefix, None),
+            _ => return None,
+        };
+        let afi = prefix.protocol;
+        let (prefix, length) = prefix.into();
+
+        Some(Self { withdrawn, afi, safi, prefix, length, path_id })
+    }
+}
+
+/// Every IP prefix announced or withdrawn by an UPDATE, including the MP_REACH / MP_UNREACH
+/// attributes. Announcements come first
+pub fn routes(update: &Update) -> Vec<Route> {
+    // Routes outside of MP_REACH / MP_UNREACH are always IPv4 unicast
+    let mut announced = update.announced_routes.iter().map(|nlri| (nlri, SAFI::Unicast)).collect::<Vec<_>>();
+    let mut withdrawn = update.withdrawn_routes.iter().map(|nlri| (nlri, SAFI::Unicast)).collect::<Vec<_>>();
+
+    for attr in &update.attributes {
+        match attr {
+            PathAttribute::MP_REACH_NLRI(reach) => {
+                announced.extend(reach.announced_routes.iter().map(|nlri| (nlri, reach.safi)));
+            },
+            PathAttribute::MP_UNREACH_NLRI(unreach) => {
+                withdrawn.extend(unreach.withdrawn_routes.iter().map(|nlri| (nlri, unreach.safi)));
+            },
+            _ => {},
+        };
+    }
+
+    announced.into_iter()
+        .filter_map(|(nlri, safi)| Route::from_nlri(nlri, safi, false))
+        .chain(withdrawn.into_iter().filter_map(|(nlri, safi)| Route::from_nlri(nlri, safi, true)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_covered_by() {
+        let route = |prefix: &str, length| Route {
+            withdrawn: false,
+            afi: AFI::IPV4,
+            safi: SAFI::Unicast,
+            prefix: prefix.parse().unwrap(),
+            length,
+            path_id: None,
+        };
+
+        assert!(route("198.51.100.0", 24).is_covered_by("198.51.100.0".parse().unwrap(), 24));
+        assert!(route("198.51.100.0", 24).is_covered_by("198.51.0.0".parse().unwrap(), 16));
+        assert!(route("198.51.100.0", 24).is_covered_by("0.0.0.0".parse().unwrap(), 0));
+        assert!(!route("198.51.100.0", 24).is_covered_by("198.51.100.0".parse().unwrap(), 25));
+        assert!(!route("198.51.100.0", 24).is_covered_by("203.0.113.0".parse().unwrap(), 24));
+        assert!(!route("198.51.100.0", 24).is_covered_by("::".parse().unwrap(), 0));
+        assert!(route("2001:db8:1000::", 36).is_covered_by("2001:db8::".parse().unwrap(), 32));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::types::*;
+
+use bgp_rs::{AFI, Capabilities, SAFI};
+use hashbrown::{HashMap, HashSet};
+
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+/// Which of a peer's RIBs a Route Monitoring message comes from, set by the L and O Peer Flags
+///
+/// A router may monitor a peer in several views, each sends its own initial dump and End-of-RIB
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct RibView {
+    /// Post-policy (L flag), otherwise pre-policy
+    pub post_policy: bool,
+    /// Adj-RIB-Out (O flag), otherwise Adj-RIB-In
+    pub adj_rib_out: bool,
+}
+
+impl From<PeerFlags> for RibView {
+    fn from(flags: PeerFlags) -> Self {
+        Self { post_policy: flags.L, adj_rib_out: flags.O }
+    }
+}
+
+/// A peer which is currently up on the monitored router
+#[derive(Clone, Debug)]
+pub struct PeerState {
+    /// Per-Peer Header from the Peer Up message
+    pub header: PeerHeader,
+    /// Peer Up message body
+    pub peer_up: PeerUp,
+    /// When the router reported the session as established
+    pub up_since: SystemTime,
+    /// Address families negotiated on the session, or set by a capability override
+    pub families: HashSet<(AFI, SAFI)>,
+    /// Address families for which an End-of-RIB marker has been received, for each view the
+    /// router has sent routes from
+    pub end_of_rib: HashMap<RibView, HashSet<(AFI, SAFI)>>,
+}
+
+impl PeerState {
+    fn new(header: PeerHeader, peer_up: PeerUp) -> Self {
+        let mut families: HashSet<(AFI, SAFI)> = match (&peer_up.capabilities, &peer_up.sent_open, &peer_up.recv_open) {
+            // What the decoder will use for the peer, which takes capability overrides into account
+            (Some(caps), _, _) => caps.multiprotocol.iter()
+                .filter_map(|family| Some((AFI::try_from(family.afi).ok()?, SAFI::try_from(family.safi).ok()?)))
+                .collect(),
+            (None, Some(s), Some(r)) => {
+                let local = Capabilities::from_parameters(s.parameters.clone()).MP_BGP_SUPPORT;
+                let remote = Capabilities::from_parameters(r.parameters.clone()).MP_BGP_SUPPORT;
+
+                local.intersection(&remote).copied().collect()
+            },
+            _ => HashSet::new(),
+        };
+
+        // Without any multiprotocol capabilities the session is IPv4 unicast only (RFC4760)
+        if families.is_empty() {
+            families.insert((AFI::IPV4, SAFI::Unicast));
+        }
+
+        Self {
+            up_since: header.system_time(),
+            header,
+            peer_up,
+            families,
+            end_of_rib: HashMap::new(),
+        }
+    }
+
+    /// Whether the initial table dump for an address family has completed in a view
+    pub fn is_synced(&self, view: RibView, afi: AFI, safi: SAFI) -> bool {
+        self.end_of_rib.get(&view).is_some_and(|synced| synced.contains(&(afi, safi)))
+    }
+
+    /// Whether the initial table dump has completed for every negotiated address family in a view
+    pub fn view_sync_complete(&self, view: RibView) -> bool {
+        self.end_of_rib.get(&view).is_some_and(|synced| self.families.is_subset(synced))
+    }
+
+    /// Whether the initial table dump has completed in every view the router has sent routes from
+    /// (and it has sent some)
+    pub fn initial_sync_complete(&self) -> bool {
+        !self.end_of_rib.is_empty() && self.end_of_rib.values().all(|synced| self.families.is_subset(synced))
+    }
+}
+
+/// Notable state changes produced while processing messages
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SessionEvent {
+    /// An End-of-RIB marker was received from a peer for an address family
+    EndOfRib {
+        /// Peer
+        peer: PeerKey,
+        /// View the marker was sent in
+        view: RibView,
+        /// Address Family Identifier
+        afi: AFI,
+        /// Subsequent Address Family Identifier
+        safi: SAFI,
+    },
+    /// End-of-RIB markers have now been received for every address family negotiated with
+    /// the peer, so the router has finished the initial table dump of that view
+    InitialSyncComplete {
+        /// Peer
+        peer: PeerKey,
+        /// View which has finished
+        view: RibView,
+    },
+}
+
+/// Counts of messages received for a single peer
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerCounters {
+    /// Route Monitoring messages
+    pub route_monitoring: u64,
+    /// Peer Up messages
+    pub peer_up: u64,
+    /// Peer Down messages
+    pub peer_down: u64,
+}
+
+/// State of a single BMP session (ie. one monitored router)
+///
+/// Feed every decoded message into `RouterSession::process` and the session can be queried at
+/// any time for the router information, the set of peers which are currently up and some
+/// per-peer counters.
+#[derive(Clone, Debug, Default)]
+pub struct RouterSession {
+    information: Vec<InformationTlv>,
+    peers: HashMap<PeerKey, PeerState>,
+    counters: HashMap<PeerKey, PeerCounters>,
+    last_peer_up: Option<SystemTime>,
+    last_peer_down: Option<SystemTime>,
+    termination: Option<Vec<TerminationTlv>>,
+}
+
+impl RouterSession {
+    /// Create a new, empty, session
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the session state with a decoded message, returning any resulting events
+    pub fn process(&mut self, message: &BmpMessage) -> Vec<SessionEvent> {
+        let mut events = vec![];
+
+        match &message.message {
+            MessageData::Initiation(tlv) => {
+                self.information = tlv.clone();
+            },
+            MessageData::PeerUp((header, peer_up)) => {
+                let key = PeerKey::from(header);
+                let state = PeerState::new(*header, peer_up.clone());
+
+                self.counters.entry(key).or_default().peer_up += 1;
+                self.last_peer_up = Some(state.up_since);
+                self.peers.insert(key, state);
+            },
+            MessageData::PeerDown((header, _)) => {
+                let key = PeerKey::from(header);
+
+                self.counters.entry(key).or_default().peer_down += 1;
+                self.peers.remove(&key);
+                self.last_peer_down = Some(header.system_time());
+            },
+            MessageData::RouteMonitoring((header, update)) => {
+                let key = PeerKey::from(header);
+                self.counters.entry(key).or_default().route_monitoring += 1;
+
+                if let Some(peer) = self.peers.get_mut(&key) {
+                    let view = RibView::from(header.peer_flags);
+                    let was_complete = peer.view_sync_complete(view);
+                    let synced = peer.end_of_rib.entry(view).or_default();
+
+                    if let Some((afi, safi)) = end_of_rib(update) {
+                        if synced.insert((afi, safi)) {
+                            events.push(SessionEvent::EndOfRib { peer: key, view, afi, safi });
+                        }
+                        if !was_complete && peer.view_sync_complete(view) {
+                            events.push(SessionEvent::InitialSyncComplete { peer: key, view });
+                        }
+                    }
+                }
+            },
+            MessageData::Termination(tlv) => {
+                self.peers.clear();
+                self.termination = Some(tlv.clone());
+            },
+            MessageData::Unimplemented => {},
+        };
+
+        events
+    }
+
+    /// Information TLVs from the Initiation message
+    pub fn information(&self) -> &[InformationTlv] {
+        &self.information
+    }
+
+    /// sysName from the Initiation message
+    pub fn sys_name(&self) -> Option<&str> {
+        self.find_information(InformationType::SysName)
+    }
+
+    /// sysDescr from the Initiation message
+    pub fn sys_descr(&self) -> Option<&str> {
+        self.find_information(InformationType::SysDescr)
+    }
+
+    fn find_information(&self, kind: InformationType) -> Option<&str> {
+        self.information.iter()
+            .find(|tlv| tlv.information_type() == Some(kind))
+            .and_then(InformationTlv::value)
+    }
+
+    /// Iterate over the peers which are currently up
+    pub fn peers(&self) -> impl Iterator<Item = &PeerState> {
+        self.peers.values()
+    }
+
+    /// Fetch the state of a peer, if it is currently up
+    pub fn peer(&self, key: &PeerKey) -> Option<&PeerState> {
+        self.peers.get(key)
+    }
+
+    /// Message counters for a peer. These are kept across Peer Down messages
+    pub fn counters(&self, key: &PeerKey) -> Option<&PeerCounters> {
+        self.counters.get(key)
+    }
+
+    /// Whether there are peers up and every one has completed the initial table dump
+    pub fn initial_sync_complete(&self) -> bool {
+        !self.peers.is_empty() && self.peers.values().all(PeerState::initial_sync_complete)
+    }
+
+    /// Timestamp of the last Peer Up message
+    pub fn last_peer_up(&self) -> Option<SystemTime> {
+        self.last_peer_up
+    }
+
+    /// Timestamp of the last Peer Down message
+    pub fn last_peer_down(&self) -> Option<SystemTime> {
+        self.last_peer_down
+    }
+
+    /// Whether the router has sent a Termination message
+    pub fn is_terminated(&self) -> bool {
+        self.termination.is_some()
+    }
+
+    /// TLVs from the Termination message, if one has been received
+    pub fn termination(&self) -> Option<&[TerminationTlv]> {
+        self.termination.as_deref()
+    }
+
+    /// Reason code from the Termination message
+    pub fn termination_reason(&self) -> Option<TerminationReason> {
+        self.termination.as_ref()?
+            .iter()
+            .find_map(|tlv| match tlv {
+                TerminationTlv::Reason(reason) => Some(*reason),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BmpDecoder;
+
+    use bytes::BytesMut;
+
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_session() {
+        let mut buf = BytesMut::from(&fs::read("test_data/synthetic.dump").unwrap()[..]);
+        let mut decoder = BmpDecoder::new();
+        let mut session = RouterSession::new();
+
+        let global = |addr: &str| PeerKey {
+            peer_type: PeerType::GlobalInstance,
+            peer_distinguisher: (0, 0),
+            peer_addr: addr.parse().unwrap(),
+        };
+        let v4 = global("192.0.2.1");
+        let v6 = global("2001:db8::1");
+        let view = RibView::default();
+
+        // Nothing is synced until a peer has come up
+        assert!(!session.initial_sync_complete());
+
+        // Initiation + both Peer Ups
+        for _ in 0..3 {
+            session.process(&decoder.decode_buf(&mut buf).unwrap().unwrap());
+        }
+        assert_eq!(session.sys_descr(), Some("Synthetic Router OS 1.0"));
+        assert_eq!(session.peers().count(), 2);
+        assert_eq!(session.peer(&v6).unwrap().header.peer_asn, 65002);
+
+        assert!(!session.initial_sync_complete());
+
+        let mut events = vec![];
+        for _ in 0..4 {
+            events.extend(session.process(&decoder.decode_buf(&mut buf).unwrap().unwrap()));
+        }
+        assert_eq!(events, vec![
+            SessionEvent::EndOfRib { peer: v4, view, afi: AFI::IPV4, safi: SAFI::Unicast },
+            SessionEvent::InitialSyncComplete { peer: v4, view },
+            SessionEvent::EndOfRib { peer: v6, view, afi: AFI::IPV6, safi: SAFI::Unicast },
+            SessionEvent::InitialSyncComplete { peer: v6, view },
+        ]);
+        assert!(session.initial_sync_complete());
+
+        while let Some(message) = decoder.decode_buf(&mut buf).unwrap() {
+            session.process(&message);
+        }
+
+        assert_eq!(session.peers().count(), 0);
+        assert!(session.is_terminated());
+        assert_eq!(session.termination_reason(), Some(TerminationReason::AdminClose));
+        assert_eq!(session.counters(&v4), Some(&PeerCounters { route_monitoring: 2, peer_up: 1, peer_down: 1 }));
+        assert_eq!(session.last_peer_down(), Some(UNIX_EPOCH + Duration::from_secs(1_577_836_801)));
+    }
+
+    fn frames() -> Vec<Vec<u8>> {
+        let data = fs::read("test_data/synthetic.dump").unwrap();
+        let mut frames = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let length = u32::from_be_bytes([data[offset + 1], data[offset + 2], data[offset + 3], data[offset + 4]]) as usize;
+            frames.push(data[offset..offset + length].to_vec());
+            offset += length;
+        }
+
+        frames
+    }
+
+    #[test]
+    fn test_session_views() {
+        let frames = frames();
+        let post_policy = |frame: &[u8]| {
+            let mut frame = frame.to_vec();
+            frame[7] |= 0x40;
+            frame
+        };
+
+        let mut decoder = BmpDecoder::new();
+        let mut session = RouterSession::new();
+        let mut process = |frame: &[u8]| {
+            let events = session.process(&decoder.decode_slice(frame).1.unwrap().unwrap());
+            (events, session.initial_sync_complete())
+        };
+
+        // The IPv4 peer's Peer Up, then its routes in the post-policy view and then the pre-policy
+        // view, which finishes first
+        process(&frames[1]);
+        process(&post_policy(&frames[3]));
+        process(&frames[3]);
+
+        let peer = PeerKey { peer_type: PeerType::GlobalInstance, peer_distinguisher: (0, 0), peer_addr: "192.0.2.1".parse().unwrap() };
+        let pre = RibView::default();
+        let post = RibView { post_policy: true, adj_rib_out: false };
+        assert_eq!(process(&frames[4]), (vec![
+            SessionEvent::EndOfRib { peer, view: pre, afi: AFI::IPV4, safi: SAFI::Unicast },
+            SessionEvent::InitialSyncComplete { peer, view: pre },
+        ], false));
+        assert_eq!(process(&post_policy(&frames[4])), (vec![
+            SessionEvent::EndOfRib { peer, view: post, afi: AFI::IPV4, safi: SAFI::Unicast },
+            SessionEvent::InitialSyncComplete { peer, view: post },
+        ], true));
+    }
+
+    #[test]
+    fn test_session_rd_peers() {
+        let frames = frames();
+
+        // The IPv4 peer in two VRFs, with the same address
+        let in_vrf = |frame: &[u8], vrf: u8| {
+            let mut frame = frame.to_vec();
+            frame[6] = PeerType::RdInstance as u8;
+            frame[15] = vrf;
+            frame
+        };
+        let (peer_up, peer_down) = (&frames[1], &frames[7]);
+
+        let mut decoder = BmpDecoder::new();
+        let mut session = RouterSession::new();
+        for frame in &[in_vrf(peer_up, 1), in_vrf(peer_up, 2), in_vrf(peer_down, 1)] {
+            session.process(&decoder.decode_slice(frame).1.unwrap().unwrap());
+        }
+
+        let key = |vrf: u8| PeerKey {
+            peer_type: PeerType::RdInstance,
+            peer_distinguisher: (0, u32::from(vrf)),
+            peer_addr: "192.0.2.1".parse().unwrap(),
+        };
+        assert!(session.peer(&key(1)).is_none());
+        assert!(session.peer(&key(2)).is_some());
+        assert_eq!(session.counters(&key(1)), Some(&PeerCounters { route_monitoring: 0, peer_up: 1, peer_down: 1 }));
+        assert_eq!(session.counters(&key(2)), Some(&PeerCounters { route_monitoring: 0, peer_up: 1, peer_down: 0 }));
+    }
+
+    #[test]
+    fn test_session_overrides() {
+        use crate::state::{CapabilityOverride, CapabilitySet, Family, OverrideMode};
+
+        let frames = frames();
+        let peer = PeerKey::from("192.0.2.1".parse::<std::net::IpAddr>().unwrap());
+        let view = RibView::default();
+
+        // Say the IPv4 peer also does IPv6, its IPv4 End-of-RIB no longer completes the sync
+        let mut decoder = BmpDecoder::new();
+        decoder.set_overrides(vec![CapabilityOverride {
+            router: None,
+            peer: Some(peer),
+            mode: OverrideMode::Always,
+            capabilities: CapabilitySet {
+                multiprotocol: vec![Family { afi: 1, safi: 1 }, Family { afi: 2, safi: 1 }],
+                ..Default::default()
+            },
+        }]).unwrap();
+        let mut session = RouterSession::new();
+
+        for frame in &frames[1..4] {
+            session.process(&decoder.decode_slice(frame).1.unwrap().unwrap());
+        }
+        let families = &session.peer(&peer).unwrap().families;
+        assert_eq!(families.len(), 2);
+        assert!(families.contains(&(AFI::IPV6, SAFI::Unicast)));
+
+        let events = session.process(&decoder.decode_slice(&frames[4]).1.unwrap().unwrap());
+        assert_eq!(events, vec![SessionEvent::EndOfRib { peer, view, afi: AFI::IPV4, safi: SAFI::Unicast }]);
+        assert!(!session.peer(&peer).unwrap().view_sync_complete(view));
+
+        // Without the decoder's capabilities the OPENs are used
+        let mut message = decoder.decode_slice(&frames[1]).1.unwrap().unwrap();
+        if let MessageData::PeerUp((_, peer_up)) = &mut message.message {
+            peer_up.capabilities = None;
+        }
+        session.process(&message);
+        let families = &session.peer(&peer).unwrap().families;
+        assert_eq!(families.iter().collect::<Vec<_>>(), vec![&(AFI::IPV4, SAFI::Unicast)]);
+    }
+}
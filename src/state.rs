@@ -0,0 +1,415 @@
+//! Saving, restoring and configuring decoder state
+//!
+//! Route Monitoring messages can only be decoded with the capabilities negotiated when the peer
+//! came up. A decoder that starts part way through a session (after a collector restart, or
+//! reading a rotated dump file) never sees those Peer Up messages, so the capabilities can be
+//! carried over from a previous decoder with `BmpDecoder::export_state` / `import_state`.
+//!
+//! With the `serde` feature `DecoderState` implements `Serialize` and `Deserialize`, AFIs and
+//! SAFIs are kept as their IANA numbers. `CapabilitySet` is also used to report the capabilities
+//! the decoder settled on for a peer, in `PeerUp::capabilities`, and to give the decoder fixed
+//! capabilities for peers whose Peer Up messages don't carry OPENs (see `CapabilityOverride`).
+//!
+//! ```
+//! # #[cfg(feature = "serde")] {
+//! # use bmp_protocol::{BmpDecoder, state::DecoderState};
+//! let decoder = BmpDecoder::new();
+//! let json = serde_json::to_string(&decoder.export_state()).unwrap();
+//!
+//! let mut restarted = BmpDecoder::new();
+//! restarted.import_state(serde_json::from_str::<DecoderState>(&json).unwrap()).unwrap();
+//! # }
+//! ```
+
+use crate::{Error, Result, types::PeerKey};
+
+use bgp_rs::{AddPathDirection, Capabilities, AFI, SAFI};
+#[cfg(feature = "serde")]
+use serde_derive::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Version of the state format written by `export_state`
+pub const STATE_VERSION: u32 = 2;
+
+/// Everything a `BmpDecoder` has learned from Peer Up messages
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DecoderState {
+    /// Format version, see `STATE_VERSION`
+    pub version: u32,
+    /// Negotiated capabilities for each peer, ordered by peer
+    pub peers: Vec<PeerCapabilities>,
+}
+
+/// Capabilities negotiated with a single peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerCapabilities {
+    /// Peer type, distinguisher and address, from the per-peer header
+    pub peer: PeerKey,
+    /// Negotiated capabilities
+    pub capabilities: CapabilitySet,
+}
+
+/// Fixed capabilities for a peer, see `BmpDecoder::set_overrides`
+///
+/// ```
+/// # #[cfg(feature = "serde")] {
+/// # use bmp_protocol::{BmpDecoder, state::CapabilityOverride};
+/// let overrides: Vec<CapabilityOverride> = serde_json::from_str(r#"[
+///     {
+///         "router": "192.0.2.1",
+///         "peer": { "peer_type": 1, "peer_distinguisher": [65000, 100], "peer_addr": "2001:db8::2" },
+///         "capabilities": { "multiprotocol": [{ "afi": 2, "safi": 1 }], "four_octet_asn": true }
+///     }
+/// ]"#).unwrap();
+///
+/// let mut decoder = BmpDecoder::new().with_router("192.0.2.1".parse().unwrap());
+/// decoder.set_overrides(overrides).unwrap();
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CapabilityOverride {
+    /// Only for this router (BMP speaker), or any router if missing
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub router: Option<IpAddr>,
+    /// Only for this peer, or any peer if missing
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peer: Option<PeerKey>,
+    /// When to use the override
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mode: OverrideMode,
+    /// Capabilities to use
+    pub capabilities: CapabilitySet,
+}
+
+/// When a `CapabilityOverride` is used
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OverrideMode {
+    /// Only when the Peer Up doesn't include both OPENs
+    #[default]
+    Missing,
+    /// Always, ignoring the OPENs
+    Always,
+}
+
+/// A serializable copy of `bgp_rs::Capabilities`
+///
+/// Missing fields default to empty / false, so hand-written sets only need what's relevant.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct CapabilitySet {
+    /// Multiprotocol extensions (capability 1)
+    pub multiprotocol: Vec<Family>,
+    /// Route refresh (capability 2)
+    pub route_refresh: bool,
+    /// Outbound route filtering (capability 3)
+    pub outbound_route_filtering: Vec<OrfFamily>,
+    /// Extended next hop encoding (capability 5)
+    pub extended_next_hop: Vec<NextHopFamily>,
+    /// BGPsec (capability 7)
+    pub bgpsec: bool,
+    /// Multiple labels (capability 8)
+    pub multiple_labels: Vec<LabelsFamily>,
+    /// Graceful restart (capability 64)
+    pub graceful_restart: Vec<Family>,
+    /// 4-octet AS numbers (capability 65)
+    pub four_octet_asn: bool,
+    /// ADD-PATH (capability 69)
+    pub add_path: Vec<AddPathFamily>,
+    /// NLRI carry path identifiers
+    pub extended_path_nlri: bool,
+    /// Enhanced route refresh (capability 70)
+    pub enhanced_route_refresh: bool,
+    /// Long-lived graceful restart (capability 71)
+    pub long_lived_graceful_restart: bool,
+}
+
+/// An address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Family {
+    /// AFI
+    pub afi: u16,
+    /// SAFI
+    pub safi: u8,
+}
+
+/// ADD-PATH support for an address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AddPathFamily {
+    /// AFI
+    pub afi: u16,
+    /// SAFI
+    pub safi: u8,
+    /// 1 to receive, 2 to send, 3 for both
+    pub direction: u8,
+}
+
+/// Outbound route filtering support for an address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OrfFamily {
+    /// AFI
+    pub afi: u16,
+    /// SAFI
+    pub safi: u8,
+    /// ORF type
+    pub orf_type: u8,
+    /// 1 to receive, 2 to send, 3 for both
+    pub direction: u8,
+}
+
+/// Extended next hop encoding for an address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NextHopFamily {
+    /// AFI
+    pub afi: u16,
+    /// SAFI
+    pub safi: u8,
+    /// AFI of the next hop
+    pub next_hop_afi: u16,
+}
+
+/// Multiple label support for an address family
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LabelsFamily {
+    /// AFI
+    pub afi: u16,
+    /// SAFI
+    pub safi: u8,
+    /// Number of labels
+    pub count: u8,
+}
+
+impl From<&Capabilities> for CapabilitySet {
+    fn from(caps: &Capabilities) -> Self {
+        let family = |(afi, safi): &(AFI, SAFI)| Family { afi: *afi as u16, safi: *safi as u8 };
+
+        Self {
+            multiprotocol: sorted(caps.MP_BGP_SUPPORT.iter().map(family)),
+            route_refresh: caps.ROUTE_REFRESH_SUPPORT,
+            outbound_route_filtering: sorted(caps.OUTBOUND_ROUTE_FILTERING_SUPPORT.iter()
+                .map(|(afi, safi, orf_type, direction)| OrfFamily {
+                    afi: *afi as u16,
+                    safi: *safi as u8,
+                    orf_type: *orf_type,
+                    direction: *direction as u8,
+                })),
+            extended_next_hop: sorted(caps.EXTENDED_NEXT_HOP_ENCODING.iter()
+                .map(|((afi, safi), next_hop_afi)| NextHopFamily {
+                    afi: *afi as u16,
+                    safi: *safi as u8,
+                    next_hop_afi: *next_hop_afi as u16,
+                })),
+            bgpsec: caps.BGPSEC_SUPPORT,
+            multiple_labels: sorted(caps.MULTIPLE_LABELS_SUPPORT.iter()
+                .map(|((afi, safi), count)| LabelsFamily { afi: *afi as u16, safi: *safi as u8, count: *count })),
+            graceful_restart: sorted(caps.GRACEFUL_RESTART_SUPPORT.iter().map(family)),
+            four_octet_asn: caps.FOUR_OCTET_ASN_SUPPORT,
+            add_path: sorted(caps.ADD_PATH_SUPPORT.iter()
+                .map(|((afi, safi), direction)| AddPathFamily {
+                    afi: *afi as u16,
+                    safi: *safi as u8,
+                    direction: *direction as u8,
+                })),
+            extended_path_nlri: caps.EXTENDED_PATH_NLRI_SUPPORT,
+            enhanced_route_refresh: caps.ENHANCED_ROUTE_REFRESH_SUPPORT,
+            long_lived_graceful_restart: caps.LONG_LIVED_GRACEFUL_RESTART,
+        }
+    }
+}
+
+impl CapabilitySet {
+    /// Convert back to `bgp_rs::Capabilities`, failing on any AFI, SAFI or direction bgp-rs
+    /// doesn't know about
+    pub fn to_capabilities(&self) -> Result<Capabilities> {
+        let mut caps = Capabilities::default();
+
+        for family in &self.multiprotocol {
+            caps.MP_BGP_SUPPORT.insert((afi(family.afi)?, safi(family.safi)?));
+        }
+        caps.ROUTE_REFRESH_SUPPORT = self.route_refresh;
+        for orf in &self.outbound_route_filtering {
+            caps.OUTBOUND_ROUTE_FILTERING_SUPPORT.insert((afi(orf.afi)?, safi(orf.safi)?, orf.orf_type, direction(orf.direction)?));
+        }
+        for next_hop in &self.extended_next_hop {
+            caps.EXTENDED_NEXT_HOP_ENCODING.insert((afi(next_hop.afi)?, safi(next_hop.safi)?), afi(next_hop.next_hop_afi)?);
+        }
+        caps.BGPSEC_SUPPORT = self.bgpsec;
+        for labels in &self.multiple_labels {
+            caps.MULTIPLE_LABELS_SUPPORT.insert((afi(labels.afi)?, safi(labels.safi)?), labels.count);
+        }
+        for family in &self.graceful_restart {
+            caps.GRACEFUL_RESTART_SUPPORT.insert((afi(family.afi)?, safi(family.safi)?));
+        }
+        caps.FOUR_OCTET_ASN_SUPPORT = self.four_octet_asn;
+        for add_path in &self.add_path {
+            caps.ADD_PATH_SUPPORT.insert((afi(add_path.afi)?, safi(add_path.safi)?), direction(add_path.direction)?);
+        }
+        caps.EXTENDED_PATH_NLRI_SUPPORT = self.extended_path_nlri;
+        caps.ENHANCED_ROUTE_REFRESH_SUPPORT = self.enhanced_route_refresh;
+        caps.LONG_LIVED_GRACEFUL_RESTART = self.long_lived_graceful_restart;
+
+        Ok(caps)
+    }
+}
+
+impl fmt::Display for CapabilitySet {
+    /// A one line summary: address families, ADD-PATH, 4-octet ASNs and graceful restart
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |items: Vec<String>| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+
+        write!(
+            f, "families: {}; add-path: {}; 4-octet AS: {}; graceful restart: {}",
+            list(self.multiprotocol.iter().map(Family::to_string).collect()),
+            list(self.add_path.iter().map(AddPathFamily::to_string).collect()),
+            if self.four_octet_asn { "yes" } else { "no" },
+            list(self.graceful_restart.iter().map(Family::to_string).collect()),
+        )
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (AFI::try_from(self.afi), SAFI::try_from(self.safi)) {
+            (Ok(afi), Ok(safi)) => write!(f, "{} {}", afi, safi),
+            _ => write!(f, "AFI {} SAFI {}", self.afi, self.safi),
+        }
+    }
+}
+
+impl fmt::Display for AddPathFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            1 => "receive",
+            2 => "send",
+            3 => "send/receive",
+            _ => "unknown",
+        };
+        write!(f, "{} ({})", Family { afi: self.afi, safi: self.safi }, direction)
+    }
+}
+
+fn sorted<T: Ord>(items: impl Iterator<Item = T>) -> Vec<T> {
+    let mut items = items.collect::<Vec<_>>();
+    items.sort();
+    items
+}
+
+fn afi(afi: u16) -> Result<AFI> {
+    AFI::try_from(afi).map_err(|_| Error::decode(&format!("unsupported AFI {}", afi)))
+}
+
+fn safi(safi: u8) -> Result<SAFI> {
+    SAFI::try_from(safi).map_err(|_| Error::decode(&format!("unsupported SAFI {}", safi)))
+}
+
+fn direction(direction: u8) -> Result<AddPathDirection> {
+    AddPathDirection::try_from(direction).map_err(|_| Error::decode(&format!("invalid ADD-PATH direction {}", direction)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{BmpDecoder, generator::Generator};
+
+    #[test]
+    fn test_capability_set() {
+        let mut caps = Capabilities::default();
+        caps.MP_BGP_SUPPORT.insert((AFI::IPV6, SAFI::Unicast));
+        caps.MP_BGP_SUPPORT.insert((AFI::IPV4, SAFI::Unicast));
+        caps.ADD_PATH_SUPPORT.insert((AFI::IPV4, SAFI::Unicast), AddPathDirection::SendReceivePaths);
+        caps.EXTENDED_NEXT_HOP_ENCODING.insert((AFI::IPV4, SAFI::Unicast), AFI::IPV6);
+        caps.FOUR_OCTET_ASN_SUPPORT = true;
+        caps.EXTENDED_PATH_NLRI_SUPPORT = true;
+
+        let set = CapabilitySet::from(&caps);
+        assert_eq!(set.multiprotocol, vec![Family { afi: 1, safi: 1 }, Family { afi: 2, safi: 1 }]);
+        assert_eq!(set.add_path, vec![AddPathFamily { afi: 1, safi: 1, direction: 3 }]);
+        assert_eq!(CapabilitySet::from(&set.to_capabilities().unwrap()), set);
+
+        // Only what's given, and nothing bgp-rs can't represent
+        assert_eq!(
+            set.to_string(),
+            "families: IPv4 Unicast, IPv6 Unicast; add-path: IPv4 Unicast (send/receive); 4-octet AS: yes; graceful restart: none"
+        );
+
+        let set = CapabilitySet { multiprotocol: vec![Family { afi: 99, safi: 1 }], ..Default::default() };
+        assert!(set.to_capabilities().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_capability_set_json() {
+        use crate::types::PeerType;
+
+        let set: CapabilitySet = serde_json::from_str(r#"{"four_octet_asn": true}"#).unwrap();
+        assert!(set.to_capabilities().unwrap().FOUR_OCTET_ASN_SUPPORT);
+        let set: CapabilitySet = serde_json::from_str(r#"{"multiprotocol": [{"afi": 99, "safi": 1}]}"#).unwrap();
+        assert!(set.to_capabilities().is_err());
+
+        // Peers are Global Instance peers unless the override says otherwise
+        let ovr: CapabilityOverride = serde_json::from_str(r#"{"peer": {"peer_addr": "192.0.2.2"}, "capabilities": {}}"#).unwrap();
+        assert_eq!(ovr.peer, Some(PeerKey::from("192.0.2.2".parse::<IpAddr>().unwrap())));
+        let ovr: CapabilityOverride = serde_json::from_str(
+            r#"{"peer": {"peer_type": 1, "peer_distinguisher": [65000, 100], "peer_addr": "192.0.2.2"}, "capabilities": {}}"#,
+        ).unwrap();
+        assert_eq!(ovr.peer.unwrap().peer_type, PeerType::RdInstance);
+        assert_eq!(ovr.peer.unwrap().peer_distinguisher, (65000, 100));
+        assert!(serde_json::from_str::<CapabilityOverride>(r#"{"peer": {"peer_type": 9, "peer_addr": "192.0.2.2"}, "capabilities": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let frames = Generator::new(3)
+            .with_peers(4)
+            .with_routes(50)
+            .with_churn(50)
+            .collect::<Vec<_>>();
+        let (head, tail) = frames.split_at(frames.len() / 2);
+
+        let mut decoder = BmpDecoder::new();
+        for frame in head {
+            decoder.decode_slice(frame).1.unwrap();
+        }
+        let state = decoder.export_state();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.peers.len(), 4);
+
+        // A fresh decoder can't pick up part way through, until it has the state
+        assert!(tail.iter().any(|frame| BmpDecoder::new().decode_slice(frame).1.is_err()));
+
+        // Via JSON, when it can be
+        #[cfg(feature = "serde")]
+        let saved = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        #[cfg(not(feature = "serde"))]
+        let saved = state.clone();
+        let mut restored = BmpDecoder::new();
+        restored.import_state(saved).unwrap();
+        assert_eq!(restored.export_state(), state);
+
+        for frame in tail {
+            let expected = decoder.decode_slice(frame).1.unwrap().unwrap();
+            let message = restored.decode_slice(frame).1.unwrap().unwrap();
+            assert_eq!(format!("{:?}", message), format!("{:?}", expected));
+        }
+
+        // Other formats are refused, version 1 was keyed by peer address alone
+        for &version in &[1, STATE_VERSION + 1] {
+            let state = DecoderState { version, peers: vec![] };
+            assert!(BmpDecoder::new().import_state(state).is_err());
+        }
+    }
+}
@@ -1,14 +1,18 @@
-use crate::{Error, Result};
+use crate::{Error, Result, state::CapabilitySet};
+use bgp_rs::{AFI, OpenCapability, OpenParameter, PathAttribute, SAFI};
 use bytes::{
     Buf,
     buf::BufExt,
-    BytesMut
+    BufMut,
+    Bytes,
 };
-// use serde_derive::Serialize;
+#[cfg(feature = "serde")]
+use serde_derive::{Deserialize, Serialize};
 
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// There are a few different types of BMP message, refer to RFC7xxx for details. This enum
 /// encapsulates the different types
@@ -26,6 +30,8 @@ pub enum MessageData {
     PeerDown((PeerHeader, PeerDown)),
     /// RouteMonitoring messages are state-compressed BGP messages
     RouteMonitoring((PeerHeader, bgp_rs::Update)),
+    /// Termination messages are sent by the speaker right before it closes the BMP session
+    Termination(Vec<TerminationTlv>),
 }
 
 /// BMP Message Types (RFC7854 Section 10.1)
@@ -42,7 +48,7 @@ pub enum MessageKind {
     PeerUp = 3,
     /// Initiation
     Initiation = 4,
-    /// Termination (unimplemented)
+    /// Termination
     Termination = 5,
     /// Route Mirroring (unimplemented)
     RouteMirroring = 6,
@@ -63,7 +69,7 @@ impl TryFrom<u8> for MessageKind {
             5 => Ok(MessageKind::Termination),
             6 => Ok(MessageKind::RouteMirroring),
 
-            v @ _ => Err(
+            v => Err(
                 Error::decode(&format!("invalid value for BMP Message Type: {}", v))
             ),
         }
@@ -85,15 +91,25 @@ impl fmt::Display for MessageKind {
 }
 
 /// BMP Peer Types (RFC7854 Section 10.2)
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+///
+/// With the `serde` feature these are kept as their IANA numbers.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
 #[repr(u8)]
 pub enum PeerType {
     /// Global Instance Peer
+    #[default]
     GlobalInstance = 0,
     /// RD Instance Peer
     RdInstance = 1,
     /// Local Instance Peer
     LocalInstance = 2,
+    /// Loc-RIB Instance Peer (RFC9069)
+    ///
+    /// For these peers the first peer flag is F (the Loc-RIB is filtered) rather than V, and the
+    /// peer address is zero-filled.
+    LocRibInstance = 3,
 }
 
 impl TryFrom<u8> for PeerType {
@@ -104,20 +120,28 @@ impl TryFrom<u8> for PeerType {
             0 => Ok(PeerType::GlobalInstance),
             1 => Ok(PeerType::RdInstance),
             2 => Ok(PeerType::LocalInstance),
+            3 => Ok(PeerType::LocRibInstance),
 
-            v @ _ => Err(
+            v => Err(
                 Error::decode(&format!("invalid value for BMP Peer Type: {}", v))
             ),
         }
     }
 }
 
+impl From<PeerType> for u8 {
+    fn from(peer_type: PeerType) -> Self {
+        peer_type as u8
+    }
+}
+
 impl fmt::Display for PeerType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             PeerType::GlobalInstance => write!(f, "global"),
             PeerType::RdInstance => write!(f, "rd"),
             PeerType::LocalInstance => write!(f, "local"),
+            PeerType::LocRibInstance => write!(f, "loc_rib"),
         }
     }
 }
@@ -148,15 +172,20 @@ impl From<u8> for PeerFlags {
     }
 }
 
-/// BMP Initiation Message TLVs (RFC7854 Section 10.5)
+/// BMP Initiation and Peer Up Information TLV types (RFC7854 Section 10.5)
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[repr(u16)]
 pub enum InformationType {
     /// Generic String
-    String,
+    String = 0,
     /// sysDescr
-    SysDescr,
+    SysDescr = 1,
     /// sysName
-    SysName,
+    SysName = 2,
+    /// VRF or table name (RFC9069)
+    VrfTableName = 3,
+    /// Admin label (RFC8671)
+    AdminLabel = 4,
 }
 
 impl TryFrom<u16> for InformationType {
@@ -167,8 +196,10 @@ impl TryFrom<u16> for InformationType {
             0 => Ok(InformationType::String),
             1 => Ok(InformationType::SysDescr),
             2 => Ok(InformationType::SysName),
+            3 => Ok(InformationType::VrfTableName),
+            4 => Ok(InformationType::AdminLabel),
 
-            v @ _ => Err(
+            v => Err(
                 Error::decode(&format!("invalid value for BMP Information Type: {}", v))
             ),
         }
@@ -181,6 +212,66 @@ impl fmt::Display for InformationType {
             InformationType::String => write!(f, "string"),
             InformationType::SysDescr => write!(f, "sys_descr"),
             InformationType::SysName => write!(f, "sys_name"),
+            InformationType::VrfTableName => write!(f, "vrf_table_name"),
+            InformationType::AdminLabel => write!(f, "admin_label"),
+        }
+    }
+}
+
+/// BMP Termination Message reasons (RFC7854 Section 4.5)
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TerminationReason {
+    /// Session administratively closed (0)
+    AdminClose,
+    /// Unspecified reason (1)
+    Unspecified,
+    /// Out of resources (2)
+    OutOfResources,
+    /// Redundant connection (3)
+    RedundantConnection,
+    /// Session permanently administratively closed (4)
+    PermanentlyAdminClose,
+    /// A reason we don't know about
+    Unknown(u16),
+}
+
+impl From<u16> for TerminationReason {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => TerminationReason::AdminClose,
+            1 => TerminationReason::Unspecified,
+            2 => TerminationReason::OutOfResources,
+            3 => TerminationReason::RedundantConnection,
+            4 => TerminationReason::PermanentlyAdminClose,
+
+            v => TerminationReason::Unknown(v),
+        }
+    }
+}
+
+impl TerminationReason {
+    /// The reason code
+    pub fn code(&self) -> u16 {
+        match self {
+            TerminationReason::AdminClose => 0,
+            TerminationReason::Unspecified => 1,
+            TerminationReason::OutOfResources => 2,
+            TerminationReason::RedundantConnection => 3,
+            TerminationReason::PermanentlyAdminClose => 4,
+            TerminationReason::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for TerminationReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TerminationReason::AdminClose => write!(f, "admin_close"),
+            TerminationReason::Unspecified => write!(f, "unspecified"),
+            TerminationReason::OutOfResources => write!(f, "out_of_resources"),
+            TerminationReason::RedundantConnection => write!(f, "redundant_connection"),
+            TerminationReason::PermanentlyAdminClose => write!(f, "permanently_admin_close"),
+            TerminationReason::Unknown(v) => write!(f, "reason_{}", v),
         }
     }
 }
@@ -196,6 +287,35 @@ pub struct BmpMessage {
 
     /// Message data
     pub message: MessageData,
+    /// The complete message as it was received, including the common header
+    pub raw: Bytes,
+}
+
+impl BmpMessage {
+    /// If this is a Route Monitoring message carrying an End-of-RIB marker (RFC4724 Section 2),
+    /// return the address family it applies to
+    pub fn end_of_rib(&self) -> Option<(AFI, SAFI)> {
+        match &self.message {
+            MessageData::RouteMonitoring((_, update)) => end_of_rib(update),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether an UPDATE is an End-of-RIB marker. For IPv4 unicast this is an UPDATE with no
+/// content at all, for other families it is an UPDATE containing only an empty MP_UNREACH_NLRI
+pub fn end_of_rib(update: &bgp_rs::Update) -> Option<(AFI, SAFI)> {
+    if !update.withdrawn_routes.is_empty() || !update.announced_routes.is_empty() {
+        return None;
+    }
+
+    match update.attributes.as_slice() {
+        [] => Some((AFI::IPV4, SAFI::Unicast)),
+        [PathAttribute::MP_UNREACH_NLRI(unreach)] if unreach.withdrawn_routes.is_empty() => {
+            Some((unreach.afi, unreach.safi))
+        },
+        _ => None,
+    }
 }
 
 /// Per-Peer Header
@@ -219,12 +339,16 @@ pub struct PeerHeader {
     pub peer_bgp_id: Ipv4Addr,
     /// Timestamp (seconds since epoch)
     pub timestamp: u32,
-    /// Optional milliseconds, to be added to `timestamp`
+    /// Optional microseconds, to be added to `timestamp`
     pub timestamp_ms: u32,
 }
 
 impl PeerHeader {
-    pub(super) fn decode(buf: &mut BytesMut) -> Result<Self> {
+    pub(super) fn decode(buf: &mut Bytes) -> Result<Self> {
+        if buf.remaining() < 42 {
+            return Err(Error::decode("truncated BMP Per-Peer header"));
+        }
+
         let peer_type: PeerType = buf.get_u8().try_into()?;
         let peer_flags: PeerFlags = buf.get_u8().into();
         let peer_distinguisher = (buf.get_u32(), buf.get_u32());
@@ -269,27 +393,210 @@ impl PeerHeader {
             timestamp_ms,
         })
     }
+
+    /// Encode the Per-Peer Header
+    pub fn encode(&self, buf: &mut impl BufMut) {
+        let mut flags = 0u8;
+        if self.peer_flags.V { flags |= 0b10000000; }
+        if self.peer_flags.L { flags |= 0b01000000; }
+        if self.peer_flags.A { flags |= 0b00100000; }
+        if self.peer_flags.O { flags |= 0b00010000; }
+
+        buf.put_u8(self.peer_type as u8);
+        buf.put_u8(flags);
+        buf.put_u32(self.peer_distinguisher.0);
+        buf.put_u32(self.peer_distinguisher.1);
+
+        match self.peer_addr {
+            IpAddr::V4(addr) => {
+                buf.put_slice(&[0; 12]);
+                buf.put_slice(&addr.octets());
+            },
+            IpAddr::V6(addr) => buf.put_slice(&addr.octets()),
+        };
+
+        buf.put_u32(self.peer_asn);
+        buf.put_slice(&self.peer_bgp_id.octets());
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.timestamp_ms);
+    }
+
+    /// Combine `timestamp` and `timestamp_ms` into a `SystemTime`
+    pub fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(u64::from(self.timestamp), 0) + Duration::from_micros(u64::from(self.timestamp_ms))
+    }
+}
+
+/// Identifies a peer on the monitored router
+///
+/// Peers in different VRFs (RD Instance peers) or instances may share an address, so the Peer
+/// Type and Peer Distinguisher are part of the key. With the `serde` feature both may be left out,
+/// for a Global Instance peer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerKey {
+    /// Peer Type
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peer_type: PeerType,
+    /// Peer Distinguisher
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub peer_distinguisher: (u32, u32),
+    /// Peer address
+    pub peer_addr: IpAddr,
+}
+
+impl From<&PeerHeader> for PeerKey {
+    fn from(header: &PeerHeader) -> Self {
+        Self {
+            peer_type: header.peer_type,
+            peer_distinguisher: header.peer_distinguisher,
+            peer_addr: header.peer_addr,
+        }
+    }
+}
+
+impl From<IpAddr> for PeerKey {
+    /// A Global Instance peer
+    fn from(peer_addr: IpAddr) -> Self {
+        Self { peer_type: PeerType::GlobalInstance, peer_distinguisher: (0, 0), peer_addr }
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer_type {
+            PeerType::GlobalInstance => write!(f, "{}", self.peer_addr),
+            peer_type => write!(
+                f, "{} ({} {}:{})",
+                self.peer_addr, peer_type, self.peer_distinguisher.0, self.peer_distinguisher.1,
+            ),
+        }
+    }
 }
 
 /// Information TLV
 ///
-/// The Information TLV is used by the Initiation and Peer Up messages.
-#[derive(Clone, Debug)]
-pub struct InformationTlv {
-    /// TLV message type
-    pub information_type: InformationType,
-    /// TLV message value 
-    pub value: String,
+/// The Information TLV is used by the Initiation and Peer Up messages. Values are meant to be
+/// UTF-8, anything that isn't is decoded lossily.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InformationTlv {
+    /// Generic String (type 0)
+    String(String),
+    /// sysDescr (type 1)
+    SysDescr(String),
+    /// sysName (type 2)
+    SysName(String),
+    /// VRF or table name (type 3, RFC9069)
+    VrfTableName(String),
+    /// Admin label (type 4, RFC8671)
+    AdminLabel(String),
+    /// Any other type, including enterprise-specific TLVs (those with the high bit of the type
+    /// set), with the value left as is
+    Unknown {
+        /// TLV type
+        kind: u16,
+        /// TLV value
+        bytes: Bytes,
+    },
 }
 
 impl InformationTlv {
-    pub(super) fn decode(kind: u16, buf: &mut BytesMut) -> Result<Self> {
-        let information_type = InformationType::try_from(kind)?;
+    pub(super) fn decode(buf: &mut Bytes) -> Result<Self> {
+        if buf.remaining() < 4 {
+            return Err(Error::decode("truncated BMP Information TLV"));
+        }
+        let kind = buf.get_u16();
         let len = buf.get_u16() as usize;
+        if buf.remaining() < len {
+            return Err(Error::decode("truncated BMP Information TLV"));
+        }
+        let bytes = buf.split_to(len);
 
-        let value = String::from_utf8((buf.bytes())[..len].to_vec()).unwrap();
+        let information_type = match InformationType::try_from(kind) {
+            Ok(information_type) => information_type,
+            Err(_) => return Ok(InformationTlv::Unknown { kind, bytes }),
+        };
+        let value = String::from_utf8_lossy(&bytes).into_owned();
+
+        Ok(match information_type {
+            InformationType::String => InformationTlv::String(value),
+            InformationType::SysDescr => InformationTlv::SysDescr(value),
+            InformationType::SysName => InformationTlv::SysName(value),
+            InformationType::VrfTableName => InformationTlv::VrfTableName(value),
+            InformationType::AdminLabel => InformationTlv::AdminLabel(value),
+        })
+    }
 
-        Ok(Self { information_type, value })
+    /// TLV type
+    pub fn kind(&self) -> u16 {
+        match self {
+            InformationTlv::Unknown { kind, .. } => *kind,
+            _ => self.information_type().unwrap() as u16,
+        }
+    }
+
+    /// TLV type, if it's one we know about
+    pub fn information_type(&self) -> Option<InformationType> {
+        match self {
+            InformationTlv::String(_) => Some(InformationType::String),
+            InformationTlv::SysDescr(_) => Some(InformationType::SysDescr),
+            InformationTlv::SysName(_) => Some(InformationType::SysName),
+            InformationTlv::VrfTableName(_) => Some(InformationType::VrfTableName),
+            InformationTlv::AdminLabel(_) => Some(InformationType::AdminLabel),
+            InformationTlv::Unknown { .. } => None,
+        }
+    }
+
+    /// String value, for the types we know about
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            InformationTlv::String(value)
+            | InformationTlv::SysDescr(value)
+            | InformationTlv::SysName(value)
+            | InformationTlv::VrfTableName(value)
+            | InformationTlv::AdminLabel(value) => Some(value),
+            InformationTlv::Unknown { .. } => None,
+        }
+    }
+}
+
+/// Termination TLV
+///
+/// The Termination message carries a free-form string and/or a reason code
+#[derive(Clone, Debug)]
+pub enum TerminationTlv {
+    /// Free-form UTF-8 string
+    String(String),
+    /// Reason the session is being closed
+    Reason(TerminationReason),
+    /// Any other type, with the value left as is
+    Unknown {
+        /// TLV type
+        kind: u16,
+        /// TLV value
+        bytes: Bytes,
+    },
+}
+
+impl TerminationTlv {
+    pub(super) fn decode(buf: &mut Bytes) -> Result<Self> {
+        if buf.remaining() < 4 {
+            return Err(Error::decode("truncated BMP Termination TLV"));
+        }
+        let kind = buf.get_u16();
+        let len = buf.get_u16() as usize;
+
+        if buf.remaining() < len {
+            return Err(Error::decode("truncated BMP Termination TLV"));
+        }
+        let value = buf.split_to(len);
+
+        match kind {
+            0 => Ok(Self::String(String::from_utf8_lossy(&value).into_owned())),
+            1 if len == 2 => Ok(Self::Reason(u16::from_be_bytes([value[0], value[1]]).into())),
+            1 => Err(Error::decode(&format!("invalid length for BMP Termination reason: {}", len))),
+            _ => Ok(Self::Unknown { kind, bytes: value }),
+        }
     }
 }
 
@@ -311,10 +618,17 @@ pub struct PeerUp {
     pub recv_open: Option<bgp_rs::Open>,
     /// Information TLVs
     pub information: Vec<InformationTlv>,
+    /// Capabilities the decoder will use for this peer's Route Monitoring messages, filled in by
+    /// `BmpDecoder`
+    pub capabilities: Option<Box<CapabilitySet>>,
 }
 
 impl PeerUp {
-    pub(super) fn decode(peer_flags: &PeerFlags, buf: &mut BytesMut) -> Result<Self> {
+    pub(super) fn decode(peer_flags: &PeerFlags, buf: &mut Bytes) -> Result<Self> {
+        if buf.remaining() < 20 {
+            return Err(Error::decode("truncated BMP Peer Up message"));
+        }
+
         let local_addr = match peer_flags.V {
             // IPv4
             false => {
@@ -332,7 +646,8 @@ impl PeerUp {
         let remote_port = buf.get_u16();
 
         // For at least some routers (ie adm-b1) the PeerUp messages are missing the
-        // OPENs. Short-circuit here until I can figure out whats going on
+        // OPENs. Short-circuit here until I can figure out whats going on. BmpDecoder::set_overrides
+        // can fill in the capabilities for these peers
         if buf.remaining() == 0 {
             return Ok(PeerUp {
                 local_addr,
@@ -340,24 +655,17 @@ impl PeerUp {
                 remote_port,
                 sent_open: None,
                 recv_open: None,
-                information: vec![]
+                information: vec![],
+                capabilities: None,
             });
         }
 
-        let mut rdr = buf.reader();
-
-        let sent_hdr = bgp_rs::Header::parse(&mut rdr)?;
-        assert!(sent_hdr.record_type == 1);
-        let sent_open = Some(bgp_rs::Open::parse(&mut rdr)?);
-
-        let recv_hdr = bgp_rs::Header::parse(&mut rdr)?;
-        assert!(recv_hdr.record_type == 1);
-        let recv_open = Some(bgp_rs::Open::parse(&mut rdr)?);
+        let sent_open = Some(decode_open(buf)?);
+        let recv_open = Some(decode_open(buf)?);
 
         let mut information = vec![];
-        while buf.remaining() > 0 {
-            let kind = buf.get_u16();
-            information.push( InformationTlv::decode(kind, buf)? );
+        while buf.has_remaining() {
+            information.push( InformationTlv::decode(buf)? );
         }
 
         Ok(PeerUp {
@@ -366,9 +674,259 @@ impl PeerUp {
             remote_port,
             sent_open,
             recv_open,
-            information
+            information,
+            capabilities: None,
         })
     }
+
+    /// Negotiated hold time, the smaller of the two proposed
+    pub fn hold_time(&self) -> Option<u16> {
+        match (&self.sent_open, &self.recv_open) {
+            (Some(sent), Some(recv)) => Some(sent.hold_timer.min(recv.hold_timer)),
+            _ => None,
+        }
+    }
+
+    /// BGP identifier of the monitored router, from the OPEN it sent
+    pub fn local_router_id(&self) -> Option<Ipv4Addr> {
+        self.sent_open.as_ref().map(|open| Ipv4Addr::from(open.identifier))
+    }
+
+    /// BGP identifier of the peer, from the OPEN it sent
+    pub fn remote_router_id(&self) -> Option<Ipv4Addr> {
+        self.recv_open.as_ref().map(|open| Ipv4Addr::from(open.identifier))
+    }
+
+    /// ASN of the monitored router, from the OPEN it sent
+    pub fn local_asn(&self) -> Option<u32> {
+        self.sent_open.as_ref().map(open_asn)
+    }
+
+    /// ASN of the peer, from the OPEN it sent
+    pub fn remote_asn(&self) -> Option<u32> {
+        self.recv_open.as_ref().map(open_asn)
+    }
+
+    /// Value of the first information TLV of type `kind`
+    pub fn find_information(&self, kind: InformationType) -> Option<&str> {
+        self.information.iter()
+            .find(|tlv| tlv.information_type() == Some(kind))
+            .and_then(InformationTlv::value)
+    }
+}
+
+/// Decode a BGP OPEN (with its header) taking exactly the length it claims, so whatever follows
+/// starts in the right place
+fn decode_open(buf: &mut Bytes) -> Result<bgp_rs::Open> {
+    let length = match buf.bytes().get(16..18) {
+        Some(length) => usize::from(u16::from_be_bytes([length[0], length[1]])),
+        None => return Err(Error::decode("truncated BGP OPEN in Peer Up")),
+    };
+    if length < 19 || length > buf.remaining() {
+        return Err(Error::decode(&format!("invalid BGP OPEN length {} in Peer Up", length)));
+    }
+
+    let mut rdr = buf.split_to(length).reader();
+    let header = bgp_rs::Header::parse(&mut rdr)?;
+    if header.record_type != 1 {
+        return Err(Error::decode(&format!("expected a BGP OPEN in Peer Up, found type {}", header.record_type)));
+    }
+
+    Ok(bgp_rs::Open::parse(&mut rdr)?)
+}
+
+/// The 4-octet ASN capability if there is one, otherwise the 2-octet field
+fn open_asn(open: &bgp_rs::Open) -> u32 {
+    open.parameters.iter()
+        .filter_map(|parameter| match parameter {
+            OpenParameter::Capabilities(capabilities) => Some(capabilities),
+            _ => None,
+        })
+        .flatten()
+        .find_map(|capability| match capability {
+            OpenCapability::FourByteASN(asn) => Some(*asn),
+            _ => None,
+        })
+        .unwrap_or_else(|| u32::from(open.peer_asn))
+}
+
+/// BGP FSM events (RFC4271 Section 8.1), as reported when the local system closes a session
+/// without a NOTIFICATION
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum FsmEvent {
+    /// No relevant event code is defined (0)
+    Unspecified,
+    /// ManualStart (Event 1)
+    ManualStart,
+    /// ManualStop (Event 2)
+    ManualStop,
+    /// AutomaticStart (Event 3)
+    AutomaticStart,
+    /// ManualStart_with_PassiveTcpEstablishment (Event 4)
+    ManualStartWithPassiveTcpEstablishment,
+    /// AutomaticStart_with_PassiveTcpEstablishment (Event 5)
+    AutomaticStartWithPassiveTcpEstablishment,
+    /// AutomaticStart_with_DampPeerOscillations (Event 6)
+    AutomaticStartWithDampPeerOscillations,
+    /// AutomaticStart_with_DampPeerOscillations_and_PassiveTcpEstablishment (Event 7)
+    AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment,
+    /// AutomaticStop (Event 8)
+    AutomaticStop,
+    /// ConnectRetryTimer_Expires (Event 9)
+    ConnectRetryTimerExpires,
+    /// HoldTimer_Expires (Event 10)
+    HoldTimerExpires,
+    /// KeepaliveTimer_Expires (Event 11)
+    KeepaliveTimerExpires,
+    /// DelayOpenTimer_Expires (Event 12)
+    DelayOpenTimerExpires,
+    /// IdleHoldTimer_Expires (Event 13)
+    IdleHoldTimerExpires,
+    /// TcpConnection_Valid (Event 14)
+    TcpConnectionValid,
+    /// Tcp_CR_Invalid (Event 15)
+    TcpCrInvalid,
+    /// Tcp_CR_Acked (Event 16)
+    TcpCrAcked,
+    /// TcpConnectionConfirmed (Event 17)
+    TcpConnectionConfirmed,
+    /// TcpConnectionFails (Event 18)
+    TcpConnectionFails,
+    /// BGPOpen (Event 19)
+    BgpOpen,
+    /// BGPOpen with DelayOpenTimer running (Event 20)
+    BgpOpenWithDelayOpenTimerRunning,
+    /// BGPHeaderErr (Event 21)
+    BgpHeaderErr,
+    /// BGPOpenMsgErr (Event 22)
+    BgpOpenMsgErr,
+    /// OpenCollisionDump (Event 23)
+    OpenCollisionDump,
+    /// NotifMsgVerErr (Event 24)
+    NotifMsgVerErr,
+    /// NotifMsg (Event 25)
+    NotifMsg,
+    /// KeepAliveMsg (Event 26)
+    KeepAliveMsg,
+    /// UpdateMsg (Event 27)
+    UpdateMsg,
+    /// UpdateMsgErr (Event 28)
+    UpdateMsgErr,
+    /// An event number not in RFC4271
+    Unknown(u16),
+}
+
+impl From<u16> for FsmEvent {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => FsmEvent::Unspecified,
+            1 => FsmEvent::ManualStart,
+            2 => FsmEvent::ManualStop,
+            3 => FsmEvent::AutomaticStart,
+            4 => FsmEvent::ManualStartWithPassiveTcpEstablishment,
+            5 => FsmEvent::AutomaticStartWithPassiveTcpEstablishment,
+            6 => FsmEvent::AutomaticStartWithDampPeerOscillations,
+            7 => FsmEvent::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment,
+            8 => FsmEvent::AutomaticStop,
+            9 => FsmEvent::ConnectRetryTimerExpires,
+            10 => FsmEvent::HoldTimerExpires,
+            11 => FsmEvent::KeepaliveTimerExpires,
+            12 => FsmEvent::DelayOpenTimerExpires,
+            13 => FsmEvent::IdleHoldTimerExpires,
+            14 => FsmEvent::TcpConnectionValid,
+            15 => FsmEvent::TcpCrInvalid,
+            16 => FsmEvent::TcpCrAcked,
+            17 => FsmEvent::TcpConnectionConfirmed,
+            18 => FsmEvent::TcpConnectionFails,
+            19 => FsmEvent::BgpOpen,
+            20 => FsmEvent::BgpOpenWithDelayOpenTimerRunning,
+            21 => FsmEvent::BgpHeaderErr,
+            22 => FsmEvent::BgpOpenMsgErr,
+            23 => FsmEvent::OpenCollisionDump,
+            24 => FsmEvent::NotifMsgVerErr,
+            25 => FsmEvent::NotifMsg,
+            26 => FsmEvent::KeepAliveMsg,
+            27 => FsmEvent::UpdateMsg,
+            28 => FsmEvent::UpdateMsgErr,
+
+            v => FsmEvent::Unknown(v),
+        }
+    }
+}
+
+impl FsmEvent {
+    /// The event number
+    pub fn code(&self) -> u16 {
+        match self {
+            FsmEvent::Unspecified => 0,
+            FsmEvent::ManualStart => 1,
+            FsmEvent::ManualStop => 2,
+            FsmEvent::AutomaticStart => 3,
+            FsmEvent::ManualStartWithPassiveTcpEstablishment => 4,
+            FsmEvent::AutomaticStartWithPassiveTcpEstablishment => 5,
+            FsmEvent::AutomaticStartWithDampPeerOscillations => 6,
+            FsmEvent::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment => 7,
+            FsmEvent::AutomaticStop => 8,
+            FsmEvent::ConnectRetryTimerExpires => 9,
+            FsmEvent::HoldTimerExpires => 10,
+            FsmEvent::KeepaliveTimerExpires => 11,
+            FsmEvent::DelayOpenTimerExpires => 12,
+            FsmEvent::IdleHoldTimerExpires => 13,
+            FsmEvent::TcpConnectionValid => 14,
+            FsmEvent::TcpCrInvalid => 15,
+            FsmEvent::TcpCrAcked => 16,
+            FsmEvent::TcpConnectionConfirmed => 17,
+            FsmEvent::TcpConnectionFails => 18,
+            FsmEvent::BgpOpen => 19,
+            FsmEvent::BgpOpenWithDelayOpenTimerRunning => 20,
+            FsmEvent::BgpHeaderErr => 21,
+            FsmEvent::BgpOpenMsgErr => 22,
+            FsmEvent::OpenCollisionDump => 23,
+            FsmEvent::NotifMsgVerErr => 24,
+            FsmEvent::NotifMsg => 25,
+            FsmEvent::KeepAliveMsg => 26,
+            FsmEvent::UpdateMsg => 27,
+            FsmEvent::UpdateMsgErr => 28,
+            FsmEvent::Unknown(v) => *v,
+        }
+    }
+}
+
+impl fmt::Display for FsmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsmEvent::Unspecified => write!(f, "unspecified"),
+            FsmEvent::ManualStart => write!(f, "manual_start"),
+            FsmEvent::ManualStop => write!(f, "manual_stop"),
+            FsmEvent::AutomaticStart => write!(f, "automatic_start"),
+            FsmEvent::ManualStartWithPassiveTcpEstablishment => write!(f, "manual_start_with_passive_tcp_establishment"),
+            FsmEvent::AutomaticStartWithPassiveTcpEstablishment => write!(f, "automatic_start_with_passive_tcp_establishment"),
+            FsmEvent::AutomaticStartWithDampPeerOscillations => write!(f, "automatic_start_with_damp_peer_oscillations"),
+            FsmEvent::AutomaticStartWithDampPeerOscillationsAndPassiveTcpEstablishment => write!(f, "automatic_start_with_damp_peer_oscillations_and_passive_tcp_establishment"),
+            FsmEvent::AutomaticStop => write!(f, "automatic_stop"),
+            FsmEvent::ConnectRetryTimerExpires => write!(f, "connect_retry_timer_expires"),
+            FsmEvent::HoldTimerExpires => write!(f, "hold_timer_expires"),
+            FsmEvent::KeepaliveTimerExpires => write!(f, "keepalive_timer_expires"),
+            FsmEvent::DelayOpenTimerExpires => write!(f, "delay_open_timer_expires"),
+            FsmEvent::IdleHoldTimerExpires => write!(f, "idle_hold_timer_expires"),
+            FsmEvent::TcpConnectionValid => write!(f, "tcp_connection_valid"),
+            FsmEvent::TcpCrInvalid => write!(f, "tcp_cr_invalid"),
+            FsmEvent::TcpCrAcked => write!(f, "tcp_cr_acked"),
+            FsmEvent::TcpConnectionConfirmed => write!(f, "tcp_connection_confirmed"),
+            FsmEvent::TcpConnectionFails => write!(f, "tcp_connection_fails"),
+            FsmEvent::BgpOpen => write!(f, "bgp_open"),
+            FsmEvent::BgpOpenWithDelayOpenTimerRunning => write!(f, "bgp_open_with_delay_open_timer_running"),
+            FsmEvent::BgpHeaderErr => write!(f, "bgp_header_err"),
+            FsmEvent::BgpOpenMsgErr => write!(f, "bgp_open_msg_err"),
+            FsmEvent::OpenCollisionDump => write!(f, "open_collision_dump"),
+            FsmEvent::NotifMsgVerErr => write!(f, "notif_msg_ver_err"),
+            FsmEvent::NotifMsg => write!(f, "notif_msg"),
+            FsmEvent::KeepAliveMsg => write!(f, "keep_alive_msg"),
+            FsmEvent::UpdateMsg => write!(f, "update_msg"),
+            FsmEvent::UpdateMsgErr => write!(f, "update_msg_err"),
+            FsmEvent::Unknown(v) => write!(f, "event_{}", v),
+        }
+    }
 }
 
 /// Peer Down
@@ -381,9 +939,9 @@ pub enum PeerDown {
     // LocalShutdown(bgp_rs::Notification),
     /// The session was cleanly shutdown
     LocalShutdown(bgp_rs::Notification),
-    /// The session was terminated because the underlying transport session was terminated,
-    /// no NOTIFICATION was sent or received
-    LocalTerminate(u16),
+    /// The session was closed by the local system without a NOTIFICATION, because of the FSM
+    /// event given
+    LocalTerminate(FsmEvent),
     /// The session was cleanly shutdown by the remote peer
     RemoteShutdown(bgp_rs::Notification),
     /// The session was terminated because the underlying transport session was terminated,
@@ -392,10 +950,18 @@ pub enum PeerDown {
     /// The session hasn't necessarily been torn down, but a configuration change on the BMP
     /// speaker means the collector will no longer receive updates for the session
     ConfigurationChange,
+    /// The local system closed the session (typically a Loc-RIB instance going away), with TLVs
+    /// describing it such as the VRF/Table name (RFC9069)
+    LocalClosed(Vec<InformationTlv>),
+    /// A reason we don't know about, with whatever data followed it
+    Unknown(u8, Bytes),
 }
 
 impl PeerDown {
-    pub(super) fn decode(buf: &mut BytesMut) -> Result<Self> {
+    pub(super) fn decode(buf: &mut Bytes) -> Result<Self> {
+        if !buf.has_remaining() {
+            return Err(Error::decode("missing BMP Peer Down reason"));
+        }
         let reason = buf.get_u8();
 
         match reason {
@@ -407,7 +973,8 @@ impl PeerDown {
                 Ok(Self::LocalShutdown(notification))
                 // Ok(Self::LocalShutdown)
             },
-            2 => Ok(Self::LocalTerminate(buf.get_u16())),
+            2 if buf.remaining() >= 2 => Ok(Self::LocalTerminate(buf.get_u16().into())),
+            2 => Err(Error::decode("missing FSM event in BMP Peer Down")),
             3 => {
                 let mut rdr = buf.reader();
                 let header = bgp_rs::Header::parse(&mut rdr)?;
@@ -418,8 +985,37 @@ impl PeerDown {
             },
             4 => Ok(Self::RemoteTerminate),
             5 => Ok(Self::ConfigurationChange),
+            6 => {
+                let mut tlvs = vec![];
+                while buf.has_remaining() {
+                    tlvs.push(InformationTlv::decode(buf)?);
+                }
+
+                Ok(Self::LocalClosed(tlvs))
+            },
 
-            v @ _ => Err(Error::decode(&format!("invalid value for BMP Peer Down reason: {}", v)))
+            v => Ok(Self::Unknown(v, buf.split_to(buf.remaining()))),
+        }
+    }
+
+    /// The Peer Down reason code
+    pub fn reason(&self) -> u8 {
+        match self {
+            Self::LocalShutdown(_) => 1,
+            Self::LocalTerminate(_) => 2,
+            Self::RemoteShutdown(_) => 3,
+            Self::RemoteTerminate => 4,
+            Self::ConfigurationChange => 5,
+            Self::LocalClosed(_) => 6,
+            Self::Unknown(reason, _) => *reason,
+        }
+    }
+
+    /// The NOTIFICATION sent or received, for reasons 1 and 3
+    pub fn notification(&self) -> Option<&bgp_rs::Notification> {
+        match self {
+            Self::LocalShutdown(notification) | Self::RemoteShutdown(notification) => Some(notification),
+            _ => None,
         }
     }
 }
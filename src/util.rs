@@ -0,0 +1,69 @@
+use bytes::BufMut;
+
+#[cfg(any(feature = "pmacct", feature = "openbmp"))]
+use std::net::Ipv4Addr;
+#[cfg(any(feature = "pmacct", feature = "openbmp"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Format a timestamp as "YYYY-MM-DD HH:MM:SS.ffffff" (UTC)
+#[cfg(any(feature = "pmacct", feature = "openbmp"))]
+pub(crate) fn format_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date (http://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+        year, month, day, secs / 3600, (secs % 3600) / 60, secs % 60, since_epoch.subsec_micros()
+    )
+}
+
+/// Format a Route Distinguisher (RFC4364 Section 4.2)
+#[cfg(any(feature = "pmacct", feature = "openbmp"))]
+pub(crate) fn format_rd((high, low): (u32, u32)) -> String {
+    match high >> 16 {
+        0 => format!("{}:{}", high & 0xffff, low),
+        1 => format!("{}:{}", Ipv4Addr::from((high << 16) | (low >> 16)), low & 0xffff),
+        2 => format!("{}:{}", ((high & 0xffff) << 16) | (low >> 16), low & 0xffff),
+        _ => format!("{:08x}{:08x}", high, low),
+    }
+}
+
+/// Append a BGP path attribute, using the extended length flag if the value needs it
+pub(crate) fn put_attribute(buf: &mut Vec<u8>, flags: u8, kind: u8, value: &[u8]) {
+    if value.len() > usize::from(u8::MAX) {
+        buf.put_u8(flags | 0x10);
+        buf.put_u8(kind);
+        buf.put_u16(value.len() as u16);
+    } else {
+        buf.put_u8(flags & !0x10);
+        buf.put_u8(kind);
+        buf.put_u8(value.len() as u8);
+    }
+    buf.put_slice(value);
+}
+
+#[cfg(all(test, any(feature = "pmacct", feature = "openbmp")))]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_582_977_600) + Duration::from_micros(123_456);
+        assert_eq!(format_time(time), "2020-02-29 12:00:00.123456");
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00.000000");
+    }
+}
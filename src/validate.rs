@@ -0,0 +1,250 @@
+//! Pre-flight checks for BGP UPDATEs
+//!
+//! `bgp_rs::Update::parse` trusts some of the lengths inside MP_REACH_NLRI / MP_UNREACH_NLRI and
+//! will panic (underflow, out of range slices, `unimplemented!()`) rather than return an error when
+//! they're wrong. We walk the message first and reject anything it can't cope with, everything
+//! else is left for bgp-rs to decode (or error on) as usual.
+
+use crate::{Error, Result};
+
+const BGP_HEADER_LEN: usize = 19;
+
+const AFI_IPV4: u16 = 1;
+const AFI_IPV6: u16 = 2;
+const AFI_L2VPN: u16 = 25;
+const AFI_BGPLS: u16 = 16388;
+
+const SAFI_MPLS: u8 = 4;
+const SAFI_MPLS_VPN: u8 = 128;
+
+// Label / RD bits that precede the prefix in labelled and VPN NLRI
+const LABEL_BITS: u8 = 24;
+const RD_BITS: u8 = 64;
+
+/// Check that a BGP UPDATE (including the BGP header) is safe to hand to `bgp_rs::Update::parse`
+pub(crate) fn check_update(msg: &[u8]) -> Result<()> {
+    let length = match msg.get(16..18) {
+        Some(length) => usize::from(u16::from_be_bytes([length[0], length[1]])),
+        None => return Ok(()),
+    };
+    let body = match msg.get(BGP_HEADER_LEN..length.min(msg.len())) {
+        Some(body) => body,
+        None => return Ok(()),
+    };
+
+    let withdrawn = match read_u16(body, 0) {
+        Some(withdrawn) => 2 + withdrawn,
+        None => return Ok(()),
+    };
+    match read_u16(body, withdrawn) {
+        Some(attr_len) => {
+            let start = withdrawn + 2;
+            let end = (start + attr_len).min(body.len());
+            check_attributes(&body[start..end])
+        },
+        None => Ok(()),
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<usize> {
+    buf.get(offset..offset + 2).map(|v| usize::from(u16::from_be_bytes([v[0], v[1]])))
+}
+
+fn check_attributes(mut attrs: &[u8]) -> Result<()> {
+    while attrs.len() >= 3 {
+        let (flags, code) = (attrs[0], attrs[1]);
+        let (header, length) = if flags & 0x10 == 0 {
+            (3, usize::from(attrs[2]))
+        } else {
+            match read_u16(attrs, 2) {
+                Some(length) => (4, length),
+                None => break,
+            }
+        };
+        let value = match attrs.get(header..header + length) {
+            Some(value) => value,
+            None => break,
+        };
+
+        match code {
+            14 => check_mp_reach(value)?,
+            15 => check_mp_unreach(value)?,
+            // ATTR_SET holds an origin AS and a nested set of attributes
+            128 if value.len() >= 4 => check_attributes(&value[4..])?,
+            _ => {},
+        };
+        attrs = &attrs[header + length..];
+    }
+
+    Ok(())
+}
+
+fn check_mp_reach(value: &[u8]) -> Result<()> {
+    if value.len() < 5 {
+        return Ok(());
+    }
+    let afi = u16::from_be_bytes([value[0], value[1]]);
+    let next_hop_len = value[3];
+
+    if next_hop_len > 250 {
+        return Err(Error::decode(&format!("invalid MP_REACH_NLRI next hop length {}", next_hop_len)));
+    }
+
+    let nlri = value.get(5 + usize::from(next_hop_len)..).unwrap_or_default();
+    match afi {
+        AFI_IPV4 | AFI_IPV6 => check_nlri(afi, value[2], nlri),
+        AFI_BGPLS => Err(Error::decode("unsupported MP_REACH_NLRI AFI BGP-LS")),
+        _ => Ok(()),
+    }
+}
+
+fn check_mp_unreach(value: &[u8]) -> Result<()> {
+    if value.len() <= 3 {
+        return Ok(());
+    }
+    let afi = u16::from_be_bytes([value[0], value[1]]);
+
+    match afi {
+        AFI_IPV4 | AFI_IPV6 => check_nlri(afi, value[2], &value[3..]),
+        AFI_L2VPN | AFI_BGPLS => Err(Error::decode(&format!("unsupported MP_UNREACH_NLRI AFI {}", afi))),
+        _ => Ok(()),
+    }
+}
+
+/// Labelled and VPN NLRI need enough bits for the label (and RD), and no more than fit in an
+/// address
+fn check_nlri(afi: u16, safi: u8, nlri: &[u8]) -> Result<()> {
+    let max_bits = if afi == AFI_IPV4 { 32 } else { 128 };
+    let min_bits = match safi {
+        SAFI_MPLS => LABEL_BITS,
+        SAFI_MPLS_VPN => LABEL_BITS + RD_BITS,
+        _ => return Ok(()),
+    };
+
+    let mut offset = 0;
+    while offset < nlri.len() {
+        // bgp-rs guesses at ADD-PATH for labelled routes, and only for those
+        if safi == SAFI_MPLS && looks_like_add_path(nlri, offset) {
+            offset += 4;
+        }
+
+        let len_bits = match nlri.get(offset) {
+            Some(len_bits) => *len_bits,
+            None => break,
+        };
+        if len_bits < min_bits || u16::from(len_bits) > u16::from(min_bits) + max_bits {
+            return Err(Error::decode(&format!("invalid labelled prefix length {}", len_bits)));
+        }
+        offset += 1 + usize::from(len_bits).div_ceil(8);
+    }
+
+    Ok(())
+}
+
+/// The ADD-PATH heuristic bgp-rs uses for labelled NLRI (`util::detect_add_path_prefix`, with a
+/// maximum prefix length of 255), so we read the NLRI the same way it will
+fn looks_like_add_path(nlri: &[u8], start: usize) -> bool {
+    let end = nlri.len();
+    let trailing_bits = |len_bits: u8, last: u8| !len_bits.is_multiple_of(8) && last & (0xff >> (len_bits % 8)) > 0;
+
+    // Does it parse as prefixes with path IDs in front?
+    let mut offset = start + 4;
+    while offset < end {
+        let len_bits = nlri[offset];
+        offset += 1 + usize::from(len_bits).div_ceil(8);
+        if offset > end || trailing_bits(len_bits, nlri[offset - 1]) {
+            return false;
+        }
+        offset += 4;
+    }
+
+    // And if so, does it parse without them?
+    let mut offset = start;
+    while offset < end {
+        let len_bits = nlri[offset];
+        if len_bits == 0 && end - (offset + 1) > 0 {
+            return true;
+        }
+        offset += 1 + usize::from(len_bits).div_ceil(8);
+        if offset > end || trailing_bits(len_bits, nlri[offset - 1]) {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::util::put_attribute;
+
+    fn update(attrs: &[u8]) -> Vec<u8> {
+        let mut msg = vec![0xff; 16];
+        msg.extend_from_slice(&(23 + attrs.len() as u16).to_be_bytes());
+        msg.push(2);
+        msg.extend_from_slice(&[0, 0]);
+        msg.extend_from_slice(&(attrs.len() as u16).to_be_bytes());
+        msg.extend_from_slice(attrs);
+        msg
+    }
+
+    fn mp_reach(afi: u16, safi: u8, nlri: &[u8]) -> Vec<u8> {
+        let mut value = afi.to_be_bytes().to_vec();
+        value.extend_from_slice(&[safi, 4, 192, 0, 2, 1, 0]);
+        value.extend_from_slice(nlri);
+
+        let mut attrs = vec![];
+        put_attribute(&mut attrs, 0x80, 14, &value);
+        attrs
+    }
+
+    fn parses(msg: &[u8]) -> bool {
+        let mut rdr = msg;
+        let header = bgp_rs::Header::parse(&mut rdr).unwrap();
+        bgp_rs::Update::parse(&header, &mut rdr, &Default::default()).is_ok()
+    }
+
+    #[test]
+    fn test_check_update() {
+        // Labelled 10.0.0.0/8 and VPN 10.1.0.0/16 are fine
+        let msg = update(&mp_reach(1, 4, &[32, 0, 1, 0x01, 10]));
+        assert!(check_update(&msg).is_ok());
+        assert!(parses(&msg));
+
+        let msg = update(&mp_reach(1, 128, &[104, 0, 1, 0x01, 0, 0, 0xfd, 0xe8, 0, 0, 0, 1, 10, 1]));
+        assert!(check_update(&msg).is_ok());
+        assert!(parses(&msg));
+
+        // Labelled prefix lengths that don't leave room for the label (the trailing 200 stops
+        // it looking like ADD-PATH)
+        assert!(check_update(&update(&mp_reach(1, 4, &[16, 0, 1, 0x01, 200]))).is_err());
+        assert!(check_update(&update(&mp_reach(1, 4, &[20, 0, 1, 0x01, 10, 200]))).is_err());
+        // Or are too long for the address family
+        assert!(check_update(&update(&mp_reach(1, 4, &[64, 0, 1, 0x01, 10, 0, 0, 0, 0]))).is_err());
+        // VPN prefixes without the RD
+        assert!(check_update(&update(&mp_reach(2, 128, &[48, 0, 1, 0x01, 0x20, 0x01, 0x0d]))).is_err());
+
+        // Next hop lengths that overflow
+        let mut value = vec![0, 1, 1, 251];
+        value.extend_from_slice(&[0; 252]);
+        let mut attrs = vec![];
+        put_attribute(&mut attrs, 0x80, 14, &value);
+        assert!(check_update(&update(&attrs)).is_err());
+
+        // Nested inside an ATTR_SET
+        let mut value = vec![0, 0, 0xfd, 0xe8];
+        value.extend_from_slice(&mp_reach(1, 4, &[16, 0, 1, 0x01, 200]));
+        let mut attrs = vec![];
+        put_attribute(&mut attrs, 0xc0, 128, &value);
+        assert!(check_update(&update(&attrs)).is_err());
+
+        // BGP-LS isn't supported
+        assert!(check_update(&update(&mp_reach(16388, 71, &[]))).is_err());
+
+        // Plain unicast and garbage are left to bgp-rs
+        assert!(check_update(&update(&mp_reach(1, 1, &[8, 10]))).is_ok());
+        assert!(check_update(&[0xff; 12]).is_ok());
+    }
+}